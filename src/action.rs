@@ -1,7 +1,21 @@
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Action {
     Delete,
     Replace,
+    Yank,
+    Indent,
+    Dedent,
+    /// `gu`, `gU` and `g~`
+    ChangeCase(Case),
+    /// `gc`, which comments the lines or uncomments them if they all are comments
+    ToggleComment,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Case {
+    Lower,
+    Upper,
+    Toggle,
 }
 
 
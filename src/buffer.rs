@@ -1,14 +1,99 @@
+use regex::Regex;
 use ropey::Rope;
+use std::collections::{BTreeMap, HashMap};
+use std::ops::RangeInclusive;
+use std::time::SystemTime;
+
+use crate::action::Case;
+use crate::highlight::HighlightCache;
+use crate::io::{delete_swap, Encoding, LineEnding};
+use crate::motion::{get_character_class, CharacterClass};
+use crate::state::{ModeChange, Options};
 
 pub type Cursor = usize;
 
-pub fn cursor_add(cursor: Cursor, value: i32) -> Cursor {
-    return (cursor as i32 + value).max(0) as Cursor;
+/// How `:sort` orders lines
+#[derive(Debug, Clone, Copy, Default)]
+pub struct SortOptions {
+    pub reverse: bool,
+    /// order by the first number in the line instead of the text
+    pub numeric: bool,
+    /// keep only the first of lines that compare equal
+    pub unique: bool,
+    pub ignore_case: bool,
+}
+
+/// A glyph shown in the sign column, left of the line numbers
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sign {
+    /// the line has the mark of the name
+    Mark(char),
+    /// the line has the search match the cursor is on
+    SearchMatch,
+}
+
+impl Sign {
+    pub fn get_glyph(&self) -> char {
+        match self {
+            Sign::Mark(name) => *name,
+            Sign::SearchMatch => '»',
+        }
+    }
 }
 
 pub struct Buffer {
     pub text: Rope,
     pub cursor: Cursor,
+    /// secondary cursors, which typing in insert mode, `x` and motions apply at as well. other
+    ///     operators, pastes and visual mode only use the primary cursor
+    pub cursors: Vec<Cursor>,
+    /// file the buffer was loaded from and is written to
+    pub filepath: Option<String>,
+    /// column that vertical movement tries to return to, cleared by horizontal movement and edits
+    pub desired_column: Option<usize>,
+    /// line ending written in place of '\n' when saving
+    pub line_ending: LineEnding,
+    /// encoding the text is written in when saving
+    pub encoding: Encoding,
+    /// whether edits and writes are refused, as for files that cannot be written
+    pub read_only: bool,
+    /// whether an edit was refused because the buffer is read-only, until the flag is taken
+    pub refused_edit: bool,
+    /// number of edits made to the text, to tell whether it changed
+    pub version: usize,
+    /// modification time of the file when it was last loaded or written, to notice changes made
+    ///     by other programs
+    pub file_time: Option<SystemTime>,
+    /// whether there are changes that have not been saved
+    pub modified: bool,
+    /// version of the text last written to the swap file, None if the editor has not written one
+    pub swap_version: Option<usize>,
+    /// the end of the selection opposite to the cursor, in visual mode
+    pub selection_anchor: Option<Cursor>,
+    /// parse states of the highlighted lines, invalidated by edits
+    pub highlight_cache: HighlightCache,
+    /// positions set with `m`, which move along with the text around them
+    pub marks: HashMap<char, Cursor>,
+    /// first and last character of the last visual selection, which are the marks `<` and `>`.
+    ///     they move along with the text like marks, but have no signs
+    pub last_selection: Option<(Cursor, Cursor)>,
+    /// signs of the lines that have any, the first of which is shown in the sign column
+    pub signs: BTreeMap<usize, Vec<Sign>>,
+    /// matches of the search that is shown, found again only when the search or the text changes
+    pub search_cache: SearchCache,
+}
+
+/// The matches of a search, kept along with the query and the search options the pattern was
+///     compiled with and the version of the text it was matched against
+#[derive(Default)]
+pub struct SearchCache {
+    query: String,
+    /// `ignore_case`, `smart_case` and `magic`
+    options: (bool, bool, bool),
+    pattern: Option<Regex>,
+    /// None until the matches are found, and after the text is replaced
+    version: Option<usize>,
+    matches: Vec<(Cursor, Cursor)>,
 }
 
 pub struct Viewport {
@@ -16,43 +101,1748 @@ pub struct Viewport {
 }
 
 impl Buffer {
+    pub fn new(text: Rope, filepath: Option<String>) -> Self {
+        Buffer {
+            text,
+            cursor: 0,
+            cursors: Vec::new(),
+            filepath,
+            desired_column: None,
+            line_ending: LineEnding::Lf,
+            encoding: Encoding::Utf8,
+            read_only: false,
+            refused_edit: false,
+            version: 0,
+            file_time: None,
+            modified: false,
+            swap_version: None,
+            selection_anchor: None,
+            highlight_cache: HighlightCache::default(),
+            marks: HashMap::new(),
+            last_selection: None,
+            signs: BTreeMap::new(),
+            search_cache: SearchCache::default(),
+        }
+    }
+
+    /// Return the position of the mark, where `<` and `>` are the ends of the last selection
+    pub fn get_mark(&self, name: char) -> Option<Cursor> {
+        match name {
+            '<' => self.last_selection.map(|(start, _)| start),
+            '>' => self.last_selection.map(|(_, end)| end),
+            _ => self.marks.get(&name).copied(),
+        }
+    }
+
+    /// Place the signs again for the marks and the search match the cursor is on, if any
+    pub fn update_signs(&mut self, search_match: Option<Cursor>) {
+        self.signs.clear();
+        if let Some(cursor) = search_match {
+            let line = self.text.char_to_line(cursor.min(self.text.len_chars()));
+            self.signs.entry(line).or_default().push(Sign::SearchMatch);
+        }
+
+        let mut marks: Vec<(char, Cursor)> = self.marks.iter().map(|(n, c)| (*n, *c)).collect();
+        marks.sort();
+        for (name, cursor) in marks {
+            let line = self.text.char_to_line(cursor.min(self.text.len_chars()));
+            self.signs.entry(line).or_default().push(Sign::Mark(name));
+        }
+    }
+
+    /// Return the name shown for the buffer, the file name or `[No Name]`
+    pub fn get_name(&self) -> String {
+        match &self.filepath {
+            Some(filepath) => std::path::Path::new(filepath)
+                .file_name()
+                .map_or(filepath.clone(), |name| name.to_string_lossy().into_owned()),
+            None => String::from("[No Name]"),
+        }
+    }
+
+    /// Return the extension of the file, which decides how it is highlighted
+    pub fn get_extension(&self) -> &str {
+        self.filepath
+            .as_deref()
+            .and_then(|filepath| std::path::Path::new(filepath).extension())
+            .and_then(|extension| extension.to_str())
+            .unwrap_or("")
+    }
+
+    /// Replace the whole text with an unmodified one, as when reloading the file. the cursor stays
+    ///     on the same line and column where the new text allows
+    pub fn replace_text(&mut self, text: Rope) {
+        let line = self.text.char_to_line(self.cursor);
+        let column = self.find_line_position(self.cursor);
+
+        self.text = text;
+        self.cursor = self.get_cursor_at(line.min(self.get_last_line()), column);
+        self.cursors.clear();
+        self.desired_column = None;
+        self.selection_anchor = None;
+        self.modified = false;
+        self.highlight_cache.invalidate(0);
+        self.search_cache.version = None;
+    }
+
+    /// Delete the swap file if it was written for this buffer, leaving those of other instances
+    pub fn remove_swap_file(&mut self) {
+        if let (Some(_), Some(filepath)) = (self.swap_version.take(), &self.filepath) {
+            delete_swap(filepath);
+        }
+    }
+
+    /// Insert the text at the char index, marking the buffer as modified. nothing is inserted
+    ///     into a read-only buffer
+    pub fn insert(&mut self, position: Cursor, text: &str) {
+        if self.read_only {
+            self.refused_edit = true;
+            return;
+        }
+        self.highlight_cache
+            .invalidate(self.text.char_to_line(position));
+        self.text.insert(position, text);
+        self.version += 1;
+        let length = text.chars().count();
+        let selection = self
+            .last_selection
+            .iter_mut()
+            .flat_map(|(start, end)| [start, end]);
+        for mark in self
+            .marks
+            .values_mut()
+            .chain(selection)
+            .filter(|mark| **mark >= position)
+        {
+            *mark += length;
+        }
+        for cursor in self
+            .cursors
+            .iter_mut()
+            .filter(|cursor| **cursor >= position)
+        {
+            *cursor += length;
+        }
+        self.modified = true;
+        self.desired_column = None;
+    }
+
+    /// Remove the chars in the range, marking the buffer as modified. nothing is removed from a
+    ///     read-only buffer
+    pub fn remove(&mut self, range: std::ops::Range<Cursor>) {
+        if range.is_empty() {
+            return;
+        }
+        if self.read_only {
+            self.refused_edit = true;
+            return;
+        }
+        self.highlight_cache
+            .invalidate(self.text.char_to_line(range.start));
+        let selection = self
+            .last_selection
+            .iter_mut()
+            .flat_map(|(start, end)| [start, end]);
+        for mark in self
+            .marks
+            .values_mut()
+            .chain(selection)
+            .filter(|mark| **mark > range.start)
+        {
+            // marks in the removed text move to where it was
+            *mark = range.start.max(*mark - range.len().min(*mark));
+        }
+        for cursor in self
+            .cursors
+            .iter_mut()
+            .filter(|cursor| **cursor > range.start)
+        {
+            *cursor = range.start.max(*cursor - range.len().min(*cursor));
+        }
+        self.text.remove(range);
+        self.version += 1;
+        self.modified = true;
+        self.desired_column = None;
+    }
+
+    /// Remove the chars in the range and return them, for the register, or None if the range is
+    ///     empty
+    pub fn cut(&mut self, range: std::ops::Range<Cursor>) -> Option<String> {
+        let text = (!range.is_empty()).then(|| self.text.slice(range.clone()).to_string());
+        self.remove(range);
+        text
+    }
+
+    /// Move the cursors back into the text if it got shorter under them, and off the newline at the
+    ///     end of their line
+    pub fn clamp_cursor(&mut self) {
+        let clamp = |buffer: &Buffer, cursor: Cursor| {
+            let cursor = cursor.min(buffer.text.len_chars());
+            let line = buffer.text.char_to_line(cursor);
+            let column = buffer.find_line_position(cursor);
+            buffer.get_cursor_at(line.min(buffer.get_last_line()), column)
+        };
+        self.cursor = clamp(self, self.cursor);
+        for index in 0..self.cursors.len() {
+            self.cursors[index] = clamp(self, self.cursors[index]);
+        }
+        self.merge_cursors();
+        if let Some(anchor) = self.selection_anchor {
+            self.selection_anchor = Some(anchor.min(self.text.len_chars().max(1) - 1));
+        }
+    }
+
+    /// Run the function with each cursor in turn as the primary one, so that an edit is made at
+    ///     all of them. the cursors that are not being edited at move along with the text
+    pub fn at_each_cursor(&mut self, mut function: impl FnMut(&mut Buffer)) {
+        function(self);
+        for index in 0..self.cursors.len() {
+            // the primary cursor is kept with the secondary ones meanwhile, so that it is moved
+            //     by the edits too
+            std::mem::swap(&mut self.cursor, &mut self.cursors[index]);
+            self.desired_column = None;
+            function(self);
+            std::mem::swap(&mut self.cursor, &mut self.cursors[index]);
+        }
+        self.merge_cursors();
+    }
+
+    /// Remove the secondary cursors that ended up at the same position as another cursor
+    pub fn merge_cursors(&mut self) {
+        let primary = self.cursor;
+        self.cursors.retain(|cursor| *cursor != primary);
+        self.cursors.sort_unstable();
+        self.cursors.dedup();
+    }
+
+    /// Add a secondary cursor at the next occurrence of the word under the primary cursor, after
+    ///     the cursors that were added before. return false if there is no other occurrence
+    pub fn add_cursor_at_next_occurrence(&mut self) -> bool {
+        let is_word = |position: Cursor| {
+            self.text.get_char(position).is_some_and(|character| {
+                get_character_class(character, false) == CharacterClass::Word
+            })
+        };
+        if !is_word(self.cursor) {
+            return false;
+        }
+        let mut start = self.cursor;
+        while start > 0 && is_word(start - 1) {
+            start -= 1;
+        }
+        let mut end = self.cursor;
+        while is_word(end) {
+            end += 1;
+        }
+
+        // only whole words count as occurrences
+        let word = self.text.slice(start..end).to_string();
+        let offset = self.cursor - start;
+        let occurrences: Vec<Cursor> = self
+            .find_matches(&Regex::new(&regex::escape(&word)).unwrap())
+            .into_iter()
+            .filter(|(start, end)| (*start == 0 || !is_word(start - 1)) && !is_word(*end))
+            .map(|(start, _)| start + offset)
+            .collect();
+
+        let last = self.cursors.iter().copied().max().unwrap_or(self.cursor);
+        let next = occurrences
+            .iter()
+            .chain(occurrences.iter())
+            .skip_while(|occurrence| **occurrence <= last)
+            .find(|occurrence| **occurrence != self.cursor && !self.cursors.contains(occurrence));
+        match next {
+            Some(occurrence) => {
+                self.cursors.push(*occurrence);
+                true
+            }
+            None => false,
+        }
+    }
+
     pub fn find_line_position(&self, cursor: Cursor) -> usize {
         // find the char index of the cursor within the current line
-        let line = self.text.byte_to_line(cursor);
+        let line = self.text.char_to_line(cursor);
         let line_start = self.text.line_to_char(line);
         cursor - line_start
     }
 
-    pub fn get_movement_x(&self, cursor: Cursor, x: i32) -> Cursor {
-        // move the cursor in by x. positive x -> move right; negative -> move left.
-        //      automatically moves across lines when the end of line is reache
-        (cursor as i64 + x as i64).clamp(0, self.text.len_chars() as i64 - 1) as Cursor
+    /// Return the column the cursor is displayed at, with tabs extending to the next tab stop
+    pub fn get_display_column(&self, cursor: Cursor, tab_stop: usize) -> usize {
+        let line_start = self.text.line_to_char(self.text.char_to_line(cursor));
+        self.text
+            .slice(line_start..cursor)
+            .chars()
+            .fold(0, |column, character| match character {
+                '\t' => column + tab_stop - column % tab_stop,
+                _ => column + 1,
+            })
     }
 
-    pub fn move_x(&mut self, x: i32) {
-        self.cursor = self.get_movement_x(self.cursor, x);
+    /// Return the cursor on the line at the character displayed at the column
+    pub fn get_cursor_at_display_column(
+        &self,
+        line: usize,
+        column: usize,
+        tab_stop: usize,
+    ) -> Cursor {
+        let mut display_column = 0;
+        let mut character_column = 0;
+        for character in self
+            .text
+            .line(line)
+            .chars()
+            .take(self.get_line_length(line))
+        {
+            display_column += match character {
+                '\t' => tab_stop - display_column % tab_stop,
+                _ => 1,
+            };
+            if display_column > column {
+                break;
+            }
+            character_column += 1;
+        }
+        self.get_cursor_at(line, character_column)
     }
 
-    pub fn get_movement_y(&self, cursor: Cursor, y: i32) -> Cursor {
-        let current_y = self.text.byte_to_line(cursor);
-        let new_y =
-            (current_y as i64 + y as i64).clamp(0, (self.text.len_lines() - 1) as i64) as Cursor;
-        let current_x = self.find_line_position(cursor);
+    /// Return the starts of the display lines the line wraps into, so that none of them is wider
+    ///     than width columns. the first one is the start of the line
+    pub fn get_wrap_starts(&self, line: usize, width: usize, tab_stop: usize) -> Vec<Cursor> {
+        let line_start = self.text.line_to_char(line);
+        let mut starts = vec![line_start];
+        let mut column = 0;
+        let mut start_column = 0;
+        let characters = self
+            .text
+            .line(line)
+            .chars()
+            .take(self.get_line_length(line));
+        for (offset, character) in characters.enumerate() {
+            let character_width = match character {
+                '\t' => tab_stop - column % tab_stop,
+                _ => 1,
+            };
+            // a character wider than the whole width still gets a display line of its own
+            if column > start_column && column + character_width - start_column > width {
+                starts.push(line_start + offset);
+                start_column = column;
+            }
+            column += character_width;
+        }
+        starts
+    }
+
+    /// Return the display line within the cursor's line and the column within that display line
+    ///     that the cursor is shown at, when lines wrap at width columns
+    pub fn get_wrapped_position(
+        &self,
+        cursor: Cursor,
+        width: usize,
+        tab_stop: usize,
+    ) -> (usize, usize) {
+        let line = self.text.char_to_line(cursor);
+        let starts = self.get_wrap_starts(line, width, tab_stop);
+        let row = starts.partition_point(|start| *start <= cursor) - 1;
+        let start_column = self.get_display_column(starts[row], tab_stop);
+        (
+            row,
+            self.get_display_column(cursor, tab_stop) - start_column,
+        )
+    }
+
+    /// Return the cursor at the column of a display line of the line, or at the last character of
+    ///     that display line if it is shorter
+    pub fn get_cursor_at_wrapped_position(
+        &self,
+        line: usize,
+        row: usize,
+        column: usize,
+        width: usize,
+        tab_stop: usize,
+    ) -> Cursor {
+        let starts = self.get_wrap_starts(line, width, tab_stop);
+        let row = row.min(starts.len() - 1);
+        let start_column = self.get_display_column(starts[row], tab_stop);
+        let cursor = self.get_cursor_at_display_column(line, start_column + column, tab_stop);
+        match starts.get(row + 1) {
+            Some(next_start) => cursor.min(next_start - 1),
+            None => cursor,
+        }
+    }
+
+    /// Return the cursor moved by count display lines, down or up if count is negative, keeping
+    ///     its column within the display line, as `gj` and `gk` do
+    pub fn get_display_line_movement(
+        &self,
+        cursor: Cursor,
+        count: i32,
+        width: usize,
+        tab_stop: usize,
+    ) -> Cursor {
+        let mut line = self.text.char_to_line(cursor);
+        let (mut row, column) = self.get_wrapped_position(cursor, width, tab_stop);
+        let row_count = |line: usize| self.get_wrap_starts(line, width, tab_stop).len();
+        for _ in 0..count.unsigned_abs() {
+            if count > 0 && row + 1 < row_count(line) {
+                row += 1;
+            } else if count > 0 && line < self.get_last_line() {
+                line += 1;
+                row = 0;
+            } else if count < 0 && row > 0 {
+                row -= 1;
+            } else if count < 0 && line > 0 {
+                line -= 1;
+                row = row_count(line) - 1;
+            } else {
+                break;
+            }
+        }
+        self.get_cursor_at_wrapped_position(line, row, column, width, tab_stop)
+    }
+
+    /// Return the range of the selection between the anchor and the cursor, including both ends
+    pub fn get_selection(&self) -> Option<(Cursor, Cursor)> {
+        let anchor = self.selection_anchor?;
+        let start = anchor.min(self.cursor);
+        let end = (anchor.max(self.cursor) + 1).min(self.text.len_chars());
+        Some((start, end))
+    }
+
+    /// Return the range of the word, run of punctuation or whitespace the cursor is on
+    pub fn get_word_range(&self, cursor: Cursor, big_word: bool) -> (Cursor, Cursor) {
+        let line = self.text.char_to_line(cursor);
+        let line_start = self.text.line_to_char(line);
+        let line_end = line_start + self.get_line_length(line);
+        if cursor >= line_end {
+            return (cursor, cursor);
+        }
+
+        let class = |character: char| get_character_class(character, big_word);
+        let cursor_class = class(self.text.char(cursor));
+
+        let mut start = cursor;
+        while start > line_start && class(self.text.char(start - 1)) == cursor_class {
+            start -= 1;
+        }
+        let mut end = cursor + 1;
+        while end < line_end && class(self.text.char(end)) == cursor_class {
+            end += 1;
+        }
+        (start, end)
+    }
+
+    /// Return the lines and the display columns of the block between the anchor and the cursor in
+    ///     visual block mode, both as inclusive ranges
+    pub fn get_block(
+        &self,
+        tab_stop: usize,
+    ) -> Option<(RangeInclusive<usize>, RangeInclusive<usize>)> {
+        let anchor = self.selection_anchor?;
+        let [anchor_line, line] =
+            [anchor, self.cursor].map(|cursor| self.text.char_to_line(cursor));
+        let [anchor_column, column] =
+            [anchor, self.cursor].map(|cursor| self.get_display_column(cursor, tab_stop));
+        Some((
+            anchor_line.min(line)..=anchor_line.max(line),
+            anchor_column.min(column)..=anchor_column.max(column),
+        ))
+    }
+
+    /// Return the characters of the line that are displayed in the columns of a block, which is
+    ///     empty at the end of the line if the line ends before the block
+    pub fn get_block_row(
+        &self,
+        line: usize,
+        columns: RangeInclusive<usize>,
+        tab_stop: usize,
+    ) -> std::ops::Range<Cursor> {
+        let line_end = self.text.line_to_char(line) + self.get_line_length(line);
+        if self.get_display_column(line_end, tab_stop) <= *columns.start() {
+            return line_end..line_end;
+        }
+        let start = self.get_cursor_at_display_column(line, *columns.start(), tab_stop);
+        let end = self.get_cursor_at_display_column(line, *columns.end(), tab_stop) + 1;
+        start..end.min(line_end)
+    }
+
+    /// Return the range of the word the cursor is on for `iw`, or for `aw` the word along with
+    ///     the whitespace after it, or before it if there is none after
+    pub fn get_word_object_range(
+        &self,
+        cursor: Cursor,
+        big_word: bool,
+        around: bool,
+    ) -> (Cursor, Cursor) {
+        let (start, end) = self.get_word_range(cursor, big_word);
+        if !around || start == end {
+            return (start, end);
+        }
+
+        let line = self.text.char_to_line(cursor);
+        let line_start = self.text.line_to_char(line);
+        let line_end = line_start + self.get_line_length(line);
+        let is_whitespace = |position: Cursor| self.text.char(position).is_whitespace();
+
+        if is_whitespace(start) {
+            // on whitespace, the word after it is included instead
+            if end < line_end {
+                return (start, self.get_word_range(end, big_word).1);
+            }
+            (start, end)
+        } else if end < line_end && is_whitespace(end) {
+            (start, self.get_word_range(end, big_word).1)
+        } else if start > line_start && is_whitespace(start - 1) {
+            (self.get_word_range(start - 1, big_word).0, end)
+        } else {
+            (start, end)
+        }
+    }
+
+    /// Return the range between the brackets that enclose the cursor, skipping nested pairs.
+    ///     with `inner` the brackets themselves are excluded
+    pub fn get_bracket_range(
+        &self,
+        cursor: Cursor,
+        open: char,
+        close: char,
+        inner: bool,
+    ) -> Option<(Cursor, Cursor)> {
+        if cursor >= self.text.len_chars() {
+            return None;
+        }
+
+        // a closing bracket under the cursor is skipped, so that its own pair is found
+        let start = if self.text.char(cursor) == open {
+            cursor
+        } else {
+            let mut depth = 0;
+            let mut chars = self.text.chars_at(cursor);
+            let mut position = cursor;
+            loop {
+                let character = chars.prev()?;
+                position -= 1;
+                if character == close {
+                    depth += 1;
+                } else if character == open {
+                    if depth == 0 {
+                        break position;
+                    }
+                    depth -= 1;
+                }
+            }
+        };
+
+        let mut depth = 0;
+        let mut end = None;
+        for (offset, character) in self.text.chars_at(start + 1).enumerate() {
+            if character == open {
+                depth += 1;
+            } else if character == close {
+                if depth == 0 {
+                    end = Some(start + 1 + offset);
+                    break;
+                }
+                depth -= 1;
+            }
+        }
+        let end = end?;
+
+        if inner {
+            Some((start + 1, end))
+        } else {
+            Some((start, end + 1))
+        }
+    }
+
+    /// Return the range of the quoted text on the cursor's line that contains the cursor, or of
+    ///     the next one after it. quotes escaped with a backslash are skipped
+    pub fn get_quote_range(
+        &self,
+        cursor: Cursor,
+        quote: char,
+        inner: bool,
+    ) -> Option<(Cursor, Cursor)> {
+        let line = self.text.char_to_line(cursor);
+        let line_start = self.text.line_to_char(line);
+        let line_end = line_start + self.get_line_length(line);
+
+        let mut quotes = Vec::new();
+        let mut escaped = false;
+        for (offset, character) in self.text.slice(line_start..line_end).chars().enumerate() {
+            if character == quote && !escaped {
+                quotes.push(line_start + offset);
+            }
+            escaped = character == '\\' && !escaped;
+        }
 
-        let new_x = current_x.clamp(0, self.text.line(new_y).len_chars() - 1);
-        let new_cursor = self.text.line_to_char(new_y);
+        // quotes pair up from the start of the line
+        let (start, end) = quotes
+            .chunks_exact(2)
+            .map(|pair| (pair[0], pair[1]))
+            .find(|(_, end)| *end >= cursor)?;
 
-        self.get_movement_x(new_cursor, new_x as i32)
+        if inner {
+            Some((start + 1, end))
+        } else {
+            Some((start, end + 1))
+        }
+    }
+
+    /// Delete count characters from the cursor on, but never past the end of the line, and
+    ///     return them. the cursor stays in the same column if the line is still long enough
+    pub fn delete_characters(&mut self, count: usize) -> Option<String> {
+        let line = self.text.char_to_line(self.cursor);
+        let column = self.find_line_position(self.cursor);
+        let line_end = self.text.line_to_char(line) + self.get_line_length(line);
+        let end = (self.cursor + count.max(1)).min(line_end);
+
+        let deleted = (self.cursor < end).then(|| {
+            let text = self.text.slice(self.cursor..end).to_string();
+            self.remove(self.cursor..end);
+            text
+        });
+        self.cursor = self.get_cursor_at(line, column);
+        self.desired_column = None;
+        deleted
+    }
+
+    /// Move the cursor to where inserting starts for the mode change, opening a new line for
+    ///     `o` and `O`. other mode changes leave the cursor where it is
+    pub fn prepare_insert(&mut self, mode_change: &ModeChange) {
+        let line = self.text.char_to_line(self.cursor);
+        let line_end = self.text.line_to_char(line) + self.get_line_length(line);
+        match mode_change {
+            // `a` goes after the character under the cursor, but never onto the next line
+            ModeChange::InsertAfter => self.cursor = (self.cursor + 1).min(line_end),
+            ModeChange::InsertEnd => self.cursor = line_end,
+            ModeChange::InsertStart => self.cursor = self.get_first_non_blank_cursor(line),
+            ModeChange::OpenBelow => {
+                // the new line starts where the newline of the current one is, or at the very end
+                let indentation = self.get_indentation(line);
+                self.insert(line_end, &format!("\n{}", indentation));
+                self.cursor = line_end + 1 + indentation.chars().count();
+            }
+            ModeChange::OpenAbove => {
+                let indentation = self.get_indentation(line);
+                let position = self.text.line_to_char(line);
+                self.insert(position, &format!("{}\n", indentation));
+                self.cursor = position + indentation.chars().count();
+            }
+            _ => {}
+        }
+        self.desired_column = None;
+    }
+
+    /// Type the character over the one under the cursor, as in replace mode, and return the
+    ///     character it replaced. newlines and characters typed at the end of the line are
+    ///     inserted without replacing anything
+    pub fn overwrite_character(&mut self, c: char) -> Option<char> {
+        let line = self.text.char_to_line(self.cursor);
+        let line_end = self.text.line_to_char(line) + self.get_line_length(line);
+        let replaced = (c != '\n' && self.cursor < line_end).then(|| self.text.char(self.cursor));
+        if replaced.is_some() {
+            self.remove(self.cursor..self.cursor + 1);
+        }
+        self.insert(self.cursor, &c.to_string());
+        self.cursor += 1;
+        replaced
+    }
+
+    /// Take back the character typed before the cursor in replace mode, putting back the
+    ///     character it replaced, if there was one
+    pub fn restore_character(&mut self, replaced: Option<char>) {
+        self.cursor -= 1;
+        self.remove(self.cursor..self.cursor + 1);
+        if let Some(character) = replaced {
+            self.insert(self.cursor, &character.to_string());
+        }
+    }
+
+    pub fn get_movement_x(&self, cursor: Cursor, x: i32) -> Cursor {
+        // move the cursor in by x. positive x -> move right; negative -> move left.
+        //      the cursor stays within its line and does not go onto the newline
+        let line = self.text.char_to_line(cursor);
+        let column = self.find_line_position(cursor) as i64 + x as i64;
+        let column = column.clamp(0, self.last_normal_column(line) as i64) as usize;
+        self.text.line_to_char(line) + column
+    }
+
+    pub fn get_movement_y(&self, cursor: Cursor, y: i32) -> Cursor {
+        // move the cursor by y lines, landing as close to the desired column as the line allows
+        let current_y = self.text.char_to_line(cursor);
+        let new_y = (current_y as i64 + y as i64).clamp(0, self.get_last_line() as i64) as usize;
+        let column = self
+            .desired_column
+            .unwrap_or_else(|| self.find_line_position(cursor));
+
+        self.get_cursor_at(new_y, column)
     }
 
     pub fn move_y(&mut self, y: i32) {
+        self.remember_column();
         self.cursor = self.get_movement_y(self.cursor, y);
     }
 
+    /// Store the cursor's current column as the desired column, unless one is already stored
+    pub fn remember_column(&mut self) {
+        if self.desired_column.is_none() {
+            self.desired_column = Some(self.find_line_position(self.cursor));
+        }
+    }
+
+    /// Insert the text after the cursor, or before it if `before` is set. linewise text goes on
+    ///     lines of its own below or above the cursor's line
+    pub fn paste(&mut self, text: &str, linewise: bool, before: bool) {
+        let line = self.text.char_to_line(self.cursor);
+
+        if linewise {
+            let target_line = if before { line } else { line + 1 };
+            let position = self.text.line_to_char(target_line);
+            let length = self.text.len_chars();
+
+            if position == length && length > 0 && self.text.char(length - 1) != '\n' {
+                // pasting below a last line without newline needs to start a new line first
+                self.insert(position, &format!("\n{}", &text[..text.len() - 1]));
+            } else {
+                self.insert(position, text);
+            }
+            self.cursor = self.get_first_non_blank_cursor(target_line);
+        } else {
+            let position = if before || self.get_line_length(line) == 0 {
+                self.cursor
+            } else {
+                (self.cursor + 1).min(self.text.len_chars())
+            };
+            self.insert(position, text);
+            self.cursor = position + text.chars().count() - 1;
+        }
+    }
+
     pub fn get_end_of_line_cursor(&self, cursor: Cursor) -> Cursor {
         let y = self.text.char_to_line(cursor);
-        let line_start = self.text.line_to_byte(y);
-        let line_length = self.text.line(y).len_chars();
-        line_start + line_length - 1
+        self.text.line_to_char(y) + self.last_normal_column(y)
+    }
+
+    /// Return the last column the cursor can be at in normal mode, the last character before the
+    ///     newline. empty lines only have the column 0
+    pub fn last_normal_column(&self, line: usize) -> usize {
+        self.get_line_length(line).max(1) - 1
+    }
+
+    /// Return the index of the last line, ignoring the empty line after a trailing newline
+    pub fn get_last_line(&self) -> usize {
+        let last_line = self.text.len_lines() - 1;
+        if last_line > 0 && self.text.line(last_line).len_chars() == 0 {
+            last_line - 1
+        } else {
+            last_line
+        }
+    }
+
+    /// Return the number of characters in the line, excluding the newline
+    pub fn get_line_length(&self, line: usize) -> usize {
+        let line = self.text.line(line);
+        let length = line.len_chars();
+        if length > 0 && line.char(length - 1) == '\n' {
+            length - 1
+        } else {
+            length
+        }
+    }
+
+    /// Return the cursor at the column of the line, clamped to the last character of the line
+    pub fn get_cursor_at(&self, line: usize, column: usize) -> Cursor {
+        self.text.line_to_char(line) + column.min(self.last_normal_column(line))
+    }
+
+    /// Return the leading whitespace of the line
+    pub fn get_indentation(&self, line: usize) -> String {
+        self.text
+            .line(line)
+            .chars()
+            .take(self.get_line_length(line))
+            .take_while(|character| character.is_whitespace())
+            .collect()
+    }
+
+    /// Return the cursor at the first non-whitespace character of the line
+    pub fn get_first_non_blank_cursor(&self, line: usize) -> Cursor {
+        let indentation = self.get_indentation(line).chars().count();
+        self.get_cursor_at(line, indentation)
+    }
+
+    /// Indent the lines from first to last by width spaces, or by a tab unless expand_tab is set,
+    ///     or dedent them by up to width spaces or a tab. the cursor stays on the same character
+    pub fn shift_lines(
+        &mut self,
+        first_line: usize,
+        last_line: usize,
+        width: usize,
+        expand_tab: bool,
+        indent: bool,
+    ) {
+        let unit = if expand_tab {
+            " ".repeat(width)
+        } else {
+            String::from("\t")
+        };
+        let cursor_line = self.text.char_to_line(self.cursor);
+        let mut column = self.find_line_position(self.cursor);
+
+        for line in first_line..=last_line {
+            let start = self.text.line_to_char(line);
+            let change = if indent {
+                // empty lines stay empty
+                if self.get_line_length(line) == 0 {
+                    continue;
+                }
+                self.insert(start, &unit);
+                unit.chars().count() as i64
+            } else {
+                let indentation = self.get_indentation(line);
+                let removed = if indentation.starts_with('\t') {
+                    1
+                } else {
+                    indentation
+                        .chars()
+                        .take(width)
+                        .take_while(|character| *character == ' ')
+                        .count()
+                };
+                self.remove(start..start + removed);
+                -(removed as i64)
+            };
+
+            if line == cursor_line {
+                column = (column as i64 + change).max(0) as usize;
+            }
+        }
+        self.cursor = self.get_cursor_at(cursor_line, column);
+    }
+
+    /// Comment out the lines by putting the token after their indentation, or remove the tokens
+    ///     if all of the lines are comments already. blank lines are left alone
+    pub fn toggle_comment(&mut self, first_line: usize, last_line: usize, token: &str) {
+        let cursor_line = self.text.char_to_line(self.cursor);
+        let mut column = self.find_line_position(self.cursor);
+
+        let lines: Vec<usize> = (first_line..=last_line)
+            .filter(|line| {
+                self.get_indentation(*line).chars().count() < self.get_line_length(*line)
+            })
+            .collect();
+        let token_length = token.chars().count();
+        let commented = lines.iter().all(|line| {
+            let start = self.get_first_non_blank_cursor(*line);
+            self.text
+                .slice(start..)
+                .chars()
+                .take(token_length)
+                .eq(token.chars())
+        });
+
+        for line in lines {
+            let start = self.get_first_non_blank_cursor(line);
+            let indentation = start - self.text.line_to_char(line);
+            let change = if commented {
+                // the space after the token is removed along with it
+                let mut end = start + token_length;
+                if self.text.get_char(end) == Some(' ') {
+                    end += 1;
+                }
+                self.remove(start..end);
+                -((end - start) as i64)
+            } else {
+                let text = format!("{} ", token);
+                self.insert(start, &text);
+                text.chars().count() as i64
+            };
+
+            if line == cursor_line && column >= indentation {
+                column = (column as i64 + change).max(indentation as i64) as usize;
+            }
+        }
+        self.cursor = self.get_cursor_at(cursor_line, column);
+    }
+
+    /// Join the line with the lines after it so that count lines become one, like `J`. with
+    ///     spaces set, the indentation of each joined line is replaced by a single space
+    pub fn join_lines(&mut self, line: usize, count: usize, spaces: bool) {
+        for _ in 1..count.max(2) {
+            if line >= self.get_last_line() {
+                break;
+            }
+            let line_length = self.get_line_length(line);
+            let seam = self.text.line_to_char(line) + line_length;
+
+            if spaces {
+                let indentation = self.get_indentation(line + 1).chars().count();
+                let next_character = (indentation < self.get_line_length(line + 1))
+                    .then(|| self.text.char(seam + 1 + indentation));
+                let ends_with_space = line_length > 0 && self.text.char(seam - 1) == ' ';
+                self.remove(seam..seam + 1 + indentation);
+
+                // no space is added after an empty or spaced line, nor before nothing or a ')'
+                let needs_space = line_length > 0
+                    && !ends_with_space
+                    && next_character.map_or(false, |character| character != ')');
+                if needs_space {
+                    self.insert(seam, " ");
+                }
+            } else {
+                self.remove(seam..seam + 1);
+            }
+            self.cursor = seam;
+        }
+    }
+
+    /// Change the case of the characters in the range. characters without a single character
+    ///     counterpart, like 'ß', are left as they are
+    pub fn change_case(&mut self, start: Cursor, end: Cursor, case: Case) {
+        let convert = |character: char| {
+            let upper = match case {
+                Case::Lower => false,
+                Case::Upper => true,
+                Case::Toggle => character.is_lowercase(),
+            };
+            let mut converted = if upper {
+                character.to_uppercase().collect::<Vec<_>>()
+            } else {
+                character.to_lowercase().collect::<Vec<_>>()
+            };
+            match converted.len() {
+                1 => converted.remove(0),
+                _ => character,
+            }
+        };
+        let changed: Vec<(Cursor, char)> = self
+            .text
+            .slice(start..end)
+            .chars()
+            .enumerate()
+            .filter_map(|(index, character)| {
+                let converted = convert(character);
+                (converted != character).then_some((start + index, converted))
+            })
+            .collect();
+        if changed.is_empty() {
+            return;
+        }
+        if self.read_only {
+            self.refused_edit = true;
+            return;
+        }
+
+        // the characters are swapped in place, so the marks and the cursors stay where they are
+        self.highlight_cache
+            .invalidate(self.text.char_to_line(start));
+        for (position, character) in changed {
+            self.text.remove(position..position + 1);
+            self.text.insert_char(position, character);
+        }
+        self.version += 1;
+        self.modified = true;
+    }
+
+    /// Return the char ranges of all matches of the pattern in the text
+    pub fn find_matches(&self, pattern: &Regex) -> Vec<(Cursor, Cursor)> {
+        let string = self.text.to_string();
+        // empty matches could not be moved past or highlighted
+        pattern
+            .find_iter(&string)
+            .filter(|found| !found.is_empty())
+            .map(|found| {
+                let start = self.text.byte_to_char(found.start());
+                (start, start + found.as_str().chars().count())
+            })
+            .collect()
+    }
+
+    /// Return the matches of the search query, as find_matches does. the pattern is compiled
+    ///     again only when the query or the search options changed, and the text is searched
+    ///     again only when either or the text changed
+    pub fn get_search_matches(&mut self, query: &str, options: &Options) -> &[(Cursor, Cursor)] {
+        let search_options = (options.ignore_case, options.smart_case, options.magic);
+        let cache = &mut self.search_cache;
+        if cache.query != query || cache.options != search_options {
+            cache.pattern = options.compile_search(query);
+            cache.query = query.to_string();
+            cache.options = search_options;
+            cache.version = None;
+        }
+
+        if self.search_cache.version != Some(self.version) {
+            let matches = match &self.search_cache.pattern {
+                Some(pattern) => self.find_matches(pattern),
+                None => Vec::new(),
+            };
+            self.search_cache.matches = matches;
+            self.search_cache.version = Some(self.version);
+        }
+        &self.search_cache.matches
+    }
+
+    /// Sort the lines from first to last, leaving the line endings where they are. the cursor
+    ///     moves to the first line
+    pub fn sort_lines(&mut self, first_line: usize, last_line: usize, options: SortOptions) {
+        let mut lines: Vec<String> = (first_line..=last_line)
+            .map(|line| {
+                let start = self.text.line_to_char(line);
+                let end = start + self.get_line_length(line);
+                self.text.slice(start..end).to_string()
+            })
+            .collect();
+
+        let compare = |a: &String, b: &String| {
+            let (a, b) = if options.reverse { (b, a) } else { (a, b) };
+            if options.numeric {
+                // lines without a number go first
+                get_sort_number(a).cmp(&get_sort_number(b))
+            } else if options.ignore_case {
+                a.to_lowercase().cmp(&b.to_lowercase())
+            } else {
+                a.cmp(b)
+            }
+        };
+        lines.sort_by(compare);
+        if options.unique {
+            lines.dedup_by(|a, b| compare(a, b) == std::cmp::Ordering::Equal);
+        }
+
+        let start = self.text.line_to_char(first_line);
+        let end = self.text.line_to_char(last_line) + self.get_line_length(last_line);
+        self.remove(start..end);
+        self.insert(start, &lines.join("\n"));
+        self.cursor = self.get_first_non_blank_cursor(first_line);
+    }
+
+    /// Replace matches of the regex on the lines from first to last, only the first match of
+    ///     each line unless global is set. returns the number of replacements
+    pub fn substitute(
+        &mut self,
+        regex: &Regex,
+        replacement: &str,
+        global: bool,
+        first_line: usize,
+        last_line: usize,
+    ) -> usize {
+        let limit = if global { 0 } else { 1 };
+        let mut count = 0;
+
+        // go through the lines in reverse so replacements spanning lines don't shift the rest
+        for line in (first_line..=last_line).rev() {
+            let start = self.text.line_to_char(line);
+            let end = start + self.get_line_length(line);
+            let content = self.text.slice(start..end).to_string();
+
+            let matches = regex.find_iter(&content).count();
+            if matches == 0 {
+                continue;
+            }
+            count += if global { matches } else { 1 };
+
+            let replaced = regex.replacen(&content, limit, replacement);
+            self.remove(start..end);
+            self.insert(start, &replaced);
+        }
+
+        let line = self
+            .text
+            .char_to_line(self.cursor.min(self.text.len_chars()));
+        self.cursor = self.get_first_non_blank_cursor(line);
+        self.desired_column = None;
+        count
+    }
+
+    /// Return the first match of the regex at or after the cursor on the lines up to last_line,
+    ///     with the text the replacement expands to for it
+    pub fn find_substitution(
+        &self,
+        regex: &Regex,
+        replacement: &str,
+        from: Cursor,
+        last_line: usize,
+    ) -> Option<(std::ops::Range<Cursor>, String)> {
+        let first_line = self.text.char_to_line(from.min(self.text.len_chars()));
+        for line in first_line..=last_line.min(self.get_last_line()) {
+            let start = self.text.line_to_char(line);
+            let end = start + self.get_line_length(line);
+            if from > end {
+                continue;
+            }
+
+            // the whole line is searched from the cursor on, so that `^` and `\b` still see
+            //     the text before it
+            let content = self.text.slice(start..end).to_string();
+            let offset = content
+                .char_indices()
+                .nth(from.saturating_sub(start))
+                .map_or(content.len(), |(index, _)| index);
+            let Some(captures) = regex.captures_at(&content, offset) else {
+                continue;
+            };
+
+            let found = captures.get(0).unwrap();
+            let mut text = String::new();
+            captures.expand(replacement, &mut text);
+            let match_start = start + content[..found.start()].chars().count();
+            return Some((
+                match_start..match_start + found.as_str().chars().count(),
+                text,
+            ));
+        }
+        None
+    }
+
+    /// Change the line ending used on save, removing any '\r' left over from a file that mixed
+    ///     both styles. the line ending of a read-only buffer is left as it is
+    pub fn set_line_ending(&mut self, line_ending: LineEnding) {
+        if self.read_only {
+            self.refused_edit = true;
+            return;
+        }
+        let carriage_returns: Vec<Cursor> = self
+            .text
+            .chars()
+            .zip(self.text.chars().skip(1))
+            .enumerate()
+            .filter(|(_, pair)| *pair == ('\r', '\n'))
+            .map(|(index, _)| index)
+            .collect();
+        // removed from the last, so that the positions still to come do not move
+        for position in carriage_returns.into_iter().rev() {
+            self.remove(position..position + 1);
+        }
+        self.clamp_cursor();
+
+        if self.line_ending != line_ending {
+            self.line_ending = line_ending;
+            self.version += 1;
+            self.modified = true;
+        }
+    }
+
+    /// Change the encoding used on save
+    pub fn set_encoding(&mut self, encoding: Encoding) {
+        self.modified |= self.encoding != encoding;
+        self.encoding = encoding;
+    }
+}
+
+/// Return the first decimal number in the line, with its sign
+fn get_sort_number(line: &str) -> Option<i64> {
+    let start = line.find(|character: char| character.is_ascii_digit())?;
+    let digits: String = line[start..]
+        .chars()
+        .take_while(|character| character.is_ascii_digit())
+        .collect();
+    let number = digits.parse().unwrap_or(i64::MAX);
+    if line[..start].ends_with('-') {
+        Some(-number)
+    } else {
+        Some(number)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(text: &str) -> Buffer {
+        Buffer::new(Rope::from_str(text), None)
+    }
+
+    #[test]
+    fn read_only_buffers_refuse_edits() {
+        let mut buffer = buffer("abc\n");
+        buffer.marks.insert('a', 2);
+        buffer.read_only = true;
+        buffer.insert(0, "x");
+        buffer.remove(0..2);
+        assert_eq!(buffer.text.to_string(), "abc\n");
+        assert_eq!((buffer.version, buffer.modified), (0, false));
+        assert_eq!(buffer.marks[&'a'], 2);
+        assert!(buffer.refused_edit);
+
+        buffer.read_only = false;
+        buffer.remove(0..2);
+        assert_eq!(buffer.text.to_string(), "c\n");
+    }
+
+    #[test]
+    fn shift_lines_indents_with_spaces_or_tabs() {
+        let mut spaces = buffer("a\n\nb");
+        spaces.shift_lines(0, 2, 4, true, true);
+        assert_eq!(spaces.text.to_string(), "    a\n\n    b");
+
+        let mut tabs = buffer("a\n\nb");
+        tabs.shift_lines(0, 2, 4, false, true);
+        assert_eq!(tabs.text.to_string(), "\ta\n\n\tb");
+    }
+
+    #[test]
+    fn shift_lines_dedents_a_tab_or_up_to_width_spaces() {
+        let mut buffer = buffer("\t\ta\n      b\n  c");
+        buffer.shift_lines(0, 2, 4, false, false);
+        assert_eq!(buffer.text.to_string(), "\ta\n  b\nc");
+    }
+
+    fn sort(text: &str, lines: RangeInclusive<usize>, options: SortOptions) -> String {
+        let mut buffer = buffer(text);
+        buffer.sort_lines(*lines.start(), *lines.end(), options);
+        buffer.text.to_string()
+    }
+
+    #[test]
+    fn sort_lines_keeps_the_line_endings_in_place() {
+        let options = SortOptions::default();
+        assert_eq!(sort("", 0..=0, options), "");
+        assert_eq!(sort("b\na\n", 0..=1, options), "a\nb\n");
+        // the last line has no newline, and keeps having none
+        assert_eq!(sort("c\nb\na", 0..=2, options), "a\nb\nc");
+        assert_eq!(sort("d\nc\nb\na\n", 1..=2, options), "d\nb\nc\na\n");
+    }
+
+    #[test]
+    fn sort_lines_with_flags() {
+        let reverse = SortOptions {
+            reverse: true,
+            ..SortOptions::default()
+        };
+        assert_eq!(sort("a\nc\nb", 0..=2, reverse), "c\nb\na");
+
+        let numeric = SortOptions {
+            numeric: true,
+            ..SortOptions::default()
+        };
+        assert_eq!(sort("x10\n-2\nnone\n3", 0..=3, numeric), "none\n-2\n3\nx10");
+
+        let unique = SortOptions {
+            unique: true,
+            ignore_case: true,
+            ..SortOptions::default()
+        };
+        assert_eq!(sort("b\nA\na\nB\n", 0..=3, unique), "A\nb\n");
+    }
+
+    #[test]
+    fn sort_numbers() {
+        assert_eq!(get_sort_number(""), None);
+        assert_eq!(get_sort_number("no digits"), None);
+        assert_eq!(get_sort_number("v1.20"), Some(1));
+        assert_eq!(get_sort_number("x -42 y"), Some(-42));
+        assert_eq!(get_sort_number("99999999999999999999"), Some(i64::MAX));
+    }
+
+    #[test]
+    fn line_lookups_count_characters_not_bytes() {
+        let buffer = buffer("héllo\nwörld\n");
+        assert_eq!(buffer.get_line_length(0), 5);
+        assert_eq!(buffer.find_line_position(8), 2);
+        assert_eq!(buffer.get_cursor_at(1, 2), 8);
+        // the column is clamped to the last character before the newline
+        assert_eq!(buffer.get_cursor_at(0, 99), 4);
+        assert_eq!(buffer.get_end_of_line_cursor(7), 10);
+    }
+
+    #[test]
+    fn last_line_ignores_the_empty_line_after_a_final_newline() {
+        assert_eq!(buffer("").get_last_line(), 0);
+        assert_eq!(buffer("\n").get_last_line(), 0);
+        assert_eq!(buffer("a\nb").get_last_line(), 1);
+        assert_eq!(buffer("a\nb\n").get_last_line(), 1);
+        assert_eq!(buffer("a\n\n").get_last_line(), 1);
+        assert_eq!(buffer("a\nb").get_line_length(1), 1);
+        assert_eq!(buffer("").get_line_length(0), 0);
+    }
+
+    #[test]
+    fn paste_puts_lines_on_their_own_lines() {
+        let mut buffer = buffer("  ab\ncd");
+        buffer.paste("x\n", true, true);
+        assert_eq!(buffer.text.to_string(), "x\n  ab\ncd");
+        assert_eq!(buffer.cursor, 0);
+        buffer.paste("  y\n", true, false);
+        assert_eq!(buffer.text.to_string(), "x\n  y\n  ab\ncd");
+        assert_eq!(buffer.cursor, 4);
+
+        // below a last line without a newline
+        buffer.cursor = 12;
+        buffer.paste("z\n", true, false);
+        assert_eq!(buffer.text.to_string(), "x\n  y\n  ab\ncd\nz");
+        assert_eq!(buffer.cursor, 14);
+    }
+
+    #[test]
+    fn paste_puts_characters_after_or_before_the_cursor() {
+        let mut buffer = buffer("ab\n\n");
+        buffer.paste("xy", false, false);
+        assert_eq!(buffer.text.to_string(), "axyb\n\n");
+        assert_eq!(buffer.cursor, 2);
+        buffer.paste("z", false, true);
+        assert_eq!(buffer.text.to_string(), "axzyb\n\n");
+        assert_eq!(buffer.cursor, 2);
+
+        // an empty line has no character to paste after
+        buffer.cursor = 6;
+        buffer.paste("w", false, false);
+        assert_eq!(buffer.text.to_string(), "axzyb\nw\n");
+    }
+
+    #[test]
+    fn matches_are_char_ranges() {
+        let buffer = buffer("héllo hé\n");
+        let pattern = |query: &str| Regex::new(query).unwrap();
+        assert_eq!(buffer.find_matches(&pattern("hé")), vec![(0, 2), (6, 8)]);
+        assert_eq!(buffer.find_matches(&pattern("x")), vec![]);
+        // empty matches are left out
+        assert_eq!(buffer.find_matches(&pattern("z*")), vec![]);
+    }
+
+    #[test]
+    fn substitute_replaces_on_the_lines_of_the_range() {
+        let mut buffer = buffer("aa\nba\naa\n");
+        let regex = Regex::new("a").unwrap();
+        assert_eq!(buffer.substitute(&regex, "x", false, 0, 1), 2);
+        assert_eq!(buffer.text.to_string(), "xa\nbx\naa\n");
+        assert_eq!(buffer.substitute(&regex, "y", true, 1, 2), 2);
+        assert_eq!(buffer.text.to_string(), "xa\nbx\nyy\n");
+        // replacements that add lines leave the lines before them in place
+        assert_eq!(buffer.substitute(&regex, "\n", false, 0, 2), 1);
+        assert_eq!(buffer.text.to_string(), "x\n\nbx\nyy\n");
+    }
+
+    #[test]
+    fn only_changes_to_the_text_mark_the_buffer_modified() {
+        let mut buffer = buffer("ab\n");
+        let regex = Regex::new("x").unwrap();
+        buffer.substitute(&regex, "y", true, 0, 0);
+        buffer.set_line_ending(LineEnding::Lf);
+        buffer.move_y(1);
+        assert!(!buffer.modified);
+
+        buffer.paste("x", false, false);
+        assert!(buffer.modified);
+        buffer.modified = false;
+        buffer.substitute(&regex, "y", true, 0, 0);
+        assert!(buffer.modified);
+        buffer.modified = false;
+        buffer.set_line_ending(LineEnding::CrLf);
+        assert!(buffer.modified);
+    }
+
+    #[test]
+    fn shifting_lines_keeps_the_cursor_on_its_character() {
+        let mut buffer = buffer("a\n\n\tb\n   c\n");
+        buffer.shift_lines(0, 1, 2, true, true);
+        // empty lines stay empty
+        assert_eq!(buffer.text.to_string(), "  a\n\n\tb\n   c\n");
+        assert_eq!(buffer.cursor, 2);
+
+        buffer.shift_lines(2, 3, 2, true, false);
+        assert_eq!(buffer.text.to_string(), "  a\n\nb\n c\n");
+        buffer.shift_lines(0, 0, 4, true, false);
+        assert_eq!(buffer.text.to_string(), "a\n\nb\n c\n");
+        assert_eq!(buffer.cursor, 0);
+    }
+
+    #[test]
+    fn joining_lines_replaces_the_indentation_with_a_space() {
+        let mut buffer = buffer("a\n    b\nc \n)\nd\ne");
+        buffer.join_lines(0, 3, true);
+        assert_eq!(buffer.text.to_string(), "a b c \n)\nd\ne");
+        assert_eq!(buffer.cursor, 3);
+        // no space before a ')' or after a line that ends in one
+        buffer.join_lines(0, 2, true);
+        assert_eq!(buffer.text.to_string(), "a b c )\nd\ne");
+
+        // `gJ` keeps the text as it is, and the last line has nothing to join
+        buffer.join_lines(1, 5, false);
+        assert_eq!(buffer.text.to_string(), "a b c )\nde");
+        assert_eq!(buffer.cursor, 9);
+    }
+
+    #[test]
+    fn word_ranges_stop_where_the_kind_of_character_changes() {
+        let buffer = buffer("ab_1  +-x\n");
+        assert_eq!(buffer.get_word_range(2, false), (0, 4));
+        assert_eq!(buffer.get_word_range(4, false), (4, 6));
+        assert_eq!(buffer.get_word_range(6, false), (6, 8));
+        // past the end of the line there is no word
+        assert_eq!(buffer.get_word_range(9, false), (9, 9));
+    }
+
+    #[test]
+    fn signs_put_the_current_match_before_the_marks_of_its_line() {
+        let mut buffer = Buffer::new(Rope::from_str("ab\ncd\nef\n"), None);
+        buffer.marks.insert('b', 4);
+        buffer.marks.insert('a', 3);
+        buffer.marks.insert('z', 9);
+        buffer.update_signs(Some(5));
+        assert_eq!(
+            buffer.signs[&1],
+            vec![Sign::SearchMatch, Sign::Mark('a'), Sign::Mark('b')]
+        );
+        // a mark past the end is shown on the last line
+        assert_eq!(buffer.signs[&3], vec![Sign::Mark('z')]);
+        assert!(!buffer.signs.contains_key(&0));
+
+        buffer.marks.clear();
+        buffer.update_signs(None);
+        assert!(buffer.signs.is_empty());
+    }
+
+    #[test]
+    fn block_rows_take_the_columns_of_the_block_on_each_line() {
+        let mut buffer = buffer("abcdef\nab\n\tcd\n");
+        buffer.selection_anchor = Some(1);
+        buffer.cursor = 12;
+        let (lines, columns) = buffer.get_block(4).unwrap();
+        assert_eq!((lines.clone(), columns.clone()), (0..=2, 1..=5));
+        assert_eq!(buffer.get_block_row(0, columns.clone(), 4), 1..6);
+        assert_eq!(buffer.get_block_row(1, columns.clone(), 4), 8..9);
+        // the tab is displayed in the first columns of the block
+        assert_eq!(buffer.get_block_row(2, columns, 4), 10..13);
+        // a line that ends before the block gives an empty row at its end
+        assert_eq!(buffer.get_block_row(1, 3..=4, 4), 9..9);
+
+        buffer.selection_anchor = None;
+        assert!(buffer.get_block(4).is_none());
+    }
+
+    #[test]
+    fn vertical_movement_returns_to_the_desired_column() {
+        let mut buffer = buffer("abcdef\nab\n\nabcdef\n");
+        buffer.cursor = 4;
+        buffer.move_y(1);
+        assert_eq!(buffer.cursor, 8);
+        buffer.move_y(1);
+        assert_eq!(buffer.cursor, 10);
+        buffer.move_y(1);
+        assert_eq!(buffer.cursor, 15);
+        assert_eq!(buffer.desired_column, Some(4));
+
+        // moving past the first or last line stays on it
+        buffer.move_y(5);
+        assert_eq!(buffer.cursor, 15);
+        buffer.move_y(-9);
+        assert_eq!(buffer.cursor, 4);
+
+        buffer.desired_column = None;
+        buffer.cursor = 1;
+        buffer.move_y(3);
+        assert_eq!(buffer.cursor, 12);
+    }
+
+    #[test]
+    fn deleting_characters_stays_within_the_line() {
+        let mut empty = buffer("");
+        assert_eq!(empty.delete_characters(1), None);
+        assert_eq!(empty.cursor, 0);
+
+        let mut buffer = buffer("abc\ndef\n");
+        buffer.cursor = 1;
+        assert_eq!(buffer.delete_characters(5).as_deref(), Some("bc"));
+        assert_eq!(buffer.text.to_string(), "a\ndef\n");
+        assert_eq!(buffer.cursor, 0);
+
+        assert_eq!(buffer.delete_characters(0).as_deref(), Some("a"));
+        // nothing is deleted on an empty line, the newline stays
+        assert_eq!(buffer.delete_characters(1), None);
+        assert_eq!(buffer.text.to_string(), "\ndef\n");
+    }
+
+    #[test]
+    fn edits_mark_the_buffer_modified_and_move_the_cursors() {
+        let mut buffer = buffer("abc\n");
+        buffer.remove(1..1);
+        assert_eq!((buffer.version, buffer.modified), (0, false));
+
+        buffer.cursors = vec![1, 3];
+        buffer.insert(2, "xy");
+        assert_eq!(buffer.text.to_string(), "abxyc\n");
+        assert_eq!(buffer.cursors, vec![1, 5]);
+        assert_eq!((buffer.version, buffer.modified), (1, true));
+
+        // cursors in the removed text move to its start
+        buffer.cursors = vec![0, 3, 5];
+        buffer.remove(2..5);
+        assert_eq!(buffer.text.to_string(), "ab\n");
+        assert_eq!(buffer.cursors, vec![0, 2, 2]);
+        assert_eq!(buffer.version, 2);
+    }
+
+    #[test]
+    fn tabs_extend_to_the_next_tab_stop() {
+        let buffer = buffer("\tab\tc\n");
+        assert_eq!(buffer.get_display_column(0, 4), 0);
+        assert_eq!(buffer.get_display_column(1, 4), 4);
+        assert_eq!(buffer.get_display_column(4, 4), 8);
+        assert_eq!(buffer.get_display_column(4, 8), 16);
+
+        // a column inside a tab is on the tab
+        assert_eq!(buffer.get_cursor_at_display_column(0, 2, 4), 0);
+        assert_eq!(buffer.get_cursor_at_display_column(0, 5, 4), 2);
+        assert_eq!(buffer.get_cursor_at_display_column(0, 99, 4), 4);
+    }
+
+    #[test]
+    fn change_case_keeps_the_length_of_the_text() {
+        let mut buffer = buffer("aB ß\nÉz\n");
+        buffer.cursor = 1;
+        buffer.change_case(0, 7, Case::Toggle);
+        assert_eq!(buffer.text.to_string(), "Ab ß\néZ\n");
+        assert_eq!(buffer.cursor, 1);
+
+        // ß has no single uppercase character, so it stays
+        buffer.change_case(0, 4, Case::Upper);
+        assert_eq!(buffer.text.to_string(), "AB ß\néZ\n");
+        buffer.change_case(0, 8, Case::Lower);
+        assert_eq!(buffer.text.to_string(), "ab ß\néz\n");
+
+        // text that does not change is not edited
+        let version = buffer.version;
+        buffer.change_case(0, 2, Case::Lower);
+        buffer.change_case(0, 0, Case::Upper);
+        assert_eq!(buffer.version, version);
+    }
+
+    #[test]
+    fn change_case_keeps_the_marks_on_their_characters() {
+        let mut buffer = buffer("abc def\n");
+        buffer.marks.insert('a', 0);
+        buffer.marks.insert('b', 5);
+        buffer.cursors = vec![2];
+        buffer.change_case(0, 7, Case::Upper);
+        assert_eq!(buffer.text.to_string(), "ABC DEF\n");
+        assert_eq!(
+            (buffer.get_mark('a'), buffer.get_mark('b')),
+            (Some(0), Some(5))
+        );
+        assert_eq!(buffer.cursors, vec![2]);
+    }
+
+    #[test]
+    fn changing_the_line_ending_is_an_edit() {
+        let mut text = buffer("a\r\nb\r\nc\n");
+        text.marks.insert('a', 6);
+        let version = text.version;
+        text.set_line_ending(LineEnding::CrLf);
+        assert_eq!(text.text.to_string(), "a\nb\nc\n");
+        assert_eq!(text.get_mark('a'), Some(4));
+        assert!(text.modified && text.version > version);
+
+        let mut read_only = buffer("a\r\nb\n");
+        read_only.read_only = true;
+        read_only.set_line_ending(LineEnding::CrLf);
+        assert_eq!(read_only.text.to_string(), "a\r\nb\n");
+        assert!(read_only.line_ending == LineEnding::Lf && !read_only.modified);
+        assert!(read_only.refused_edit);
+    }
+
+    #[test]
+    fn replacing_the_text_keeps_the_cursor_where_it_can() {
+        let mut buffer = buffer("abc\ndef\nghi\n");
+        buffer.insert(0, "x");
+        buffer.cursor = 10;
+        buffer.cursors = vec![2];
+        buffer.selection_anchor = Some(0);
+        buffer.replace_text(Rope::from_str("abc\nd\n"));
+        assert_eq!(buffer.cursor, 4);
+        assert!(buffer.cursors.is_empty());
+        assert_eq!(buffer.selection_anchor, None);
+        assert!(!buffer.modified);
+
+        buffer.replace_text(Rope::new());
+        assert_eq!(buffer.cursor, 0);
+    }
+
+    #[test]
+    fn toggles_comments_after_the_indentation() {
+        let mut commented = buffer("a\n\n  b\nc");
+        commented.cursor = 5;
+        commented.toggle_comment(0, 3, "//");
+        assert_eq!(commented.text.to_string(), "// a\n\n  // b\n// c");
+        // the cursor stays on the same character
+        assert_eq!(commented.cursor, 11);
+
+        commented.toggle_comment(0, 3, "//");
+        assert_eq!(commented.text.to_string(), "a\n\n  b\nc");
+        assert_eq!(commented.cursor, 5);
+
+        // lines that are not all comments are commented again
+        let mut mixed = buffer("// a\nb\n");
+        mixed.toggle_comment(0, 1, "//");
+        assert_eq!(mixed.text.to_string(), "// // a\n// b\n");
+
+        let mut empty = buffer("");
+        empty.toggle_comment(0, 0, "#");
+        assert_eq!(empty.text.to_string(), "");
+        assert_eq!(empty.cursor, 0);
+    }
+
+    #[test]
+    fn prepares_inserts_without_leaving_the_line() {
+        let insert = |text: &str, cursor: Cursor, mode_change: ModeChange| {
+            let mut buffer = buffer(text);
+            buffer.cursor = cursor;
+            buffer.prepare_insert(&mode_change);
+            (buffer.text.to_string(), buffer.cursor)
+        };
+        // `a` on the last character of a line stays before its newline
+        assert_eq!(insert("ab\ncd", 1, ModeChange::InsertAfter).1, 2);
+        assert_eq!(insert("ab\ncd", 4, ModeChange::InsertAfter).1, 5);
+        assert_eq!(insert("", 0, ModeChange::InsertAfter).1, 0);
+        assert_eq!(insert("ab\n", 0, ModeChange::InsertEnd).1, 2);
+        assert_eq!(insert("  ab", 3, ModeChange::InsertStart).1, 2);
+
+        assert_eq!(
+            insert("  ab\ncd", 2, ModeChange::OpenBelow),
+            (String::from("  ab\n  \ncd"), 7)
+        );
+        assert_eq!(
+            insert("  ab", 3, ModeChange::OpenAbove),
+            (String::from("  \n  ab"), 2)
+        );
+        assert_eq!(
+            insert("", 0, ModeChange::OpenBelow),
+            (String::from("\n"), 1)
+        );
+    }
+
+    #[test]
+    fn horizontal_movement_stays_off_the_newline() {
+        let buffer = buffer("ab\ncd\n\nef");
+        assert_eq!(buffer.get_movement_x(1, 1), 1);
+        assert_eq!(buffer.get_movement_x(3, -1), 3);
+        assert_eq!(buffer.get_movement_x(6, 1), 6);
+        // the last line has no newline to stop before
+        assert_eq!(buffer.get_movement_x(7, 5), 8);
+        assert_eq!(buffer.get_end_of_line_cursor(0), 1);
+        assert_eq!(buffer.get_end_of_line_cursor(6), 6);
+        assert_eq!(buffer.get_cursor_at(1, 10), 4);
+        assert_eq!(buffer.last_normal_column(2), 0);
+    }
+
+    #[test]
+    fn wraps_lines_at_the_width() {
+        let wrapped = buffer("abcdef\n\tab\n");
+        assert_eq!(wrapped.get_wrap_starts(0, 4, 4), vec![0, 4]);
+        assert_eq!(wrapped.get_wrap_starts(1, 4, 4), vec![7, 8]);
+        assert_eq!(wrapped.get_wrap_starts(2, 4, 4), vec![11]);
+        // a tab wider than the width gets a display line of its own
+        assert_eq!(buffer("a\tb").get_wrap_starts(0, 4, 8), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn moves_by_display_lines() {
+        let wrapped = buffer("abcdef\nxy");
+        let movement =
+            |cursor: Cursor, count: i32| wrapped.get_display_line_movement(cursor, count, 4, 4);
+        assert_eq!(movement(1, 1), 5);
+        assert_eq!(movement(1, 2), 8);
+        assert_eq!(movement(1, 5), 8);
+        // the column is kept, but the cursor stays on the shorter display line
+        assert_eq!(movement(2, 1), 5);
+        assert_eq!(movement(8, -1), 5);
+        assert_eq!(movement(5, -3), 1);
+        assert_eq!(buffer("").get_display_line_movement(0, 1, 4, 4), 0);
+    }
+
+    #[test]
+    fn overwrites_characters_and_restores_them() {
+        let mut replaced = buffer("ab\ncd");
+        let overwritten: Vec<Option<char>> = "xyz"
+            .chars()
+            .map(|character| replaced.overwrite_character(character))
+            .collect();
+        // the line gets longer at its end instead of taking the newline
+        assert_eq!(replaced.text.to_string(), "xyz\ncd");
+        assert_eq!(overwritten, vec![Some('a'), Some('b'), None]);
+
+        for character in overwritten.into_iter().rev() {
+            replaced.restore_character(character);
+        }
+        assert_eq!(replaced.text.to_string(), "ab\ncd");
+        assert_eq!(replaced.cursor, 0);
+
+        let mut split = buffer("ab");
+        assert_eq!(split.overwrite_character('\n'), None);
+        assert_eq!(split.text.to_string(), "\nab");
+        let mut empty = buffer("");
+        assert_eq!(empty.overwrite_character('x'), None);
+        assert_eq!(empty.text.to_string(), "x");
+        assert_eq!(empty.cursor, 1);
+    }
+
+    #[test]
+    fn cuts_the_removed_text() {
+        let mut cut = buffer("ab\ncd");
+        assert_eq!(cut.cut(1..4).as_deref(), Some("b\nc"));
+        assert_eq!(cut.text.to_string(), "ad");
+        // nothing is cut from an empty range, so that the register keeps its text
+        assert_eq!(cut.cut(1..1), None);
+        assert!(buffer("").cut(0..0).is_none());
+    }
+
+    #[test]
+    fn finds_the_word_under_the_cursor_as_a_whole_word() {
+        let mut words = buffer("foo foobar,foo\n");
+        assert_eq!(words.get_word_range(1, false), (0, 3));
+        assert_eq!(words.get_word_range(10, false), (10, 11));
+        assert_eq!(words.get_word_range(8, true), (4, 14));
+        // the newline and the end of the text have no word
+        assert_eq!(words.get_word_range(14, false), (14, 14));
+        assert_eq!(buffer("").get_word_range(0, false), (0, 0));
+
+        // `*` searches for the word only where it is not part of a longer one
+        let options = Options::default();
+        assert_eq!(
+            words.get_search_matches("\\<foo\\>", &options),
+            &[(0, 3), (11, 14)]
+        );
+    }
+
+    #[test]
+    fn last_selection_moves_with_the_text_without_signs() {
+        let mut buffer = buffer("ab\ncd\nef\n");
+        buffer.marks.insert('a', 0);
+        buffer.last_selection = Some((3, 7));
+        buffer.update_signs(None);
+        assert_eq!(buffer.signs.len(), 1);
+        assert_eq!(buffer.signs[&0], vec![Sign::Mark('a')]);
+
+        buffer.insert(0, "x\n");
+        assert_eq!(buffer.last_selection, Some((5, 9)));
+        buffer.remove(4..6);
+        assert_eq!(buffer.last_selection, Some((4, 7)));
+        assert_eq!(buffer.get_mark('<'), Some(4));
+        assert_eq!(buffer.get_mark('>'), Some(7));
+        assert_eq!(buffer.get_mark('a'), Some(2));
+        assert_eq!(buffer.get_mark('b'), None);
+    }
+
+    #[test]
+    fn search_matches_follow_the_text_and_the_options() {
+        let mut buffer = buffer("Foo foo\nbar\n");
+        let mut options = Options::default();
+        assert_eq!(buffer.get_search_matches("foo", &options), &[(4, 7)]);
+        assert!(buffer.get_search_matches("", &options).is_empty());
+
+        options.ignore_case = true;
+        assert_eq!(
+            buffer.get_search_matches("foo", &options),
+            &[(0, 3), (4, 7)]
+        );
+
+        buffer.insert(8, "foo");
+        assert_eq!(
+            buffer.get_search_matches("foo", &options),
+            &[(0, 3), (4, 7), (8, 11)]
+        );
+
+        buffer.replace_text(Rope::from_str("bar\n"));
+        assert!(buffer.get_search_matches("foo", &options).is_empty());
     }
 }
@@ -0,0 +1,1884 @@
+use std::collections::HashMap;
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+
+use regex::Regex;
+use ropey::Rope;
+
+use crate::buffer::{Buffer, SortOptions};
+use crate::config::{
+    get_config_path, load_config, parse_columns, parse_width, save_setting, summarize_errors,
+};
+use crate::io::{
+    append, create_parent_directories, get_modified_time, get_path_completions, is_process_running,
+    load, load_in_background, read_swap, save, Encoding, LineEnding, LoadedFile,
+};
+use crate::keymap::parse_keys;
+use crate::state::{
+    LoadingFile, Mode, NormalKeys, PendingInput, QuickMenu, QuickMenuKind, Register,
+    SplitDirection, State, Substitution, LARGE_FILE_SIZE,
+};
+
+/// The kind of value a command expects for each of its parameters
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParameterType {
+    Path,
+    /// a path, which may be preceded by `>>` to append to the file instead of replacing it
+    Output,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Parameter {
+    Path(String),
+    /// the path of a file to append to, given as `>> path`
+    Append(String),
+}
+
+/// How a command receives its arguments
+#[derive(Clone, Copy)]
+pub enum Execute {
+    /// arguments are split on spaces and parsed into the command's parameter types
+    Parameters(fn(&mut State, &[Parameter]) -> Result<(), String>),
+    /// the argument string is passed on unchanged, for commands with their own syntax
+    Raw(fn(&mut State, &str) -> Result<(), String>),
+    /// parameters as for Parameters, along with the lines of the range before the command if
+    ///     one was given
+    RangeParameters(
+        fn(&mut State, Option<RangeInclusive<usize>>, &[Parameter]) -> Result<(), String>,
+    ),
+    /// the argument string as for Raw, along with the lines of the range before the command if
+    ///     one was given
+    RangeRaw(fn(&mut State, Option<RangeInclusive<usize>>, &str) -> Result<(), String>),
+}
+
+/// A line given in the range of a command
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Address {
+    /// a line number, counted from 1
+    Number(usize),
+    /// `.`, the line of the cursor
+    Current,
+    /// `$`, the last line
+    Last,
+    /// `'a`, the line of a mark. `'<` and `'>` are the first and last line of the last visual
+    ///     selection
+    Mark(char),
+}
+
+/// The lines a command applies to, as in `:10,20d`. `%` stands for all lines
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct LineRange {
+    pub start: Address,
+    pub end: Address,
+}
+
+impl LineRange {
+    /// Return the first and last line of the range in the buffer, counted from 0. line numbers
+    ///     past the end are the last line, and the lines are swapped if they are backwards
+    pub fn resolve(&self, buffer: &Buffer) -> Result<RangeInclusive<usize>, String> {
+        let get_line = |address: Address| match address {
+            Address::Number(number) => Ok(number.saturating_sub(1).min(buffer.get_last_line())),
+            Address::Current => Ok(buffer.text.char_to_line(buffer.cursor)),
+            Address::Last => Ok(buffer.get_last_line()),
+            Address::Mark(name) => buffer
+                .get_mark(name)
+                .map(|mark| buffer.text.char_to_line(mark))
+                .ok_or(format!("Mark not set: {}", name)),
+        };
+        let start = get_line(self.start)?;
+        let end = get_line(self.end)?;
+        Ok(start.min(end)..=start.max(end))
+    }
+}
+
+#[derive(Clone)]
+pub struct Command {
+    pub name: &'static str,
+    pub parameters: Vec<ParameterType>,
+    pub execute: Execute,
+}
+
+/// Arguments of a command that is ready to be executed
+pub enum Arguments {
+    Parameters(Vec<Parameter>),
+    Raw(String),
+}
+
+impl Command {
+    fn new(name: &'static str, parameters: Vec<ParameterType>, execute: Execute) -> Self {
+        Command {
+            name,
+            parameters,
+            execute,
+        }
+    }
+
+    /// Run the command on the lines of the range, returning why it failed if it did
+    pub fn execute(
+        &self,
+        state: &mut State,
+        range: Option<LineRange>,
+        arguments: Arguments,
+    ) -> Result<(), String> {
+        let lines = match range {
+            Some(range) => Some(range.resolve(state.editor.buffer())?),
+            None => None,
+        };
+        match (self.execute, arguments) {
+            (Execute::Parameters(_) | Execute::Raw(_), _) if lines.is_some() => {
+                Err(format!("No range allowed for {}", self.name))
+            }
+            (Execute::Parameters(execute), Arguments::Parameters(parameters)) => {
+                execute(state, &parameters)
+            }
+            (Execute::Raw(execute), Arguments::Raw(argument)) => execute(state, &argument),
+            (Execute::RangeParameters(execute), Arguments::Parameters(parameters)) => {
+                execute(state, lines, &parameters)
+            }
+            (Execute::RangeRaw(execute), Arguments::Raw(argument)) => {
+                execute(state, lines, &argument)
+            }
+            _ => Err(format!("Invalid arguments for {}", self.name)),
+        }
+    }
+}
+
+pub type Commands = HashMap<String, Command>;
+
+/// Create the set of commands available in command mode
+pub fn create_commands() -> Commands {
+    let commands = vec![
+        Command::new(
+            "w",
+            vec![ParameterType::Output],
+            Execute::RangeParameters(write),
+        ),
+        Command::new(
+            "w!",
+            vec![ParameterType::Output],
+            Execute::RangeParameters(force_write),
+        ),
+        Command::new("q", vec![], Execute::Parameters(quit)),
+        Command::new("q!", vec![], Execute::Parameters(force_quit)),
+        Command::new(
+            "wq",
+            vec![ParameterType::Path],
+            Execute::Parameters(write_quit),
+        ),
+        Command::new("x", vec![ParameterType::Path], Execute::Parameters(exit)),
+        Command::new("e", vec![ParameterType::Path], Execute::Parameters(edit)),
+        Command::new(
+            "e!",
+            vec![ParameterType::Path],
+            Execute::Parameters(force_edit),
+        ),
+        Command::new("edit", vec![ParameterType::Path], Execute::Parameters(edit)),
+        Command::new(
+            "edit!",
+            vec![ParameterType::Path],
+            Execute::Parameters(force_edit),
+        ),
+        Command::new("new", vec![ParameterType::Path], Execute::Parameters(new)),
+        Command::new("enew", vec![ParameterType::Path], Execute::Parameters(new)),
+        Command::new("bn", vec![], Execute::Parameters(next_buffer)),
+        Command::new("bp", vec![], Execute::Parameters(previous_buffer)),
+        Command::new("bd", vec![], Execute::Parameters(delete_buffer)),
+        Command::new("ls", vec![], Execute::Parameters(list_buffers)),
+        Command::new("bd!", vec![], Execute::Parameters(force_delete_buffer)),
+        Command::new("bdelete", vec![], Execute::Parameters(delete_buffer)),
+        Command::new("bdelete!", vec![], Execute::Parameters(force_delete_buffer)),
+        Command::new("s", vec![], Execute::RangeRaw(substitute)),
+        Command::new("d", vec![], Execute::RangeRaw(delete_lines)),
+        Command::new("g", vec![], Execute::RangeRaw(global)),
+        Command::new("global", vec![], Execute::RangeRaw(global)),
+        Command::new("g!", vec![], Execute::RangeRaw(inverse_global)),
+        Command::new("v", vec![], Execute::RangeRaw(inverse_global)),
+        Command::new("vglobal", vec![], Execute::RangeRaw(inverse_global)),
+        Command::new("set", vec![], Execute::Raw(set)),
+        Command::new("normal", vec![], Execute::RangeRaw(normal)),
+        Command::new("norm", vec![], Execute::RangeRaw(normal)),
+        Command::new("sort", vec![], Execute::RangeRaw(sort)),
+        Command::new("goto", vec![], Execute::RangeRaw(go_to_line)),
+        Command::new("sort!", vec![], Execute::RangeRaw(reverse_sort)),
+        Command::new(
+            "source",
+            vec![ParameterType::Path],
+            Execute::Parameters(source),
+        ),
+        Command::new("so", vec![ParameterType::Path], Execute::Parameters(source)),
+        Command::new(
+            "split",
+            vec![ParameterType::Path],
+            Execute::Parameters(split),
+        ),
+        Command::new("sp", vec![ParameterType::Path], Execute::Parameters(split)),
+        Command::new(
+            "vsplit",
+            vec![ParameterType::Path],
+            Execute::Parameters(vertical_split),
+        ),
+        Command::new(
+            "vs",
+            vec![ParameterType::Path],
+            Execute::Parameters(vertical_split),
+        ),
+        Command::new("only", vec![], Execute::Parameters(only)),
+        Command::new("on", vec![], Execute::Parameters(only)),
+        Command::new("close", vec![], Execute::Parameters(close)),
+        Command::new("clo", vec![], Execute::Parameters(close)),
+        Command::new(
+            "tabnew",
+            vec![ParameterType::Path],
+            Execute::Parameters(new_tab),
+        ),
+        Command::new("tabnext", vec![], Execute::Parameters(next_tab)),
+        Command::new("tabn", vec![], Execute::Parameters(next_tab)),
+        Command::new("tabprevious", vec![], Execute::Parameters(previous_tab)),
+        Command::new("tabp", vec![], Execute::Parameters(previous_tab)),
+        Command::new("tabclose", vec![], Execute::Parameters(close_tab)),
+        Command::new("tabc", vec![], Execute::Parameters(close_tab)),
+        Command::new("colorscheme", vec![], Execute::Raw(color_scheme)),
+        Command::new("colo", vec![], Execute::Raw(color_scheme)),
+    ];
+
+    commands
+        .into_iter()
+        .map(|command| (command.name.to_string(), command))
+        .collect()
+}
+
+/// Split the command line into the range, the command and its arguments
+pub fn prepare_command(
+    commands: &Commands,
+    line: &str,
+) -> Result<(Command, Option<LineRange>, Arguments), String> {
+    let line = line.strip_prefix(':').unwrap_or(line);
+    let (range, line) = parse_range(line)?;
+
+    // a range on its own jumps to its last line, as `:goto` does
+    if range.is_some() && line.trim().is_empty() {
+        if let Some(command) = commands.get("goto") {
+            return Ok((command.clone(), range, Arguments::Raw(String::new())));
+        }
+    }
+
+    // the name is a run of letters, optionally followed by a '!'
+    let mut name_length = line
+        .char_indices()
+        .find(|(_, character)| !character.is_alphabetic())
+        .map_or(line.len(), |(index, _)| index);
+    if line[name_length..].starts_with('!') {
+        name_length += 1;
+    }
+    let (name, rest) = line.split_at(name_length);
+
+    let command = commands
+        .get(name)
+        .ok_or(format!("Not an editor command: {}", line))?
+        .clone();
+
+    let arguments = match command.execute {
+        Execute::Raw(_) | Execute::RangeRaw(_) => Arguments::Raw(rest.to_string()),
+        Execute::Parameters(_) | Execute::RangeParameters(_) => {
+            let mut words = split_arguments(rest).into_iter();
+            let mut parameters = Vec::new();
+            for parameter_type in command.parameters.iter() {
+                let Some(word) = words.next() else {
+                    break;
+                };
+                parameters.push(match parameter_type {
+                    ParameterType::Path => Parameter::Path(word),
+                    ParameterType::Output => match word.strip_prefix(">>") {
+                        // the path may be attached to the `>>` or follow it
+                        Some("") => Parameter::Append(
+                            words
+                                .next()
+                                .ok_or(String::from("Expected a file to append to"))?,
+                        ),
+                        Some(path) => Parameter::Append(path.to_string()),
+                        None => Parameter::Path(word),
+                    },
+                });
+            }
+            if words.next().is_some() {
+                return Err(format!("Too many arguments for {}", name));
+            }
+            Arguments::Parameters(parameters)
+        }
+    };
+
+    Ok((command, range, arguments))
+}
+
+/// Split the range off the start of the command line, as in `10,20d`, `.,$s/a/b/`, `%d` or
+///     `'<,'>sort`. a single line is a range of one line
+fn parse_range(line: &str) -> Result<(Option<LineRange>, &str), String> {
+    if let Some(rest) = line.strip_prefix('%') {
+        let range = LineRange {
+            start: Address::Number(1),
+            end: Address::Last,
+        };
+        return Ok((Some(range), rest));
+    }
+
+    let (Some(start), rest) = parse_address(line)? else {
+        return Ok((None, line));
+    };
+    let Some(rest) = rest.strip_prefix(',') else {
+        let range = LineRange { start, end: start };
+        return Ok((Some(range), rest));
+    };
+    match parse_address(rest)? {
+        (Some(end), rest) => Ok((Some(LineRange { start, end }), rest)),
+        (None, _) => Err(String::from("Expected a line after ','")),
+    }
+}
+
+/// Split a line number, `.`, `$` or mark off the start of the text
+fn parse_address(text: &str) -> Result<(Option<Address>, &str), String> {
+    let digits = text
+        .find(|character: char| !character.is_ascii_digit())
+        .unwrap_or(text.len());
+    if digits > 0 {
+        let number = text[..digits]
+            .parse()
+            .map_err(|_| format!("Invalid line number: {}", &text[..digits]))?;
+        return Ok((Some(Address::Number(number)), &text[digits..]));
+    }
+
+    let mut characters = text.chars();
+    let address = match characters.next() {
+        Some('.') => Address::Current,
+        Some('$') => Address::Last,
+        Some('\'') => match characters.next() {
+            Some(name) => Address::Mark(name),
+            None => return Err(String::from("Expected a mark after '")),
+        },
+        _ => return Ok((None, text)),
+    };
+    Ok((Some(address), characters.as_str()))
+}
+
+/// Split the arguments of a command on whitespace. double quotes group words into one argument as
+///     in `:e "my file.txt"`, and a backslash escapes a space or a quote. a quote that is never
+///     closed extends its argument to the end of the line
+fn split_arguments(line: &str) -> Vec<String> {
+    parse_arguments(line)
+        .into_iter()
+        .map(|(_, argument)| argument)
+        .collect()
+}
+
+/// Split the arguments as split_arguments does, along with the part of the line each of them
+///     was written in
+fn parse_arguments(line: &str) -> Vec<(Range<usize>, String)> {
+    let mut arguments = Vec::new();
+    let mut argument: Option<(usize, String)> = None;
+    let mut quoted = false;
+    let mut characters = line.char_indices().peekable();
+    while let Some((index, character)) = characters.next() {
+        match character {
+            '\\' if characters
+                .peek()
+                .is_some_and(|(_, next)| *next == '"' || next.is_whitespace()) =>
+            {
+                let (_, next) = characters.next().unwrap();
+                let (_, text) = argument.get_or_insert((index, String::new()));
+                text.push(next);
+            }
+            '"' => {
+                quoted = !quoted;
+                // `""` is an empty argument rather than none
+                argument.get_or_insert((index, String::new()));
+            }
+            _ if character.is_whitespace() && !quoted => {
+                let ended = argument.take();
+                arguments.extend(ended.map(|(start, argument)| (start..index, argument)));
+            }
+            _ => {
+                let (_, text) = argument.get_or_insert((index, String::new()));
+                text.push(character);
+            }
+        }
+    }
+    arguments.extend(argument.map(|(start, argument)| (start..line.len(), argument)));
+    arguments
+}
+
+/// Escape the whitespace and quotes in the argument with backslashes, so that split_arguments
+///     reads it back as one argument
+fn escape_argument(argument: &str) -> String {
+    let mut escaped = String::new();
+    for character in argument.chars() {
+        if character == '"' || character.is_whitespace() {
+            escaped.push('\\');
+        }
+        escaped.push(character);
+    }
+    escaped
+}
+
+/// Return the completions for the last word of the command line, along with where that word
+///     starts. the first word completes to command names, later ones to paths if the command
+///     takes a path
+pub fn get_completions(commands: &Commands, line: &str) -> (usize, Vec<String>) {
+    let line_start = if line.starts_with(':') { 1 } else { 0 };
+    // the range before the name is kept as it is
+    let rest = parse_range(&line[line_start..]).map_or(&line[line_start..], |(_, rest)| rest);
+    let offset = line.len() - rest.len();
+    let line = rest;
+
+    let Some((name, arguments)) = line.split_once(' ') else {
+        let mut names: Vec<String> = commands
+            .keys()
+            .filter(|name| name.starts_with(line))
+            .cloned()
+            .collect();
+        names.sort();
+        return (offset, names);
+    };
+
+    // the word is the last argument, unless the cursor is past its end and starts a new one
+    let (word_start, word) = match parse_arguments(arguments).pop() {
+        Some((range, word)) if range.end == arguments.len() => (name.len() + 1 + range.start, word),
+        _ => (line.len(), String::new()),
+    };
+    let takes_path = commands.get(name).map_or(false, |command| {
+        command.parameters.contains(&ParameterType::Path)
+            || command.parameters.contains(&ParameterType::Output)
+    });
+    if !takes_path {
+        return (offset + word_start, Vec::new());
+    }
+    // a path with spaces would otherwise be split into several arguments
+    let completions = get_path_completions(&word)
+        .iter()
+        .map(|path| escape_argument(path))
+        .collect();
+    (offset + word_start, completions)
+}
+
+/// Return the longest prefix that all of the texts start with
+pub fn get_common_prefix(texts: &[String]) -> String {
+    let Some(first) = texts.first() else {
+        return String::new();
+    };
+    let mut length = first.len();
+    for text in &texts[1..] {
+        length = first
+            .char_indices()
+            .zip(text.chars())
+            .take_while(|((_, a), b)| a == b)
+            .last()
+            .map_or(0, |((index, character), _)| index + character.len_utf8())
+            .min(length);
+    }
+    first[..length].to_string()
+}
+
+/// Write the buffer to the given path, or to its own file when no path is given
+fn write(
+    state: &mut State,
+    lines: Option<RangeInclusive<usize>>,
+    parameters: &[Parameter],
+) -> Result<(), String> {
+    write_buffer(state, lines, parameters, false)
+}
+
+/// Write the buffer even if it is read-only or its file was changed by another program
+fn force_write(
+    state: &mut State,
+    lines: Option<RangeInclusive<usize>>,
+    parameters: &[Parameter],
+) -> Result<(), String> {
+    write_buffer(state, lines, parameters, true)
+}
+
+/// Write the lines, or the whole buffer if no lines are given
+fn write_buffer(
+    state: &mut State,
+    lines: Option<RangeInclusive<usize>>,
+    parameters: &[Parameter],
+    force: bool,
+) -> Result<(), String> {
+    let buffer = state.editor.buffer_mut();
+    let text = match &lines {
+        Some(lines) => {
+            let start = buffer.text.line_to_char(*lines.start());
+            let end = buffer.text.line_to_char(lines.end() + 1);
+            Rope::from(buffer.text.slice(start..end))
+        }
+        None => buffer.text.clone(),
+    };
+    let filepath = match (parameters.first(), &buffer.filepath) {
+        (Some(Parameter::Append(filepath)), _) => return append_buffer(state, &text, filepath),
+        (Some(Parameter::Path(filepath)), _) | (None, Some(filepath)) => filepath.clone(),
+        (None, None) => return Err(String::from("No file name")),
+    };
+    let is_own_file = buffer.filepath.as_ref() == Some(&filepath);
+
+    if buffer.read_only && !force {
+        return Err(format!("\"{}\" is read-only (add ! to override)", filepath));
+    }
+    // writing only some lines over the file of the buffer would lose the others
+    if is_own_file && !force && lines.is_some() {
+        return Err(String::from(
+            "Only part of the buffer would be written (add ! to override)",
+        ));
+    }
+    // the file on disk may have changes that writing the buffer would discard
+    let file_time = get_modified_time(&filepath);
+    if is_own_file && !force && buffer.file_time.is_some() && file_time > buffer.file_time {
+        return Err(String::from(
+            "The file has been changed since reading it (add ! to override, or :e! to reload)",
+        ));
+    }
+    if state.options.create_directories {
+        create_parent_directories(&filepath).map_err(|error| error.to_string())?;
+    }
+    save(&text, &filepath, buffer.line_ending, buffer.encoding)
+        .map_err(|error| error.to_string())?;
+
+    // a buffer without a file takes the name it is first written to
+    if buffer.filepath.is_none() && lines.is_none() {
+        buffer.filepath = Some(filepath.clone());
+    }
+    if buffer.filepath.as_ref() == Some(&filepath) && lines.is_none() {
+        buffer.modified = false;
+        buffer.file_time = get_modified_time(&filepath);
+        buffer.remove_swap_file();
+    }
+    state.set_message(format!("\"{}\" written", filepath));
+    Ok(())
+}
+
+/// Add the text of the buffer to the end of an existing file, leaving the buffer as it is
+fn append_buffer(state: &mut State, text: &Rope, filepath: &str) -> Result<(), String> {
+    let buffer = state.editor.buffer();
+    append(text, filepath, buffer.line_ending, buffer.encoding)
+        .map_err(|error| error.to_string())?;
+    state.set_message(format!("\"{}\" appended", filepath));
+    Ok(())
+}
+
+/// Open the file at the path, or without a path reload the current file unless it has unsaved
+///     changes
+fn edit(state: &mut State, parameters: &[Parameter]) -> Result<(), String> {
+    let Some(Parameter::Path(filepath)) = parameters.first() else {
+        if state.editor.buffer().modified {
+            return Err(String::from(
+                "No write since last change (add ! to override)",
+            ));
+        }
+        return reload(state);
+    };
+    open_file(state, filepath)
+}
+
+/// Open the file in a new buffer, or switch to it if it is already open
+pub fn open_file(state: &mut State, filepath: &str) -> Result<(), String> {
+    let open_index = state
+        .editor
+        .buffers
+        .iter()
+        .position(|buffer| buffer.filepath.as_deref() == Some(filepath));
+    if let Some(index) = open_index {
+        state.editor.switch_buffer(index);
+        return Ok(());
+    }
+
+    // large files are opened once they have been read in the background
+    let size = std::fs::metadata(filepath).map_or(0, |metadata| metadata.len());
+    if size >= LARGE_FILE_SIZE {
+        let loading_files = &mut state.editor.loading_files;
+        if !loading_files.iter().any(|file| file.filepath == filepath) {
+            loading_files.push(LoadingFile {
+                filepath: filepath.to_string(),
+                receiver: load_in_background(filepath),
+            });
+        }
+        return Ok(());
+    }
+
+    let loaded =
+        load(filepath).map_err(|error| format!("Could not open {}: {}", filepath, error))?;
+    open_loaded_file(state, filepath, loaded);
+    Ok(())
+}
+
+/// Open the loaded content of the file in a new buffer
+pub fn open_loaded_file(state: &mut State, filepath: &str, loaded: LoadedFile) {
+    state.set_message(get_load_message(filepath, &loaded));
+    state
+        .editor
+        .add_buffer(loaded.text, Some(filepath.to_string()));
+    let buffer = state.editor.buffer_mut();
+    buffer.line_ending = loaded.line_ending;
+    buffer.encoding = loaded.encoding;
+    buffer.read_only = loaded.binary || !loaded.writable;
+    buffer.file_time = loaded.file_time;
+
+    // the swap file of an earlier session may hold changes that were never saved
+    let Some(swap) = read_swap(filepath).filter(|swap| swap.process_id != std::process::id())
+    else {
+        return;
+    };
+    let running = is_process_running(swap.process_id);
+    let process_id = swap.process_id;
+    let other_instance = match running {
+        Some(true) => format!(
+            " while it is open in another editor (process {})",
+            process_id
+        ),
+        Some(false) => String::new(),
+        None => format!(
+            " that may be open in another editor (process {})",
+            process_id
+        ),
+    };
+    // without a way to tell whether the editor that wrote it stopped, deleting is not offered
+    let delete = if running.is_some() { "(d)elete, " } else { "" };
+    state.set_message(format!(
+        "Found a swap file for \"{}\"{}: (r)ecover, {}(e)dit or (o)pen read-only?",
+        filepath, other_instance, delete
+    ));
+    state.pending = Some(PendingInput::RecoverSwap(swap));
+}
+
+/// Reload the current file, discarding its changes, or open the file at the given path
+fn force_edit(state: &mut State, parameters: &[Parameter]) -> Result<(), String> {
+    match parameters.first() {
+        Some(Parameter::Path(filepath))
+            if state.editor.buffer().filepath.as_ref() != Some(filepath) =>
+        {
+            edit(state, parameters)
+        }
+        _ => reload(state),
+    }
+}
+
+/// Replace the text of the current buffer with the content of its file
+fn reload(state: &mut State) -> Result<(), String> {
+    let buffer = state.editor.buffer_mut();
+    let Some(filepath) = buffer.filepath.clone() else {
+        return Err(String::from("No file name"));
+    };
+
+    let loaded =
+        load(&filepath).map_err(|error| format!("Could not open {}: {}", filepath, error))?;
+    let message = get_load_message(&filepath, &loaded);
+    buffer.replace_text(loaded.text);
+    // the changes in the swap file are gone with the ones in the buffer
+    buffer.remove_swap_file();
+    buffer.line_ending = loaded.line_ending;
+    buffer.encoding = loaded.encoding;
+    buffer.read_only = loaded.binary || !loaded.writable;
+    buffer.file_time = loaded.file_time;
+    state.set_message(message);
+    Ok(())
+}
+
+fn get_load_message(filepath: &str, loaded: &LoadedFile) -> String {
+    let mut message = format!("\"{}\"", filepath);
+    if loaded.binary {
+        message.push_str(" [binary]");
+    } else if loaded.encoding != Encoding::Utf8 {
+        message.push_str(&format!(" [{}]", loaded.encoding));
+    }
+    if !loaded.writable {
+        message.push_str(" [readonly]");
+    }
+    if loaded.mixed_line_endings {
+        message.push_str(" [mixed line endings]");
+    }
+    message
+}
+
+fn next_buffer(state: &mut State, _parameters: &[Parameter]) -> Result<(), String> {
+    state.editor.next_buffer();
+    Ok(())
+}
+
+fn previous_buffer(state: &mut State, _parameters: &[Parameter]) -> Result<(), String> {
+    state.editor.previous_buffer();
+    Ok(())
+}
+
+/// Show the open buffers in the quick menu, where one can be chosen to switch to it
+fn list_buffers(state: &mut State, _parameters: &[Parameter]) -> Result<(), String> {
+    let current_index = state.editor.current_buffer_index;
+    let entries = state
+        .editor
+        .buffers
+        .iter()
+        .enumerate()
+        .map(|(index, buffer)| {
+            format!(
+                "{:>3} {} {} {}",
+                index + 1,
+                if index == current_index { "%a" } else { "  " },
+                if buffer.modified { "+" } else { " " },
+                buffer.get_name()
+            )
+        })
+        .collect();
+
+    state.editor.quick_menu = QuickMenu::new(QuickMenuKind::Buffers, entries, current_index);
+    state.editor.command_cursor = 0;
+    state.mode = Mode::QuickMenu;
+    Ok(())
+}
+
+/// Open an empty buffer, which is written to the given path or needs one to be written
+fn new(state: &mut State, parameters: &[Parameter]) -> Result<(), String> {
+    let filepath = match parameters.first() {
+        Some(Parameter::Path(filepath)) => Some(filepath.clone()),
+        _ => None,
+    };
+    // writing the empty buffer would replace the file
+    if let Some(filepath) = filepath.as_ref().filter(|path| Path::new(path).exists()) {
+        return Err(format!(
+            "\"{}\" already exists (use :e to open it)",
+            filepath
+        ));
+    }
+    state.editor.add_buffer(ropey::Rope::new(), filepath);
+    Ok(())
+}
+
+/// Close the current buffer unless it has unsaved changes
+fn delete_buffer(state: &mut State, parameters: &[Parameter]) -> Result<(), String> {
+    let buffer = state.editor.buffer();
+    if buffer.modified {
+        return Err(format!(
+            "No write since last change for buffer \"{}\" (add ! to override)",
+            buffer.get_name()
+        ));
+    }
+    force_delete_buffer(state, parameters)
+}
+
+fn force_delete_buffer(state: &mut State, _parameters: &[Parameter]) -> Result<(), String> {
+    state.editor.buffer_mut().remove_swap_file();
+    state.editor.remove_buffer();
+    Ok(())
+}
+
+/// Close the focused pane or the tab page, or quit unless there are unsaved changes when there
+///     is only one of either
+fn quit(state: &mut State, parameters: &[Parameter]) -> Result<(), String> {
+    if state.editor.split.is_some() {
+        state.close_pane();
+        return Ok(());
+    }
+    if !state.editor.tabs.is_empty() {
+        state.close_tab();
+        return Ok(());
+    }
+    let modified_count = state
+        .editor
+        .buffers
+        .iter()
+        .filter(|buffer| buffer.modified)
+        .count();
+    if modified_count > 0 {
+        return Err(if modified_count == 1 {
+            String::from("No write since last change (add ! to override)")
+        } else {
+            format!(
+                "{} buffers have unsaved changes (add ! to override)",
+                modified_count
+            )
+        });
+    }
+    force_quit(state, parameters)
+}
+
+fn force_quit(state: &mut State, _parameters: &[Parameter]) -> Result<(), String> {
+    if state.editor.split.is_some() {
+        state.close_pane();
+        return Ok(());
+    }
+    if !state.editor.tabs.is_empty() {
+        state.close_tab();
+        return Ok(());
+    }
+    for buffer in state.editor.buffers.iter_mut() {
+        buffer.remove_swap_file();
+    }
+    std::process::exit(0);
+}
+
+/// Close the other pane, leaving the focused one as it is
+fn only(state: &mut State, _parameters: &[Parameter]) -> Result<(), String> {
+    if state.editor.split.take().is_none() {
+        state.set_message(String::from("Already only one window"));
+    }
+    Ok(())
+}
+
+/// Close the focused pane without closing its buffer, or the tab page when it has only one
+fn close(state: &mut State, _parameters: &[Parameter]) -> Result<(), String> {
+    if state.editor.split.is_some() {
+        state.close_pane();
+    } else if !state.editor.tabs.is_empty() {
+        state.close_tab();
+    } else {
+        return Err(String::from("Cannot close last window"));
+    }
+    Ok(())
+}
+
+/// Open a tab page with the file, or with an empty buffer when no file is given
+fn new_tab(state: &mut State, parameters: &[Parameter]) -> Result<(), String> {
+    state.new_tab();
+    match parameters.first() {
+        Some(Parameter::Path(filepath)) => open_file(state, filepath),
+        _ => {
+            state.editor.add_buffer(ropey::Rope::new(), None);
+            Ok(())
+        }
+    }
+}
+
+fn next_tab(state: &mut State, _parameters: &[Parameter]) -> Result<(), String> {
+    state.next_tab();
+    Ok(())
+}
+
+fn previous_tab(state: &mut State, _parameters: &[Parameter]) -> Result<(), String> {
+    state.previous_tab();
+    Ok(())
+}
+
+fn close_tab(state: &mut State, _parameters: &[Parameter]) -> Result<(), String> {
+    if state.editor.tabs.is_empty() {
+        return Err(String::from("Cannot close the last tab page"));
+    }
+    state.close_tab();
+    Ok(())
+}
+
+/// Divide the window into panes above each other, the new one showing the file if one is given
+fn split(state: &mut State, parameters: &[Parameter]) -> Result<(), String> {
+    split_window(state, parameters, SplitDirection::Horizontal)
+}
+
+/// Divide the window into panes next to each other
+fn vertical_split(state: &mut State, parameters: &[Parameter]) -> Result<(), String> {
+    split_window(state, parameters, SplitDirection::Vertical)
+}
+
+fn split_window(
+    state: &mut State,
+    parameters: &[Parameter],
+    direction: SplitDirection,
+) -> Result<(), String> {
+    state.split(direction)?;
+    match parameters.first() {
+        Some(Parameter::Path(filepath)) => open_file(state, filepath),
+        _ => Ok(()),
+    }
+}
+
+fn write_quit(state: &mut State, parameters: &[Parameter]) -> Result<(), String> {
+    write(state, None, parameters)?;
+    quit(state, &[])
+}
+
+/// Write only when there are changes, then quit
+fn exit(state: &mut State, parameters: &[Parameter]) -> Result<(), String> {
+    if state.editor.buffer().modified {
+        write(state, None, parameters)?;
+    }
+    quit(state, &[])
+}
+
+/// Split a `/pattern/replacement/flags` argument on its (unescaped) delimiter
+fn parse_substitution(argument: &str) -> Result<(String, String, String), String> {
+    let mut characters = argument.chars();
+    let delimiter = characters
+        .next()
+        .filter(|character| !character.is_alphanumeric() && !character.is_whitespace())
+        .ok_or(String::from("Expected a pattern like /old/new/"))?;
+
+    let mut parts = vec![String::new()];
+    while let Some(character) = characters.next() {
+        match character {
+            '\\' => match characters.next() {
+                Some(next) if next == delimiter => parts.last_mut().unwrap().push(next),
+                Some(next) => {
+                    parts.last_mut().unwrap().push('\\');
+                    parts.last_mut().unwrap().push(next);
+                }
+                None => parts.last_mut().unwrap().push('\\'),
+            },
+            _ if character == delimiter && parts.len() < 3 => parts.push(String::new()),
+            _ => parts.last_mut().unwrap().push(character),
+        }
+    }
+    parts.resize(3, String::new());
+
+    let flags = parts.pop().unwrap();
+    let replacement = parts.pop().unwrap();
+    let pattern = parts.pop().unwrap();
+    Ok((pattern, replacement, flags))
+}
+
+/// Translate a Vim style replacement (`&`, `\1`) into the syntax of the regex crate
+fn convert_replacement(replacement: &str) -> String {
+    let mut result = String::new();
+    let mut characters = replacement.chars();
+    while let Some(character) = characters.next() {
+        match character {
+            '&' => result.push_str("${0}"),
+            '$' => result.push_str("$$"),
+            '\\' => match characters.next() {
+                Some(digit) if digit.is_ascii_digit() => {
+                    result.push_str(&format!("${{{}}}", digit))
+                }
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some(next) => result.push(next),
+                None => result.push('\\'),
+            },
+            _ => result.push(character),
+        }
+    }
+    result
+}
+
+/// Replace the pattern on the lines of the range, or on the line of the cursor, and report the
+///     number of substitutions
+fn substitute(
+    state: &mut State,
+    lines: Option<RangeInclusive<usize>>,
+    argument: &str,
+) -> Result<(), String> {
+    let buffer = state.editor.buffer();
+    let current_line = buffer.text.char_to_line(buffer.cursor);
+    let (first_line, last_line) = lines.map_or((current_line, current_line), |lines| {
+        (*lines.start(), *lines.end())
+    });
+    let (regex, replacement, flags) = compile_substitution(state, argument)?;
+
+    // with the `c` flag every match is asked about before it is replaced
+    if flags.contains('c') {
+        let start = state.editor.buffer().text.line_to_char(first_line);
+        let pattern = regex.as_str().to_string();
+        let mut substitution =
+            Substitution::new(regex, replacement, flags.contains('g'), start, last_line);
+        if !substitution.find_next(state.editor.buffer()) {
+            return Err(format!("Pattern not found: {}", pattern));
+        }
+        state.confirm_substitution(substitution);
+        return Ok(());
+    }
+
+    let count = state.editor.buffer_mut().substitute(
+        &regex,
+        &replacement,
+        flags.contains('g'),
+        first_line,
+        last_line,
+    );
+    report_substitutions(state, &regex, count)
+}
+
+/// Return the regex, the replacement in the syntax of the regex crate and the flags of a
+///     `/pattern/replacement/flags` argument
+fn compile_substitution(state: &State, argument: &str) -> Result<(Regex, String, String), String> {
+    let (pattern, replacement, flags) = parse_substitution(argument)?;
+    let regex = compile_pattern(state, &pattern)?;
+    Ok((regex, convert_replacement(&replacement), flags))
+}
+
+/// Compile the pattern of a command. an empty pattern repeats the last search, matched as the
+///     search was, with the case and magic options
+fn compile_pattern(state: &State, pattern: &str) -> Result<Regex, String> {
+    if pattern.is_empty() {
+        return state
+            .options
+            .compile_search(&state.search_query)
+            .ok_or(String::from("No previous search pattern"));
+    }
+    Regex::new(pattern).map_err(|error| format!("Invalid pattern: {}", error))
+}
+
+fn report_substitutions(state: &mut State, regex: &Regex, count: usize) -> Result<(), String> {
+    state.set_message(match count {
+        0 => return Err(format!("Pattern not found: {}", regex.as_str())),
+        1 => String::from("1 substitution"),
+        _ => format!("{} substitutions", count),
+    });
+    Ok(())
+}
+
+/// Run the command after the pattern on the lines of the range, or of the whole buffer, that
+///     match the pattern, as in `:g/TODO/d`
+fn global(
+    state: &mut State,
+    lines: Option<RangeInclusive<usize>>,
+    argument: &str,
+) -> Result<(), String> {
+    run_global(state, lines, argument, false)
+}
+
+/// Run the command on the lines that do not match the pattern, as in `:v/TODO/d`
+fn inverse_global(
+    state: &mut State,
+    lines: Option<RangeInclusive<usize>>,
+    argument: &str,
+) -> Result<(), String> {
+    run_global(state, lines, argument, true)
+}
+
+fn run_global(
+    state: &mut State,
+    lines: Option<RangeInclusive<usize>>,
+    argument: &str,
+    inverse: bool,
+) -> Result<(), String> {
+    let (pattern, command_line) = parse_global(argument)?;
+    let regex = compile_pattern(state, &pattern)?;
+
+    let buffer = state.editor.buffer();
+    let lines = lines.unwrap_or(0..=buffer.get_last_line());
+    let matching_lines: Vec<usize> = lines
+        .filter(|line| {
+            let start = buffer.text.line_to_char(*line);
+            let end = start + buffer.get_line_length(*line);
+            let content = buffer.text.slice(start..end).to_string();
+            regex.is_match(&content) != inverse
+        })
+        .collect();
+    if matching_lines.is_empty() {
+        let pattern = if pattern.is_empty() {
+            &state.search_query
+        } else {
+            &pattern
+        };
+        return Err(format!("Pattern not found: {}", pattern));
+    }
+
+    let (command, range, arguments) = prepare_command(&state.commands, &command_line)?;
+    if range.is_some() {
+        return Err(String::from("No range allowed after the pattern"));
+    }
+    match (command.name, arguments) {
+        // the lines are deleted from the first, as in Vim, so that the register is left with the
+        //     last one. each line deleted moves the ones after it up
+        ("d", Arguments::Raw(argument)) => {
+            for (deleted, line) in matching_lines.iter().enumerate() {
+                let line = line - deleted;
+                delete_lines(state, Some(line..=line), &argument)?;
+            }
+            state.set_message(match matching_lines.len() {
+                1 => String::from("1 fewer line"),
+                count => format!("{} fewer lines", count),
+            });
+            Ok(())
+        }
+        ("s", Arguments::Raw(argument)) => {
+            let (regex, replacement, flags) = compile_substitution(state, &argument)?;
+            if flags.contains('c') {
+                return Err(String::from("The c flag can not be used after the pattern"));
+            }
+            // the lines are gone through from the last, so that adding lines does not move the
+            //     ones that are still to come
+            let buffer = state.editor.buffer_mut();
+            let count = matching_lines
+                .iter()
+                .rev()
+                .map(|line| {
+                    buffer.substitute(&regex, &replacement, flags.contains('g'), *line, *line)
+                })
+                .sum();
+            report_substitutions(state, &regex, count)
+        }
+        (name, _) => Err(format!("Only d and s can follow the pattern, not {}", name)),
+    }
+}
+
+/// Split a `/pattern/command` argument on its (unescaped) delimiter
+fn parse_global(argument: &str) -> Result<(String, String), String> {
+    let mut characters = argument.trim_start().chars();
+    let delimiter = characters
+        .next()
+        .filter(|character| !character.is_alphanumeric() && !character.is_whitespace())
+        .ok_or(String::from("Expected a pattern like /pattern/command"))?;
+
+    let mut pattern = String::new();
+    while let Some(character) = characters.next() {
+        match character {
+            '\\' => match characters.next() {
+                Some(next) if next == delimiter => pattern.push(next),
+                Some(next) => {
+                    pattern.push('\\');
+                    pattern.push(next);
+                }
+                None => pattern.push('\\'),
+            },
+            _ if character == delimiter => break,
+            _ => pattern.push(character),
+        }
+    }
+
+    let command = characters.as_str().trim();
+    if command.is_empty() {
+        return Err(String::from("Expected a command after the pattern"));
+    }
+    Ok((pattern, command.to_string()))
+}
+
+/// Delete the lines of the range, or the line of the cursor, into the register
+fn delete_lines(
+    state: &mut State,
+    lines: Option<RangeInclusive<usize>>,
+    argument: &str,
+) -> Result<(), String> {
+    if !argument.trim().is_empty() {
+        return Err(format!("Unexpected argument for d: {}", argument.trim()));
+    }
+    let buffer = state.editor.buffer_mut();
+    let current_line = buffer.text.char_to_line(buffer.cursor);
+    let lines = lines.unwrap_or(current_line..=current_line);
+
+    let mut start = buffer.text.line_to_char(*lines.start());
+    let end = buffer.text.line_to_char(lines.end() + 1);
+    let mut text = buffer.text.slice(start..end).to_string();
+    // the last line has no newline of its own, so the one before it is removed instead
+    if !text.ends_with('\n') {
+        text.push('\n');
+        start = start.saturating_sub(1);
+    }
+    buffer.remove(start..end);
+    let line = (*lines.start()).min(buffer.get_last_line());
+    buffer.cursor = buffer.get_first_non_blank_cursor(line);
+    state.register = Register {
+        text,
+        linewise: true,
+    };
+    Ok(())
+}
+
+/// Type the keys in normal mode at the start of each line of the range, or once at the cursor
+fn normal(
+    state: &mut State,
+    lines: Option<RangeInclusive<usize>>,
+    argument: &str,
+) -> Result<(), String> {
+    // only the space after the name is left out, the keys may start with spaces of their own
+    let keys = argument.strip_prefix(' ').unwrap_or(argument);
+    if keys.is_empty() {
+        return Err(String::from("Expected the keys to type"));
+    }
+    state.normal_keys = Some(NormalKeys {
+        frames: parse_keys(keys),
+        lines,
+    });
+    Ok(())
+}
+
+/// Move the cursor to the first non-blank character of the line with the given number, or of the
+///     last line of the range, or of the last line of the buffer if the number is past the end
+fn go_to_line(
+    state: &mut State,
+    lines: Option<RangeInclusive<usize>>,
+    argument: &str,
+) -> Result<(), String> {
+    let line = match (argument.trim(), lines) {
+        ("", Some(lines)) => *lines.end(),
+        (number, _) => number
+            .parse::<usize>()
+            .map_err(|_| format!("Invalid line number: {}", number))?
+            .saturating_sub(1),
+    };
+    let cursor = state.editor.buffer().cursor;
+    state.editor.push_jump(cursor);
+    let buffer = state.editor.buffer_mut();
+    let line = line.min(buffer.get_last_line());
+    buffer.cursor = buffer.get_first_non_blank_cursor(line);
+    buffer.desired_column = None;
+    Ok(())
+}
+
+/// Sort the lines of the range, or of the whole buffer, ordered by the flags in the argument:
+///     `n` for numbers, `i` to ignore case and `u` to remove duplicates
+fn sort(
+    state: &mut State,
+    lines: Option<RangeInclusive<usize>>,
+    argument: &str,
+) -> Result<(), String> {
+    let options = parse_sort_flags(argument)?;
+    sort_buffer(state, lines, options)
+}
+
+fn reverse_sort(
+    state: &mut State,
+    lines: Option<RangeInclusive<usize>>,
+    argument: &str,
+) -> Result<(), String> {
+    let options = SortOptions {
+        reverse: true,
+        ..parse_sort_flags(argument)?
+    };
+    sort_buffer(state, lines, options)
+}
+
+fn sort_buffer(
+    state: &mut State,
+    lines: Option<RangeInclusive<usize>>,
+    options: SortOptions,
+) -> Result<(), String> {
+    let buffer = state.editor.buffer_mut();
+    let lines = lines.unwrap_or(0..=buffer.get_last_line());
+    buffer.sort_lines(*lines.start(), *lines.end(), options);
+    Ok(())
+}
+
+fn parse_sort_flags(argument: &str) -> Result<SortOptions, String> {
+    let mut options = SortOptions::default();
+    for flag in argument
+        .chars()
+        .filter(|character| !character.is_whitespace())
+    {
+        match flag {
+            'n' => options.numeric = true,
+            'u' => options.unique = true,
+            'i' => options.ignore_case = true,
+            _ => return Err(format!("Invalid sort flag: {}", flag)),
+        }
+    }
+    Ok(options)
+}
+
+/// Apply the settings of the config file, or of the file at the given path
+fn source(state: &mut State, parameters: &[Parameter]) -> Result<(), String> {
+    let path = match parameters.first() {
+        Some(Parameter::Path(filepath) | Parameter::Append(filepath)) => {
+            Some(std::path::PathBuf::from(filepath))
+        }
+        None => get_config_path(),
+    };
+    let path = path.ok_or(String::from("No config file"))?;
+
+    load_config(state, &path).map_err(|errors| summarize_errors(&errors))?;
+    state.set_message(format!("\"{}\" sourced", path.display()));
+    Ok(())
+}
+
+/// Switch to the theme named in the argument, which may contain spaces as in
+///     `:colorscheme Solarized (dark)`, or list the themes in the quick menu when none is named
+fn color_scheme(state: &mut State, argument: &str) -> Result<(), String> {
+    let name = argument.trim();
+    let name = name
+        .strip_prefix('"')
+        .and_then(|name| name.strip_suffix('"'))
+        .unwrap_or(name);
+    if !name.is_empty() {
+        return set_color_scheme(state, name);
+    }
+
+    let names = state.highlighter.get_theme_names();
+    let current_index = names
+        .iter()
+        .position(|name| *name == state.options.theme)
+        .unwrap_or(0);
+    state.editor.quick_menu = QuickMenu::new(QuickMenuKind::Themes, names, current_index);
+    state.editor.command_cursor = 0;
+    state.mode = Mode::QuickMenu;
+    Ok(())
+}
+
+/// Highlight with the theme and remember it in the config file for the next start
+pub fn set_color_scheme(state: &mut State, name: &str) -> Result<(), String> {
+    state.set_theme(name)?;
+    save_setting("theme", name)?;
+    state.set_message(format!("Theme: {}", name));
+    Ok(())
+}
+
+/// Change the options named in the argument, for example `:set relativenumber shiftwidth=2`
+fn set(state: &mut State, argument: &str) -> Result<(), String> {
+    for option in split_arguments(argument) {
+        match option.split_once('=') {
+            Some((name, value)) => set_value(state, name, value)?,
+            None => set_flag(state, &option)?,
+        }
+    }
+    Ok(())
+}
+
+/// Turn a boolean option on, or off when prefixed with `no`
+fn set_flag(state: &mut State, option: &str) -> Result<(), String> {
+    let (name, enabled) = match option.strip_prefix("no") {
+        Some(name) => (name, false),
+        None => (option, true),
+    };
+    match name {
+        "number" | "nu" | "linenumbers" => state.options.number = enabled,
+        "relativenumber" | "rnu" => state.options.relative_number = enabled,
+        "autoindent" | "ai" => state.options.auto_indent = enabled,
+        "smartindent" | "si" => state.options.smart_indent = enabled,
+        "expandtab" | "et" => state.options.expand_tab = enabled,
+        "createdirectories" => state.options.create_directories = enabled,
+        "autopairs" => state.options.auto_pairs = enabled,
+        "wrap" => state.options.wrap = enabled,
+        "list" => state.options.list = enabled,
+        "cursorline" | "cul" => state.options.cursor_line = enabled,
+        "ignorecase" | "ic" => state.options.ignore_case = enabled,
+        "smartcase" | "scs" => state.options.smart_case = enabled,
+        "magic" => state.options.magic = enabled,
+        "autosave" => state.options.auto_save = enabled,
+        "swapfile" | "swf" => state.options.swap_file = enabled,
+        "readonly" | "ro" => state.editor.buffer_mut().read_only = enabled,
+        _ => return Err(format!("Unknown option: {}", option)),
+    }
+    Ok(())
+}
+
+fn set_value(state: &mut State, name: &str, value: &str) -> Result<(), String> {
+    match (name, value) {
+        ("fileformat" | "ff", "unix") => state.editor.buffer_mut().set_line_ending(LineEnding::Lf),
+        ("fileformat" | "ff", "dos") => state.editor.buffer_mut().set_line_ending(LineEnding::CrLf),
+        ("fileencoding" | "fenc", _) => {
+            let encoding =
+                Encoding::from_name(value).ok_or(format!("Unknown encoding: {}", value))?;
+            state.editor.buffer_mut().set_encoding(encoding);
+        }
+        ("shiftwidth" | "sw", _) => state.options.shift_width = parse_width(name, value)?,
+        ("tabstop" | "ts", _) => state.options.tab_stop = parse_width(name, value)?,
+        ("scrollspeed", _) => state.options.scroll_speed = parse_width(name, value)?,
+        ("swapinterval", _) => {
+            state.options.swap_interval = value
+                .parse::<f32>()
+                .ok()
+                .filter(|interval| *interval >= 0.0)
+                .ok_or(format!("Invalid value for {}: {}", name, value))?;
+        }
+        ("autosavedelay", _) => {
+            state.options.auto_save_delay = value
+                .parse::<f32>()
+                .ok()
+                .filter(|delay| *delay >= 0.0)
+                .ok_or(format!("Invalid value for {}: {}", name, value))?;
+        }
+        ("colorcolumn" | "cc", _) => state.color_columns = parse_columns(name, value)?,
+        ("sidescrolloff" | "siso", _) => {
+            state.side_scroll_off = value
+                .parse()
+                .map_err(|_| format!("Invalid value for {}: {}", name, value))?;
+        }
+        ("theme", _) => state.set_theme(value)?,
+        _ => return Err(format!("Invalid option: {}={}", name, value)),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keymap::create_keymap;
+    use ropey::Rope;
+
+    /// Return the state with a buffer of the text, as it is before any config is loaded
+    fn state(text: &str) -> State {
+        let mut state = State::new(None, create_keymap());
+        state.editor.add_buffer(Rope::from_str(text), None);
+        state
+    }
+
+    /// Run the command line as if it was typed after `:`, returning whether it succeeded
+    fn run(state: &mut State, command_line: &str) -> Result<(), String> {
+        let (command, range, arguments) = prepare_command(&state.commands, command_line)?;
+        command.execute(state, range, arguments)
+    }
+
+    /// Write the text to a file of the test's own in the temporary directory and return its path
+    fn temporary_file(name: &str, text: &str) -> String {
+        let path = std::env::temp_dir().join(format!("{}-{}", name, std::process::id()));
+        std::fs::write(&path, text).unwrap();
+        path.to_str().unwrap().to_string()
+    }
+
+    /// Resolve the range at the start of the line in the text, with the cursor on the line
+    fn resolve(
+        text: &str,
+        cursor_line: usize,
+        line: &str,
+    ) -> Result<RangeInclusive<usize>, String> {
+        let mut buffer = Buffer::new(Rope::from_str(text), None);
+        buffer.cursor = buffer.text.line_to_char(cursor_line);
+        buffer.last_selection = Some((2, 6));
+        match parse_range(line)? {
+            (Some(range), _) => range.resolve(&buffer),
+            (None, _) => Err(String::from("no range")),
+        }
+    }
+
+    #[test]
+    fn parses_ranges_before_commands() {
+        assert_eq!(parse_range("d"), Ok((None, "d")));
+        assert_eq!(parse_range(""), Ok((None, "")));
+        let (range, rest) = parse_range("10,$s/a/b/").unwrap();
+        let range = range.unwrap();
+        assert_eq!(
+            (range.start, range.end, rest),
+            (Address::Number(10), Address::Last, "s/a/b/")
+        );
+        let (range, rest) = parse_range("'<,'>sort").unwrap();
+        let range = range.unwrap();
+        assert_eq!(
+            (range.start, range.end),
+            (Address::Mark('<'), Address::Mark('>'))
+        );
+        assert_eq!(rest, "sort");
+        assert!(parse_range("1,d").is_err());
+        assert!(parse_range("'").is_err());
+        assert!(parse_range("99999999999999999999d").is_err());
+    }
+
+    #[test]
+    fn resolves_ranges_to_lines() {
+        let text = "a\nb\nc\nd\ne\n";
+        assert_eq!(resolve(text, 1, "3"), Ok(2..=2));
+        assert_eq!(resolve(text, 1, "2,4"), Ok(1..=3));
+        assert_eq!(resolve(text, 1, "%"), Ok(0..=4));
+        assert_eq!(resolve(text, 1, "."), Ok(1..=1));
+        assert_eq!(resolve(text, 1, ".,$"), Ok(1..=4));
+        assert_eq!(resolve(text, 1, "'<,'>"), Ok(1..=3));
+        // backwards ranges are swapped, and lines past the end are the last line
+        assert_eq!(resolve(text, 1, "4,2"), Ok(1..=3));
+        assert_eq!(resolve(text, 1, "0,99"), Ok(0..=4));
+        assert!(resolve(text, 1, "'a").is_err());
+    }
+
+    #[test]
+    fn resolves_ranges_in_empty_text_and_without_a_final_newline() {
+        assert_eq!(resolve("", 0, "%"), Ok(0..=0));
+        assert_eq!(resolve("", 0, "5"), Ok(0..=0));
+        assert_eq!(resolve("a\nb", 1, ".,$"), Ok(1..=1));
+    }
+
+    #[test]
+    fn splits_quoted_and_escaped_arguments() {
+        assert!(split_arguments("").is_empty());
+        assert!(split_arguments("   ").is_empty());
+        assert_eq!(split_arguments(" a  b "), vec!["a", "b"]);
+        assert_eq!(
+            split_arguments("\"my file.txt\" b"),
+            vec!["my file.txt", "b"]
+        );
+        assert_eq!(
+            split_arguments("my\\ file \\\"q\\\""),
+            vec!["my file", "\"q\""]
+        );
+        assert_eq!(split_arguments("a\\b"), vec!["a\\b"]);
+        assert_eq!(split_arguments("\"\" x"), vec!["", "x"]);
+        assert_eq!(split_arguments("a\"b c\"d"), vec!["ab cd"]);
+        // an unclosed quote runs to the end of the line
+        assert_eq!(split_arguments("\"open  end"), vec!["open  end"]);
+    }
+
+    #[test]
+    fn parses_where_arguments_were_written() {
+        let arguments = parse_arguments("a \"b c\"  d\\ ");
+        let ranges: Vec<Range<usize>> = arguments.into_iter().map(|(range, _)| range).collect();
+        assert_eq!(ranges, vec![0..1, 2..7, 9..12]);
+        assert_eq!(escape_argument("a \"b\"\tc"), "a\\ \\\"b\\\"\\\tc");
+    }
+
+    #[test]
+    fn completes_paths_with_spaces_as_one_argument() {
+        let directory = std::env::temp_dir().join(format!("completion-{}", std::process::id()));
+        std::fs::create_dir_all(directory.join("my dir")).unwrap();
+        std::fs::write(directory.join("my dir").join("file.txt"), "").unwrap();
+        let directory = directory.to_str().unwrap();
+        let commands = create_commands();
+
+        let line = format!(":e \"{}/my dir/fi", directory);
+        let (start, completions) = get_completions(&commands, &line);
+        assert_eq!(start, 3);
+        assert_eq!(
+            completions,
+            vec![format!("{}/my\\ dir/file.txt", directory)]
+        );
+
+        let line = format!(":e {}/my\\ d", directory);
+        let (start, completions) = get_completions(&commands, &line);
+        assert_eq!(start, 3);
+        assert_eq!(completions, vec![format!("{}/my\\ dir/", directory)]);
+        assert_eq!(
+            split_arguments(&completions[0]),
+            vec![format!("{}/my dir/", directory)]
+        );
+
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[test]
+    fn completes_a_new_word_after_whitespace() {
+        let commands = create_commands();
+        let (start, _) = get_completions(&commands, ":w >> ");
+        assert_eq!(start, 6);
+        let (start, completions) = get_completions(&commands, ":sort ");
+        assert_eq!(start, 6);
+        assert!(completions.is_empty());
+    }
+
+    #[test]
+    fn completes_command_names_to_their_common_prefix() {
+        let commands = create_commands();
+        let (start, completions) = get_completions(&commands, ":bd");
+        assert_eq!(start, 1);
+        assert_eq!(completions, vec!["bd", "bd!", "bdelete", "bdelete!"]);
+        assert_eq!(get_common_prefix(&completions), "bd");
+
+        // only commands that take a path complete their arguments
+        let (start, completions) = get_completions(&commands, ":bd x");
+        assert_eq!(start, 4);
+        assert!(completions.is_empty());
+    }
+
+    #[test]
+    fn write_without_a_path_saves_to_the_loaded_file() {
+        let filepath = temporary_file("write", "a\n");
+        let mut state = state("");
+        run(&mut state, &format!("e {}", filepath)).unwrap();
+        state.editor.buffer_mut().insert(0, "b");
+        run(&mut state, "w").unwrap();
+        assert_eq!(std::fs::read_to_string(&filepath).unwrap(), "ba\n");
+        assert!(!state.editor.buffer().modified);
+        std::fs::remove_file(filepath).unwrap();
+    }
+
+    #[test]
+    fn set_turns_the_indent_options_on_and_off() {
+        let mut state = state("");
+        assert!(state.options.auto_indent);
+        assert!(!state.options.smart_indent);
+        run(&mut state, "set noai si").unwrap();
+        assert!(!state.options.auto_indent);
+        assert!(state.options.smart_indent);
+        run(&mut state, "set autoindent nosmartindent").unwrap();
+        assert!(state.options.auto_indent);
+        assert!(!state.options.smart_indent);
+    }
+
+    #[test]
+    fn set_takes_a_positive_shift_width() {
+        let mut state = state("");
+        run(&mut state, "set sw=2").unwrap();
+        assert_eq!(state.options.shift_width, 2);
+        assert!(run(&mut state, "set shiftwidth=0").is_err());
+        assert!(run(&mut state, "set shiftwidth=x").is_err());
+        assert_eq!(state.options.shift_width, 2);
+        assert!(run(&mut state, "set shiftwidth").is_err());
+    }
+
+    #[test]
+    fn set_takes_the_side_scroll_margin_by_either_name() {
+        let mut state = state("");
+        run(&mut state, "set sidescrolloff=3").unwrap();
+        assert_eq!(state.side_scroll_off, 3);
+        run(&mut state, "set siso=0").unwrap();
+        assert_eq!(state.side_scroll_off, 0);
+        assert!(run(&mut state, "set siso=-1").is_err());
+        assert_eq!(state.side_scroll_off, 0);
+    }
+
+    #[test]
+    fn set_turns_the_cursor_line_on_and_off_by_either_name() {
+        let mut state = state("");
+        assert!(!state.options.cursor_line);
+        run(&mut state, "set cursorline").unwrap();
+        assert!(state.options.cursor_line);
+        run(&mut state, "set nocul").unwrap();
+        assert!(!state.options.cursor_line);
+        run(&mut state, "set cul list").unwrap();
+        assert!(state.options.cursor_line && state.options.list);
+    }
+
+    #[test]
+    fn colorscheme_without_a_name_lists_the_themes_at_the_current_one() {
+        let mut state = state("");
+        run(&mut state, "colorscheme").unwrap();
+        assert!(state.mode == Mode::QuickMenu);
+        let quick_menu = &state.editor.quick_menu;
+        assert_eq!(quick_menu.kind, Some(QuickMenuKind::Themes));
+        assert_eq!(quick_menu.entries[quick_menu.selected], state.options.theme);
+
+        // an unknown theme leaves the current one in place
+        let theme = state.options.theme.clone();
+        assert!(run(&mut state, "colo \"No such theme\"").is_err());
+        assert_eq!(state.options.theme, theme);
+    }
+
+    #[test]
+    fn only_and_close_leave_one_pane_and_keep_the_buffers() {
+        let mut state = state("abc\n");
+        assert!(run(&mut state, "close").is_err());
+        run(&mut state, "only").unwrap();
+
+        run(&mut state, "vsplit").unwrap();
+        run(&mut state, "on").unwrap();
+        assert!(state.editor.split.is_none());
+
+        run(&mut state, "split").unwrap();
+        run(&mut state, "clo").unwrap();
+        assert!(state.editor.split.is_none());
+        assert_eq!(state.editor.buffers.len(), 1);
+    }
+
+    #[test]
+    fn new_opens_an_empty_buffer_but_not_over_an_existing_file() {
+        let filepath = temporary_file("new", "abc\n");
+        let mut state = state("abc\n");
+        assert!(run(&mut state, &format!("new {}", filepath)).is_err());
+        assert_eq!(state.editor.buffers.len(), 1);
+        std::fs::remove_file(&filepath).unwrap();
+
+        run(&mut state, &format!("new {}", filepath)).unwrap();
+        run(&mut state, "enew").unwrap();
+        assert_eq!(state.editor.buffers.len(), 3);
+        assert_eq!(state.editor.current_buffer_index, 2);
+        assert_eq!(state.editor.buffer().text.len_chars(), 0);
+        assert_eq!(state.editor.buffers[1].filepath.as_ref(), Some(&filepath));
+    }
+
+    #[test]
+    fn substitutions_split_on_the_unescaped_delimiter() {
+        let (pattern, replacement, flags) = parse_substitution("/a/b/g").unwrap();
+        assert_eq!(
+            (pattern.as_str(), replacement.as_str(), flags.as_str()),
+            ("a", "b", "g")
+        );
+        let (pattern, replacement, flags) = parse_substitution("#a\\#b\\.#c").unwrap();
+        assert_eq!(
+            (pattern.as_str(), replacement.as_str(), flags.as_str()),
+            ("a#b\\.", "c", "")
+        );
+        assert!(parse_substitution("abc").is_err());
+        assert!(parse_substitution("").is_err());
+    }
+
+    #[test]
+    fn replacements_are_translated_to_the_regex_syntax() {
+        assert_eq!(convert_replacement("[&]"), "[${0}]");
+        assert_eq!(convert_replacement("\\2-\\1"), "${2}-${1}");
+        assert_eq!(convert_replacement("$5\\n\\&"), "$$5\n&");
+    }
+
+    #[test]
+    fn ls_lists_the_buffers_with_the_current_one_selected() {
+        let mut state = state("a\n");
+        state
+            .editor
+            .add_buffer(Rope::from_str("b\n"), Some(String::from("notes.txt")));
+        state.editor.buffer_mut().insert(0, "x");
+        state.editor.current_buffer_index = 0;
+
+        run(&mut state, "ls").unwrap();
+        assert!(state.mode == Mode::QuickMenu);
+        let quick_menu = &state.editor.quick_menu;
+        assert_eq!(quick_menu.kind, Some(QuickMenuKind::Buffers));
+        assert_eq!(quick_menu.entries.len(), 2);
+        assert!(quick_menu.entries[0].starts_with("  1 %a"));
+        assert!(quick_menu.entries[1].ends_with("+ notes.txt"));
+        assert_eq!(quick_menu.selected, 0);
+    }
+
+    #[test]
+    fn write_appends_to_a_file_after_two_angle_brackets() {
+        let filepath = temporary_file("append", "a\n");
+        let mut state = state("b\n");
+        run(&mut state, &format!("w >> {}", filepath)).unwrap();
+        run(&mut state, &format!("w >>{}", filepath)).unwrap();
+        assert_eq!(std::fs::read_to_string(&filepath).unwrap(), "a\nb\nb\n");
+        // the buffer does not take the name of the file it was appended to
+        assert!(state.editor.buffer().filepath.is_none());
+        assert!(run(&mut state, "w >>").is_err());
+        std::fs::remove_file(filepath).unwrap();
+    }
+
+    #[test]
+    fn reload_discards_the_changes_and_keeps_the_cursor_in_the_text() {
+        let filepath = temporary_file("reload", "abc\ndef\nghi\n");
+        let mut state = state("");
+        run(&mut state, &format!("e {}", filepath)).unwrap();
+        let buffer = state.editor.buffer_mut();
+        buffer.insert(0, "x");
+        buffer.cursor = 10;
+        assert!(run(&mut state, "e").is_err());
+
+        std::fs::write(&filepath, "ab\n").unwrap();
+        run(&mut state, "e!").unwrap();
+        let buffer = state.editor.buffer();
+        assert_eq!(buffer.text.to_string(), "ab\n");
+        assert!(!buffer.modified);
+        // the cursor keeps its column on what is now the last line
+        assert_eq!(buffer.cursor, 1);
+        std::fs::remove_file(filepath).unwrap();
+    }
+
+    #[test]
+    fn delete_takes_the_lines_of_the_range_into_the_register() {
+        let mut state = state("a\nb\nc\nd\n");
+        run(&mut state, "2,3d").unwrap();
+        assert_eq!(state.editor.buffer().text.to_string(), "a\nd\n");
+        assert_eq!(state.register.text, "b\nc\n");
+        assert!(state.register.linewise);
+
+        // the last line gives up the newline before it
+        run(&mut state, "$d").unwrap();
+        assert_eq!(state.editor.buffer().text.to_string(), "a\n");
+        assert_eq!(state.register.text, "d\n");
+        assert!(run(&mut state, "d x").is_err());
+    }
+
+    #[test]
+    fn completes_command_names_after_a_range() {
+        let commands = create_commands();
+        let (start, completions) = get_completions(&commands, ":1,2sor");
+        assert_eq!(start, 4);
+        assert!(completions.contains(&String::from("sort")));
+    }
+
+    #[test]
+    fn prepare_command_explains_what_is_wrong() {
+        let commands = create_commands();
+        let error = |line: &str| prepare_command(&commands, line).err();
+        assert_eq!(
+            error(":nope").as_deref(),
+            Some("Not an editor command: nope")
+        );
+        assert_eq!(error(":w a b").as_deref(), Some("Too many arguments for w"));
+        assert_eq!(error(":1,d").as_deref(), Some("Expected a line after ','"));
+        assert!(error(":w \"my file\"").is_none());
+    }
+
+    #[test]
+    fn forced_write_takes_the_same_arguments() {
+        let commands = create_commands();
+        let (command, _, arguments) = prepare_command(&commands, ":w! \"a file\"").unwrap();
+        assert_eq!(command.name, "w!");
+        let Arguments::Parameters(parameters) = arguments else {
+            panic!("w! takes parameters")
+        };
+        assert!(matches!(parameters.as_slice(), [Parameter::Path(path)] if path == "a file"));
+        assert!(prepare_command(&commands, ":w! a b").is_err());
+    }
+
+    #[test]
+    fn large_files_open_once_they_are_loaded_in_the_background() {
+        let text = "a line of a large file\n".repeat(LARGE_FILE_SIZE as usize / 10);
+        let filepath = temporary_file("large", &text);
+        let mut state = state("");
+        run(&mut state, &format!("e {}", filepath)).unwrap();
+        assert_eq!(state.editor.buffers.len(), 1);
+        assert_eq!(state.editor.loading_files.len(), 1);
+
+        let loading_file = state.editor.loading_files.remove(0);
+        let loaded = loading_file.receiver.recv().unwrap().unwrap();
+        open_loaded_file(&mut state, &loading_file.filepath, loaded);
+        assert_eq!(state.editor.buffers.len(), 2);
+        assert_eq!(state.editor.buffer().text.len_chars(), text.len());
+        std::fs::remove_file(filepath).unwrap();
+    }
+
+    #[test]
+    fn write_refuses_to_overwrite_changes_made_on_disk() {
+        let filepath = temporary_file("changed", "a\n");
+        let mut state = state("");
+        run(&mut state, &format!("e {}", filepath)).unwrap();
+        state.editor.buffer_mut().insert(0, "b");
+
+        // another program writes the file after it was loaded
+        std::fs::write(&filepath, "c\n").unwrap();
+        let later = std::time::SystemTime::now() + std::time::Duration::from_secs(10);
+        let file = std::fs::File::options()
+            .write(true)
+            .open(&filepath)
+            .unwrap();
+        file.set_modified(later).unwrap();
+
+        assert!(run(&mut state, "w").is_err());
+        assert_eq!(std::fs::read_to_string(&filepath).unwrap(), "c\n");
+        run(&mut state, "w!").unwrap();
+        assert_eq!(std::fs::read_to_string(&filepath).unwrap(), "ba\n");
+        std::fs::remove_file(filepath).unwrap();
+    }
+
+    #[test]
+    fn a_range_on_its_own_goes_to_its_last_line() {
+        let commands = create_commands();
+        for line in [":5", ":$", ":2,3 ", ":'<,'>"] {
+            let (command, range, arguments) = prepare_command(&commands, line).unwrap();
+            assert_eq!(command.name, "goto", "{}", line);
+            assert!(range.is_some(), "{}", line);
+            assert!(matches!(arguments, Arguments::Raw(argument) if argument.is_empty()));
+        }
+        let (command, range, arguments) = prepare_command(&commands, ":goto 3").unwrap();
+        assert_eq!(command.name, "goto");
+        assert!(range.is_none());
+        assert!(matches!(arguments, Arguments::Raw(argument) if argument.trim() == "3"));
+
+        let mut state = state("a\n  b\nc\n");
+        run(&mut state, "1,2").unwrap();
+        assert_eq!(state.editor.buffer().cursor, 4);
+        // a number past the end goes to the last line
+        run(&mut state, "goto 99").unwrap();
+        assert_eq!(state.editor.buffer().cursor, 6);
+        assert!(run(&mut state, "goto x").is_err());
+    }
+
+    #[test]
+    fn empty_pattern_repeats_the_search_with_its_options() {
+        let mut state = state("Foo bar\nabc\n");
+        state.search_query = String::from("foo");
+        state.options.ignore_case = true;
+        run(&mut state, "s//x/").unwrap();
+        assert_eq!(state.editor.buffer().text.to_string(), "x bar\nabc\n");
+
+        state.search_query = String::from("a.c");
+        run(&mut state, "%s//y/").unwrap();
+        assert_eq!(state.editor.buffer().text.to_string(), "x bar\ny\n");
+
+        state.search_query.clear();
+        assert!(run(&mut state, "s//z/").is_err());
+    }
+
+    #[test]
+    fn splits_global_patterns_from_their_commands() {
+        assert_eq!(
+            parse_global(" /a\\/b\\d/ d "),
+            Ok((String::from("a/b\\d"), String::from("d")))
+        );
+        assert_eq!(
+            parse_global("#x/y# s/x/z/"),
+            Ok((String::from("x/y"), String::from("s/x/z/")))
+        );
+        assert!(parse_global("").is_err());
+        assert!(parse_global("abc").is_err());
+        assert!(parse_global("/pattern/").is_err());
+        assert!(parse_global("/unfinished").is_err());
+    }
+
+    #[test]
+    fn global_delete_leaves_the_last_line_in_the_register() {
+        let mut state = state("foo 1\nbar\nfoo 2\nbaz\nfoo 3\n");
+        run(&mut state, "g/foo/d").unwrap();
+        assert_eq!(state.editor.buffer().text.to_string(), "bar\nbaz\n");
+        assert_eq!(state.register.text, "foo 3\n");
+        assert!(state.register.linewise);
+    }
+
+    #[test]
+    fn inverse_global_deletes_the_other_lines() {
+        let mut state = state("foo 1\nbar\nfoo 2\n");
+        run(&mut state, "v/foo/d").unwrap();
+        assert_eq!(state.editor.buffer().text.to_string(), "foo 1\nfoo 2\n");
+        assert_eq!(state.register.text, "bar\n");
+    }
+}
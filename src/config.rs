@@ -0,0 +1,237 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::highlight::HighlightCache;
+use crate::keymap::parse_shortcut;
+use crate::state::State;
+
+/// Return the directory the config files are read from
+pub fn get_config_directory() -> Option<PathBuf> {
+    let config_home = std::env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+    Some(config_home.join("text-editor"))
+}
+
+/// Return the path of the file with the editor settings
+pub fn get_config_path() -> Option<PathBuf> {
+    Some(get_config_directory()?.join("config.toml"))
+}
+
+/// Return the path of the file the command history is kept in
+fn get_history_path() -> Option<PathBuf> {
+    Some(get_config_directory()?.join("history"))
+}
+
+/// Return the command history saved by an earlier session
+pub fn load_history() -> Vec<String> {
+    get_history_path()
+        .and_then(|path| std::fs::read_to_string(path).ok())
+        .map(|content| content.lines().map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// Save the command history for the next session
+pub fn save_history(entries: &[String]) -> Result<(), String> {
+    let Some(path) = get_history_path() else {
+        return Ok(());
+    };
+    path.parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&path, entries.join("\n") + "\n"))
+        .map_err(|error| {
+            format!(
+                "Could not save the history to {}: {}",
+                path.display(),
+                error
+            )
+        })
+}
+
+/// Apply the settings in the config file, which has lines like `tab_size = 4` or
+///     `theme = "base16-ocean.dark"`. settings that are not in the file keep their values, and
+///     the errors of lines that could not be applied are returned with their line numbers
+pub fn load_config(state: &mut State, path: &Path) -> Result<(), Vec<String>> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|error| vec![format!("Could not read {}: {}", path.display(), error)])?;
+
+    // the themes and syntaxes of the user are loaded first, so that the theme may be one of them
+    let mut lines: Vec<(usize, &str)> = content.lines().enumerate().collect();
+    lines.sort_by_key(|(_, line)| !line.trim_start().starts_with("highlight_directory"));
+
+    let errors: Vec<String> = lines
+        .into_iter()
+        .filter_map(|(index, line)| {
+            apply_setting(state, line)
+                .err()
+                .map(|error| format!("{} line {}: {}", path.display(), index + 1, error))
+        })
+        .collect();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+/// Write the setting into the config file, replacing the line that sets it if there is one, so
+///     that it is applied again at the next start
+pub fn save_setting(name: &str, value: &str) -> Result<(), String> {
+    let path = get_config_path().ok_or(String::from("No config directory"))?;
+    let content = match std::fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(error) => return Err(format!("Could not read {}: {}", path.display(), error)),
+    };
+
+    let setting = format!("{} = \"{}\"", name, value);
+    let mut replaced = false;
+    let mut lines: Vec<String> = content
+        .lines()
+        .map(|line| {
+            let sets_name = line
+                .split_once('=')
+                .is_some_and(|(line_name, _)| line_name.trim() == name);
+            if sets_name && !replaced {
+                replaced = true;
+                setting.clone()
+            } else {
+                line.to_string()
+            }
+        })
+        .collect();
+    if !replaced {
+        lines.push(setting);
+    }
+
+    path.parent()
+        .map_or(Ok(()), std::fs::create_dir_all)
+        .and_then(|_| std::fs::write(&path, lines.join("\n") + "\n"))
+        .map_err(|error| format!("Could not write {}: {}", path.display(), error))
+}
+
+/// Return a message that shows the first of the errors and how many more there are
+pub fn summarize_errors(errors: &[String]) -> String {
+    match errors.len() {
+        0 => String::new(),
+        1 => errors[0].clone(),
+        count => format!("{} (and {} more errors)", errors[0], count - 1),
+    }
+}
+
+fn apply_setting(state: &mut State, line: &str) -> Result<(), String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(());
+    }
+
+    let Some((name, value)) = line.split_once('=') else {
+        return Err(format!("Expected `name = value`, found `{}`", line));
+    };
+    let name = name.trim();
+    let value = value.trim();
+    // strings may be quoted as in TOML
+    let value = value
+        .strip_prefix('"')
+        .and_then(|value| value.strip_suffix('"'))
+        .unwrap_or(value);
+
+    let options = &mut state.options;
+    match name {
+        "tab_size" => {
+            let width = parse_width(name, value)?;
+            options.tab_stop = width;
+            options.shift_width = width;
+        }
+        "tab_stop" => options.tab_stop = parse_width(name, value)?,
+        "shift_width" => options.shift_width = parse_width(name, value)?,
+        "expand_tab" => options.expand_tab = parse_value(name, value)?,
+        "auto_indent" => options.auto_indent = parse_value(name, value)?,
+        "smart_indent" => options.smart_indent = parse_value(name, value)?,
+        "line_numbers" => options.number = parse_value(name, value)?,
+        "relative_line_numbers" => options.relative_number = parse_value(name, value)?,
+        "scroll_speed" => options.scroll_speed = parse_width(name, value)?,
+        "scroll_off" => state.scroll_off = parse_value(name, value)?,
+        "side_scroll_off" => state.side_scroll_off = parse_value(name, value)?,
+        "color_column" => state.color_columns = parse_columns(name, value)?,
+        "persist_history" => options.persist_history = parse_value(name, value)?,
+        "create_directories" => options.create_directories = parse_value(name, value)?,
+        "auto_pairs" => options.auto_pairs = parse_value(name, value)?,
+        "wrap" => options.wrap = parse_value(name, value)?,
+        "list" => options.list = parse_value(name, value)?,
+        "cursor_line" => options.cursor_line = parse_value(name, value)?,
+        "ignore_case" => options.ignore_case = parse_value(name, value)?,
+        "smart_case" => options.smart_case = parse_value(name, value)?,
+        "magic" => options.magic = parse_value(name, value)?,
+        "auto_save" => options.auto_save = parse_value(name, value)?,
+        "auto_save_delay" => options.auto_save_delay = parse_value::<f32>(name, value)?.max(0.0),
+        "swap_file" => options.swap_file = parse_value(name, value)?,
+        "swap_interval" => options.swap_interval = parse_value::<f32>(name, value)?.max(0.0),
+        "line_height" => state.line_height = parse_value::<f32>(name, value)?.max(1.0),
+        "command_box_padding" => state.command_box_padding = parse_value(name, value)?,
+        "initial_movement_delay" => state.key_repeat.initial_delay = parse_value(name, value)?,
+        "inter_movement_delay" => state.key_repeat.interval = parse_value(name, value)?,
+        "leader" => state.sequence.leader = parse_shortcut(value)?,
+        "sequence_timeout" => state.sequence.timeout = parse_value(name, value)?,
+        "highlight_directory" => {
+            let result = state.highlighter.load_directory(Path::new(value));
+            // the saved parse states refer to the syntaxes as they were before
+            for buffer in state.editor.buffers.iter_mut() {
+                buffer.highlight_cache = HighlightCache::default();
+            }
+            result.map_err(|errors| summarize_errors(&errors))?;
+        }
+        "theme" => {
+            // an unknown theme is reported and the current one is kept
+            if let Err(error) = state.set_theme(value) {
+                return Err(format!("{}, using {}", error, state.options.theme));
+            }
+        }
+        _ => return Err(format!("Unknown setting: {}", name)),
+    }
+    Ok(())
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("Invalid value for {}: {}", name, value))
+}
+
+/// Parse a list of columns like `80,100`, which may be empty
+pub fn parse_columns(name: &str, value: &str) -> Result<Vec<usize>, String> {
+    value
+        .split(',')
+        .filter(|column| !column.trim().is_empty())
+        .map(|column| parse_width(name, column.trim()))
+        .collect()
+}
+
+/// Parse a width like a tab stop, which has to be at least 1
+pub fn parse_width(name: &str, value: &str) -> Result<usize, String> {
+    match parse_value(name, value)? {
+        0 => Err(format!("Invalid value for {}: {}", name, value)),
+        width => Ok(width),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn widths_have_to_be_positive() {
+        assert_eq!(parse_width("tab_stop", "4"), Ok(4));
+        assert!(parse_width("tab_stop", "0").is_err());
+        assert!(parse_width("tab_stop", "-1").is_err());
+        assert!(parse_width("tab_stop", "").is_err());
+    }
+
+    #[test]
+    fn columns_may_be_empty() {
+        assert_eq!(parse_columns("color_column", ""), Ok(Vec::new()));
+        assert_eq!(parse_columns("color_column", "80, 100,"), Ok(vec![80, 100]));
+        assert!(parse_columns("color_column", "80,0").is_err());
+    }
+}
@@ -0,0 +1,57 @@
+/// Return how well the query matches the text, or None if the characters of the query do not all
+///     appear in the text in order. matches at the start of a word or path component and runs of
+///     consecutive characters score higher, gaps between the matched characters score lower
+pub fn get_fuzzy_score(query: &str, text: &str) -> Option<i64> {
+    // the query only matches case-sensitively if it contains upper case characters
+    let case_sensitive = query.chars().any(char::is_uppercase);
+    let normalize = |character: char| {
+        if case_sensitive {
+            character
+        } else {
+            character.to_lowercase().next().unwrap_or(character)
+        }
+    };
+
+    let text: Vec<char> = text.chars().collect();
+    let mut score = 0;
+    let mut position = 0;
+    for query_character in query.chars().map(normalize) {
+        let gap = text[position..]
+            .iter()
+            .position(|character| normalize(*character) == query_character)?;
+        let index = position + gap;
+
+        score += 1;
+        if index == 0 || matches!(text[index - 1], '/' | '\\' | '_' | '-' | '.' | ' ') {
+            score += 8;
+        }
+        if gap == 0 && index > 0 {
+            score += 4;
+        } else {
+            score -= gap as i64;
+        }
+        position = index + 1;
+    }
+    Some(score)
+}
+
+/// Return the texts matching the query, the best matches first, and at most limit of them
+pub fn rank_matches(query: &str, texts: &[String], limit: usize) -> Vec<String> {
+    let mut matches: Vec<(i64, &String)> = texts
+        .iter()
+        .filter_map(|text| Some((get_fuzzy_score(query, text)?, text)))
+        .collect();
+
+    // shorter texts go first among equal matches
+    matches.sort_by(|(score, text), (other_score, other_text)| {
+        other_score
+            .cmp(score)
+            .then(text.len().cmp(&other_text.len()))
+            .then(text.cmp(other_text))
+    });
+    matches
+        .into_iter()
+        .take(limit)
+        .map(|(_, text)| text.clone())
+        .collect()
+}
@@ -1,38 +1,341 @@
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
 use ropey::Rope;
-use syntect::easy::HighlightLines;
-use syntect::highlighting::{Style, Theme, ThemeSet};
-use syntect::parsing::SyntaxSet;
-use syntect::util::LinesWithEndings;
-
-/// Highlight the text stored in the given rope and return a list of highlighted lines.
-pub fn highlight(rope: &Rope, extension: &str, theme: &str) -> (Theme, Vec<Vec<(Style, String)>>) {
-    // setup
-    let syntax_set = SyntaxSet::load_defaults_newlines();
-    let theme_set = ThemeSet::load_defaults();
-
-    // create syntax based on extension, select theme and extract string from rope
-    let syntax = syntax_set.find_syntax_by_extension(extension).unwrap();
-    let theme = theme_set.themes[theme].clone();
-    let mut highlight_lines = HighlightLines::new(syntax, &theme);
-    let string = rope.to_string();
-
-    // keep track of highlighted lines in a results vector
-    let mut result: Vec<Vec<(Style, String)>> = Vec::new();
-
-    for line in LinesWithEndings::from(&string) {
-        // map the highlighted strings from a referenced str to an owned one
-        let highlighted_line = Vec::from_iter(
-            highlight_lines
-                .highlight_line(line, &syntax_set)
-                .unwrap()
-                .iter()
-                .map(|(style, string)| (*style, String::from(*string))),
-        );
-        result.push(highlighted_line);
+use syntect::highlighting::{
+    Color, HighlightIterator, HighlightState, Highlighter as ThemeHighlighter, Style, Theme,
+    ThemeSet,
+};
+use syntect::parsing::{ParseState, ScopeStack, SyntaxDefinition, SyntaxSet};
+
+/// number of lines between the parse states kept in a highlight cache
+const CHECKPOINT_INTERVAL: usize = 128;
+
+/// theme whose background and foreground are used by user themes that do not set their own
+const FALLBACK_THEME: &str = "base16-ocean.dark";
+
+/// The syntaxes and themes used for highlighting, which are loaded only once
+pub struct Highlighter {
+    syntax_set: SyntaxSet,
+    theme_set: ThemeSet,
+}
+
+/// Parse states saved while highlighting a buffer, so that highlighting can resume close to the
+///     visible lines instead of at the start of the file
+#[derive(Default)]
+pub struct HighlightCache {
+    /// the extension and theme the states were computed with
+    key: (String, String),
+    /// the states at the start of every `CHECKPOINT_INTERVAL`th line, as far as the text has been
+    ///     highlighted
+    checkpoints: Vec<(ParseState, HighlightState)>,
+}
+
+impl HighlightCache {
+    /// Forget the states that depend on the line, because the text from there on has changed
+    pub fn invalidate(&mut self, line: usize) {
+        self.checkpoints.truncate(line / CHECKPOINT_INTERVAL + 1);
+    }
+}
+
+impl Default for Highlighter {
+    fn default() -> Self {
+        Highlighter {
+            syntax_set: SyntaxSet::load_defaults_newlines(),
+            theme_set: ThemeSet::load_defaults(),
+        }
+    }
+}
+
+impl Highlighter {
+    pub fn get_theme(&self, name: &str) -> Option<&Theme> {
+        self.theme_set.themes.get(name)
+    }
+
+    /// Add the `.tmTheme` color schemes and `.sublime-syntax` definitions in the directory to
+    ///     the loaded ones. a theme is named after its file, and a syntax is used for the
+    ///     extensions it lists. the files that could not be loaded are returned as errors, while
+    ///     the others are still added
+    pub fn load_directory(&mut self, directory: &Path) -> Result<(), Vec<String>> {
+        let entries = std::fs::read_dir(directory)
+            .map_err(|error| vec![format!("Could not read {}: {}", directory.display(), error)])?;
+        let mut paths: Vec<PathBuf> = entries
+            .filter_map(|entry| Some(entry.ok()?.path()))
+            .collect();
+        paths.sort();
+
+        let mut errors = Vec::new();
+        let mut syntaxes = std::mem::take(&mut self.syntax_set).into_builder();
+        for path in paths {
+            let name = path
+                .file_stem()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            let result = match path.extension().and_then(|extension| extension.to_str()) {
+                Some("tmTheme") => ThemeSet::get_theme(&path)
+                    .map(|mut theme| {
+                        self.fill_missing_colors(&mut theme);
+                        self.theme_set.themes.insert(name, theme);
+                    })
+                    .map_err(|error| error.to_string()),
+                Some("sublime-syntax") => std::fs::read_to_string(&path)
+                    .map_err(|error| error.to_string())
+                    .and_then(|content| {
+                        SyntaxDefinition::load_from_str(&content, true, Some(&name))
+                            .map_err(|error| error.to_string())
+                    })
+                    .map(|syntax| syntaxes.add(syntax)),
+                Some("tmLanguage") => Err(String::from(
+                    "Only .sublime-syntax definitions are supported, convert it to one",
+                )),
+                _ => Ok(()),
+            };
+            if let Err(error) = result {
+                errors.push(format!("{}: {}", path.display(), error));
+            }
+        }
+        self.syntax_set = syntaxes.build();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Set the colors that the editor draws with but the theme leaves out. the background and
+    ///     foreground come from the fallback theme, and the caret and selection are derived from
+    ///     the foreground
+    fn fill_missing_colors(&self, theme: &mut Theme) {
+        let fallback = &self.theme_set.themes[FALLBACK_THEME].settings;
+        let settings = &mut theme.settings;
+        settings.background = settings.background.or(fallback.background);
+        settings.foreground = settings.foreground.or(fallback.foreground);
+        let foreground = settings.foreground.unwrap();
+        settings.caret = settings.caret.or(Some(foreground));
+        settings.selection = settings.selection.or(Some(Color {
+            a: 0x40,
+            ..foreground
+        }));
+    }
+
+    /// Return the names of the themes that can be used, in alphabetical order
+    pub fn get_theme_names(&self) -> Vec<String> {
+        self.theme_set.themes.keys().cloned().collect()
+    }
+
+    /// Highlight the lines in the range and return their styled fragments, resuming from the
+    ///     closest state before them that is saved in the cache
+    pub fn highlight(
+        &self,
+        rope: &Rope,
+        cache: &mut HighlightCache,
+        extension: &str,
+        theme_name: &str,
+        theme: &Theme,
+        lines: Range<usize>,
+    ) -> Vec<Vec<(Style, String)>> {
+        let syntax = self
+            .syntax_set
+            .find_syntax_by_extension(extension)
+            .unwrap_or_else(|| self.syntax_set.find_syntax_plain_text());
+        let highlighter = ThemeHighlighter::new(theme);
+
+        let key = (extension.to_string(), theme_name.to_string());
+        if cache.key != key || cache.checkpoints.is_empty() {
+            cache.key = key;
+            cache.checkpoints = vec![(
+                ParseState::new(syntax),
+                HighlightState::new(&highlighter, ScopeStack::new()),
+            )];
+        }
+
+        let first_line = lines.start.min(rope.len_lines());
+        let last_line = lines.end.min(rope.len_lines());
+        let checkpoint = (first_line / CHECKPOINT_INTERVAL).min(cache.checkpoints.len() - 1);
+        let (mut parse_state, mut highlight_state) = cache.checkpoints[checkpoint].clone();
+
+        let mut result = Vec::new();
+        for line_index in checkpoint * CHECKPOINT_INTERVAL..last_line {
+            if line_index % CHECKPOINT_INTERVAL == 0
+                && line_index / CHECKPOINT_INTERVAL == cache.checkpoints.len()
+            {
+                cache
+                    .checkpoints
+                    .push((parse_state.clone(), highlight_state.clone()));
+            }
+
+            let line = rope.line(line_index).to_string();
+            let operations = parse_state
+                .parse_line(&line, &self.syntax_set)
+                .unwrap_or_default();
+            let fragments =
+                HighlightIterator::new(&mut highlight_state, &operations, &line, &highlighter);
+            // the lines before the range only advance the state
+            if line_index < first_line {
+                fragments.for_each(drop);
+            } else {
+                result.push(
+                    fragments
+                        .map(|(style, fragment)| (style, String::from(fragment)))
+                        .collect(),
+                );
+            }
+        }
+        result
+    }
+}
+
+/// Combine background overlays like search matches and the selection into ranges that do not
+///     overlap, limited to the visible range. where overlays overlap, the one that comes later in
+///     the list is shown, and neighbouring ranges with the same color are joined
+pub fn merge_overlays<T: Copy + PartialEq>(
+    overlays: &[(Range<usize>, T)],
+    visible: Range<usize>,
+) -> Vec<(Range<usize>, T)> {
+    let overlays: Vec<(Range<usize>, T)> = overlays
+        .iter()
+        .map(|(range, color)| {
+            let start = range.start.clamp(visible.start, visible.end);
+            let end = range.end.clamp(visible.start, visible.end);
+            (start..end, *color)
+        })
+        .filter(|(range, _)| !range.is_empty())
+        .collect();
+
+    let mut boundaries: Vec<usize> = overlays
+        .iter()
+        .flat_map(|(range, _)| [range.start, range.end])
+        .collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut merged: Vec<(Range<usize>, T)> = Vec::new();
+    for pair in boundaries.windows(2) {
+        let Some((_, color)) = overlays
+            .iter()
+            .rev()
+            .find(|(range, _)| range.contains(&pair[0]))
+        else {
+            continue;
+        };
+        match merged.last_mut() {
+            Some((range, last_color)) if range.end == pair[0] && last_color == color => {
+                range.end = pair[1];
+            }
+            _ => merged.push((pair[0]..pair[1], *color)),
+        }
+    }
+    merged
+}
+
+/// Return the token that starts a line comment in files with the extension
+pub fn get_comment_token(extension: &str) -> Option<&'static str> {
+    match extension {
+        "rs" | "c" | "h" | "cpp" | "hpp" | "cc" | "cs" | "java" | "js" | "jsx" | "ts" | "tsx"
+        | "go" | "swift" | "kt" | "scala" | "dart" | "zig" => Some("//"),
+        "py" | "sh" | "bash" | "zsh" | "rb" | "pl" | "r" | "toml" | "yaml" | "yml" | "conf"
+        | "cmake" | "nix" | "jl" | "ex" | "exs" => Some("#"),
+        "lua" | "sql" | "hs" | "elm" => Some("--"),
+        "vim" => Some("\""),
+        "tex" | "erl" => Some("%"),
+        "lisp" | "clj" | "scm" | "el" | "ini" | "asm" => Some(";"),
+        _ => None,
     }
-    (theme, result)
 }
 
 pub fn convert_color(from: syntect::highlighting::Color) -> notan::prelude::Color {
     notan::prelude::Color::from_bytes(from.r, from.g, from.b, from.a)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn highlighting_resumes_from_the_cached_states() {
+        let highlighter = Highlighter::default();
+        let name = "base16-ocean.dark";
+        let theme = highlighter.get_theme(name).unwrap();
+        // a comment that spans several checkpoints
+        let mut text = String::from("/*\n");
+        for _ in 0..300 {
+            text.push_str("fn a() {}\n");
+        }
+        text.push_str("*/\nfn a() {}\n");
+        let rope = Rope::from_str(&text);
+
+        let mut cache = HighlightCache::default();
+        let all = highlighter.highlight(&rope, &mut cache, "rs", name, theme, 0..rope.len_lines());
+        assert_eq!(cache.checkpoints.len(), 3);
+        let resumed = highlighter.highlight(&rope, &mut cache, "rs", name, theme, 200..303);
+        assert_eq!(resumed, all[200..303]);
+        let mut fresh = HighlightCache::default();
+        let skipped = highlighter.highlight(&rope, &mut fresh, "rs", name, theme, 200..210);
+        assert_eq!(skipped, all[200..210]);
+
+        // an edit drops the states after the edited line
+        cache.invalidate(130);
+        assert_eq!(cache.checkpoints.len(), 2);
+    }
+
+    #[test]
+    fn later_overlays_cover_earlier_ones_and_equal_neighbours_join() {
+        let overlays = [(0..4, 'm'), (4..6, 'm'), (3..8, 's'), (10..20, 'm')];
+        assert_eq!(
+            merge_overlays(&overlays, 1..15),
+            vec![(1..3, 'm'), (3..8, 's'), (10..15, 'm')]
+        );
+        assert!(merge_overlays(&overlays, 8..10).is_empty());
+        assert!(merge_overlays::<char>(&[], 0..10).is_empty());
+    }
+
+    const BARE_THEME: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<plist version="1.0">
+<dict>
+    <key>name</key>
+    <string>Bare</string>
+    <key>settings</key>
+    <array>
+        <dict>
+            <key>settings</key>
+            <dict>
+                <key>foreground</key>
+                <string>#102030</string>
+            </dict>
+        </dict>
+    </array>
+</dict>
+</plist>
+"#;
+
+    #[test]
+    fn themes_have_the_colors_the_editor_draws_with() {
+        let directory = std::env::temp_dir().join(format!("themes-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("bare.tmTheme"), BARE_THEME).unwrap();
+        let mut highlighter = Highlighter::default();
+        highlighter.load_directory(&directory).unwrap();
+        std::fs::remove_dir_all(&directory).unwrap();
+
+        for name in highlighter.get_theme_names() {
+            let settings = &highlighter.get_theme(&name).unwrap().settings;
+            assert!(settings.background.is_some(), "{} has no background", name);
+            assert!(settings.foreground.is_some(), "{} has no foreground", name);
+            assert!(settings.caret.is_some(), "{} has no caret", name);
+            assert!(settings.selection.is_some(), "{} has no selection", name);
+        }
+
+        let settings = &highlighter.get_theme("bare").unwrap().settings;
+        let foreground = Color {
+            r: 0x10,
+            g: 0x20,
+            b: 0x30,
+            a: 0xFF,
+        };
+        assert_eq!(settings.foreground, Some(foreground));
+        assert_eq!(settings.caret, Some(foreground));
+        let fallback = highlighter.get_theme(FALLBACK_THEME).unwrap();
+        assert_eq!(settings.background, fallback.settings.background);
+    }
+}
@@ -1,25 +1,708 @@
 use std::{
-    fs::File,
-    io::{Read, Write},
+    borrow::Cow,
+    fmt,
+    fs::{self, File},
+    io::{BufRead, BufReader, Write},
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::mpsc::{self, Receiver},
+    time::SystemTime,
 };
 
-use ropey::Rope;
+use ropey::{Rope, RopeBuilder};
 
-/// Save the content of the rope to the specified filepath
-pub fn save(rope: &Rope, filepath: &str) -> std::io::Result<()> {
-    let mut file = File::create(filepath)?;
+/// Line ending style of a file. the rope always uses '\n', which is converted on save
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+/// Character encoding of a file. the rope always holds the decoded text, which is encoded again
+///     on save
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Utf8,
+    /// UTF-8 starting with a byte order mark
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+    /// ISO 8859-1, where every byte is the character with the same code point
+    Latin1,
+}
+
+impl Encoding {
+    /// Return the encoding with the name used by `:set fileencoding`
+    pub fn from_name(name: &str) -> Option<Self> {
+        let encoding = match name.to_lowercase().as_str() {
+            "utf-8" | "utf8" => Encoding::Utf8,
+            "utf-8-bom" | "utf8-bom" => Encoding::Utf8Bom,
+            "utf-16le" | "utf16le" => Encoding::Utf16Le,
+            "utf-16be" | "utf16be" => Encoding::Utf16Be,
+            "latin1" | "latin-1" | "iso-8859-1" => Encoding::Latin1,
+            _ => return None,
+        };
+        Some(encoding)
+    }
+
+    fn get_byte_order_mark(&self) -> &'static [u8] {
+        match self {
+            Encoding::Utf8 | Encoding::Latin1 => &[],
+            Encoding::Utf8Bom => &[0xEF, 0xBB, 0xBF],
+            Encoding::Utf16Le => &[0xFF, 0xFE],
+            Encoding::Utf16Be => &[0xFE, 0xFF],
+        }
+    }
+
+    /// Return the bytes of the text in this encoding, without the byte order mark
+    fn encode<'a>(&self, text: &'a str) -> std::io::Result<Cow<'a, [u8]>> {
+        let bytes = match self {
+            Encoding::Utf8 | Encoding::Utf8Bom => Cow::Borrowed(text.as_bytes()),
+            Encoding::Utf16Le => text.encode_utf16().flat_map(u16::to_le_bytes).collect(),
+            Encoding::Utf16Be => text.encode_utf16().flat_map(u16::to_be_bytes).collect(),
+            Encoding::Latin1 => text
+                .chars()
+                .map(|character| u8::try_from(character))
+                .collect::<Result<_, _>>()
+                .map_err(|_| {
+                    std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        "The text contains characters that latin1 cannot encode",
+                    )
+                })?,
+        };
+        Ok(bytes)
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Encoding::Utf8 => "utf-8",
+            Encoding::Utf8Bom => "utf-8-bom",
+            Encoding::Utf16Le => "utf-16le",
+            Encoding::Utf16Be => "utf-16be",
+            Encoding::Latin1 => "latin1",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Decode the content of a file, detecting UTF-8 and UTF-16 by their byte order marks and falling
+///     back to latin1 for text that is not valid UTF-8 or UTF-16. latin1 keeps every byte as it
+///     is, so such a file is written back unchanged. also returns whether the content looks like
+///     binary data rather than text
+fn decode(bytes: Vec<u8>) -> (String, Encoding, bool) {
+    let decode_utf16 = |bytes: &[u8], from_bytes: fn([u8; 2]) -> u16| {
+        // a byte left over cannot be part of UTF-16 text
+        if bytes.len() % 2 != 0 {
+            return None;
+        }
+        let units = bytes
+            .chunks_exact(2)
+            .map(|pair| from_bytes([pair[0], pair[1]]));
+        char::decode_utf16(units)
+            .collect::<Result<String, _>>()
+            .ok()
+    };
+
+    let decoded = match bytes.as_slice() {
+        [0xEF, 0xBB, 0xBF, rest @ ..] => std::str::from_utf8(rest)
+            .ok()
+            .map(|text| (text.to_string(), Encoding::Utf8Bom)),
+        [0xFF, 0xFE, rest @ ..] => {
+            decode_utf16(rest, u16::from_le_bytes).map(|text| (text, Encoding::Utf16Le))
+        }
+        [0xFE, 0xFF, rest @ ..] => {
+            decode_utf16(rest, u16::from_be_bytes).map(|text| (text, Encoding::Utf16Be))
+        }
+        _ => None,
+    };
+    if let Some((text, encoding)) = decoded {
+        return (text, encoding, false);
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(text) => (text, Encoding::Utf8, false),
+        Err(error) => {
+            let bytes = error.into_bytes();
+            // text files hardly ever contain null bytes
+            let binary = bytes.contains(&0);
+            let text = bytes.iter().map(|byte| char::from(*byte)).collect();
+            (text, Encoding::Latin1, binary)
+        }
+    }
+}
+
+/// The content of a loaded file along with how it was stored on disk
+pub struct LoadedFile {
+    pub text: Rope,
+    pub encoding: Encoding,
+    /// whether the file could not be decoded as text. it is then shown as latin1 and read-only
+    pub binary: bool,
+    /// whether the file can be opened for writing
+    pub writable: bool,
+    /// when the file was last modified, as it was read
+    pub file_time: Option<SystemTime>,
+    pub line_ending: LineEnding,
+    /// whether both line ending styles occur. the '\r' characters are then kept in the text
+    ///     and written back unchanged, until the user picks a style to normalize to
+    pub mixed_line_endings: bool,
+}
+
+/// Return the line ending style used by most lines and whether both styles occur
+pub fn detect_line_ending(text: &Rope) -> (LineEnding, bool) {
+    let mut crlf_count = 0;
+    let mut newline_count = 0;
+    // ropey never splits a "\r\n" pair between two chunks
+    for chunk in text.chunks() {
+        crlf_count += chunk.matches("\r\n").count();
+        newline_count += chunk.matches('\n').count();
+    }
+    let lf_count = newline_count - crlf_count;
+
+    let line_ending = if crlf_count > lf_count {
+        LineEnding::CrLf
+    } else {
+        LineEnding::Lf
+    };
+    (line_ending, crlf_count > 0 && lf_count > 0)
+}
+
+/// Return the path of the temporary file used while saving, next to the target so that it is on
+///     the same filesystem and can be renamed over it
+fn get_temporary_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().map_or(String::from("untitled"), |name| {
+        name.to_string_lossy().into_owned()
+    });
+    path.with_file_name(format!(".{}.{}.tmp", file_name, std::process::id()))
+}
+
+/// Add a description of what failed to an io error
+fn with_context(error: std::io::Error, context: &str, path: &Path) -> std::io::Error {
+    std::io::Error::new(
+        error.kind(),
+        format!("{} {}: {}", context, path.display(), error),
+    )
+}
 
-    file.write_all(rope.to_string().as_bytes());
+fn write_file(
+    path: &Path,
+    rope: &Rope,
+    line_ending: LineEnding,
+    encoding: Encoding,
+) -> std::io::Result<()> {
+    let mut file = File::create(path)?;
 
+    file.write_all(encoding.get_byte_order_mark())?;
+    write_text(&mut file, rope, line_ending, encoding)?;
+    file.sync_all()
+}
+
+fn write_text(
+    file: &mut File,
+    rope: &Rope,
+    line_ending: LineEnding,
+    encoding: Encoding,
+) -> std::io::Result<()> {
+    for chunk in rope.chunks() {
+        let chunk = match line_ending {
+            LineEnding::Lf => Cow::Borrowed(chunk),
+            LineEnding::CrLf => Cow::Owned(chunk.replace('\n', "\r\n")),
+        };
+        file.write_all(&encoding.encode(&chunk)?)?;
+    }
     Ok(())
 }
 
-/// Read the file at filepath and return a rope
-pub fn load(filepath: &str) -> std::io::Result<Rope> {
-    let mut file = File::create(filepath)?;
+/// Write the content of the rope to the end of the file at filepath, which has to exist already
+pub fn append(
+    rope: &Rope,
+    filepath: &str,
+    line_ending: LineEnding,
+    encoding: Encoding,
+) -> std::io::Result<()> {
+    let path = Path::new(filepath);
+    let mut file = fs::OpenOptions::new()
+        .append(true)
+        .open(path)
+        .map_err(|error| with_context(error, "Could not open", path))?;
+    write_text(&mut file, rope, line_ending, encoding)
+        .map_err(|error| with_context(error, "Could not write", path))?;
+    file.sync_all()
+}
+
+/// Save the content of the rope to the specified filepath. the content is written to a temporary
+///     file first, which then replaces the target, so a failed write leaves the original intact
+pub fn save(
+    rope: &Rope,
+    filepath: &str,
+    line_ending: LineEnding,
+    encoding: Encoding,
+) -> std::io::Result<()> {
+    let path = Path::new(filepath);
+    let temporary_path = get_temporary_path(path);
+
+    let result = write_file(&temporary_path, rope, line_ending, encoding)
+        .map_err(|error| with_context(error, "Could not write", &temporary_path))
+        .and_then(|_| {
+            // keep the permissions of the file that is replaced
+            if let Ok(metadata) = fs::metadata(path) {
+                let _ = fs::set_permissions(&temporary_path, metadata.permissions());
+            }
+            fs::rename(&temporary_path, path)
+                .map_err(|error| with_context(error, "Could not replace", path))
+        });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&temporary_path);
+    }
+    result
+}
+
+/// The unsaved text of a file, kept in case the editor stops before it is saved
+pub struct Swap {
+    /// id of the process that wrote the swap file
+    pub process_id: u32,
+    pub text: Rope,
+}
+
+/// Return the path of the swap file of the file, a hidden file next to it like `.notes.txt.swp`
+pub fn get_swap_path(filepath: &str) -> PathBuf {
+    let path = Path::new(filepath);
+    let file_name = path.file_name().map_or(String::from("untitled"), |name| {
+        name.to_string_lossy().into_owned()
+    });
+    path.with_file_name(format!(".{}.swp", file_name))
+}
+
+/// Write the text to the swap file of the file, after the id of this process so that another
+///     instance can tell that the file is open
+pub fn write_swap(rope: &Rope, filepath: &str) -> std::io::Result<()> {
+    let path = get_swap_path(filepath);
+    File::create(&path)
+        .and_then(|mut file| {
+            writeln!(file, "{}", std::process::id())?;
+            write_text(&mut file, rope, LineEnding::Lf, Encoding::Utf8)
+        })
+        .map_err(|error| with_context(error, "Could not write", &path))
+}
+
+/// Read the swap file of the file, or None if there is none or it was not written by the editor
+pub fn read_swap(filepath: &str) -> Option<Swap> {
+    let content = fs::read_to_string(get_swap_path(filepath)).ok()?;
+    let (process_id, text) = content.split_once('\n')?;
+    Some(Swap {
+        process_id: process_id.parse().ok()?,
+        text: Rope::from_str(text),
+    })
+}
+
+/// Delete the swap file of the file, if there is one
+pub fn delete_swap(filepath: &str) {
+    let _ = fs::remove_file(get_swap_path(filepath));
+}
+
+/// Whether the process is still running, or None if that cannot be told. processes are looked
+///     up in /proc on Linux and with `kill -0` on other Unix systems
+pub fn is_process_running(process_id: u32) -> Option<bool> {
+    if cfg!(target_os = "linux") {
+        return Some(Path::new("/proc").join(process_id.to_string()).exists());
+    }
+    if !cfg!(unix) {
+        return None;
+    }
+    Command::new("kill")
+        .args(["-0", &process_id.to_string()])
+        .stderr(Stdio::null())
+        .status()
+        .ok()
+        .map(|status| status.success())
+}
+
+/// Return when the file was last modified, or None if that is not known
+pub fn get_modified_time(filepath: &str) -> Option<SystemTime> {
+    fs::metadata(filepath)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+/// Create the directories of the path to the file that do not exist yet
+pub fn create_parent_directories(filepath: &str) -> std::io::Result<()> {
+    match Path::new(filepath).parent() {
+        Some(directory) if !directory.as_os_str().is_empty() => fs::create_dir_all(directory)
+            .map_err(|error| with_context(error, "Could not create directory", directory)),
+        _ => Ok(()),
+    }
+}
+
+/// Directories that are skipped when listing files, besides hidden ones
+const IGNORED_DIRECTORIES: [&str; 2] = ["target", "node_modules"];
+
+/// Return the paths of the files under the directory and its subdirectories, relative to it and
+///     without hidden files. stops after limit files, so that huge trees are not listed entirely
+pub fn list_files(directory: &Path, limit: usize) -> Vec<String> {
+    let mut files = Vec::new();
+    let mut directories = vec![directory.to_path_buf()];
+
+    while let Some(current) = directories.pop() {
+        let Ok(entries) = fs::read_dir(&current) else {
+            continue;
+        };
+        let mut entries: Vec<_> = entries.filter_map(Result::ok).collect();
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(file_type) if file_type.is_dir() => {
+                    if !IGNORED_DIRECTORIES.contains(&name.as_str()) {
+                        directories.push(path);
+                    }
+                }
+                Ok(_) => {
+                    let relative = path.strip_prefix(directory).unwrap_or(&path);
+                    files.push(relative.to_string_lossy().into_owned());
+                    if files.len() >= limit {
+                        return files;
+                    }
+                }
+                Err(_) => {}
+            }
+        }
+    }
+    files
+}
+
+/// Return the paths that complete the partly typed path, with a '/' after directories. hidden
+///     files are only included when the typed file name starts with a '.'
+pub fn get_path_completions(partial_path: &str) -> Vec<String> {
+    let (directory, prefix) = match partial_path.rfind('/') {
+        Some(index) => partial_path.split_at(index + 1),
+        None => ("", partial_path),
+    };
+    let Ok(entries) = fs::read_dir(if directory.is_empty() { "." } else { directory }) else {
+        return Vec::new();
+    };
+
+    // file names only differ by case on case-sensitive filesystems
+    let case_sensitive = !cfg!(any(windows, target_os = "macos"));
+    let matches_prefix = |name: &str| {
+        if case_sensitive {
+            name.starts_with(prefix)
+        } else {
+            name.to_lowercase().starts_with(&prefix.to_lowercase())
+        }
+    };
+
+    let mut completions: Vec<String> = entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !matches_prefix(&name) || name.starts_with('.') && !prefix.starts_with('.') {
+                return None;
+            }
+            let is_directory = entry
+                .file_type()
+                .map_or(false, |file_type| file_type.is_dir());
+            let separator = if is_directory { "/" } else { "" };
+            Some(format!("{}{}{}", directory, name, separator))
+        })
+        .collect();
+    completions.sort();
+    completions
+}
+
+/// Replace the "\r\n" line endings of the text with "\n", one chunk at a time
+fn remove_carriage_returns(text: &Rope) -> Rope {
+    let mut builder = RopeBuilder::new();
+    for chunk in text.chunks() {
+        builder.append(&chunk.replace("\r\n", "\n"));
+    }
+    builder.finish()
+}
+
+/// Stream the file into a rope if it is UTF-8, so that the whole file is never held in memory as
+///     bytes or as a string. returns None for files in another encoding
+fn read_utf8(filepath: &str) -> std::io::Result<Option<(Rope, Encoding)>> {
+    let mut reader = BufReader::new(File::open(filepath)?);
+    let encoding = match reader.fill_buf()? {
+        [0xEF, 0xBB, 0xBF, ..] => Encoding::Utf8Bom,
+        [0xFF, 0xFE, ..] | [0xFE, 0xFF, ..] => return Ok(None),
+        _ => Encoding::Utf8,
+    };
+    if encoding == Encoding::Utf8Bom {
+        reader.consume(3);
+    }
+
+    match Rope::from_reader(reader) {
+        Ok(text) => Ok(Some((text, encoding))),
+        Err(error) if error.kind() == std::io::ErrorKind::InvalidData => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+/// Read the file at filepath and return its decoded content
+pub fn load(filepath: &str) -> std::io::Result<LoadedFile> {
+    // taken before reading, so that a change during the read is noticed later
+    let file_time = get_modified_time(filepath);
+    let (mut text, encoding, binary) = match read_utf8(filepath)? {
+        Some((text, encoding)) => (text, encoding, false),
+        None => {
+            let (text, encoding, binary) = decode(fs::read(filepath)?);
+            (Rope::from_str(&text), encoding, binary)
+        }
+    };
+    // appending is the least intrusive way to try whether the file could be written
+    let writable = fs::OpenOptions::new().append(true).open(filepath).is_ok();
+
+    // files with mixed line endings are kept as they are
+    let (line_ending, mixed_line_endings) = detect_line_ending(&text);
+    let line_ending = if mixed_line_endings {
+        LineEnding::Lf
+    } else {
+        line_ending
+    };
+
+    if line_ending == LineEnding::CrLf {
+        text = remove_carriage_returns(&text);
+    }
+
+    Ok(LoadedFile {
+        text,
+        encoding,
+        binary,
+        writable,
+        file_time,
+        line_ending,
+        mixed_line_endings,
+    })
+}
+
+/// Load the file on another thread, so that reading a large file does not block the editor. the
+///     result is sent through the returned channel
+pub fn load_in_background(filepath: &str) -> Receiver<std::io::Result<LoadedFile>> {
+    let (sender, receiver) = mpsc::channel();
+    let filepath = filepath.to_string();
+    std::thread::spawn(move || {
+        // the receiver is gone if the editor was closed in the meantime
+        let _ = sender.send(load(&filepath));
+    });
+    receiver
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Write the bytes to a file of its own and load it again
+    fn load_bytes(name: &str, bytes: &[u8]) -> LoadedFile {
+        let path = std::env::temp_dir().join(format!("load-{}-{}", std::process::id(), name));
+        fs::write(&path, bytes).unwrap();
+        let loaded = load(path.to_str().unwrap()).unwrap();
+        fs::remove_file(path).unwrap();
+        loaded
+    }
+
+    #[test]
+    fn decodes_by_byte_order_mark() {
+        assert_eq!(decode(Vec::new()), (String::new(), Encoding::Utf8, false));
+        assert_eq!(
+            decode(vec![0xFE, 0xFF, 0x00, 0xE9]),
+            (String::from("\u{e9}"), Encoding::Utf16Be, false)
+        );
+        assert_eq!(
+            decode(vec![0xFF, 0xFE]),
+            (String::new(), Encoding::Utf16Le, false)
+        );
+    }
+
+    #[test]
+    fn odd_utf16_falls_back_to_latin1_without_losing_bytes() {
+        let bytes = vec![0xFF, 0xFE, b'a', 0x00, b'b'];
+        let (text, encoding, binary) = decode(bytes.clone());
+        assert_eq!(encoding, Encoding::Latin1);
+        assert!(binary);
+        assert_eq!(
+            Encoding::Latin1.encode(&text).unwrap().as_ref(),
+            bytes.as_slice()
+        );
+
+        // an unpaired surrogate is not UTF-16 either
+        let (_, encoding, _) = decode(vec![0xFF, 0xFE, 0x00, 0xD8]);
+        assert_eq!(encoding, Encoding::Latin1);
+    }
+
+    #[test]
+    fn encodes_without_byte_order_mark() {
+        assert_eq!(
+            Encoding::Utf16Le.encode("a\u{e9}").unwrap().as_ref(),
+            b"a\x00\xE9\x00"
+        );
+        assert_eq!(Encoding::Utf16Be.encode("").unwrap().as_ref(), b"");
+        assert_eq!(
+            Encoding::Utf8Bom.encode("\u{e9}").unwrap().as_ref(),
+            "\u{e9}".as_bytes()
+        );
+        assert_eq!(Encoding::Latin1.encode("\u{ff}").unwrap().as_ref(), b"\xFF");
+        assert!(Encoding::Latin1.encode("\u{20ac}").is_err());
+    }
+
+    #[test]
+    fn loads_empty_files_and_files_without_a_final_newline() {
+        let loaded = load_bytes("empty", b"");
+        assert_eq!(loaded.text.len_chars(), 0);
+        assert_eq!(loaded.encoding, Encoding::Utf8);
+
+        let loaded = load_bytes("last-line", "a\r\nb\u{e9}".as_bytes());
+        assert_eq!(loaded.text.to_string(), "a\nb\u{e9}");
+        assert_eq!(loaded.line_ending, LineEnding::CrLf);
+    }
+
+    #[test]
+    fn streams_large_files_with_crlf_line_endings() {
+        let text = "line with some text\r\n".repeat(50_000);
+        let loaded = load_bytes("large", text.as_bytes());
+        assert_eq!(loaded.line_ending, LineEnding::CrLf);
+        assert!(!loaded.mixed_line_endings);
+        assert_eq!(loaded.text.len_lines(), 50_001);
+        assert_eq!(loaded.text.to_string(), text.replace("\r\n", "\n"));
+    }
+
+    #[test]
+    fn keeps_mixed_line_endings() {
+        let loaded = load_bytes("mixed", b"a\r\nb\nc\r\n");
+        assert!(loaded.mixed_line_endings);
+        assert_eq!(loaded.line_ending, LineEnding::Lf);
+        assert_eq!(loaded.text.to_string(), "a\r\nb\nc\r\n");
+    }
+
+    #[test]
+    fn falls_back_to_decoding_files_that_are_not_utf8() {
+        let loaded = load_bytes("bom", b"\xEF\xBB\xBFa\n");
+        assert_eq!(loaded.encoding, Encoding::Utf8Bom);
+        assert_eq!(loaded.text.to_string(), "a\n");
+
+        let loaded = load_bytes("latin1", b"caf\xE9\n");
+        assert_eq!(loaded.encoding, Encoding::Latin1);
+        assert_eq!(loaded.text.to_string(), "caf\u{e9}\n");
+
+        let loaded = load_bytes("utf16", b"\xFF\xFEa\x00\n\x00");
+        assert_eq!(loaded.encoding, Encoding::Utf16Le);
+        assert_eq!(loaded.text.to_string(), "a\n");
+    }
+
+    #[test]
+    fn saves_with_the_line_ending_it_loaded() {
+        let path = std::env::temp_dir().join(format!("save-{}", std::process::id()));
+        let filepath = path.to_str().unwrap();
+        for original in ["a\r\nb\r\n", "a\r\n\r\nb", "a\nb\n", ""] {
+            fs::write(&path, original).unwrap();
+            let loaded = load(filepath).unwrap();
+            save(&loaded.text, filepath, loaded.line_ending, loaded.encoding).unwrap();
+            assert_eq!(fs::read_to_string(&path).unwrap(), original);
+        }
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn saving_replaces_the_file_without_leaving_the_temporary_file() {
+        let path = std::env::temp_dir().join(format!("atomic-{}", std::process::id()));
+        let filepath = path.to_str().unwrap();
+        fs::write(&path, "old\n").unwrap();
+        save(
+            &Rope::from_str("new\n"),
+            filepath,
+            LineEnding::Lf,
+            Encoding::Utf8,
+        )
+        .unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new\n");
+        assert!(!get_temporary_path(&path).exists());
+        fs::remove_file(&path).unwrap();
+
+        // a directory that does not exist can not take the temporary file
+        let missing = path.join("missing");
+        let error = save(
+            &Rope::new(),
+            missing.to_str().unwrap(),
+            LineEnding::Lf,
+            Encoding::Utf8,
+        )
+        .unwrap_err();
+        assert!(error.to_string().starts_with("Could not write"));
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn failed_saves_name_the_path_and_leave_no_temporary_file() {
+        let directory = std::env::temp_dir().join(format!("missing-{}", std::process::id()));
+        let path = directory.join("file.txt");
+        let filepath = path.to_str().unwrap();
+        let rope = Rope::from_str("a");
+        let error = save(&rope, filepath, LineEnding::Lf, Encoding::Utf8)
+            .unwrap_err()
+            .to_string();
+        assert!(error.starts_with("Could not write"), "{}", error);
+        let directory_name = directory.display().to_string();
+        assert!(error.contains(&directory_name), "{}", error);
+        assert!(!directory.exists());
+    }
+
+    #[test]
+    fn creates_missing_parent_directories() {
+        let root = std::env::temp_dir().join(format!("parents-{}", std::process::id()));
+        let path = root.join("a").join("b").join("file.txt");
+        create_parent_directories(path.to_str().unwrap()).unwrap();
+        assert!(root.join("a").join("b").is_dir());
+        // existing directories and bare file names are fine as well
+        create_parent_directories(path.to_str().unwrap()).unwrap();
+        create_parent_directories("file.txt").unwrap();
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn remembers_when_loaded_files_were_modified() {
+        let path = std::env::temp_dir().join(format!("modified-{}", std::process::id()));
+        let filepath = path.to_str().unwrap();
+        assert_eq!(get_modified_time(filepath), None);
+        fs::write(&path, "a").unwrap();
+        let loaded = load(filepath).unwrap();
+        assert!(loaded.file_time.is_some());
+        assert_eq!(loaded.file_time, get_modified_time(filepath));
+        fs::remove_file(path).unwrap();
+    }
 
-    let mut buffer_string = String::new();
-    file.read_to_string(&mut buffer_string)?;
+    #[test]
+    fn utf16_files_save_back_byte_for_byte() {
+        let path = std::env::temp_dir().join(format!("utf16-{}", std::process::id()));
+        let filepath = path.to_str().unwrap();
+        let files: [&[u8]; 4] = [
+            b"\xFF\xFEa\x00\r\x00\n\x00\xE9\x00",
+            b"\xFE\xFF\x00a\x00\n\x00\xE9",
+            b"\xFF\xFE",
+            // an odd number of bytes is not UTF-16, and is kept as Latin-1
+            b"\xFF\xFEa\x00b",
+        ];
+        for original in files {
+            fs::write(&path, original).unwrap();
+            let loaded = load(filepath).unwrap();
+            save(&loaded.text, filepath, loaded.line_ending, loaded.encoding).unwrap();
+            assert_eq!(fs::read(&path).unwrap(), original);
+        }
+        fs::remove_file(path).unwrap();
+    }
 
-    Ok(Rope::from_str(&buffer_string))
+    #[test]
+    fn this_process_is_running() {
+        assert_ne!(is_process_running(std::process::id()), Some(false));
+        if cfg!(unix) {
+            assert_eq!(is_process_running(std::process::id()), Some(true));
+            assert_eq!(is_process_running(u32::MAX), Some(false));
+        }
+    }
 }
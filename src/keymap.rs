@@ -0,0 +1,748 @@
+use std::collections::HashMap;
+
+use notan::prelude::KeyCode;
+
+use crate::action::{Action, Case};
+use crate::config::get_config_directory;
+use crate::motion::Motion;
+use crate::state::*;
+
+/// Create the key bindings used when there is no config file
+pub fn create_keymap() -> Keymap {
+    let mut action_bindings = ActionBindings::new();
+    let mut motion_bindings = MotionBindings::new();
+    let mut character_motion_bindings = CharacterMotionBindings::new();
+    let mut mode_change_bindings: HashMap<Mode, ModeChangeBindings> = HashMap::new();
+    let mut insert_mode_change_bindings = ModeChangeBindings::new();
+    let mut replace_mode_change_bindings = ModeChangeBindings::new();
+    let mut normal_mode_change_bindings = ModeChangeBindings::new();
+    let mut command_mode_change_bindings = ModeChangeBindings::new();
+    let mut search_mode_change_bindings = ModeChangeBindings::new();
+    let mut quick_menu_mode_change_bindings = ModeChangeBindings::new();
+    let mut visual_mode_change_bindings = ModeChangeBindings::new();
+    let mut visual_block_mode_change_bindings = ModeChangeBindings::new();
+
+    action_bindings.insert(Shortcut::new(KeyCode::D), Action::Delete);
+    action_bindings.insert(Shortcut::new(KeyCode::C), Action::Replace);
+    action_bindings.insert(Shortcut::new(KeyCode::Y), Action::Yank);
+    action_bindings.insert(Shortcut::new(KeyCode::Period).shift(), Action::Indent);
+    action_bindings.insert(Shortcut::new(KeyCode::Comma).shift(), Action::Dedent);
+
+    motion_bindings.insert(Shortcut::new(KeyCode::H), Motion::Left);
+    motion_bindings.insert(Shortcut::new(KeyCode::J), Motion::Down);
+    motion_bindings.insert(Shortcut::new(KeyCode::K), Motion::Up);
+    motion_bindings.insert(Shortcut::new(KeyCode::L), Motion::Right);
+
+    motion_bindings.insert(Shortcut::new(KeyCode::W), Motion::ForwardWord);
+    motion_bindings.insert(Shortcut::new(KeyCode::E), Motion::ForwardWordEnd);
+    motion_bindings.insert(Shortcut::new(KeyCode::B), Motion::BackWord);
+    motion_bindings.insert(Shortcut::new(KeyCode::W).shift(), Motion::ForwardWORD);
+    motion_bindings.insert(Shortcut::new(KeyCode::E).shift(), Motion::ForwardWORDEnd);
+    motion_bindings.insert(Shortcut::new(KeyCode::B).shift(), Motion::BackWORD);
+    motion_bindings.insert(Shortcut::new(KeyCode::Key4).shift(), Motion::EndOfLine);
+    motion_bindings.insert(Shortcut::new(KeyCode::Key0), Motion::StartOfLine);
+    motion_bindings.insert(Shortcut::new(KeyCode::Key6).shift(), Motion::FirstNonBlank);
+    motion_bindings.insert(Shortcut::new(KeyCode::G).shift(), Motion::LastLine);
+    motion_bindings.insert(
+        Shortcut::new(KeyCode::RBracket).shift(),
+        Motion::NextParagraph,
+    );
+    motion_bindings.insert(
+        Shortcut::new(KeyCode::LBracket).shift(),
+        Motion::PrevParagraph,
+    );
+    motion_bindings.insert(Shortcut::new(KeyCode::Key5).shift(), Motion::MatchBracket);
+    motion_bindings.insert(Shortcut::new(KeyCode::H).shift(), Motion::ScreenTop);
+    motion_bindings.insert(Shortcut::new(KeyCode::M).shift(), Motion::ScreenMiddle);
+    motion_bindings.insert(Shortcut::new(KeyCode::L).shift(), Motion::ScreenBottom);
+
+    character_motion_bindings.insert(Shortcut::new(KeyCode::F), Motion::FindForward);
+    character_motion_bindings.insert(Shortcut::new(KeyCode::F).shift(), Motion::FindBackward);
+    character_motion_bindings.insert(Shortcut::new(KeyCode::T), Motion::TillForward);
+    character_motion_bindings.insert(Shortcut::new(KeyCode::T).shift(), Motion::TillBackward);
+    character_motion_bindings.insert(Shortcut::new(KeyCode::Grave), Motion::Mark);
+    character_motion_bindings.insert(Shortcut::new(KeyCode::Apostrophe), Motion::MarkLine);
+
+    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::I), ModeChange::Insert);
+    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::A).shift(), ModeChange::InsertEnd);
+    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::A), ModeChange::InsertAfter);
+    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::I).shift(), ModeChange::InsertStart);
+    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::O), ModeChange::OpenBelow);
+    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::O).shift(), ModeChange::OpenAbove);
+    normal_mode_change_bindings.insert(
+        Shortcut::new(KeyCode::Semicolon).shift(),
+        ModeChange::EnterCommand,
+    );
+    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::Slash), ModeChange::EnterSearch);
+    normal_mode_change_bindings
+        .insert(Shortcut::new(KeyCode::P).ctrl(), ModeChange::EnterQuickMenu);
+    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::V), ModeChange::EnterVisual);
+    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::R).shift(), ModeChange::EnterReplace);
+    normal_mode_change_bindings.insert(
+        Shortcut::new(KeyCode::V).ctrl(),
+        ModeChange::EnterVisualBlock,
+    );
+    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::Escape), ModeChange::Escape);
+    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::LBracket).ctrl(), ModeChange::Escape);
+
+    insert_mode_change_bindings.insert(Shortcut::new(KeyCode::Escape), ModeChange::Escape);
+    insert_mode_change_bindings.insert(Shortcut::new(KeyCode::LBracket).ctrl(), ModeChange::Escape);
+
+    replace_mode_change_bindings.insert(Shortcut::new(KeyCode::Escape), ModeChange::Escape);
+    replace_mode_change_bindings
+        .insert(Shortcut::new(KeyCode::LBracket).ctrl(), ModeChange::Escape);
+
+    command_mode_change_bindings.insert(Shortcut::new(KeyCode::Escape), ModeChange::Escape);
+    command_mode_change_bindings
+        .insert(Shortcut::new(KeyCode::LBracket).ctrl(), ModeChange::Escape);
+
+    search_mode_change_bindings.insert(Shortcut::new(KeyCode::Escape), ModeChange::Escape);
+    search_mode_change_bindings.insert(Shortcut::new(KeyCode::LBracket).ctrl(), ModeChange::Escape);
+
+    quick_menu_mode_change_bindings.insert(Shortcut::new(KeyCode::Escape), ModeChange::Escape);
+    quick_menu_mode_change_bindings
+        .insert(Shortcut::new(KeyCode::LBracket).ctrl(), ModeChange::Escape);
+
+    visual_mode_change_bindings.insert(Shortcut::new(KeyCode::Escape), ModeChange::Escape);
+    visual_mode_change_bindings.insert(Shortcut::new(KeyCode::LBracket).ctrl(), ModeChange::Escape);
+    visual_mode_change_bindings.insert(Shortcut::new(KeyCode::V), ModeChange::Escape);
+    visual_mode_change_bindings.insert(
+        Shortcut::new(KeyCode::Semicolon).shift(),
+        ModeChange::EnterCommand,
+    );
+    visual_mode_change_bindings.insert(
+        Shortcut::new(KeyCode::V).ctrl(),
+        ModeChange::EnterVisualBlock,
+    );
+
+    visual_block_mode_change_bindings.insert(Shortcut::new(KeyCode::Escape), ModeChange::Escape);
+    visual_block_mode_change_bindings
+        .insert(Shortcut::new(KeyCode::LBracket).ctrl(), ModeChange::Escape);
+    visual_block_mode_change_bindings.insert(Shortcut::new(KeyCode::V).ctrl(), ModeChange::Escape);
+    visual_block_mode_change_bindings.insert(Shortcut::new(KeyCode::V), ModeChange::EnterVisual);
+    visual_block_mode_change_bindings.insert(
+        Shortcut::new(KeyCode::Semicolon).shift(),
+        ModeChange::EnterCommand,
+    );
+    visual_block_mode_change_bindings
+        .insert(Shortcut::new(KeyCode::I).shift(), ModeChange::InsertStart);
+    visual_block_mode_change_bindings
+        .insert(Shortcut::new(KeyCode::A).shift(), ModeChange::InsertEnd);
+
+    mode_change_bindings.insert(Mode::Normal, normal_mode_change_bindings);
+    mode_change_bindings.insert(Mode::Insert, insert_mode_change_bindings);
+    mode_change_bindings.insert(Mode::Replace, replace_mode_change_bindings);
+    mode_change_bindings.insert(Mode::Command, command_mode_change_bindings);
+    mode_change_bindings.insert(Mode::Search, search_mode_change_bindings);
+    mode_change_bindings.insert(Mode::QuickMenu, quick_menu_mode_change_bindings);
+    mode_change_bindings.insert(Mode::Visual, visual_mode_change_bindings);
+    mode_change_bindings.insert(Mode::VisualBlock, visual_block_mode_change_bindings);
+
+    // the `g` commands are sequences like the ones in the keymap file, so they can be rebound
+    let mut sequence_bindings = SequenceBindings::default();
+    let change_case = |case| SequenceCommand::Action(Action::ChangeCase(case));
+    let built_in_sequences = [
+        ("gg", SequenceCommand::FirstLine),
+        ("gJ", SequenceCommand::JoinLines),
+        ("gj", SequenceCommand::DisplayLines(1)),
+        ("gk", SequenceCommand::DisplayLines(-1)),
+        ("gt", SequenceCommand::NextTab),
+        ("gT", SequenceCommand::PreviousTab),
+        ("gu", change_case(Case::Lower)),
+        ("gU", change_case(Case::Upper)),
+        ("g~", change_case(Case::Toggle)),
+        ("gc", SequenceCommand::Action(Action::ToggleComment)),
+        // other editors add a cursor with Ctrl-d, which scrolls half a page as in Vim here. it
+        //     can be rebound with `sequence <C-d> add_cursor`
+        ("<C-n>", SequenceCommand::AddCursor),
+    ];
+    for (keys, command) in built_in_sequences {
+        let keys = parse_sequence(keys).unwrap();
+        sequence_bindings.insert(&keys, command);
+    }
+
+    Keymap {
+        motion_bindings,
+        action_bindings,
+        character_motion_bindings,
+        mode_change_bindings,
+        sequence_bindings,
+    }
+}
+
+/// Return the default key bindings with those of the config file added, or None if there is no
+///     config file. the errors in a malformed file are returned with their line numbers.
+///     each line of the file binds a key to a motion, action or mode change, like
+///     `motion <C-n> down`, `action shift+x delete` or `normal <C-i> insert`, where mode changes
+///     are listed under the mode they apply in. a sequence of keys typed in normal mode is
+///     bound to a command like `command <leader>w write`, where the command is the rest of the
+///     line, replacing a built-in sequence like `gt`, or to a built-in one like
+///     `sequence <C-d> add_cursor`. lines starting with '#' are ignored
+pub fn load_keymap() -> Result<Option<Keymap>, Vec<String>> {
+    let Some(directory) = get_config_directory() else {
+        return Ok(None);
+    };
+    let path = directory.join("keymap");
+    let Ok(content) = std::fs::read_to_string(&path) else {
+        return Ok(None);
+    };
+
+    let mut keymap = create_keymap();
+    let errors: Vec<String> = content
+        .lines()
+        .enumerate()
+        .filter_map(|(index, line)| {
+            parse_binding(&mut keymap, line)
+                .err()
+                .map(|error| format!("{} line {}: {}", path.display(), index + 1, error))
+        })
+        .collect();
+
+    if errors.is_empty() {
+        Ok(Some(keymap))
+    } else {
+        Err(errors)
+    }
+}
+
+/// Add the binding on the line to the keymap
+fn parse_binding(keymap: &mut Keymap, line: &str) -> Result<(), String> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+        return Ok(());
+    }
+
+    // the command may contain spaces, so it is not split like the other bindings
+    if let Some(binding) = line
+        .strip_prefix("command")
+        .filter(|binding| binding.starts_with(char::is_whitespace))
+    {
+        let Some((keys, command)) = binding.trim_start().split_once(char::is_whitespace) else {
+            return Err(format!(
+                "Expected `command <keys> <command>`, found `{}`",
+                line
+            ));
+        };
+        let keys = parse_sequence(keys)?;
+        let command = SequenceCommand::Command(command.trim().to_string());
+        keymap.sequence_bindings.insert(&keys, command);
+        return Ok(());
+    }
+
+    let parts: Vec<&str> = line.split_whitespace().collect();
+    let [section, key, name] = parts[..] else {
+        return Err(format!(
+            "Expected `<section> <key> <name>`, found `{}`",
+            line
+        ));
+    };
+    // a sequence may be several keys, unlike the other bindings
+    if section == "sequence" {
+        let command = parse_sequence_command(name).ok_or(format!("Unknown sequence: {}", name))?;
+        keymap
+            .sequence_bindings
+            .insert(&parse_sequence(key)?, command);
+        return Ok(());
+    }
+    let shortcut = parse_shortcut(key)?;
+
+    match section {
+        "motion" => {
+            let motion = parse_motion(name).ok_or(format!("Unknown motion: {}", name))?;
+            keymap.motion_bindings.insert(shortcut, motion);
+        }
+        "character_motion" => {
+            let motion = parse_character_motion(name).ok_or(format!("Unknown motion: {}", name))?;
+            keymap.character_motion_bindings.insert(shortcut, motion);
+        }
+        "action" => {
+            let action = parse_action(name).ok_or(format!("Unknown action: {}", name))?;
+            keymap.action_bindings.insert(shortcut, action);
+        }
+        _ => {
+            let mode = parse_mode(section).ok_or(format!("Unknown section: {}", section))?;
+            let mode_change =
+                parse_mode_change(name).ok_or(format!("Unknown mode change: {}", name))?;
+            keymap
+                .mode_change_bindings
+                .entry(mode)
+                .or_default()
+                .insert(shortcut, mode_change);
+        }
+    }
+    Ok(())
+}
+
+/// Parse a key with modifiers, written either like `<C-S-d>` or like `ctrl+shift+d`. symbols
+///     typed with shift on a US keyboard, like `$`, imply the shift modifier
+pub fn parse_shortcut(text: &str) -> Result<Shortcut, String> {
+    let (modifiers, key): (Vec<&str>, &str) = match text
+        .strip_prefix('<')
+        .and_then(|text| text.strip_suffix('>'))
+    {
+        Some(inner) => {
+            let mut modifiers = Vec::new();
+            let mut key = inner;
+            // the key itself may be '-', so only a prefix followed by more is a modifier
+            while let Some((modifier, rest)) =
+                key.split_once('-').filter(|(_, rest)| !rest.is_empty())
+            {
+                modifiers.push(modifier);
+                key = rest;
+            }
+            (modifiers, key)
+        }
+        None if text.len() > 1 && text.contains('+') => {
+            let (modifiers, key) = match text.strip_suffix("++") {
+                Some(modifiers) => (modifiers, "+"),
+                None => text.rsplit_once('+').unwrap_or(("", text)),
+            };
+            (modifiers.split('+').collect(), key)
+        }
+        None => (Vec::new(), text),
+    };
+
+    let (key, shift) = parse_key(key).ok_or(format!("Unknown key: {}", key))?;
+    let mut shortcut = Shortcut::new(key);
+    if shift {
+        shortcut = shortcut.shift();
+    }
+    for modifier in modifiers {
+        shortcut = match modifier.to_lowercase().as_str() {
+            "c" | "ctrl" | "control" => shortcut.ctrl(),
+            "s" | "shift" => shortcut.shift(),
+            "a" | "m" | "alt" => shortcut.alt(),
+            _ => return Err(format!("Unknown modifier: {}", modifier)),
+        };
+    }
+    Ok(shortcut)
+}
+
+/// Parse a sequence of keys like `<leader>w` or `gd`, where keys with modifiers or names are
+///     written in angle brackets
+fn parse_sequence(text: &str) -> Result<Vec<SequenceKey>, String> {
+    let mut keys = Vec::new();
+    let mut rest = text;
+    while let Some(character) = rest.chars().next() {
+        let length = match rest.find('>') {
+            Some(end) if character == '<' && end > 1 => end + 1,
+            _ => character.len_utf8(),
+        };
+        let (key, remaining) = rest.split_at(length);
+        keys.push(if key.eq_ignore_ascii_case("<leader>") {
+            SequenceKey::Leader
+        } else {
+            SequenceKey::Key(parse_shortcut(key)?)
+        });
+        rest = remaining;
+    }
+    Ok(keys)
+}
+
+/// Parse keys typed as in `dwA;<Esc>` into the frames they would be received in, one key to a
+///     frame. keys with modifiers or names are written in angle brackets, and a `<` that does
+///     not start one is typed as it is
+pub fn parse_keys(text: &str) -> Vec<MacroFrame> {
+    let mut frames = Vec::new();
+    let mut rest = text;
+    while let Some(character) = rest.chars().next() {
+        let bracketed = rest
+            .find('>')
+            .filter(|end| character == '<' && *end > 1)
+            .and_then(|end| Some((parse_shortcut(&rest[..=end]).ok()?, end + 1)));
+        let (frame, length) = match bracketed {
+            Some((shortcut, length)) => {
+                // a space is typed as a character too, as it would be with the key
+                let characters = if shortcut == Shortcut::new(KeyCode::Space) {
+                    vec![' ']
+                } else {
+                    Vec::new()
+                };
+                let keys = vec![shortcut];
+                (MacroFrame { keys, characters }, length)
+            }
+            None => {
+                let key = match character {
+                    ' ' => Some((KeyCode::Space, false)),
+                    _ => parse_key_character(character),
+                };
+                let keys = key
+                    .map(|(key, shift)| {
+                        let shortcut = Shortcut::new(key);
+                        if shift {
+                            shortcut.shift()
+                        } else {
+                            shortcut
+                        }
+                    })
+                    .into_iter()
+                    .collect();
+                let characters = vec![character];
+                (MacroFrame { keys, characters }, character.len_utf8())
+            }
+        };
+        frames.push(frame);
+        rest = &rest[length..];
+    }
+    frames
+}
+
+/// Return the key code for the key name and whether it is typed with shift
+fn parse_key(name: &str) -> Option<(KeyCode, bool)> {
+    let mut characters = name.chars();
+    if let (Some(character), None) = (characters.next(), characters.next()) {
+        return parse_key_character(character);
+    }
+
+    let key = match name.to_lowercase().as_str() {
+        "esc" | "escape" => KeyCode::Escape,
+        "cr" | "enter" | "return" => KeyCode::Return,
+        "tab" => KeyCode::Tab,
+        "space" => KeyCode::Space,
+        "bs" | "backspace" => KeyCode::Back,
+        "del" | "delete" => KeyCode::Delete,
+        "home" => KeyCode::Home,
+        "end" => KeyCode::End,
+        "pageup" => KeyCode::PageUp,
+        "pagedown" => KeyCode::PageDown,
+        "left" => KeyCode::Left,
+        "right" => KeyCode::Right,
+        "up" => KeyCode::Up,
+        "down" => KeyCode::Down,
+        "lt" => return Some((KeyCode::Comma, true)),
+        "gt" => return Some((KeyCode::Period, true)),
+        _ => return None,
+    };
+    Some((key, false))
+}
+
+fn parse_key_character(character: char) -> Option<(KeyCode, bool)> {
+    const LETTERS: [KeyCode; 26] = [
+        KeyCode::A,
+        KeyCode::B,
+        KeyCode::C,
+        KeyCode::D,
+        KeyCode::E,
+        KeyCode::F,
+        KeyCode::G,
+        KeyCode::H,
+        KeyCode::I,
+        KeyCode::J,
+        KeyCode::K,
+        KeyCode::L,
+        KeyCode::M,
+        KeyCode::N,
+        KeyCode::O,
+        KeyCode::P,
+        KeyCode::Q,
+        KeyCode::R,
+        KeyCode::S,
+        KeyCode::T,
+        KeyCode::U,
+        KeyCode::V,
+        KeyCode::W,
+        KeyCode::X,
+        KeyCode::Y,
+        KeyCode::Z,
+    ];
+    const DIGITS: [KeyCode; 10] = [
+        KeyCode::Key0,
+        KeyCode::Key1,
+        KeyCode::Key2,
+        KeyCode::Key3,
+        KeyCode::Key4,
+        KeyCode::Key5,
+        KeyCode::Key6,
+        KeyCode::Key7,
+        KeyCode::Key8,
+        KeyCode::Key9,
+    ];
+
+    if character.is_ascii_alphabetic() {
+        let index = (character.to_ascii_lowercase() as u8 - b'a') as usize;
+        return Some((LETTERS[index], character.is_ascii_uppercase()));
+    }
+    if let Some(digit) = character.to_digit(10) {
+        return Some((DIGITS[digit as usize], false));
+    }
+
+    let key = match character {
+        ')' => (KeyCode::Key0, true),
+        '!' => (KeyCode::Key1, true),
+        '@' => (KeyCode::Key2, true),
+        '#' => (KeyCode::Key3, true),
+        '$' => (KeyCode::Key4, true),
+        '%' => (KeyCode::Key5, true),
+        '^' => (KeyCode::Key6, true),
+        '&' => (KeyCode::Key7, true),
+        '*' => (KeyCode::Key8, true),
+        '(' => (KeyCode::Key9, true),
+        ',' => (KeyCode::Comma, false),
+        '<' => (KeyCode::Comma, true),
+        '.' => (KeyCode::Period, false),
+        '>' => (KeyCode::Period, true),
+        '/' => (KeyCode::Slash, false),
+        '?' => (KeyCode::Slash, true),
+        ';' => (KeyCode::Semicolon, false),
+        ':' => (KeyCode::Semicolon, true),
+        '\'' => (KeyCode::Apostrophe, false),
+        '"' => (KeyCode::Apostrophe, true),
+        '[' => (KeyCode::LBracket, false),
+        '{' => (KeyCode::LBracket, true),
+        ']' => (KeyCode::RBracket, false),
+        '}' => (KeyCode::RBracket, true),
+        '\\' => (KeyCode::Backslash, false),
+        '|' => (KeyCode::Backslash, true),
+        '-' => (KeyCode::Minus, false),
+        '_' => (KeyCode::Minus, true),
+        '=' => (KeyCode::Equals, false),
+        '+' => (KeyCode::Equals, true),
+        '`' => (KeyCode::Grave, false),
+        '~' => (KeyCode::Grave, true),
+        _ => return None,
+    };
+    Some(key)
+}
+
+fn parse_motion(name: &str) -> Option<Motion> {
+    let motion = match name {
+        "left" => Motion::Left,
+        "right" => Motion::Right,
+        "up" => Motion::Up,
+        "down" => Motion::Down,
+        "forward_word" => Motion::ForwardWord,
+        "forward_word_end" => Motion::ForwardWordEnd,
+        "back_word" => Motion::BackWord,
+        "forward_big_word" => Motion::ForwardWORD,
+        "forward_big_word_end" => Motion::ForwardWORDEnd,
+        "back_big_word" => Motion::BackWORD,
+        "end_of_line" => Motion::EndOfLine,
+        "start_of_line" => Motion::StartOfLine,
+        "first_non_blank" => Motion::FirstNonBlank,
+        "first_line" => Motion::FirstLine,
+        "last_line" => Motion::LastLine,
+        "next_paragraph" => Motion::NextParagraph,
+        "previous_paragraph" => Motion::PrevParagraph,
+        "match_bracket" => Motion::MatchBracket,
+        "screen_top" => Motion::ScreenTop,
+        "screen_middle" => Motion::ScreenMiddle,
+        "screen_bottom" => Motion::ScreenBottom,
+        _ => return None,
+    };
+    Some(motion)
+}
+
+fn parse_character_motion(name: &str) -> Option<fn(char) -> Motion> {
+    let motion: fn(char) -> Motion = match name {
+        "find_forward" => Motion::FindForward,
+        "find_backward" => Motion::FindBackward,
+        "till_forward" => Motion::TillForward,
+        "till_backward" => Motion::TillBackward,
+        "mark" => Motion::Mark,
+        "mark_line" => Motion::MarkLine,
+        _ => return None,
+    };
+    Some(motion)
+}
+
+fn parse_sequence_command(name: &str) -> Option<SequenceCommand> {
+    let command = match name {
+        "first_line" => SequenceCommand::FirstLine,
+        "next_tab" => SequenceCommand::NextTab,
+        "previous_tab" => SequenceCommand::PreviousTab,
+        "add_cursor" => SequenceCommand::AddCursor,
+        _ => return None,
+    };
+    Some(command)
+}
+
+fn parse_action(name: &str) -> Option<Action> {
+    let action = match name {
+        "delete" => Action::Delete,
+        "change" => Action::Replace,
+        "yank" => Action::Yank,
+        "indent" => Action::Indent,
+        "dedent" => Action::Dedent,
+        "lowercase" => Action::ChangeCase(Case::Lower),
+        "uppercase" => Action::ChangeCase(Case::Upper),
+        "toggle_case" => Action::ChangeCase(Case::Toggle),
+        "toggle_comment" => Action::ToggleComment,
+        _ => return None,
+    };
+    Some(action)
+}
+
+fn parse_mode(name: &str) -> Option<Mode> {
+    let mode = match name {
+        "normal" => Mode::Normal,
+        "insert" => Mode::Insert,
+        "replace" => Mode::Replace,
+        "visual" => Mode::Visual,
+        "visual_block" => Mode::VisualBlock,
+        "command" => Mode::Command,
+        "search" => Mode::Search,
+        "quick_menu" => Mode::QuickMenu,
+        _ => return None,
+    };
+    Some(mode)
+}
+
+fn parse_mode_change(name: &str) -> Option<ModeChange> {
+    let mode_change = match name {
+        "insert" => ModeChange::Insert,
+        "insert_after" => ModeChange::InsertAfter,
+        "insert_end" => ModeChange::InsertEnd,
+        "insert_start" => ModeChange::InsertStart,
+        "open_below" => ModeChange::OpenBelow,
+        "open_above" => ModeChange::OpenAbove,
+        "visual" => ModeChange::EnterVisual,
+        "visual_block" => ModeChange::EnterVisualBlock,
+        "replace" => ModeChange::EnterReplace,
+        "escape" => ModeChange::Escape,
+        "command" => ModeChange::EnterCommand,
+        "search" => ModeChange::EnterSearch,
+        "quick_menu" => ModeChange::EnterQuickMenu,
+        _ => return None,
+    };
+    Some(mode_change)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(keymap: &Keymap, keys: &str) -> SequenceMatch {
+        let keys: Vec<Shortcut> = parse_keys(keys)
+            .iter()
+            .filter_map(|frame| frame.keys.first().copied())
+            .collect();
+        let leader = Shortcut::new(KeyCode::Space);
+        keymap.sequence_bindings.find(&keys, leader, false)
+    }
+
+    #[test]
+    fn built_in_sequences_are_bound() {
+        let keymap = create_keymap();
+        assert!(matches!(find(&keymap, "g"), SequenceMatch::Prefix));
+        assert!(matches!(
+            find(&keymap, "gT"),
+            SequenceMatch::Complete(SequenceCommand::PreviousTab)
+        ));
+        assert!(matches!(
+            find(&keymap, "g~"),
+            SequenceMatch::Complete(SequenceCommand::Action(Action::ChangeCase(Case::Toggle)))
+        ));
+    }
+
+    #[test]
+    fn command_bindings_replace_and_extend_built_in_sequences() {
+        let mut keymap = create_keymap();
+        parse_binding(&mut keymap, "command gt tabnext").unwrap();
+        parse_binding(&mut keymap, "command gd  write now").unwrap();
+
+        match find(&keymap, "gt") {
+            SequenceMatch::Complete(SequenceCommand::Command(command)) => {
+                assert_eq!(command, "tabnext");
+            }
+            _ => panic!("expected the bound command"),
+        }
+        match find(&keymap, "gd") {
+            SequenceMatch::Complete(SequenceCommand::Command(command)) => {
+                assert_eq!(command, "write now");
+            }
+            _ => panic!("expected the bound command"),
+        }
+        assert!(matches!(
+            find(&keymap, "gg"),
+            SequenceMatch::Complete(SequenceCommand::FirstLine)
+        ));
+        assert!(parse_binding(&mut keymap, "command gx").is_err());
+    }
+
+    #[test]
+    fn escape_and_ctrl_bracket_leave_every_mode() {
+        let keymap = create_keymap();
+        let escapes = [
+            Shortcut::new(KeyCode::Escape),
+            Shortcut::new(KeyCode::LBracket).ctrl(),
+        ];
+        for (mode, bindings) in &keymap.mode_change_bindings {
+            for shortcut in &escapes {
+                assert!(
+                    bindings.get(shortcut) == Some(&ModeChange::Escape),
+                    "{:?}",
+                    mode
+                );
+            }
+        }
+        assert!(keymap.mode_change_bindings.contains_key(&Mode::Normal));
+    }
+
+    #[test]
+    fn parses_typed_keys_into_frames() {
+        let frames = parse_keys("dA<Esc><C-w>< <Space>");
+        let keys: Vec<Option<Shortcut>> = frames
+            .iter()
+            .map(|frame| frame.keys.first().copied())
+            .collect();
+        let expected = [
+            Some(Shortcut::new(KeyCode::D)),
+            Some(Shortcut::new(KeyCode::A).shift()),
+            Some(Shortcut::new(KeyCode::Escape)),
+            Some(Shortcut::new(KeyCode::W).ctrl()),
+            // a `<` that does not start a key name is typed as it is
+            Some(Shortcut::new(KeyCode::Comma).shift()),
+            Some(Shortcut::new(KeyCode::Space)),
+            Some(Shortcut::new(KeyCode::Space)),
+        ];
+        assert!(keys == expected);
+
+        let characters: String = frames.iter().flat_map(|frame| &frame.characters).collect();
+        assert_eq!(characters, "dA<  ");
+        assert!(parse_keys("").is_empty());
+    }
+
+    #[test]
+    fn adding_a_cursor_is_a_sequence_that_can_be_rebound() {
+        let mut keymap = create_keymap();
+        assert!(matches!(
+            find(&keymap, "<C-n>"),
+            SequenceMatch::Complete(SequenceCommand::AddCursor)
+        ));
+        parse_binding(&mut keymap, "sequence <C-d> add_cursor").unwrap();
+        assert!(matches!(
+            find(&keymap, "<C-d>"),
+            SequenceMatch::Complete(SequenceCommand::AddCursor)
+        ));
+        assert!(parse_binding(&mut keymap, "sequence <C-d> scroll").is_err());
+    }
+
+    #[test]
+    fn shortcuts_are_parsed_in_both_notations() {
+        let mut ctrl_d = Shortcut::new(KeyCode::D);
+        ctrl_d.ctrl = true;
+        assert!(parse_shortcut("<C-d>") == Ok(ctrl_d));
+        assert!(parse_shortcut("ctrl+d") == Ok(ctrl_d));
+        assert!(parse_shortcut("$") == Ok(Shortcut::new(KeyCode::Key4).shift()));
+        assert!(parse_shortcut("<C-->").is_ok());
+        assert!(parse_shortcut("hyper+d").is_err());
+        assert!(parse_shortcut("<nothing>").is_err());
+    }
+
+    #[test]
+    fn bindings_replace_the_defaults_and_report_their_errors() {
+        let mut keymap = create_keymap();
+        parse_binding(&mut keymap, "motion <C-e> end_of_line").unwrap();
+        parse_binding(&mut keymap, "  # a comment").unwrap();
+        let mut ctrl_e = Shortcut::new(KeyCode::E);
+        ctrl_e.ctrl = true;
+        assert!(matches!(
+            keymap.motion_bindings.get(&ctrl_e),
+            Some(Motion::EndOfLine)
+        ));
+
+        assert!(parse_binding(&mut keymap, "motion x").is_err());
+        assert!(parse_binding(&mut keymap, "motion x fly").is_err());
+        assert!(parse_binding(&mut keymap, "elsewhere x insert").is_err());
+    }
+}
@@ -1,30 +1,59 @@
 mod action;
 mod buffer;
+mod commands;
+mod config;
+mod fuzzy;
 mod highlight;
 mod io;
+mod keymap;
 mod motion;
 mod state;
+mod text_object;
 
 use highlight::convert_color;
-use highlight::highlight;
+use highlight::get_comment_token;
+use highlight::merge_overlays;
 
 use action::*;
-use buffer::Buffer;
-use io::{load, save};
+use buffer::{Buffer, Cursor, Sign};
+use commands::{
+    get_common_prefix, get_completions, open_file, open_loaded_file, prepare_command,
+    set_color_scheme,
+};
+use config::{get_config_path, load_config, load_history, save_history, summarize_errors};
+use io::{delete_swap, is_process_running, list_files, load, write_swap, Swap};
+use keymap::{create_keymap, load_keymap};
 use motion::*;
 use notan_egui::TextBuffer;
 use state::*;
+use text_object::TextObject;
 
-use std::collections::HashMap;
+use regex::Regex;
+use std::ops::{Range, RangeInclusive};
+use std::path::Path;
+use std::sync::mpsc::TryRecvError;
+use std::time::Duration;
 
 use notan::app::Plugins;
 use notan::draw::*;
 use notan::prelude::*;
 use notan_egui::{EguiConfig, EguiPluginSugar};
 
-const TAB_SIZE: usize = 4;
-const COMMAND_BOX_PADDING: f32 = 8.0;
-const SHOW_LINE_NUMBERS: bool = true;
+/// seconds between clicks that still count as a double or triple click
+const MULTI_CLICK_TIME: f32 = 0.4;
+/// number of frames a macro may replay, including the macros it calls, before it is stopped
+const MAX_MACRO_FRAMES: usize = 10_000;
+/// how long the error of a failed command stays on screen
+const ERROR_DURATION: Duration = Duration::from_secs(4);
+const ERROR_COLOR: Color = Color::new(0.94, 0.33, 0.31, 1.0);
+const MODIFIER_KEYS: [KeyCode; 6] = [
+    KeyCode::LShift,
+    KeyCode::RShift,
+    KeyCode::LControl,
+    KeyCode::RControl,
+    KeyCode::LAlt,
+    KeyCode::RAlt,
+];
 
 #[notan_main]
 fn main() -> Result<(), String> {
@@ -69,472 +98,3562 @@ fn setup(app: &mut App, gfx: &mut Graphics, plugins: &mut Plugins) -> State {
 
 print(fib(0))"#;
 
-    let mut action_bindings = ActionBindings::new();
-    let mut motion_bindings = MotionBindings::new();
-    let mut mode_change_bindings: HashMap<Mode, ModeChangeBindings> = HashMap::new();
-    let mut insert_mode_change_bindings = ModeChangeBindings::new();
-    let mut normal_mode_change_bindings = ModeChangeBindings::new();
-    let mut command_mode_change_bindings = ModeChangeBindings::new();
-
-    action_bindings.insert(Shortcut::new(KeyCode::D), Action::Delete);
-    action_bindings.insert(Shortcut::new(KeyCode::C), Action::Replace);
-
-    motion_bindings.insert(Shortcut::new(KeyCode::H), Motion::Left);
-    motion_bindings.insert(Shortcut::new(KeyCode::J), Motion::Down);
-    motion_bindings.insert(Shortcut::new(KeyCode::K), Motion::Up);
-    motion_bindings.insert(Shortcut::new(KeyCode::L), Motion::Right);
-
-    motion_bindings.insert(Shortcut::new(KeyCode::W), Motion::ForwardWord);
-    motion_bindings.insert(Shortcut::new(KeyCode::E), Motion::ForwardWordEnd);
-    motion_bindings.insert(Shortcut::new(KeyCode::B), Motion::BackWord);
-    motion_bindings.insert(Shortcut::new(KeyCode::Key4).shift(), Motion::EndOfLine);
-
-    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::I), ModeChange::Insert);
-    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::A).shift(), ModeChange::InsertEnd);
-    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::A), ModeChange::InsertAfter);
-    normal_mode_change_bindings.insert(Shortcut::new(KeyCode::I).shift(), ModeChange::InsertStart);
-    normal_mode_change_bindings.insert(
-        Shortcut::new(KeyCode::Semicolon).shift(),
-        ModeChange::EnterCommand,
-    );
-
-    insert_mode_change_bindings.insert(Shortcut::new(KeyCode::Escape), ModeChange::Escape);
-    insert_mode_change_bindings.insert(Shortcut::new(KeyCode::LBracket).ctrl(), ModeChange::Escape);
-
-    command_mode_change_bindings.insert(Shortcut::new(KeyCode::Escape), ModeChange::Escape);
-    command_mode_change_bindings
-        .insert(Shortcut::new(KeyCode::LBracket).ctrl(), ModeChange::Escape);
-
-    mode_change_bindings.insert(Mode::Normal, normal_mode_change_bindings);
-    mode_change_bindings.insert(Mode::Insert, insert_mode_change_bindings);
-    mode_change_bindings.insert(Mode::Command, command_mode_change_bindings);
-
-    let keymap = Keymap {
-        motion_bindings,
-        action_bindings,
-        mode_change_bindings,
+    // a malformed config file is reported and the default bindings are used instead
+    let (keymap, message) = match load_keymap() {
+        Ok(keymap) => (keymap.unwrap_or_else(create_keymap), None),
+        Err(errors) => (create_keymap(), Some(summarize_errors(&errors))),
     };
 
-    State {
-        font,
-        line_height: 16.0,
-
-        buffer: Buffer {
-            cursor: 0,
-            text: ropey::Rope::from(text_string),
-        },
-        command_line: String::new(),
-
-        mode: Mode::Normal,
+    let mut editor = Editor::new();
+    editor.add_buffer(ropey::Rope::from(text_string), None);
 
-        action: Option::None,
-        keymap,
+    let mut state = State::new(Some(font), keymap);
+    state.editor = editor;
+    if let Some(message) = message {
+        state.set_message(message);
+    }
 
-        last_time: 0.0,
-        inter_movement_delay: 0.05,
-        initial_movement_delay: 0.005,
+    // settings from the config file override the defaults above
+    if let Some(path) = get_config_path().filter(|path| path.exists()) {
+        if let Err(errors) = load_config(&mut state, &path) {
+            state.set_message(summarize_errors(&errors));
+        }
     }
+    if state.options.persist_history {
+        state.editor.command_history.entries = load_history();
+    }
+    state
 }
 
 fn event(state: &mut State, event: Event) {
+    if let Event::MouseWheel { delta_x, delta_y } = event {
+        scroll_wheel(state, delta_x, delta_y);
+    }
+
+    // notan does not report when the window loses the focus, so leaving it with the mouse is
+    //     taken as the sign to save
+    if let Event::MouseLeft { .. } = event {
+        state.auto_save.flush = true;
+    }
+
+    if let Event::ReceivedCharacter(c) = event {
+        // characters that are replayed are already part of the macro that is being recorded
+        if state.editor.macros.recording.is_some() && state.editor.macros.replay_depth == 0 {
+            state.editor.macros.characters.push(c);
+        }
+    }
+
     match state.mode {
-        Mode::Normal => {}
+        Mode::Normal | Mode::Visual | Mode::VisualBlock => match event {
+            Event::ReceivedCharacter(c) if !c.is_control() => {
+                state.received_character = Some(c);
+            }
+            _ => {}
+        },
         Mode::Insert => match event {
             Event::ReceivedCharacter(c) if c != '\u{7f}' && !c.is_control() => {
-                state.buffer.text.insert_char(state.buffer.cursor, c);
-                state.buffer.move_x(1);
+                insert_character(state, c);
+            }
+            _ => {}
+        },
+        Mode::Replace => match event {
+            Event::ReceivedCharacter(c) if c != '\u{7f}' && !c.is_control() => {
+                overwrite_character(state, c);
+            }
+            _ => {}
+        },
+        Mode::Command | Mode::Search => match event {
+            Event::ReceivedCharacter(c) if c != '\u{7f}' && !c.is_control() => {
+                state.command_line.insert(state.editor.command_cursor, c);
+                state.editor.command_cursor += c.len_utf8();
+                state.editor.completion = None;
             }
             _ => {}
         },
-        Mode::Command => match event {
+        Mode::QuickMenu => match event {
             Event::ReceivedCharacter(c) if c != '\u{7f}' && !c.is_control() => {
-                state.command_line.push(c);
+                let quick_menu = &mut state.editor.quick_menu;
+                quick_menu.line.insert(state.editor.command_cursor, c);
+                state.editor.command_cursor += c.len_utf8();
+                quick_menu.update_entries();
             }
             _ => {}
         },
     }
 }
 
-fn was_pressed_or_held(app: &mut App, state: &mut State, key_code: KeyCode) -> bool {
-    let pressed = app.keyboard.was_pressed(key_code)
-        || ((app.keyboard.down_delta(key_code) > state.initial_movement_delay)
-            && app.timer.elapsed_f32() - state.last_time > state.inter_movement_delay);
-    if pressed {
-        state.last_time = app.timer.elapsed_f32();
+/// Return the character that closes a pair opened by the given one
+fn get_closing_character(character: char) -> Option<char> {
+    match character {
+        '(' => Some(')'),
+        '[' => Some(']'),
+        '{' => Some('}'),
+        '"' => Some('"'),
+        '\'' => Some('\''),
+        _ => None,
+    }
+}
+
+/// Insert a character typed in insert mode. with autopairs set, an opening bracket or quote
+///     is closed right away and a closing one that is already after the cursor is typed over
+fn insert_character(state: &mut State, c: char) {
+    state.editor.inserted_text.push(c);
+    let auto_pairs = state.options.auto_pairs;
+    state.editor.buffer_mut().at_each_cursor(|buffer| {
+        let next = buffer.text.get_char(buffer.cursor);
+        let is_closing = matches!(c, ')' | ']' | '}' | '"' | '\'');
+        if auto_pairs && is_closing && next == Some(c) {
+            buffer.cursor += 1;
+            return;
+        }
+
+        // a quote after a word is more likely an apostrophe than the start of a string
+        let previous = buffer
+            .cursor
+            .checked_sub(1)
+            .map(|index| buffer.text.char(index));
+        let after_word = previous.is_some_and(|previous| previous.is_alphanumeric());
+        match get_closing_character(c) {
+            Some(closing) if auto_pairs && !(matches!(c, '"' | '\'') && after_word) => {
+                buffer.insert(buffer.cursor, &format!("{}{}", c, closing));
+            }
+            _ => buffer.insert(buffer.cursor, &c.to_string()),
+        }
+        buffer.cursor += 1;
+    });
+}
+
+/// Type the character over the one under the cursor in replace mode, remembering the one it
+///     replaced so that backspace can put it back
+fn overwrite_character(state: &mut State, c: char) {
+    state.editor.inserted_text.push(c);
+    let replaced = state.editor.buffer_mut().overwrite_character(c);
+    state.editor.overwritten.push(replaced);
+}
+
+/// Undo the last character typed in replace mode, putting back the character it replaced. before
+///     the first typed character, the cursor only moves left
+fn restore_overwritten_character(state: &mut State) {
+    let overwritten = state.editor.overwritten.pop();
+    let buffer = state.editor.buffer_mut();
+    if buffer.cursor == 0 {
+        return;
+    }
+    match overwritten {
+        Some(replaced) => {
+            state.editor.inserted_text.pop();
+            state.editor.buffer_mut().restore_character(replaced);
+        }
+        None if buffer.find_line_position(buffer.cursor) > 0 => buffer.cursor -= 1,
+        None => {}
+    }
+}
+
+fn was_pressed_or_held(input: &KeyInput, state: &mut State, key_code: KeyCode) -> bool {
+    state.key_repeat.is_triggered(
+        key_code,
+        input.was_pressed(key_code),
+        input.down_delta(key_code),
+        input.time,
+    )
+}
+
+/// Return the input of the keyboard in this frame
+fn get_key_input(app: &App) -> KeyInput {
+    let keyboard = &app.keyboard;
+    let is_modifier = |key: &KeyCode| MODIFIER_KEYS.contains(key);
+    KeyInput {
+        pressed: keyboard
+            .pressed
+            .iter()
+            .filter(|key| !is_modifier(key))
+            .copied()
+            .collect(),
+        down: keyboard
+            .down
+            .iter()
+            .filter(|(key, _)| !is_modifier(key))
+            .map(|(key, time)| (*key, *time))
+            .collect(),
+        ctrl: keyboard.ctrl(),
+        alt: keyboard.alt(),
+        shift: keyboard.shift(),
+        time: app.timer.elapsed_f32(),
     }
-    pressed
 }
 
-fn get_action_input(app: &App, state: &Keymap) -> Option<Action> {
+fn get_action_input(input: &KeyInput, state: &Keymap) -> Option<Action> {
     for (shortcut, action) in state.action_bindings.iter() {
-        if app.keyboard.was_pressed(shortcut.key) {
+        if input.matches(shortcut) {
             return Some(action.clone());
         }
     }
     Option::None
 }
 
-fn get_motion_input(app: &App, state: &mut State) -> Option<Motion> {
+fn get_character_motion_input(input: &KeyInput, state: &Keymap) -> Option<fn(char) -> Motion> {
+    for (shortcut, motion) in state.character_motion_bindings.iter() {
+        if input.matches(shortcut) {
+            return Some(*motion);
+        }
+    }
+    None
+}
+
+fn get_motion_input(input: &KeyInput, state: &mut State) -> Option<Motion> {
     let mut result: Option<Motion> = None;
 
     for (shortcut, motion) in state.keymap.motion_bindings.iter() {
-        let shift = shortcut.shift == app.keyboard.shift();
-        let control = shortcut.ctrl == app.keyboard.ctrl();
-        let alt = shortcut.alt == app.keyboard.alt();
-        let modifiers_satisfied = shift && control && alt;
-
-        let just_pressed = app.keyboard.was_pressed(shortcut.key);
-        let continuous_pressed = (app.keyboard.down_delta(shortcut.key)
-            > state.initial_movement_delay)
-            && app.timer.elapsed_f32() - state.last_time > state.inter_movement_delay;
-        let pressed = (just_pressed || continuous_pressed) && modifiers_satisfied;
+        let modifiers_satisfied = shortcut.matches_modifiers(input.shift, input.ctrl, input.alt);
+
+        // the repeat of the key is only checked for the binding with the held modifiers, which
+        //     would otherwise use it up
+        let pressed = modifiers_satisfied
+            && state.key_repeat.is_triggered(
+                shortcut.key,
+                input.was_pressed(shortcut.key),
+                input.down_delta(shortcut.key),
+                input.time,
+            );
         if pressed {
             result = Some(motion.clone());
-            state.last_time = app.timer.elapsed_f32();
         }
     }
     result
 }
 
-fn execute_command(state: &mut State) {
-    println!("{}", state.command_line);
+/// Leave the current mode for normal mode, as escape does
+fn return_to_normal_mode(state: &mut State) {
+    if state.mode == Mode::Search {
+        cancel_search_preview(state);
+    }
+    // in normal mode, escape cancels an operator that is waiting for its motion,
+    //     and then the extra cursors
+    if state.mode == Mode::Normal && state.action.take().is_none() {
+        state.editor.buffer_mut().cursors.clear();
+    }
+    if let Mode::Insert | Mode::Replace = state.mode {
+        // the cursor steps back onto the last inserted character, as in Vim, so
+        //     that it does not rest on the newline
+        state.editor.buffer_mut().at_each_cursor(|buffer| {
+            if buffer.find_line_position(buffer.cursor) > 0 {
+                buffer.cursor -= 1;
+            }
+        });
+        if std::mem::take(&mut state.block_insert) {
+            state.editor.buffer_mut().cursors.clear();
+        }
+    }
+    state.mode = Mode::Normal;
+    state.editor.buffer_mut().selection_anchor = None;
+}
+
+/// Return the change of mode bound to the keys of the frame in the current mode
+fn get_mode_change_input(input: &KeyInput, state: &State) -> Option<ModeChange> {
+    let bindings = state.keymap.mode_change_bindings.get(&state.mode)?;
+    bindings
+        .iter()
+        .find(|(shortcut, _)| input.matches(shortcut))
+        .map(|(_, mode_change)| mode_change.clone())
+}
 
-    match state.command_line.clone() {
-        x if x.get(1..2) == Some("w") => {
-            let mut splits = x.split(" ");
-            splits.next();
-            if let Some(string) = splits.next() {
-                let result = save(&state.buffer.text, string);
-                println!("{:#}", result.is_ok());
+/// Leave the current mode as the mode change does, dropping the count and the message
+fn change_mode(state: &mut State, mode_change: ModeChange) {
+    state.count = None;
+    state.message = None;
+    match mode_change {
+        ModeChange::Insert
+        | ModeChange::InsertAfter
+        | ModeChange::InsertEnd
+        | ModeChange::InsertStart
+        | ModeChange::OpenBelow
+        | ModeChange::OpenAbove => {
+            if state.mode == Mode::VisualBlock {
+                let append = matches!(mode_change, ModeChange::InsertAfter | ModeChange::InsertEnd);
+                start_block_insert(state, append);
+            } else {
+                start_insert(state, mode_change);
             }
         }
-        x if x.get(1..2) == Some("q") => {
-            std::process::exit(0);
+        ModeChange::Escape => return_to_normal_mode(state),
+        ModeChange::EnterReplace => {
+            state.mode = Mode::Replace;
+            state.editor.overwritten.clear();
+            record_change(state, Change::Overwrite);
+        }
+        ModeChange::EnterVisual | ModeChange::EnterVisualBlock => {
+            report_primary_cursor_only(state, "Visual mode");
+            // switching between the visual modes keeps the selection
+            if let Mode::Normal | Mode::Insert = state.mode {
+                let buffer = state.editor.buffer_mut();
+                buffer.selection_anchor = Some(buffer.cursor);
+            }
+            state.mode = match mode_change {
+                ModeChange::EnterVisual => Mode::Visual,
+                _ => Mode::VisualBlock,
+            };
+        }
+        ModeChange::EnterCommand => {
+            state.command_line.clear();
+            state.command_line.push(':');
+            // from visual mode, the command applies to the lines of the selection, which
+            //     are remembered as the marks `<` and `>`
+            if let Mode::Visual | Mode::VisualBlock = state.mode {
+                let buffer = state.editor.buffer_mut();
+                if let Some((start, end)) = buffer.get_selection() {
+                    buffer.last_selection = Some((start, end.saturating_sub(1).max(start)));
+                }
+                buffer.selection_anchor = None;
+                state.command_line.push_str("'<,'>");
+            }
+            state.mode = Mode::Command;
+            state.editor.command_cursor = state.command_line.len();
+            state.editor.command_history.reset();
+            state.editor.completion = None;
+        }
+        ModeChange::EnterSearch => {
+            state.mode = Mode::Search;
+            state.command_line.clear();
+            state.command_line.push('/');
+            state.editor.command_cursor = 1;
+            state.editor.search_history.reset();
+            state.editor.search_start = Some(state.editor.buffer().cursor);
+        }
+        ModeChange::EnterQuickMenu => {
+            state.mode = Mode::QuickMenu;
+            let files = list_files(Path::new("."), MAX_FINDER_FILES);
+            state.editor.quick_menu = QuickMenu::new(QuickMenuKind::Files, files, 0);
+            state.editor.command_cursor = 0;
         }
-        _ => {}
     }
-
-    state.command_line.clear();
-    state.mode = Mode::Normal;
 }
 
-fn update(app: &mut App, state: &mut State) {
-    if app.keyboard.was_pressed(KeyCode::Return) && app.keyboard.alt() {
-        let is_fullscreen = app.window().is_fullscreen();
-        app.window().set_fullscreen(!is_fullscreen);
+/// Tell that only the primary cursor is used by what is done, if there are extra cursors
+fn report_primary_cursor_only(state: &mut State, what: &str) {
+    if !state.editor.buffer().cursors.is_empty() {
+        state.set_message(format!("{} only uses the primary cursor", what));
     }
+}
 
-    if state.mode == Mode::Normal {
-        // if there is a new action input, replace the previous
-        let input_action = get_action_input(app, &state.keymap);
-        if let Some(new_action) = input_action {
-            state.action = Some(new_action.clone());
-            println!("{:?}", new_action);
-        }
+/// Move the cursor by the motion, or apply the pending action over the range it covers
+fn execute_motion(state: &mut State, motion: Motion) {
+    if state.action.is_some() {
+        report_primary_cursor_only(state, "The operator");
+    }
+    let vertical = matches!(motion, Motion::Up | Motion::Down);
+    let count = state.count.take();
+    let (scroll_top, visible_lines) = (state.scroll_top, state.visible_lines);
+    let shift_width = state.options.shift_width;
+    let expand_tab = state.options.expand_tab;
+    let buffer = state.editor.buffer_mut();
+
+    if vertical {
+        buffer.remember_column();
+    } else {
+        buffer.desired_column = None;
     }
 
-    let mut enacted_mode_change: Option<ModeChange> = None;
-    for mode in state.keymap.mode_change_bindings.keys().cloned() {
-        for (shortcut, mode_change) in state.keymap.mode_change_bindings.get(&mode).unwrap() {
-            let shift = shortcut.shift == app.keyboard.shift();
-            let control = shortcut.ctrl == app.keyboard.ctrl();
-            let alt = shortcut.alt == app.keyboard.alt();
-            let modifiers_satisfied = shift && control && alt;
+    let last_line = buffer.get_last_line();
+    let last_visible_line = (scroll_top + visible_lines.max(1) - 1).min(last_line);
+    let visible = scroll_top.min(last_line)..=last_visible_line;
+    let target = motion
+        .get_screen_target(buffer, visible, count)
+        .or_else(|| motion.clone().get_target(buffer, count));
+    let Some(target) = target else {
+        // a failed motion also cancels the pending action
+        state.action = None;
+        return;
+    };
 
-            if mode == state.mode && app.keyboard.was_pressed(shortcut.key) && modifiers_satisfied {
-                enacted_mode_change = Some((mode_change).clone());
-            }
+    if let Some(action) = state.action.take() {
+        if motion.is_linewise() {
+            // the lines from the cursor to the target are handled like `dd` with a count
+            let first_line = buffer.text.char_to_line(buffer.cursor.min(target));
+            let last_line = buffer.text.char_to_line(buffer.cursor.max(target));
+            buffer.cursor = buffer.cursor.min(target);
+            state.count = Some(last_line - first_line + 1);
+            execute_line_action(state, action);
+            return;
         }
-    }
 
-    if let Some(mode_change) = enacted_mode_change {
-        match mode_change {
-            ModeChange::Insert => {
-                state.mode = Mode::Insert;
-            }
-            ModeChange::InsertAfter => {
-                state.mode = Mode::Insert;
-                state.buffer.move_x(1);
+        let Range { start, end } = motion.get_action_range(buffer, target);
+
+        match action {
+            Action::Yank => {
+                state.register = Register {
+                    text: buffer.text.slice(start..end).to_string(),
+                    linewise: false,
+                };
+                buffer.cursor = start;
             }
-            ModeChange::InsertEnd => {
-                state.mode = Mode::Insert;
+            Action::Delete | Action::Replace => {
+                // the deleted text can be pasted again, unless nothing was deleted
+                if let Some(text) = buffer.cut(start..end) {
+                    state.register = Register {
+                        text,
+                        linewise: false,
+                    };
+                }
+                buffer.cursor = start;
             }
-            ModeChange::InsertStart => {
-                state.mode = Mode::Insert;
+            Action::Indent | Action::Dedent => {
+                let first_line = buffer.text.char_to_line(start);
+                let last_line = buffer.text.char_to_line(end).min(buffer.get_last_line());
+                let indent = action == Action::Indent;
+                buffer.shift_lines(first_line, last_line, shift_width, expand_tab, indent);
             }
-            ModeChange::Escape => {
-                state.mode = Mode::Normal;
+            Action::ChangeCase(case) => {
+                buffer.change_case(start, end, case);
+                buffer.cursor = start;
             }
-            ModeChange::EnterCommand => {
-                state.mode = Mode::Command;
-                state.command_line.clear();
-                state.command_line.push(':');
+            Action::ToggleComment => {
+                let first_line = buffer.text.char_to_line(start);
+                let last_line = buffer.text.char_to_line(end).min(buffer.get_last_line());
+                toggle_comment(state, first_line, last_line);
             }
         }
-        return;
+
+        if let Action::Replace = action {
+            state.mode = Mode::Insert;
+        }
+        if action != Action::Yank {
+            record_change(
+                state,
+                Change::Motion {
+                    action,
+                    motion,
+                    count,
+                },
+            );
+        }
+    } else {
+        if motion.is_jump() {
+            let cursor = buffer.cursor;
+            state.editor.push_jump(cursor);
+        }
+        let buffer = state.editor.buffer_mut();
+        buffer.cursor = target;
+        if !buffer.cursors.is_empty() {
+            move_secondary_cursors(buffer, motion, count);
+        }
     }
-    match state.mode {
-        Mode::Normal => {
-            let action = state.action.clone();
-
-            if let Some(motion) = get_motion_input(app, state) {
-                let target = motion.get_target(&state.buffer);
-                if let Some(action) = action {
-                    match action {
-                        Action::Delete => {
-                            if state.buffer.cursor <= target {
-                                state.buffer.text.remove(state.buffer.cursor..target);
-                            } else {
-                                state.buffer.text.remove(target..state.buffer.cursor);
-                                state.buffer.cursor = target;
-                            }
-                        }
-                        Action::Replace => {
-                            state.mode = Mode::Insert;
-                            if state.buffer.cursor <= target {
-                                state.buffer.text.remove(state.buffer.cursor..target);
-                            } else {
-                                state.buffer.text.remove(target..state.buffer.cursor);
-                                state.buffer.cursor = target;
-                            }
-                        }
-                    }
-                    state.action = None;
-                } else {
-                    state.buffer.cursor = target;
-                }
-            }
+}
 
-            if was_pressed_or_held(app, state, KeyCode::Equals) && app.keyboard.ctrl() {
-                state.line_height += 1f32;
-            }
+/// Move each secondary cursor by the motion, from its own position
+fn move_secondary_cursors(buffer: &mut Buffer, motion: Motion, count: Option<usize>) {
+    let desired_column = buffer.desired_column;
+    let primary = buffer.cursor;
+    for index in 0..buffer.cursors.len() {
+        buffer.cursor = buffer.cursors[index];
+        buffer.desired_column = None;
+        if let Some(target) = motion.clone().get_target(buffer, count) {
+            buffer.cursors[index] = target;
+        }
+    }
+    buffer.cursor = primary;
+    buffer.desired_column = desired_column;
+    buffer.merge_cursors();
+}
 
-            if was_pressed_or_held(app, state, KeyCode::Minus) && app.keyboard.ctrl() {
-                state.line_height = (state.line_height - 1f32).max(1f32);
-            }
+/// Comment out the lines or uncomment them, with the comment token of the buffer's file type
+fn toggle_comment(state: &mut State, first_line: usize, last_line: usize) {
+    let buffer = state.editor.buffer_mut();
+    let Some(token) = get_comment_token(buffer.get_extension()) else {
+        let error = String::from("No comment token for this file type");
+        state.set_error(error);
+        return;
+    };
+    buffer.toggle_comment(first_line, last_line, token);
+}
 
-            if app.keyboard.was_pressed(KeyCode::A) {
-                state.buffer.move_x(1);
-                state.mode = Mode::Insert;
-                return;
-            }
+/// Move the cursor by display lines, as `gj` and `gk`. without wrapping every line is a single
+///     display line
+fn move_display_lines(state: &mut State, direction: i32) {
+    let count = state.count.take().unwrap_or(1).max(1) as i32;
+    let tab_stop = state.options.tab_stop;
+    let Some(width) = state.wrap_width else {
+        let motion = if direction > 0 {
+            Motion::Down
+        } else {
+            Motion::Up
+        };
+        state.count = Some(count as usize);
+        execute_motion(state, motion);
+        return;
+    };
+    let buffer = state.editor.buffer_mut();
+    let cursor = buffer.cursor;
+    buffer.cursor = buffer.get_display_line_movement(cursor, direction * count, width, tab_stop);
+    buffer.desired_column = None;
+}
 
-            if app.keyboard.was_pressed(KeyCode::X) {
-                state
-                    .buffer
-                    .text
-                    .remove(state.buffer.cursor..state.buffer.cursor + 1);
-                state.buffer.move_x(0);
-            }
-        }
-        Mode::Insert => {
-            if was_pressed_or_held(app, state, KeyCode::Back) {
-                if state.buffer.cursor > 0 {
-                    state
-                        .buffer
-                        .text
-                        .remove(state.buffer.cursor - 1..state.buffer.cursor);
-                    state.buffer.move_x(-1);
-                }
-            }
+/// Go back to the position before the last jump, or forward again after going back
+fn jump(state: &mut State, forward: bool) {
+    let current = (
+        state.editor.current_buffer_index,
+        state.editor.buffer().cursor,
+    );
+    let jumps = &mut state.editor.jumps;
+    let position = if forward {
+        jumps.forward()
+    } else {
+        jumps.back(current)
+    };
+    let Some((buffer_index, cursor)) = position else {
+        return;
+    };
+    if buffer_index >= state.editor.buffers.len() {
+        return;
+    }
 
-            if was_pressed_or_held(app, state, KeyCode::Return) {
-                state.buffer.text.insert_char(state.buffer.cursor, '\n');
-                state.buffer.move_x(1)
-            }
+    state.editor.switch_buffer(buffer_index);
+    let buffer = state.editor.buffer_mut();
+    buffer.cursor = cursor;
+    buffer.clamp_cursor();
+    buffer.desired_column = None;
+}
 
-            if was_pressed_or_held(app, state, KeyCode::Tab) {
-                state
-                    .buffer
-                    .text
-                    .insert(state.buffer.cursor, &" ".repeat(TAB_SIZE));
-                state.buffer.move_x(TAB_SIZE as i32);
-            }
+/// Apply the action to whole lines starting at the cursor's line, as done by doubling an operator
+fn execute_line_action(state: &mut State, action: Action) {
+    report_primary_cursor_only(state, "The operator");
+    state.action = None;
+    let count = state.count.take().unwrap_or(1);
+    if action != Action::Yank {
+        record_change(
+            state,
+            Change::Line {
+                action: action.clone(),
+                count,
+            },
+        );
+    }
+    let shift_width = state.options.shift_width;
+    let expand_tab = state.options.expand_tab;
+    let buffer = state.editor.buffer_mut();
+
+    let first_line = buffer.text.char_to_line(buffer.cursor);
+    let last_line = (first_line + count.max(1) - 1).min(buffer.get_last_line());
+    if let Action::Indent | Action::Dedent = action {
+        let indent = action == Action::Indent;
+        buffer.shift_lines(first_line, last_line, shift_width, expand_tab, indent);
+        return;
+    }
+    if let Action::ChangeCase(case) = action {
+        let start = buffer.text.line_to_char(first_line);
+        let end = buffer.text.line_to_char(last_line) + buffer.get_line_length(last_line);
+        buffer.change_case(start, end, case);
+        return;
+    }
+    if action == Action::ToggleComment {
+        toggle_comment(state, first_line, last_line);
+        return;
+    }
 
-            if was_pressed_or_held(app, state, KeyCode::Delete) {
-                let length = state.buffer.text.len_chars();
-                state
-                    .buffer
-                    .text
-                    .remove(state.buffer.cursor..(state.buffer.cursor + 1).min(length));
-            }
-        }
+    let mut start = buffer.text.line_to_char(first_line);
+    let end = buffer.text.line_to_char(last_line + 1);
 
-        Mode::Command => {
-            if was_pressed_or_held(app, state, KeyCode::Return) {
-                execute_command(state);
-            }
+    let mut text = buffer.text.slice(start..end).to_string();
+    let has_newline = text.ends_with('\n');
+    if !has_newline {
+        text.push('\n');
+    }
+    state.register = Register {
+        text,
+        linewise: true,
+    };
 
-            if was_pressed_or_held(app, state, KeyCode::Back) {
-                state.command_line.pop();
-                if state.command_line.is_empty() {
-                    state.mode = Mode::Normal;
-                }
+    match action {
+        Action::Yank
+        | Action::Indent
+        | Action::Dedent
+        | Action::ChangeCase(_)
+        | Action::ToggleComment => {}
+        Action::Delete => {
+            // the last line has no newline of its own, so remove the one before it instead
+            if !has_newline && start > 0 {
+                start -= 1;
             }
+            buffer.remove(start..end);
+            let line = first_line.min(buffer.get_last_line());
+            buffer.cursor = buffer.get_first_non_blank_cursor(line);
+        }
+        Action::Replace => {
+            let end = if has_newline { end - 1 } else { end };
+            buffer.remove(start..end);
+            buffer.cursor = start;
+            state.mode = Mode::Insert;
         }
     }
+    buffer.desired_column = None;
 }
 
-fn calculate_camera_offset(
-    cursor_x: usize,
-    cursor_y: usize,
-    char_width: f32,
-    char_height: f32,
-    screen_size: (u32, u32),
-) -> (f32, f32) {
-    let margin_x = 8;
-    let margin_y = 4;
-
-    let (cursor_x, cursor_y) = (
-        (cursor_x + margin_x + 1) as f32 * char_width,
-        (cursor_y + margin_y + 1) as f32 * char_height,
-    );
+/// Delete the characters under and after the cursor, but never past the end of the line
+fn delete_characters(state: &mut State) {
+    let count = state.count.take().unwrap_or(1);
+    record_change(state, Change::DeleteCharacters { count });
 
-    let (screen_x, screen_y) = screen_size;
-    (
-        -(cursor_x - screen_x as f32).max(0.0),
-        -(cursor_y - screen_y as f32).max(0.0),
-    )
+    // the register gets the characters deleted at the primary cursor
+    let mut deleted = None;
+    state.editor.buffer_mut().at_each_cursor(|buffer| {
+        if let Some(text) = buffer.delete_characters(count) {
+            deleted.get_or_insert(text);
+        }
+    });
+    if let Some(text) = deleted {
+        state.register = Register {
+            text,
+            linewise: false,
+        };
+    }
 }
 
-fn draw(gfx: &mut Graphics, state: &mut State) {
-    let (theme, highlighted_lines) = highlight(&state.buffer.text, "py", "base16-ocean.dark");
+/// Insert the register's content after the cursor, or before it if `before` is set
+fn paste(state: &mut State, before: bool) {
+    report_primary_cursor_only(state, "Pasting");
+    let count = state.count.take().unwrap_or(1);
+    record_change(state, Change::Paste { before, count });
+    let text = state.register.text.repeat(count.max(1));
+    if text.is_empty() {
+        return;
+    }
 
-    let mut draw = gfx.create_draw();
-    draw.clear(convert_color(theme.settings.background.unwrap()));
+    state
+        .editor
+        .buffer_mut()
+        .paste(&text, state.register.linewise, before);
+}
 
-    draw.text(&state.font, "0")
-        .color(Color::TRANSPARENT)
-        .size(state.line_height);
-    let bounds = draw.last_text_bounds();
-    let char_width = bounds.width;
+/// Delete the characters under and after the cursor and insert in their place, like `s`
+fn substitute_characters(state: &mut State) {
+    let count = state.count.unwrap_or(1);
+    // insert where the characters were, even when that is past the end of the line
+    let cursor = state.editor.buffer().cursor;
+    delete_characters(state);
+    state.editor.buffer_mut().cursor = cursor;
+    state.mode = Mode::Insert;
+    record_change(state, Change::SubstituteCharacters { count });
+}
 
-    let cursor_line = state.buffer.text.char_to_line(state.buffer.cursor);
-    let cursor_line_position = state.buffer.find_line_position(state.buffer.cursor);
+/// Overwrite the characters under and after the cursor with the character, like `r`. a newline
+///     replaces them all with a single line break
+fn replace_characters(state: &mut State, character: char) {
+    let count = state.count.take().unwrap_or(1);
+    let buffer = state.editor.buffer_mut();
+
+    let line = buffer.text.char_to_line(buffer.cursor);
+    let line_end = buffer.text.line_to_char(line) + buffer.get_line_length(line);
+    let end = buffer.cursor + count.max(1);
+    // nothing is replaced if the line is too short
+    if end > line_end {
+        return;
+    }
 
-    let line_count = state.buffer.text.len_lines() - 1;
-    let line_number_digit_count = line_count.to_string().len().max(3);
-    let line_number_offset = if SHOW_LINE_NUMBERS {
-        line_number_digit_count as f32 * char_width + 4.0
+    let start = buffer.cursor;
+    buffer.remove(start..end);
+    if character == '\n' {
+        buffer.insert(start, "\n");
+        buffer.cursor = start + 1;
     } else {
-        0.0
-    };
+        let text = character.to_string().repeat(count.max(1));
+        buffer.insert(start, &text);
+        buffer.cursor = end - 1;
+    }
+    record_change(state, Change::ReplaceCharacters { character, count });
+}
 
-    let camera_offset = calculate_camera_offset(
-        cursor_line_position,
-        cursor_line,
-        char_width,
-        state.line_height,
-        gfx.size(),
-    );
+/// Toggle the case of the characters under and after the cursor and move past them, like `~`
+fn toggle_case(state: &mut State) {
+    let count = state.count.take().unwrap_or(1);
+    let buffer = state.editor.buffer_mut();
 
-    // draw highlighted text
-    for (index, line) in highlighted_lines.iter().enumerate() {
-        let y_position = index as f32 * state.line_height;
-        let mut char_index = 0usize;
+    let line = buffer.text.char_to_line(buffer.cursor);
+    let line_end = buffer.text.line_to_char(line) + buffer.get_line_length(line);
+    let end = (buffer.cursor + count.max(1)).min(line_end);
+    if buffer.cursor >= end {
+        return;
+    }
 
-        for (style, fragment) in line {
-            let x_position = char_index as f32 * char_width;
-            let text_position = (
-                line_number_offset + camera_offset.0 + x_position,
-                y_position + camera_offset.1,
-            );
-            draw.text(&state.font, &fragment)
-                .position(text_position.0, text_position.1)
-                .size(state.line_height)
-                .color(convert_color(style.foreground));
+    buffer.change_case(buffer.cursor, end, Case::Toggle);
+    buffer.cursor = end.min(line_end - 1);
+    buffer.desired_column = None;
+    record_change(state, Change::ToggleCase { count });
+}
 
-            let word_length = fragment.chars().count();
-            char_index += word_length;
-        }
-    }
+/// Join count lines starting at the cursor's line, with a space in between unless `gJ` was used
+fn join_lines(state: &mut State, spaces: bool) {
+    let count = state.count.take().unwrap_or(2);
+    record_change(state, Change::Join { count, spaces });
+    let buffer = state.editor.buffer_mut();
+    let line = buffer.text.char_to_line(buffer.cursor);
+    buffer.join_lines(line, count, spaces);
+}
 
-    // render cursor
-    {
-        let x_position = char_width * cursor_line_position as f32;
-        let y_position = state.line_height * cursor_line as f32;
-        let cursor_color = convert_color(theme.settings.caret.unwrap());
+/// Enter insert mode at the position the mode change asks for
+fn start_insert(state: &mut State, mode_change: ModeChange) {
+    state
+        .editor
+        .buffer_mut()
+        .at_each_cursor(|buffer| buffer.prepare_insert(&mode_change));
+    state.mode = Mode::Insert;
+    record_change(state, Change::Insert(mode_change));
+}
 
-        match state.mode {
-            Mode::Normal => {
-                draw.rect(
-                    (
-                        x_position + line_number_offset + camera_offset.0,
-                        y_position + camera_offset.1,
-                    ),
-                    (char_width, state.line_height),
-                )
-                .color(cursor_color);
+/// Remember the change so that `.` can repeat it, starting over the record of inserted text
+fn record_change(state: &mut State, change: Change) {
+    state.editor.last_change = Some(change);
+    state.editor.inserted_text.clear();
+}
+
+/// Repeat the last change at the cursor. a count replaces the count of the original change
+fn repeat_change(state: &mut State) {
+    let Some(change) = state.editor.last_change.clone() else {
+        return;
+    };
+    let count = state.count.take();
+    let inserted_text = state.editor.inserted_text.clone();
+
+    match change {
+        Change::Motion {
+            action,
+            motion,
+            count: original_count,
+        } => {
+            state.action = Some(action);
+            state.count = count.or(original_count);
+            execute_motion(state, motion);
+        }
+        Change::TextObject {
+            action,
+            object,
+            inner,
+        } => {
+            state.action = Some(action);
+            apply_text_object(state, object, inner);
+        }
+        Change::Line {
+            action,
+            count: original_count,
+        } => {
+            state.count = count.or(Some(original_count));
+            execute_line_action(state, action);
+        }
+        Change::DeleteCharacters {
+            count: original_count,
+        } => {
+            state.count = count.or(Some(original_count));
+            delete_characters(state);
+        }
+        Change::SubstituteCharacters {
+            count: original_count,
+        } => {
+            state.count = count.or(Some(original_count));
+            substitute_characters(state);
+        }
+        Change::ReplaceCharacters {
+            character,
+            count: original_count,
+        } => {
+            state.count = count.or(Some(original_count));
+            replace_characters(state, character);
+        }
+        Change::ToggleCase {
+            count: original_count,
+        } => {
+            state.count = count.or(Some(original_count));
+            toggle_case(state);
+        }
+        Change::Paste {
+            before,
+            count: original_count,
+        } => {
+            state.count = count.or(Some(original_count));
+            paste(state, before);
+        }
+        Change::Join {
+            spaces,
+            count: original_count,
+        } => {
+            state.count = count.or(Some(original_count));
+            join_lines(state, spaces);
+        }
+        Change::Insert(mode_change) => start_insert(state, mode_change),
+        Change::Overwrite => {
+            state.editor.overwritten.clear();
+            for character in inserted_text.chars() {
+                overwrite_character(state, character);
             }
-            Mode::Insert => {
-                draw.line(
-                    (
-                        x_position + line_number_offset + camera_offset.0,
-                        y_position + camera_offset.1,
-                    ),
-                    (
-                        x_position + line_number_offset + camera_offset.0,
-                        y_position + state.line_height + camera_offset.1,
-                    ),
-                )
-                .color(cursor_color);
+            let buffer = state.editor.buffer_mut();
+            if buffer.find_line_position(buffer.cursor) > 0 {
+                buffer.cursor -= 1;
             }
-            Mode::Command => {}
         }
     }
 
-    // render line number background
-    let number_background_color = convert_color(theme.settings.background.unwrap());
-    draw.rect(
-        (0.0, 0.0),
-        (
-            line_number_digit_count as f32 * char_width + 2.0,
-            gfx.size().1 as f32,
-        ),
-    )
-    .color(number_background_color);
+    if state.mode == Mode::Insert {
+        let buffer = state.editor.buffer_mut();
+        buffer.insert(buffer.cursor, &inserted_text);
+        buffer.cursor += inserted_text.chars().count();
+        state.mode = Mode::Normal;
+    }
+    // replaying the change recorded it again, without the text typed the first time
+    state.editor.inserted_text = inserted_text;
+}
 
-    // render line numbers
-    for index in 0..line_count + 1 {
-        let y_position = index as f32 * state.line_height;
+/// Scroll the viewport by the number of lines and move the cursor along with it
+fn scroll_lines(state: &mut State, lines: i32) {
+    let buffer = state.editor.buffer_mut();
+    let last_line = buffer.get_last_line();
+    state.scroll_top = calculate_scrolled_top(state.scroll_top, lines, last_line);
+    buffer.move_y(lines);
+}
 
-        if SHOW_LINE_NUMBERS {
-            // pad the line number with spaces on the left
-            let line_number = format!(
-                "{:>width$}",
-                &index.to_string(),
-                width = line_number_digit_count
-            );
+/// Return the first visible line after scrolling by the number of lines, which stays between the
+///     first and the last line
+fn calculate_scrolled_top(scroll_top: usize, lines: i32, last_line: usize) -> usize {
+    (scroll_top as i64 + lines as i64).clamp(0, last_line as i64) as usize
+}
 
-            // draw the line number
-            draw.text(&state.font, &line_number)
-                .position(0.0, y_position + camera_offset.1)
-                .size(state.line_height)
-                .color(Color::GRAY);
+/// Return the start of the next match of the pattern after the cursor, wrapping around the buffer
+fn find_next_match(buffer: &Buffer, pattern: &Regex, forward: bool) -> Option<Cursor> {
+    let starts: Vec<Cursor> = buffer
+        .find_matches(pattern)
+        .iter()
+        .map(|(start, _)| *start)
+        .collect();
+    let target = if forward {
+        let next = starts.iter().find(|start| **start > buffer.cursor);
+        next.or(starts.first())
+    } else {
+        let previous = starts.iter().rev().find(|start| **start < buffer.cursor);
+        previous.or(starts.last())
+    };
+    target.copied()
+}
+
+/// Move the cursor to the next match of the last search query
+fn search_next(state: &mut State, forward: bool) {
+    let count = state.count.take().unwrap_or(1);
+    let Some(pattern) = state.options.compile_search(&state.search_query) else {
+        return;
+    };
+    let buffer = state.editor.buffer_mut();
+    let start = buffer.cursor;
+    for _ in 0..count.max(1) {
+        if let Some(target) = find_next_match(buffer, &pattern, forward) {
+            buffer.cursor = target;
         }
     }
+    buffer.desired_column = None;
+    if buffer.cursor != start {
+        state.editor.push_jump(start);
+    }
+}
 
-    // render command line at the bottom of the screen
-    if state.mode == Mode::Command {
-        let (w, h) = gfx.size();
-        draw.rect(
-            (0.0, h as f32 - COMMAND_BOX_PADDING - state.line_height),
-            (w as f32, h as f32),
-        )
-        .color(convert_color(theme.settings.background.unwrap()));
+/// Search for the word under the cursor as a whole word, forward for `*` and backward for `#`.
+///     nothing happens if the cursor is not on a word
+fn search_word_under_cursor(state: &mut State, forward: bool) {
+    let buffer = state.editor.buffer_mut();
+    let on_word = buffer
+        .text
+        .get_char(buffer.cursor)
+        .is_some_and(|character| get_character_class(character, false) == CharacterClass::Word);
+    if !on_word {
+        state.count = None;
+        return;
+    }
 
-        draw.line(
-            (0.0, h as f32 - COMMAND_BOX_PADDING - state.line_height),
-            (w as f32, h as f32 - COMMAND_BOX_PADDING - state.line_height),
-        ).color(convert_color(theme.settings.guide.unwrap()));
+    let (start, end) = buffer.get_word_range(buffer.cursor, false);
+    let query = format!("\\<{}\\>", buffer.text.slice(start..end));
+    // starting at the start of the word, `#` does not stop at the word itself
+    buffer.cursor = start;
+    state.editor.search_history.add(&query);
+    state.search_query = query;
+    state.search_forward = forward;
+    search_next(state, forward);
+}
+
+/// Move the cursor to the first match of the search that is being typed, counting from where the
+///     search started, so that the view scrolls to it. the cursor stays there without a match
+fn preview_search(state: &mut State) {
+    let Some(start) = state.editor.search_start else {
+        return;
+    };
+    let query = state.command_line.strip_prefix('/').unwrap_or("");
+    let pattern = state.options.compile_search(query);
+    let buffer = state.editor.buffer_mut();
+    buffer.cursor = start.min(buffer.text.len_chars());
+    if let Some(target) = pattern.and_then(|pattern| find_next_match(buffer, &pattern, true)) {
+        buffer.cursor = target;
+    }
+}
+
+/// Put the cursor back where it was before the search that is being typed
+fn cancel_search_preview(state: &mut State) {
+    if let Some(start) = state.editor.search_start.take() {
+        let buffer = state.editor.buffer_mut();
+        buffer.cursor = start.min(buffer.text.len_chars());
+    }
+}
+
+fn execute_search(state: &mut State) {
+    // the search starts from the cursor before the preview moved it
+    cancel_search_preview(state);
+    let query = state
+        .command_line
+        .strip_prefix('/')
+        .unwrap_or("")
+        .to_string();
+    state.editor.search_history.add(&query);
+    if !query.is_empty() {
+        state.search_query = query;
+    }
+    state.search_forward = true;
+    search_next(state, true);
+
+    state.command_line.clear();
+    state.mode = Mode::Normal;
+}
+
+/// Complete the word before the end of the command line. the common part of all candidates is
+///     inserted first, then each Tab cycles through them
+fn complete_command_line(state: &mut State) {
+    if let Some(completion) = &mut state.editor.completion {
+        let index = completion.index.map_or(0, |index| index + 1) % completion.candidates.len();
+        completion.index = Some(index);
+        state.command_line.truncate(completion.start);
+        state.command_line.push_str(&completion.candidates[index]);
+        state.editor.command_cursor = state.command_line.len();
+        return;
+    }
+
+    let (start, candidates) = get_completions(&state.commands, &state.command_line);
+    if candidates.is_empty() {
+        return;
+    }
+    state.command_line.truncate(start);
+    state.command_line.push_str(&get_common_prefix(&candidates));
+    state.editor.command_cursor = state.command_line.len();
+    if candidates.len() > 1 {
+        state.editor.completion = Some(Completion {
+            candidates,
+            index: None,
+            start,
+        });
+    }
+}
+
+fn execute_command(state: &mut State, time: f32) {
+    let command_line = state.command_line.clone();
+    state.command_line.clear();
+    state.mode = Mode::Normal;
+
+    let history = &mut state.editor.command_history;
+    history.add(command_line.strip_prefix(':').unwrap_or(&command_line));
+    if state.options.persist_history {
+        if let Err(error) = save_history(&history.entries) {
+            state.set_error(error);
+        }
+    }
+
+    run_command(state, &command_line, time);
+}
+
+/// Run the command line and show the error if it fails. keys given to `:normal` are typed at the
+///     time
+fn run_command(state: &mut State, command_line: &str, time: f32) {
+    let result = prepare_command(&state.commands, command_line)
+        .and_then(|(command, range, arguments)| command.execute(state, range, arguments));
+    if let Err(error) = result {
+        state.set_error(error);
+    }
+    if let Some(normal_keys) = state.normal_keys.take() {
+        type_normal_keys(state, normal_keys, time);
+    }
+}
+
+/// Type the keys of `:normal` as if they were received one per frame, at the start of each line
+///     of the range or once at the cursor. a mode the keys leave open is escaped afterwards, and
+///     a command they leave unfinished is cancelled
+fn type_normal_keys(state: &mut State, normal_keys: NormalKeys, time: f32) {
+    let NormalKeys { frames, lines } = normal_keys;
+    let escape = MacroFrame {
+        keys: vec![Shortcut::new(KeyCode::Escape)],
+        characters: Vec::new(),
+    };
+
+    // the keys count as replayed, so that they are neither recorded again nor run forever
+    let macros = &mut state.editor.macros;
+    if macros.replay_depth == 0 {
+        macros.replayed_frames = 0;
+    }
+    macros.replay_depth += 1;
+
+    let buffer = state.editor.buffer();
+    let current_line = buffer.text.char_to_line(buffer.cursor);
+    let (mut line, mut last_line) = lines
+        .as_ref()
+        .map_or((current_line, current_line), |lines| {
+            (*lines.start(), *lines.end())
+        });
+    'lines: while line <= last_line && line <= state.editor.buffer().get_last_line() {
+        let buffer = state.editor.buffer_mut();
+        let line_count = buffer.text.len_lines();
+        if lines.is_some() {
+            buffer.cursor = buffer.text.line_to_char(line);
+            buffer.desired_column = None;
+        }
+
+        for frame in frames.iter() {
+            if state.editor.macros.replayed_frames >= MAX_MACRO_FRAMES {
+                state.set_message(format!("Normal stopped after {} steps", MAX_MACRO_FRAMES));
+                break 'lines;
+            }
+            state.editor.macros.replayed_frames += 1;
+            replay_frame(state, frame, time);
+        }
+        // a sequence is not waited for, since no more keys will follow
+        if !state.sequence.frames.is_empty() {
+            finish_sequence(state, time);
+        }
+        state.pending = None;
+        state.action = None;
+        state.count = None;
+        if state.mode != Mode::Normal {
+            replay_frame(state, &escape, time);
+        }
+
+        // the lines after this one move by the lines the keys added or deleted
+        let added = state.editor.buffer().text.len_lines() as isize - line_count as isize;
+        last_line = match (last_line as isize + added).try_into() {
+            Ok(last_line) => last_line,
+            Err(_) => break,
+        };
+        line = (line as isize + 1 + added).max(line as isize) as usize;
+    }
+
+    state.editor.macros.replay_depth -= 1;
+}
+
+/// Scroll the view with the mouse wheel, leaving the cursor where it is
+fn scroll_wheel(state: &mut State, delta_x: f32, delta_y: f32) {
+    let speed = state.options.scroll_speed as i64;
+    if delta_y != 0.0 {
+        let lines = if delta_y > 0.0 { -speed } else { speed };
+        let last_line = state.editor.buffer().get_last_line() as i64;
+        state.scroll_top = (state.scroll_top as i64 + lines).clamp(0, last_line) as usize;
+    }
+    if delta_x != 0.0 {
+        let columns = if delta_x > 0.0 { -speed } else { speed };
+        state.scroll_x = (state.scroll_x + columns as f32 * state.char_width).max(0.0);
+    }
+}
+
+/// Return the cursor at the character under the screen position
+fn get_cursor_at_position(state: &State, x: f32, y: f32) -> Cursor {
+    let buffer = state.editor.buffer();
+    let tab_stop = state.options.tab_stop;
+    let row = ((y - state.text_origin.1) / state.line_height).max(0.0) as usize;
+    let column = ((x - state.text_origin.0) / state.char_width.max(1.0)).max(0.0) as usize;
+    let Some(width) = state.wrap_width else {
+        return buffer.get_cursor_at_display_column(
+            row.min(buffer.get_last_line()),
+            column,
+            tab_stop,
+        );
+    };
+
+    // with wrapping, the rows are counted from the first visible line
+    let mut line = state.scroll_top.min(buffer.get_last_line());
+    let mut row = row.saturating_sub(state.scroll_top);
+    loop {
+        let row_count = buffer.get_wrap_starts(line, width, tab_stop).len();
+        if row < row_count || line >= buffer.get_last_line() {
+            return buffer.get_cursor_at_wrapped_position(line, row, column, width, tab_stop);
+        }
+        row -= row_count;
+        line += 1;
+    }
+}
+
+/// Move the focus to the pane in the direction of `h`, `j`, `k` or `l`, or to the other pane
+///     with `w`
+fn focus_pane(state: &mut State, direction: char) {
+    let Some(split) = &state.editor.split else {
+        return;
+    };
+    let first = match (split.direction, direction) {
+        (_, 'w') => !split.focus_first,
+        (SplitDirection::Horizontal, 'k') | (SplitDirection::Vertical, 'h') => true,
+        (SplitDirection::Horizontal, 'j') | (SplitDirection::Vertical, 'l') => false,
+        _ => return,
+    };
+    if first != split.focus_first {
+        state.swap_panes();
+        state.editor.buffer_mut().clamp_cursor();
+    }
+}
+
+/// Place the cursor with the left mouse button and select text by dragging it. a double click
+///     selects a word, and a triple click or a click on the line number the whole line
+fn handle_mouse(app: &App, state: &mut State) {
+    let (x, y) = app.mouse.position();
+
+    if app.mouse.was_pressed(MouseButton::Left) {
+        if let Some(index) = get_tab_at_position(state, x, y) {
+            if state.mode == Mode::Normal {
+                state.switch_tab(index);
+            }
+            return;
+        }
+        let contains = |(left, top, width, height): (f32, f32, f32, f32)| {
+            (left..left + width).contains(&x) && (top..top + height).contains(&y)
+        };
+        // a click on the other pane only gives it the focus, and clicks on the status line and
+        //     command line are ignored
+        if !contains(state.pane_area) {
+            let other_area = state.editor.split.as_ref().map(|split| split.other.area);
+            if state.mode == Mode::Normal && other_area.is_some_and(contains) {
+                focus_pane(state, 'w');
+            }
+            return;
+        }
+        let time = app.timer.elapsed_f32();
+        state.click_count = if time - state.last_click_time < MULTI_CLICK_TIME {
+            state.click_count % 3 + 1
+        } else {
+            1
+        };
+        state.last_click_time = time;
+        let on_gutter = x < state.gutter_width;
+        state.dragging = !on_gutter;
+
+        let cursor = get_cursor_at_position(state, x, y);
+        let buffer = state.editor.buffer_mut();
+        buffer.desired_column = None;
+        match state.click_count {
+            count if count == 3 || on_gutter => {
+                let line = buffer.text.char_to_line(cursor);
+                let line_start = buffer.text.line_to_char(line);
+                buffer.selection_anchor = Some(line_start);
+                buffer.cursor = line_start + buffer.get_line_length(line).max(1) - 1;
+                state.mode = Mode::Visual;
+            }
+            2 => {
+                let (start, end) = buffer.get_word_range(cursor, false);
+                buffer.selection_anchor = Some(start);
+                buffer.cursor = end.max(start + 1) - 1;
+                state.mode = Mode::Visual;
+            }
+            _ => {
+                buffer.selection_anchor = Some(cursor);
+                buffer.cursor = cursor;
+                if let Mode::Visual | Mode::VisualBlock = state.mode {
+                    state.mode = Mode::Normal;
+                }
+            }
+        }
+    } else if state.dragging && app.mouse.is_down(MouseButton::Left) && state.click_count == 1 {
+        // moving past the top or bottom of the text scrolls, as the cursor is kept visible
+        let cursor = get_cursor_at_position(state, x, y);
+        let buffer = state.editor.buffer_mut();
+        if cursor != buffer.cursor {
+            buffer.cursor = cursor;
+            // dragging in visual block mode changes the block
+            if state.mode != Mode::VisualBlock {
+                state.mode = Mode::Visual;
+            }
+        }
+    }
+
+    if app.mouse.was_released(MouseButton::Left) {
+        state.dragging = false;
+    }
+}
+
+/// Apply the action to the selected text and return to normal mode
+fn apply_selection_action(state: &mut State, action: Action) {
+    state.action = None;
+    state.count = None;
+    state.mode = Mode::Normal;
+
+    let buffer = state.editor.buffer_mut();
+    let Some((start, end)) = buffer.get_selection() else {
+        return;
+    };
+    buffer.selection_anchor = None;
+    apply_range_action(state, action, start, end);
+}
+
+/// `D` and `C` apply their operator up to the end of the line like `d$` and `c$`, while `Y`
+///     yanks whole lines like `yy`
+fn apply_to_end_of_line(state: &mut State, action: Action) {
+    if action == Action::Yank {
+        execute_line_action(state, action);
+        return;
+    }
+    state.action = Some(action);
+    execute_motion(state, Motion::EndOfLine);
+    // the cursor moves off the newline that it ends up on after the deleted text
+    if state.mode == Mode::Normal {
+        state.editor.buffer_mut().clamp_cursor();
+    }
+}
+
+/// Apply the operator to the block selected in visual block mode, row by row. `c` deletes the
+///     rows and inserts on all of them, like `I`
+fn apply_block_action(state: &mut State, action: Action) {
+    state.action = None;
+    state.count = None;
+    state.mode = Mode::Normal;
+
+    let tab_stop = state.options.tab_stop;
+    let shift_width = state.options.shift_width;
+    let expand_tab = state.options.expand_tab;
+    let buffer = state.editor.buffer_mut();
+    let Some((lines, columns)) = buffer.get_block(tab_stop) else {
+        return;
+    };
+    buffer.selection_anchor = None;
+    let rows: Vec<Range<Cursor>> = lines
+        .clone()
+        .map(|line| buffer.get_block_row(line, columns.clone(), tab_stop))
+        .collect();
+    let top_left = rows[0].start;
+
+    if let Action::Yank | Action::Delete | Action::Replace = action {
+        // each row becomes a line of the register
+        let text: Vec<String> = rows
+            .iter()
+            .map(|row| buffer.text.slice(row.clone()).to_string())
+            .collect();
+        state.register = Register {
+            text: text.join("\n"),
+            linewise: false,
+        };
+    }
+
+    match action {
+        Action::Yank => buffer.cursor = top_left,
+        Action::Delete | Action::Replace => {
+            for row in rows.into_iter().rev() {
+                buffer.remove(row);
+            }
+            buffer.cursor = top_left;
+            if action == Action::Replace {
+                insert_at_block_column(state, lines, *columns.start(), false);
+            } else {
+                buffer.clamp_cursor();
+            }
+        }
+        Action::ChangeCase(case) => {
+            for row in rows {
+                buffer.change_case(row.start, row.end, case);
+            }
+            buffer.cursor = top_left;
+        }
+        Action::Indent | Action::Dedent => {
+            let indent = action == Action::Indent;
+            buffer.shift_lines(
+                *lines.start(),
+                *lines.end(),
+                shift_width,
+                expand_tab,
+                indent,
+            );
+        }
+        Action::ToggleComment => toggle_comment(state, *lines.start(), *lines.end()),
+    }
+}
+
+/// Enter insert mode on every row of the block selected in visual block mode, at its left edge
+///     for `I` or after its right edge for `A`
+fn start_block_insert(state: &mut State, append: bool) {
+    let tab_stop = state.options.tab_stop;
+    let buffer = state.editor.buffer_mut();
+    let Some((lines, columns)) = buffer.get_block(tab_stop) else {
+        return;
+    };
+    buffer.selection_anchor = None;
+    let column = if append {
+        columns.end() + 1
+    } else {
+        *columns.start()
+    };
+    insert_at_block_column(state, lines, column, append);
+}
+
+/// Enter insert mode with a cursor at the display column of each of the lines, so that the typed
+///     text is inserted on all of them. lines that end before the column are skipped, or padded
+///     with spaces up to it if `pad` is set, as `A` does so that the appended text lines up
+fn insert_at_block_column(
+    state: &mut State,
+    lines: RangeInclusive<usize>,
+    column: usize,
+    pad: bool,
+) {
+    let tab_stop = state.options.tab_stop;
+    let buffer = state.editor.buffer_mut();
+    let mut cursors = Vec::new();
+    for line in lines {
+        let line_end = buffer.text.line_to_char(line) + buffer.get_line_length(line);
+        let width = buffer.get_display_column(line_end, tab_stop);
+        if width < column && pad {
+            buffer.insert(line_end, &" ".repeat(column - width));
+            cursors.push(line_end + column - width);
+        } else if width == column {
+            cursors.push(line_end);
+        } else if width > column {
+            cursors.push(buffer.get_cursor_at_display_column(line, column, tab_stop));
+        }
+    }
+    if cursors.is_empty() {
+        state.mode = Mode::Normal;
+        return;
+    }
+
+    buffer.cursor = cursors.remove(0);
+    buffer.cursors = cursors;
+    buffer.desired_column = None;
+    state.mode = Mode::Insert;
+    state.block_insert = true;
+    record_change(state, Change::Insert(ModeChange::Insert));
+}
+
+/// Apply the operator to the text object at the cursor, or select the object in visual mode
+fn apply_text_object(state: &mut State, object: TextObject, inner: bool) {
+    if state.action.is_some() {
+        report_primary_cursor_only(state, "The operator");
+    }
+    state.count = None;
+    let buffer = state.editor.buffer_mut();
+    let Some((start, end)) = object.get_range(buffer, inner) else {
+        state.action = None;
+        return;
+    };
+
+    if state.mode == Mode::Visual {
+        buffer.selection_anchor = Some(start);
+        buffer.cursor = end.max(start + 1) - 1;
+        return;
+    }
+
+    let Some(action) = state.action.take() else {
+        return;
+    };
+    if action != Action::Yank {
+        record_change(
+            state,
+            Change::TextObject {
+                action: action.clone(),
+                object,
+                inner,
+            },
+        );
+    }
+    apply_range_action(state, action, start, end);
+}
+
+/// Apply the operator to the characters from start up to end
+fn apply_range_action(state: &mut State, action: Action, start: Cursor, end: Cursor) {
+    let shift_width = state.options.shift_width;
+    let expand_tab = state.options.expand_tab;
+    let buffer = state.editor.buffer_mut();
+    let end = end.min(buffer.text.len_chars());
+    let start = start.min(end);
+
+    match action {
+        Action::Yank => {
+            state.register = Register {
+                text: buffer.text.slice(start..end).to_string(),
+                linewise: false,
+            };
+            buffer.cursor = start;
+        }
+        Action::Delete | Action::Replace => {
+            // the deleted text can be pasted again, unless nothing was deleted
+            if let Some(text) = buffer.cut(start..end) {
+                state.register = Register {
+                    text,
+                    linewise: false,
+                };
+            }
+            if action == Action::Replace {
+                buffer.cursor = start;
+                state.mode = Mode::Insert;
+            } else {
+                buffer.cursor = start.min(buffer.text.len_chars().max(1) - 1);
+            }
+        }
+        Action::Indent | Action::Dedent => {
+            let first_line = buffer.text.char_to_line(start);
+            let last_line = buffer.text.char_to_line(end.max(start + 1) - 1);
+            let indent = action == Action::Indent;
+            buffer.shift_lines(first_line, last_line, shift_width, expand_tab, indent);
+        }
+        Action::ChangeCase(case) => {
+            buffer.change_case(start, end, case);
+            buffer.cursor = start;
+        }
+        Action::ToggleComment => {
+            let first_line = buffer.text.char_to_line(start);
+            let last_line = buffer.text.char_to_line(end.max(start + 1) - 1);
+            toggle_comment(state, first_line, last_line);
+        }
+    }
+}
+
+/// Add the input of this frame to the macro that is being recorded
+fn record_macro_frame(input: &KeyInput, state: &mut State, characters: Vec<char>) {
+    let keys = input.get_shortcuts();
+    if keys.is_empty() && characters.is_empty() {
+        return;
+    }
+
+    if let Some((_, frames)) = &mut state.editor.macros.recording {
+        frames.push(MacroFrame { keys, characters });
+    }
+}
+
+/// Start recording a macro into the register, or stop and store the one being recorded
+fn toggle_macro_recording(state: &mut State, register: Option<char>) {
+    if let Some((register, frames)) = state.editor.macros.recording.take() {
+        state.editor.macros.registers.insert(register, frames);
+    } else if let Some(register) = register {
+        state.editor.macros.recording = Some((register, Vec::new()));
+    }
+}
+
+/// Feed the frames of the macro in the register back through the input handling, count times,
+///     as if they were received at the time
+fn replay_macro(state: &mut State, register: char, time: f32) {
+    let count = state.count.take().unwrap_or(1);
+    let Some(frames) = state.editor.macros.registers.get(&register).cloned() else {
+        state.set_message(format!("Register {} is empty", register));
+        return;
+    };
+    state.editor.macros.last_register = Some(register);
+
+    let macros = &mut state.editor.macros;
+    if macros.replay_depth == 0 {
+        macros.replayed_frames = 0;
+    }
+    macros.replay_depth += 1;
+
+    'replay: for _ in 0..count.max(1) {
+        for frame in frames.iter() {
+            if state.editor.macros.replayed_frames >= MAX_MACRO_FRAMES {
+                state.set_message(format!("Macro stopped after {} steps", MAX_MACRO_FRAMES));
+                break 'replay;
+            }
+            state.editor.macros.replayed_frames += 1;
+            replay_frame(state, frame, time);
+        }
+    }
+
+    state.editor.macros.replay_depth -= 1;
+}
+
+/// Handle the recorded input as if it was received in a frame at the time
+fn replay_frame(state: &mut State, frame: &MacroFrame, time: f32) {
+    for character in frame.characters.iter() {
+        event(state, Event::ReceivedCharacter(*character));
+    }
+    handle_input(&KeyInput::from_frame(frame, time), state);
+}
+
+/// Stop waiting for the next key of a sequence once the timeout has passed, running the command
+///     of the keys typed so far if they are a whole sequence and handling them as usual otherwise
+fn expire_sequence(state: &mut State, time: f32) {
+    let sequence = &state.sequence;
+    if sequence.frames.is_empty() || time - sequence.time <= sequence.timeout {
+        return;
+    }
+    finish_sequence(state, time);
+}
+
+/// Run the command of the keys typed so far if they are a whole sequence, and handle them as
+///     usual otherwise
+fn finish_sequence(state: &mut State, time: f32) {
+    let keys = state.sequence.get_keys();
+    match find_sequence(state, &keys) {
+        SequenceMatch::Complete(command) | SequenceMatch::Ambiguous(command) => {
+            state.sequence.frames.clear();
+            run_sequence_command(state, command, time);
+        }
+        SequenceMatch::Prefix | SequenceMatch::None => replay_sequence(state, time),
+    }
+}
+
+/// Look up the keys in the sequence bindings. with an operator or count waiting, or in visual
+///     mode, only the built-in sequences apply, since a command line does not take them
+fn find_sequence(state: &State, keys: &[Shortcut]) -> SequenceMatch {
+    let built_in_only =
+        state.mode != Mode::Normal || state.action.is_some() || state.count.is_some();
+    state
+        .keymap
+        .sequence_bindings
+        .find(keys, state.sequence.leader, built_in_only)
+}
+
+fn run_sequence_command(state: &mut State, command: SequenceCommand, time: f32) {
+    match command {
+        SequenceCommand::FirstLine => execute_motion(state, Motion::FirstLine),
+        SequenceCommand::JoinLines => join_lines(state, false),
+        SequenceCommand::DisplayLines(direction) => move_display_lines(state, direction),
+        SequenceCommand::NextTab => state.next_tab(),
+        SequenceCommand::PreviousTab => state.previous_tab(),
+        SequenceCommand::AddCursor if state.mode == Mode::Normal => {
+            if !state.editor.buffer_mut().add_cursor_at_next_occurrence() {
+                state.set_message(String::from("No other occurrence"));
+            }
+        }
+        // the extra cursors are not used in visual mode
+        SequenceCommand::AddCursor => {}
+        SequenceCommand::Action(action) if state.mode == Mode::Visual => {
+            apply_selection_action(state, action);
+        }
+        SequenceCommand::Action(action) if state.action.as_ref() == Some(&action) => {
+            // `gugu` and `gcgc`, like `guu` and `gcc`
+            execute_line_action(state, action);
+        }
+        SequenceCommand::Action(action) => state.action = Some(action),
+        SequenceCommand::Command(command_line) => run_command(state, &command_line, time),
+    }
+}
+
+/// Whether a key that leaves the current mode was pressed, which also cancels pending input
+fn was_escape_pressed(input: &KeyInput, state: &State) -> bool {
+    let Some(bindings) = state.keymap.mode_change_bindings.get(&state.mode) else {
+        return false;
+    };
+    bindings.iter().any(|(shortcut, mode_change)| {
+        *mode_change == ModeChange::Escape && input.matches(shortcut)
+    })
+}
+
+/// Collect the keys typed in normal or visual mode while they start a sequence binding, and run
+///     its command once the sequence is complete. keys that do not continue a sequence are
+///     handled as usual, after the keys before them. returns whether the input of the frame was
+///     taken
+fn handle_sequence(input: &KeyInput, state: &mut State, character: Option<char>) -> bool {
+    if state.sequence.replaying || !matches!(state.mode, Mode::Normal | Mode::Visual) {
+        return false;
+    }
+
+    let Some(key) = input.get_shortcuts().first().copied() else {
+        return false;
+    };
+
+    let frame = MacroFrame {
+        keys: vec![key],
+        characters: character.into_iter().collect(),
+    };
+    let mut keys = state.sequence.get_keys();
+    keys.push(key);
+    match find_sequence(state, &keys) {
+        SequenceMatch::Prefix | SequenceMatch::Ambiguous(_) => {
+            state.sequence.frames.push(frame);
+            state.sequence.time = input.time;
+            true
+        }
+        SequenceMatch::Complete(command) => {
+            state.sequence.frames.clear();
+            run_sequence_command(state, command, input.time);
+            true
+        }
+        SequenceMatch::None if state.sequence.frames.is_empty() => false,
+        SequenceMatch::None => {
+            // a shorter sequence that the key does not continue still runs, like on a timeout
+            let keys = state.sequence.get_keys();
+            if let SequenceMatch::Ambiguous(command) = find_sequence(state, &keys) {
+                state.sequence.frames.clear();
+                run_sequence_command(state, command, input.time);
+            }
+            state.sequence.frames.push(frame);
+            replay_sequence(state, input.time);
+            true
+        }
+    }
+}
+
+/// Handle the keys collected for a sequence that did not complete as if there were no sequence
+fn replay_sequence(state: &mut State, time: f32) {
+    let frames = std::mem::take(&mut state.sequence.frames);
+    state.sequence.replaying = true;
+    for frame in frames.iter() {
+        replay_frame(state, frame, time);
+    }
+    state.sequence.replaying = false;
+}
+
+fn update(app: &mut App, state: &mut State) {
+    if app.keyboard.was_pressed(KeyCode::Return) && app.keyboard.alt() {
+        let is_fullscreen = app.window().is_fullscreen();
+        app.window().set_fullscreen(!is_fullscreen);
+    }
+
+    if !state.editor.loading_files.is_empty() {
+        receive_loaded_files(app, state);
+    }
+
+    if let Mode::Normal | Mode::Visual | Mode::VisualBlock | Mode::Insert | Mode::Replace =
+        state.mode
+    {
+        handle_mouse(app, state);
+    }
+
+    let input = get_key_input(app);
+    let key_pressed = !input.pressed.is_empty();
+    if let Some(message) = &state.message {
+        let timed_out = message.time.elapsed() > ERROR_DURATION;
+        if message.severity == Severity::Error && (key_pressed || timed_out) {
+            state.message = None;
+        }
+    }
+
+    // only frames in between starting and stopping the recording are part of the macro
+    let was_recording = state.editor.macros.recording.is_some();
+    let characters = std::mem::take(&mut state.editor.macros.characters);
+
+    // the buffer refuses edits while it is read-only, and what the commands that tried them did
+    //     besides is undone afterwards
+    let buffer = state.editor.buffer();
+    let read_only_snapshot = buffer.read_only.then(|| ReadOnlySnapshot {
+        buffer_index: state.editor.current_buffer_index,
+        buffer_count: state.editor.buffers.len(),
+        cursor: buffer.cursor,
+        cursors: buffer.cursors.clone(),
+        register: state.register.clone(),
+        last_change: state.editor.last_change.clone(),
+    });
+
+    expire_sequence(state, input.time);
+    handle_input(&input, state);
+
+    if let Some(snapshot) = read_only_snapshot {
+        reject_read_only_edits(state, snapshot);
+    }
+
+    if was_recording {
+        record_macro_frame(&input, state, characters);
+    }
+
+    auto_save(app, state);
+    write_swap_files(app, state);
+}
+
+/// Write the text of the buffers with changes that are not in their swap files yet, at most once
+///     every swap interval
+fn write_swap_files(app: &mut App, state: &mut State) {
+    if !state.options.swap_file {
+        return;
+    }
+    let is_stale = |buffer: &Buffer| {
+        buffer.modified && buffer.filepath.is_some() && buffer.swap_version != Some(buffer.version)
+    };
+    if !state.editor.buffers.iter().any(is_stale) {
+        return;
+    }
+    let now = app.timer.elapsed_f32();
+    if now - state.swap_time < state.options.swap_interval {
+        // keep polling, since the lazy loop only runs on input
+        app.window().request_frame();
+        return;
+    }
+
+    state.swap_time = now;
+    let mut failure = None;
+    for buffer in state
+        .editor
+        .buffers
+        .iter_mut()
+        .filter(|buffer| is_stale(buffer))
+    {
+        // a swap that could not be written is reported and not tried again until the next change
+        buffer.swap_version = Some(buffer.version);
+        if let Err(error) = write_swap(&buffer.text, buffer.filepath.as_ref().unwrap()) {
+            failure = Some(error);
+        }
+    }
+    if let Some(error) = failure {
+        state.set_error(error.to_string());
+    }
+}
+
+/// Save the current buffer once it has been left alone for the auto-save delay after a change,
+///     or right away when the changes are flushed. buffers without a file or that are read-only
+///     are left alone
+fn auto_save(app: &mut App, state: &mut State) {
+    if !state.options.auto_save {
+        state.auto_save.flush = false;
+        return;
+    }
+
+    let now = app.timer.elapsed_f32();
+    let buffer_index = state.editor.current_buffer_index;
+    let delay = state.options.auto_save_delay;
+    if state
+        .auto_save
+        .is_due(buffer_index, state.editor.buffer(), now, delay)
+    {
+        run_command(state, "w", now);
+    } else if state.auto_save.waiting {
+        // keep polling, since the lazy loop only runs on input
+        app.window().request_frame();
+    }
+}
+
+/// A read-only buffer and the state that edits change, as they were before the input of a frame
+///     was handled
+struct ReadOnlySnapshot {
+    buffer_index: usize,
+    buffer_count: usize,
+    cursor: Cursor,
+    cursors: Vec<Cursor>,
+    register: Register,
+    last_change: Option<Change>,
+}
+
+/// Undo what the commands whose edits a read-only buffer refused did besides, like moving the
+///     cursor or filling the register, and leave insert mode, reporting why
+fn reject_read_only_edits(state: &mut State, snapshot: ReadOnlySnapshot) {
+    let editor = &mut state.editor;
+    if editor.current_buffer_index != snapshot.buffer_index
+        || editor.buffers.len() != snapshot.buffer_count
+    {
+        return;
+    }
+    let buffer = editor.buffer_mut();
+    let refused = std::mem::take(&mut buffer.refused_edit);
+    let inserting = matches!(state.mode, Mode::Insert | Mode::Replace);
+    if !buffer.read_only || !refused && !inserting {
+        return;
+    }
+
+    if refused {
+        buffer.cursor = snapshot.cursor;
+        buffer.cursors = snapshot.cursors;
+        // `.` would repeat the edit, and the register would hold text that was not deleted
+        editor.last_change = snapshot.last_change;
+        state.register = snapshot.register;
+        state.pending = None;
+    }
+    state.mode = Mode::Normal;
+    state.action = None;
+    let error = String::from("Cannot modify a read-only buffer (:set noreadonly to allow)");
+    state.set_error(error);
+}
+
+/// Open the files that finished loading in the background
+fn receive_loaded_files(app: &mut App, state: &mut State) {
+    for file in std::mem::take(&mut state.editor.loading_files) {
+        match file.receiver.try_recv() {
+            Ok(Ok(loaded)) => open_loaded_file(state, &file.filepath, loaded),
+            Ok(Err(error)) => {
+                let error = format!("Could not open {}: {}", file.filepath, error);
+                state.set_error(error);
+            }
+            Err(TryRecvError::Empty) => state.editor.loading_files.push(file),
+            Err(TryRecvError::Disconnected) => {
+                let error = format!("Could not open {}", file.filepath);
+                state.set_error(error);
+            }
+        }
+    }
+
+    // keep polling, since the lazy loop only runs on input
+    if !state.editor.loading_files.is_empty() || !state.sequence.frames.is_empty() {
+        app.window().request_frame();
+    }
+}
+
+fn handle_input(input: &KeyInput, state: &mut State) {
+    let received_character = state.received_character.take();
+    if let Some(pending) = state.pending.take() {
+        // wait for the character that completes the pending input, escape cancels it
+        match (pending, received_character) {
+            (PendingInput::ConfirmSubstitution(substitution), _)
+                if was_escape_pressed(input, state) =>
+            {
+                state.finish_substitution(substitution);
+            }
+            _ if was_escape_pressed(input, state) => {
+                // the operator or count that the input belongs to is cancelled with it
+                state.action = None;
+                state.count = None;
+            }
+            (PendingInput::Motion(motion), Some(character)) => {
+                execute_motion(state, motion(character));
+            }
+            (PendingInput::ReplaceCharacter, Some('\r' | '\n')) => {
+                replace_characters(state, '\n');
+            }
+            (PendingInput::ReplaceCharacter, Some(character)) if !character.is_control() => {
+                replace_characters(state, character);
+            }
+            (PendingInput::ReplaceCharacter, Some(_)) => state.count = None,
+            (PendingInput::TextObject { inner }, Some(character)) => {
+                match TextObject::from_character(character) {
+                    Some(object) => apply_text_object(state, object, inner),
+                    None => {
+                        state.action = None;
+                        state.count = None;
+                    }
+                }
+            }
+            (PendingInput::SetMark, Some(name)) if name.is_ascii_alphabetic() => {
+                let buffer = state.editor.buffer_mut();
+                buffer.marks.insert(name, buffer.cursor);
+            }
+            (PendingInput::SetMark, Some(_)) => {}
+            (PendingInput::RecordMacro, Some(register)) if register.is_ascii_alphanumeric() => {
+                toggle_macro_recording(state, Some(register));
+            }
+            (PendingInput::ReplayMacro, Some('@')) => {
+                if let Some(register) = state.editor.macros.last_register {
+                    replay_macro(state, register, input.time);
+                }
+            }
+            (PendingInput::ReplayMacro, Some(register)) => {
+                replay_macro(state, register, input.time)
+            }
+            (PendingInput::RecordMacro, Some(_)) => {}
+            (PendingInput::Window, Some(direction)) => focus_pane(state, direction),
+            (PendingInput::ConfirmSubstitution(substitution), Some(answer)) => {
+                answer_substitution(state, substitution, answer);
+            }
+            (PendingInput::RecoverSwap(swap), Some(answer)) => answer_swap(state, swap, answer),
+            (pending, None) => state.pending = Some(pending),
+        }
+        return;
+    }
+
+    if handle_sequence(input, state, received_character) {
+        return;
+    }
+
+    if let Mode::Normal | Mode::Visual | Mode::VisualBlock = state.mode {
+        // digits typed in normal mode build up the count for the next command
+        if let Some(digit) = received_character.and_then(|c| c.to_digit(10)) {
+            if digit != 0 || state.count.is_some() {
+                state.count = Some(state.count.unwrap_or(0) * 10 + digit as usize);
+                return;
+            }
+        }
+    }
+
+    // `guu`, `gUU` and `g~~` change the case of whole lines, `gcc` toggles their comments
+    let line_action = match (&state.action, received_character) {
+        (Some(Action::ChangeCase(Case::Lower)), Some('u')) => Some(Action::ChangeCase(Case::Lower)),
+        (Some(Action::ChangeCase(Case::Upper)), Some('U')) => Some(Action::ChangeCase(Case::Upper)),
+        (Some(Action::ChangeCase(Case::Toggle)), Some('~')) => {
+            Some(Action::ChangeCase(Case::Toggle))
+        }
+        (Some(Action::ToggleComment), Some('c')) => Some(Action::ToggleComment),
+        _ => None,
+    };
+    if let Some(action) = line_action {
+        execute_line_action(state, action);
+        return;
+    }
+
+    // `i` and `a` select a text object after an operator, instead of entering insert mode
+    if state.action.is_some() || state.mode == Mode::Visual {
+        if let Some(character @ ('i' | 'a')) = received_character {
+            state.pending = Some(PendingInput::TextObject {
+                inner: character == 'i',
+            });
+            return;
+        }
+    }
+
+    if state.mode == Mode::Normal {
+        // if there is a new action input, replace the previous
+        let input_action = get_action_input(input, &state.keymap);
+        if let Some(new_action) = input_action {
+            // pressing the same action twice applies it to the whole line
+            if state.action.as_ref() == Some(&new_action) {
+                execute_line_action(state, new_action);
+                return;
+            }
+            state.action = Some(new_action);
+        }
+    }
+
+    if state.mode == Mode::Visual {
+        // actions apply to the selection right away
+        if let Some(action) = get_action_input(input, &state.keymap) {
+            apply_selection_action(state, action);
+            return;
+        }
+    }
+    if state.mode == Mode::VisualBlock {
+        if let Some(action) = get_action_input(input, &state.keymap) {
+            apply_block_action(state, action);
+            return;
+        }
+    }
+
+    if let Some(mode_change) = get_mode_change_input(input, state) {
+        change_mode(state, mode_change);
+        return;
+    }
+    match state.mode {
+        Mode::Normal => {
+            if let Some(motion) = get_character_motion_input(input, &state.keymap) {
+                state.pending = Some(PendingInput::Motion(motion));
+                return;
+            }
+
+            if let Some(motion) = get_motion_input(input, state) {
+                execute_motion(state, motion);
+            }
+
+            let half_page = (state.visible_lines / 2).max(1) as i32;
+            let full_page = state.visible_lines.max(1) as i32;
+            if input.ctrl && was_pressed_or_held(input, state, KeyCode::D) {
+                scroll_lines(state, half_page);
+            }
+
+            if input.ctrl && was_pressed_or_held(input, state, KeyCode::U) {
+                scroll_lines(state, -half_page);
+            }
+
+            if input.ctrl && was_pressed_or_held(input, state, KeyCode::F) {
+                scroll_lines(state, full_page);
+            }
+
+            if input.ctrl && was_pressed_or_held(input, state, KeyCode::B) {
+                scroll_lines(state, -full_page);
+            }
+
+            if input.ctrl && was_pressed_or_held(input, state, KeyCode::Equals) {
+                state.line_height += 1f32;
+            }
+
+            if input.ctrl && was_pressed_or_held(input, state, KeyCode::Minus) {
+                state.line_height = (state.line_height - 1f32).max(1f32);
+            }
+
+            if input.was_pressed(KeyCode::N) && !input.ctrl {
+                search_next(state, state.search_forward != input.shift);
+            }
+
+            if input.was_pressed(KeyCode::Key8) && input.shift {
+                search_word_under_cursor(state, true);
+            }
+            if input.was_pressed(KeyCode::Key3) && input.shift {
+                search_word_under_cursor(state, false);
+            }
+
+            if input.was_pressed(KeyCode::P) {
+                paste(state, input.shift);
+            }
+
+            if input.was_pressed(KeyCode::X) {
+                delete_characters(state);
+            }
+
+            if input.shift {
+                let shortcuts = [
+                    (KeyCode::D, Action::Delete),
+                    (KeyCode::C, Action::Replace),
+                    (KeyCode::Y, Action::Yank),
+                ];
+                for (key, action) in shortcuts {
+                    if input.was_pressed(key) {
+                        apply_to_end_of_line(state, action);
+                    }
+                }
+            }
+
+            if input.was_pressed(KeyCode::S) && !input.shift {
+                substitute_characters(state);
+                return;
+            }
+
+            if input.was_pressed(KeyCode::J) && input.shift {
+                join_lines(state, true);
+            }
+
+            if input.was_pressed(KeyCode::Period) && !input.shift {
+                repeat_change(state);
+            }
+
+            if input.was_pressed(KeyCode::Grave) && input.shift {
+                toggle_case(state);
+            }
+
+            if input.was_pressed(KeyCode::R) && !input.shift {
+                state.pending = Some(PendingInput::ReplaceCharacter);
+                return;
+            }
+
+            if input.was_pressed(KeyCode::O) && input.ctrl {
+                jump(state, false);
+            }
+
+            if input.was_pressed(KeyCode::W) && input.ctrl {
+                state.pending = Some(PendingInput::Window);
+                return;
+            }
+
+            // terminals send Tab for Ctrl-i
+            if input.was_pressed(KeyCode::I) && input.ctrl || input.was_pressed(KeyCode::Tab) {
+                jump(state, true);
+            }
+
+            if input.was_pressed(KeyCode::M) && !input.shift {
+                state.pending = Some(PendingInput::SetMark);
+                return;
+            }
+
+            if input.was_pressed(KeyCode::Q) && !input.shift {
+                if state.editor.macros.recording.is_some() {
+                    toggle_macro_recording(state, None);
+                } else {
+                    state.pending = Some(PendingInput::RecordMacro);
+                }
+                return;
+            }
+
+            if input.was_pressed(KeyCode::Key2) && input.shift {
+                state.pending = Some(PendingInput::ReplayMacro);
+                return;
+            }
+        }
+        Mode::Visual => {
+            if let Some(motion) = get_character_motion_input(input, &state.keymap) {
+                state.pending = Some(PendingInput::Motion(motion));
+                return;
+            }
+
+            if let Some(motion) = get_motion_input(input, state) {
+                execute_motion(state, motion);
+            }
+
+            if input.was_pressed(KeyCode::X) {
+                apply_selection_action(state, Action::Delete);
+            }
+
+            if input.was_pressed(KeyCode::Grave) && input.shift {
+                state.mode = Mode::Normal;
+                state.count = None;
+                let buffer = state.editor.buffer_mut();
+                if let Some((start, end)) = buffer.get_selection() {
+                    buffer.change_case(start, end, Case::Toggle);
+                    buffer.cursor = start;
+                }
+                buffer.selection_anchor = None;
+            }
+        }
+        Mode::VisualBlock => {
+            if let Some(motion) = get_character_motion_input(input, &state.keymap) {
+                state.pending = Some(PendingInput::Motion(motion));
+                return;
+            }
+
+            if let Some(motion) = get_motion_input(input, state) {
+                execute_motion(state, motion);
+            }
+
+            if input.was_pressed(KeyCode::X) {
+                apply_block_action(state, Action::Delete);
+            }
+
+            if input.was_pressed(KeyCode::Grave) && input.shift {
+                apply_block_action(state, Action::ChangeCase(Case::Toggle));
+            }
+        }
+        Mode::Insert => {
+            if was_pressed_or_held(input, state, KeyCode::Back) {
+                let auto_pairs = state.options.auto_pairs;
+                if state.editor.buffer().cursor > 0 {
+                    state.editor.inserted_text.pop();
+                }
+                state.editor.buffer_mut().at_each_cursor(|buffer| {
+                    if buffer.cursor == 0 {
+                        return;
+                    }
+                    // an empty pair is deleted as a whole
+                    let previous = buffer.text.char(buffer.cursor - 1);
+                    let next = buffer.text.get_char(buffer.cursor);
+                    let mut end = buffer.cursor;
+                    if auto_pairs && next.is_some() && get_closing_character(previous) == next {
+                        end += 1;
+                    }
+                    buffer.remove(buffer.cursor - 1..end);
+                    buffer.cursor -= 1;
+                });
+            }
+
+            let ctrl = input.ctrl;
+            if ctrl && was_pressed_or_held(input, state, KeyCode::W) {
+                delete_before_cursor(state, Motion::BackWord);
+            }
+            if ctrl && was_pressed_or_held(input, state, KeyCode::U) {
+                delete_before_cursor(state, Motion::StartOfLine);
+            }
+
+            if was_pressed_or_held(input, state, KeyCode::Return) {
+                let options = &state.options;
+                // the text inserted at the primary cursor is the one repeated by `.`
+                let mut inserted_text = None;
+                state.editor.buffer_mut().at_each_cursor(|buffer| {
+                    let line = buffer.text.char_to_line(buffer.cursor);
+                    let column = buffer.find_line_position(buffer.cursor);
+
+                    let mut indentation = String::new();
+                    if options.auto_indent {
+                        indentation = buffer.get_indentation(line).chars().take(column).collect();
+                    }
+                    let before_cursor = buffer.text.slice(buffer.cursor - column..buffer.cursor);
+                    if options.smart_indent && before_cursor.to_string().trim_end().ends_with(':') {
+                        indentation.push_str(&options.get_indentation_unit());
+                    }
+
+                    let text = format!("\n{}", indentation);
+                    buffer.insert(buffer.cursor, &text);
+                    buffer.cursor += text.chars().count();
+                    inserted_text.get_or_insert(text);
+                });
+                state
+                    .editor
+                    .inserted_text
+                    .push_str(&inserted_text.unwrap_or_default());
+            }
+
+            if was_pressed_or_held(input, state, KeyCode::Tab) {
+                if input.shift {
+                    let shift_width = state.options.shift_width;
+                    let expand_tab = state.options.expand_tab;
+                    let buffer = state.editor.buffer_mut();
+                    let line = buffer.text.char_to_line(buffer.cursor);
+                    buffer.shift_lines(line, line, shift_width, expand_tab, false);
+                } else {
+                    let tab = if state.options.expand_tab {
+                        " ".repeat(state.options.tab_stop)
+                    } else {
+                        String::from("\t")
+                    };
+                    state.editor.buffer_mut().at_each_cursor(|buffer| {
+                        buffer.insert(buffer.cursor, &tab);
+                        buffer.cursor += tab.chars().count();
+                    });
+                    state.editor.inserted_text.push_str(&tab);
+                }
+            }
+
+            if was_pressed_or_held(input, state, KeyCode::Delete) {
+                state.editor.buffer_mut().at_each_cursor(|buffer| {
+                    let length = buffer.text.len_chars();
+                    buffer.remove(buffer.cursor..(buffer.cursor + 1).min(length));
+                });
+            }
+        }
+
+        Mode::Replace => {
+            if was_pressed_or_held(input, state, KeyCode::Back) {
+                restore_overwritten_character(state);
+            }
+            if was_pressed_or_held(input, state, KeyCode::Return) {
+                overwrite_character(state, '\n');
+            }
+        }
+
+        Mode::Command => {
+            if was_pressed_or_held(input, state, KeyCode::Return) {
+                execute_command(state, input.time);
+                return;
+            }
+
+            if input.was_pressed(KeyCode::Tab) {
+                complete_command_line(state);
+            }
+
+            let history = &mut state.editor.command_history;
+            if input.was_pressed(KeyCode::Up) {
+                if let Some(line) = history.previous(&state.command_line[1..]) {
+                    state.command_line = format!(":{}", line);
+                    state.editor.command_cursor = state.command_line.len();
+                }
+            }
+            if input.was_pressed(KeyCode::Down) {
+                if let Some(line) = history.next() {
+                    state.command_line = format!(":{}", line);
+                    state.editor.command_cursor = state.command_line.len();
+                }
+            }
+
+            if edit_input_line(input, state, 1) {
+                state.editor.completion = None;
+            }
+        }
+
+        Mode::Search => {
+            if was_pressed_or_held(input, state, KeyCode::Return) {
+                execute_search(state);
+                return;
+            }
+
+            let history = &mut state.editor.search_history;
+            if input.was_pressed(KeyCode::Up) {
+                if let Some(line) = history.previous(&state.command_line[1..]) {
+                    state.command_line = format!("/{}", line);
+                    state.editor.command_cursor = state.command_line.len();
+                }
+            }
+            if input.was_pressed(KeyCode::Down) {
+                if let Some(line) = history.next() {
+                    state.command_line = format!("/{}", line);
+                    state.editor.command_cursor = state.command_line.len();
+                }
+            }
+
+            edit_input_line(input, state, 1);
+            if state.mode == Mode::Search {
+                preview_search(state);
+            } else {
+                cancel_search_preview(state);
+            }
+        }
+
+        Mode::QuickMenu => {
+            if edit_input_line(input, state, 0) {
+                state.editor.quick_menu.update_entries();
+            }
+
+            let ctrl = input.ctrl;
+            if was_pressed_or_held(input, state, KeyCode::Down)
+                || ctrl && was_pressed_or_held(input, state, KeyCode::N)
+            {
+                state.editor.quick_menu.move_selection(1);
+            }
+
+            if was_pressed_or_held(input, state, KeyCode::Up)
+                || ctrl && was_pressed_or_held(input, state, KeyCode::P)
+            {
+                state.editor.quick_menu.move_selection(-1);
+            }
+
+            if input.was_pressed(KeyCode::Return) {
+                choose_quick_menu_entry(state);
+            }
+        }
+    }
+}
+
+/// Replace or skip the current match of a `:s` with the `c` flag, depending on the answer: `y`
+///     replaces it, `n` skips it, `a` replaces it and all that follow and `q` stops. other keys
+///     leave the question open
+fn answer_substitution(state: &mut State, mut substitution: Substitution, answer: char) {
+    let buffer = state.editor.buffer_mut();
+    match answer {
+        'y' => substitution.replace(buffer),
+        'n' => substitution.skip(buffer),
+        'a' => {
+            while substitution.current.is_some() {
+                substitution.replace(buffer);
+                substitution.find_next(buffer);
+            }
+        }
+        'q' => {
+            state.finish_substitution(substitution);
+            return;
+        }
+        _ => {
+            state.pending = Some(PendingInput::ConfirmSubstitution(substitution));
+            return;
+        }
+    }
+    substitution.find_next(buffer);
+    state.confirm_substitution(substitution);
+}
+
+/// Recover the text of the swap file of the current buffer or delete it, depending on the
+///     answer to the question asked when it was found
+fn answer_swap(state: &mut State, swap: Swap, answer: char) {
+    let buffer = state.editor.buffer_mut();
+    state.message = None;
+    match answer {
+        'r' => {
+            buffer.replace_text(swap.text);
+            buffer.modified = true;
+            // the swap file now holds the text of this buffer, and is deleted once it is written
+            buffer.swap_version = Some(buffer.version);
+            state.set_message(String::from(
+                "Recovered the unsaved changes, write the buffer to keep them",
+            ));
+        }
+        // the swap file is kept when it may belong to an editor that is still running
+        'd' if is_process_running(swap.process_id).is_none() => {
+            state.pending = Some(PendingInput::RecoverSwap(swap));
+        }
+        'd' => {
+            if let Some(filepath) = &buffer.filepath {
+                delete_swap(filepath);
+            }
+        }
+        'e' => {}
+        'o' => buffer.read_only = true,
+        _ => state.pending = Some(PendingInput::RecoverSwap(swap)),
+    }
+}
+
+/// Delete from where the motion leads back to up to the cursor in insert mode, but not past the
+///     start of the line, as Ctrl-w and Ctrl-u do. at the start of a line the newline before it
+///     is deleted instead, joining the line with the previous one
+fn delete_before_cursor(state: &mut State, motion: Motion) {
+    let mut deleted_count = None;
+    state.editor.buffer_mut().at_each_cursor(|buffer| {
+        let line_start = buffer
+            .text
+            .line_to_char(buffer.text.char_to_line(buffer.cursor));
+        let start = if buffer.cursor == line_start {
+            buffer.cursor.saturating_sub(1)
+        } else {
+            let target = motion
+                .clone()
+                .get_target(buffer, None)
+                .unwrap_or(buffer.cursor);
+            target.clamp(line_start, buffer.cursor)
+        };
+        buffer.remove(start..buffer.cursor);
+        deleted_count.get_or_insert(buffer.cursor - start);
+        buffer.cursor = start;
+    });
+
+    let inserted_text = &mut state.editor.inserted_text;
+    for _ in 0..deleted_count.unwrap_or(0) {
+        inserted_text.pop();
+    }
+}
+
+/// Apply the editing keys to the line that is being typed, the command line, the search or the
+///     quick menu line, and return whether its text changed. the caret stays after the first
+///     prefix bytes, the `:` or `/`, and backspacing over an empty command line leaves the mode
+fn edit_input_line(input: &KeyInput, state: &mut State, prefix: usize) -> bool {
+    let back = was_pressed_or_held(input, state, KeyCode::Back);
+    let delete = was_pressed_or_held(input, state, KeyCode::Delete);
+    let left = was_pressed_or_held(input, state, KeyCode::Left);
+    let right = was_pressed_or_held(input, state, KeyCode::Right);
+    let home = input.was_pressed(KeyCode::Home);
+    let end = input.was_pressed(KeyCode::End);
+
+    let line = match state.mode {
+        Mode::QuickMenu => &mut state.editor.quick_menu.line,
+        _ => &mut state.command_line,
+    };
+    let caret = &mut state.editor.command_cursor;
+    *caret = (*caret).clamp(prefix.min(line.len()), line.len());
+    let before = line[prefix.min(*caret)..*caret].chars().next_back();
+    let after = line[*caret..].chars().next();
+
+    let mut changed = false;
+    if back {
+        match before {
+            Some(character) => {
+                *caret -= character.len_utf8();
+                line.remove(*caret);
+                changed = true;
+            }
+            None if line.len() <= prefix && state.mode != Mode::QuickMenu => {
+                line.clear();
+                state.mode = Mode::Normal;
+            }
+            None => {}
+        }
+    } else if delete {
+        if after.is_some() {
+            line.remove(*caret);
+            changed = true;
+        }
+    } else if left {
+        *caret -= before.map_or(0, char::len_utf8);
+    } else if right {
+        *caret += after.map_or(0, char::len_utf8);
+    }
+
+    if home {
+        *caret = prefix.min(line.len());
+    }
+    if end {
+        *caret = line.len();
+    }
+    changed
+}
+
+/// Close the quick menu and act on the selected entry
+fn choose_quick_menu_entry(state: &mut State) {
+    state.mode = Mode::Normal;
+    let quick_menu = &state.editor.quick_menu;
+    if quick_menu.selected >= quick_menu.entries.len() {
+        return;
+    }
+
+    match quick_menu.kind {
+        Some(QuickMenuKind::Buffers) => {
+            state.editor.switch_buffer(quick_menu.selected);
+        }
+        Some(QuickMenuKind::Files) => {
+            let filepath = quick_menu.entries[quick_menu.selected].clone();
+            if let Err(error) = open_file(state, &filepath) {
+                state.set_error(error);
+            }
+        }
+        Some(QuickMenuKind::Themes) => {
+            let name = quick_menu.entries[quick_menu.selected].clone();
+            if let Err(error) = set_color_scheme(state, &name) {
+                state.set_error(error);
+            }
+        }
+        None => {}
+    }
+}
+
+/// Return the first visible line or column, scrolled just enough to keep the cursor at least
+///     `scroll_off` lines or columns away from both edges of the screen. the view does not move
+///     while the cursor is between the margins
+fn calculate_scroll_start(
+    scroll_start: usize,
+    cursor: usize,
+    visible_count: usize,
+    scroll_off: usize,
+) -> usize {
+    let visible_count = visible_count.max(1);
+    let margin = scroll_off.min((visible_count - 1) / 2);
+
+    if cursor < scroll_start + margin {
+        cursor.saturating_sub(margin)
+    } else if cursor + margin >= scroll_start + visible_count {
+        cursor + margin + 1 - visible_count
+    } else {
+        scroll_start
+    }
+}
+
+/// Return the glyphs that `list` draws over the tabs and the trailing spaces of a line, with the
+///     offset of each one in the line
+fn get_list_glyphs(characters: &[char]) -> Vec<(usize, &'static str)> {
+    let trailing_start = characters
+        .iter()
+        .rposition(|character| !character.is_whitespace())
+        .map_or(0, |index| index + 1);
+    characters
+        .iter()
+        .enumerate()
+        .filter_map(|(offset, character)| match character {
+            '\t' => Some((offset, "»")),
+            ' ' if offset >= trailing_start => Some((offset, "·")),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Return the column of the caret in a typed line, counting the characters before its byte index
+fn get_caret_column(line: &str, caret: usize) -> usize {
+    line[..caret.min(line.len())].chars().count()
+}
+
+fn draw(gfx: &mut Graphics, state: &mut State) {
+    let mut draw = gfx.create_draw();
+    draw.clear(convert_color(state.theme.settings.background.unwrap()));
+
+    draw.text(state.font(), "0")
+        .color(Color::TRANSPARENT)
+        .size(state.line_height);
+    let bounds = draw.last_text_bounds();
+    let char_width = bounds.width;
+    state.char_width = char_width;
+
+    // the status line and command line take up the bottom of the screen, and the tab bar the
+    //     top when there are several tab pages
+    let bottom_row_height = state.line_height + state.command_box_padding;
+    let tab_bar_height = match state.editor.tabs.is_empty() {
+        true => 0.0,
+        false => bottom_row_height,
+    };
+    let (w, h) = gfx.size();
+    let text_area = (
+        0.0,
+        tab_bar_height,
+        w as f32,
+        (h as f32 - 2.0 * bottom_row_height - tab_bar_height).max(0.0),
+    );
+
+    // the panes are drawn from the top left, so that each covers the text that overflows from the
+    //     one before it. the other pane is drawn with its view swapped in for the moment
+    let split = state
+        .editor
+        .split
+        .as_ref()
+        .map(|split| (split.direction, split.focus_first));
+    let search_matches = match split {
+        None => draw_pane(&mut draw, state, text_area, true),
+        Some((direction, focus_first)) => {
+            let (first, second) = split_area(text_area, direction);
+            let (focused_area, other_area) = match focus_first {
+                true => (first, second),
+                false => (second, first),
+            };
+            if !focus_first {
+                state.swap_panes();
+                draw_pane(&mut draw, state, other_area, false);
+                state.swap_panes();
+            }
+            let search_matches = draw_pane(&mut draw, state, focused_area, true);
+            if focus_first {
+                state.swap_panes();
+                draw_pane(&mut draw, state, other_area, false);
+                state.swap_panes();
+            }
+
+            let theme = &state.theme;
+            let border_color = theme
+                .settings
+                .gutter_foreground
+                .or(theme.settings.foreground)
+                .map_or(Color::GRAY, convert_color);
+            let (x, y, _, _) = second;
+            match direction {
+                SplitDirection::Horizontal => draw.line((0.0, y), (text_area.2, y)),
+                SplitDirection::Vertical => draw.line((x, y), (x, y + text_area.3)),
+            }
+            .color(border_color);
+            search_matches
+        }
+    };
+
+    let buffer = state.editor.buffer();
+    let theme = &state.theme;
+    let cursor_line = buffer.text.char_to_line(buffer.cursor);
+    let cursor_line_position = buffer.find_line_position(buffer.cursor);
+
+    // render the status line and the background of the command line
+    let status_line_y = h as f32 - 2.0 * bottom_row_height;
+    let background_color = convert_color(theme.settings.background.unwrap());
+    let foreground_color = convert_color(theme.settings.foreground.unwrap());
+    draw.rect((0.0, status_line_y), (w as f32, 2.0 * bottom_row_height))
+        .color(background_color);
+    draw.rect((0.0, status_line_y), (w as f32, bottom_row_height))
+        .color(convert_color(
+            theme
+                .settings
+                .line_highlight
+                .or(theme.settings.selection)
+                .unwrap(),
+        ));
+
+    // render the tab bar, with the shown tab page in the background color
+    if tab_bar_height > 0.0 {
+        draw.rect((0.0, 0.0), (w as f32, tab_bar_height))
+            .color(convert_color(
+                theme
+                    .settings
+                    .line_highlight
+                    .or(theme.settings.selection)
+                    .unwrap(),
+            ));
+        let mut column = 0;
+        for (index, label) in get_tab_labels(state).iter().enumerate() {
+            let label_width = label.chars().count();
+            if index == state.editor.current_tab {
+                draw.rect(
+                    (column as f32 * char_width, 0.0),
+                    (label_width as f32 * char_width, tab_bar_height),
+                )
+                .color(background_color);
+            }
+            draw.text(state.font(), label)
+                .position(column as f32 * char_width, state.command_box_padding / 2.0)
+                .color(foreground_color)
+                .size(state.line_height);
+            column += label_width;
+        }
+    }
+
+    let modified_marker = if buffer.modified { " [+]" } else { "" };
+    let mut status = format!(" {}  {}{}", state.mode, buffer.get_name(), modified_marker);
+    if buffer.read_only {
+        status.push_str(" [RO]");
+    }
+    if let Some((register, _)) = &state.editor.macros.recording {
+        status.push_str(&format!("  recording @{}", register));
+    }
+    for file in &state.editor.loading_files {
+        status.push_str(&format!("  loading {}...", file.filepath));
+    }
+
+    // while completing a command, the candidates are listed in place of the status
+    let completion = state.editor.completion.as_ref();
+    if let (Mode::Command, Some(completion)) = (&state.mode, completion) {
+        status.clear();
+        for (index, candidate) in completion.candidates.iter().enumerate() {
+            status.push(' ');
+            if completion.index == Some(index) {
+                let column = status.chars().count();
+                draw.rect(
+                    (column as f32 * char_width, status_line_y),
+                    (
+                        candidate.chars().count() as f32 * char_width,
+                        bottom_row_height,
+                    ),
+                )
+                .color(convert_color(theme.settings.selection.unwrap()));
+            }
+            status.push_str(candidate);
+            status.push(' ');
+        }
+    }
+    draw.text(state.font(), &status)
+        .position(0.0, status_line_y + state.command_box_padding / 2.0)
+        .color(foreground_color)
+        .size(state.line_height);
+
+    let cursor_position = format!("{}:{} ", cursor_line + 1, cursor_line_position + 1);
+    draw.text(state.font(), &cursor_position)
+        .position(
+            w as f32 - cursor_position.len() as f32 * char_width,
+            status_line_y + state.command_box_padding / 2.0,
+        )
+        .color(foreground_color)
+        .size(state.line_height);
+
+    // render the error or message of the last command until it is dismissed
+    if let (Mode::Normal, Some(message)) = (&state.mode, &state.message) {
+        let color = match message.severity {
+            Severity::Info => foreground_color,
+            Severity::Error => ERROR_COLOR,
+        };
+        draw.text(state.font(), &message.text)
+            .position(
+                0.0,
+                h as f32 - state.line_height - state.command_box_padding / 2.0,
+            )
+            .color(color)
+            .size(state.line_height);
+    }
 
-        draw.text(&state.font, &state.command_line)
+    // render command line at the bottom of the screen, with a block caret behind the text
+    let caret_color = convert_color(theme.settings.caret.unwrap());
+    if state.mode == Mode::Command || state.mode == Mode::Search {
+        let caret_column = get_caret_column(&state.command_line, state.editor.command_cursor);
+        draw.rect(
+            (
+                caret_column as f32 * char_width,
+                h as f32 - state.line_height - state.command_box_padding / 2.0,
+            ),
+            (char_width, state.line_height),
+        )
+        .color(caret_color);
+        draw.text(state.font(), &state.command_line)
             .position(
                 0.0,
-                h as f32 - state.line_height - COMMAND_BOX_PADDING / 2.0,
+                h as f32 - state.line_height - state.command_box_padding / 2.0,
+            )
+            .color(foreground_color)
+            .size(state.line_height);
+    }
+
+    // render the quick menu as a box at the top of the screen, with the typed line above the
+    //     entries
+    if state.mode == Mode::QuickMenu {
+        let padding = state.command_box_padding;
+        let quick_menu = &state.editor.quick_menu;
+        let selection_color = convert_color(theme.settings.selection.unwrap());
+
+        // scroll the entries when the selected one would be below the last that fits
+        let max_entries = ((h as f32 * 0.6 / state.line_height) as usize).max(1);
+        let entry_count = quick_menu.entries.len().min(max_entries);
+        let scroll_top = (quick_menu.selected + 1).saturating_sub(max_entries);
+
+        let menu_width = (w as f32 * 0.6).max(char_width * 20.0);
+        let menu_x = (w as f32 - menu_width) / 2.0;
+        let menu_height = bottom_row_height + entry_count as f32 * state.line_height;
+        draw.rect((menu_x, padding), (menu_width, menu_height))
+            .color(background_color);
+        draw.rect((menu_x, padding), (menu_width, menu_height))
+            .color(foreground_color)
+            .stroke(1.0);
+        let caret_column = get_caret_column(&quick_menu.line, state.editor.command_cursor) + 2;
+        draw.rect(
+            (
+                menu_x + padding + caret_column as f32 * char_width,
+                1.5 * padding,
+            ),
+            (char_width, state.line_height),
+        )
+        .color(caret_color);
+        draw.text(state.font(), &format!("> {}", quick_menu.line))
+            .position(menu_x + padding, 1.5 * padding)
+            .color(foreground_color)
+            .size(state.line_height);
+
+        let entries = quick_menu.entries.iter().enumerate().skip(scroll_top);
+        for (row, (index, entry)) in entries.take(entry_count).enumerate() {
+            let y = 1.5 * padding + (row + 1) as f32 * state.line_height;
+            if index == quick_menu.selected {
+                draw.rect((menu_x + 1.0, y), (menu_width - 2.0, state.line_height))
+                    .color(selection_color);
+            }
+            draw.text(state.font(), entry)
+                .position(menu_x + padding, y)
+                .color(foreground_color)
+                .size(state.line_height);
+        }
+    }
+
+    // render the index of the search match under the cursor in the bottom right corner
+    if !search_matches.is_empty() {
+        let current = search_matches
+            .iter()
+            .position(|(start, end)| (*start..*end).contains(&buffer.cursor))
+            .map_or(String::from("-"), |index| (index + 1).to_string());
+        let match_index = format!("[{}/{}]", current, search_matches.len());
+
+        draw.text(state.font(), &match_index)
+            .position(
+                w as f32 - (match_index.len() + 1) as f32 * char_width,
+                h as f32 - state.line_height - state.command_box_padding / 2.0,
             )
-            .color(convert_color(theme.settings.foreground.unwrap()))
+            .color(foreground_color)
             .size(state.line_height);
     }
     gfx.render(&draw);
 }
+
+/// Return the labels of the tab pages in the tab bar, which name the buffer of their focused
+///     pane
+fn get_tab_labels(state: &State) -> Vec<String> {
+    let editor = &state.editor;
+    let mut buffer_indices: Vec<usize> = editor
+        .tabs
+        .iter()
+        .map(|tab| tab.pane.buffer_index)
+        .collect();
+    buffer_indices.insert(editor.current_tab, editor.current_buffer_index);
+    buffer_indices
+        .into_iter()
+        .map(|index| {
+            let buffer = &editor.buffers[index.min(editor.buffers.len() - 1)];
+            let modified_marker = if buffer.modified { " [+]" } else { "" };
+            format!(" {}{} ", buffer.get_name(), modified_marker)
+        })
+        .collect()
+}
+
+/// Return the index of the tab page whose label in the tab bar is at the screen position
+fn get_tab_at_position(state: &State, x: f32, y: f32) -> Option<usize> {
+    if state.editor.tabs.is_empty() || y >= state.line_height + state.command_box_padding {
+        return None;
+    }
+    let mut label_end = 0.0;
+    get_tab_labels(state).iter().position(|label| {
+        label_end += label.chars().count() as f32 * state.char_width;
+        x < label_end
+    })
+}
+
+/// Return the areas of the two panes of a split, which divide the text area equally
+fn split_area(
+    area: (f32, f32, f32, f32),
+    direction: SplitDirection,
+) -> ((f32, f32, f32, f32), (f32, f32, f32, f32)) {
+    let (x, y, width, height) = area;
+    match direction {
+        SplitDirection::Horizontal => {
+            let first_height = (height / 2.0).floor();
+            (
+                (x, y, width, first_height),
+                (x, y + first_height, width, height - first_height),
+            )
+        }
+        SplitDirection::Vertical => {
+            let first_width = (width / 2.0).floor();
+            (
+                (x, y, first_width, height),
+                (x + first_width, y, width - first_width, height),
+            )
+        }
+    }
+}
+
+/// Draw the current buffer into the area of a pane, scrolled to keep its cursor in view, and
+///     return the matches of the search in it. only the focused pane shows the cursor and the
+///     selection
+fn draw_pane(
+    draw: &mut Draw,
+    state: &mut State,
+    area: (f32, f32, f32, f32),
+    focused: bool,
+) -> Vec<(Cursor, Cursor)> {
+    // the query that is being typed is used while searching
+    let search_query = match state.mode {
+        Mode::Search => state.command_line.strip_prefix('/').unwrap_or(""),
+        _ => &state.search_query,
+    };
+    let search_matches = state
+        .editor
+        .buffer_mut()
+        .get_search_matches(search_query, &state.options)
+        .to_vec();
+    let cursor = state.editor.buffer().cursor;
+    let current_match = search_matches
+        .iter()
+        .find(|(start, end)| (*start..*end).contains(&cursor));
+    state
+        .editor
+        .buffer_mut()
+        .update_signs(current_match.map(|(start, _)| *start));
+
+    let buffer = state.editor.buffer();
+    let theme = &state.theme;
+    let char_width = state.char_width;
+    let (pane_x, pane_y, pane_width, pane_height) = area;
+    state.pane_area = area;
+    draw.rect((pane_x, pane_y), (pane_width, pane_height))
+        .color(convert_color(theme.settings.background.unwrap()));
+
+    let tab_stop = state.options.tab_stop;
+    let cursor_line = buffer.text.char_to_line(buffer.cursor);
+    let cursor_column = buffer.get_display_column(buffer.cursor, tab_stop);
+
+    let line_count = buffer.text.len_lines() - 1;
+    let line_number_digit_count = line_count.to_string().len().max(3);
+    let show_line_numbers = state.options.number || state.options.relative_number;
+    // the sign column is only there while some line has a sign
+    let sign_column_width = if buffer.signs.is_empty() {
+        0.0
+    } else {
+        2.0 * char_width
+    };
+    let line_number_offset = if show_line_numbers {
+        sign_column_width + line_number_digit_count as f32 * char_width + 4.0
+    } else {
+        sign_column_width
+    };
+    state.gutter_width = line_number_offset;
+
+    state.visible_lines = (pane_height / state.line_height) as usize;
+    let wrap_width = state.options.wrap.then(|| {
+        let text_width = pane_width - line_number_offset;
+        ((text_width / char_width.max(1.0)) as usize).max(1)
+    });
+    state.wrap_width = wrap_width;
+    let get_row_count = |line: usize| match wrap_width {
+        Some(width) => buffer.get_wrap_starts(line, width, tab_stop).len(),
+        None => 1,
+    };
+    if buffer.cursor != state.last_cursor {
+        state.scroll_top = calculate_scroll_start(
+            state.scroll_top,
+            cursor_line,
+            state.visible_lines,
+            state.scroll_off,
+        );
+        // wrapped lines take up several rows, so the cursor's row may still be below the screen
+        if let Some(width) = wrap_width {
+            let (cursor_row, _) = buffer.get_wrapped_position(buffer.cursor, width, tab_stop);
+            while state.scroll_top < cursor_line {
+                let rows: usize = (state.scroll_top..cursor_line).map(get_row_count).sum();
+                if rows + cursor_row < state.visible_lines {
+                    break;
+                }
+                state.scroll_top += 1;
+            }
+        }
+        state.scroll_x = 0.0;
+        state.last_cursor = buffer.cursor;
+    }
+
+    // the row each line on screen starts at, counted from the first visible line
+    let last_drawn_line = (state.scroll_top + state.visible_lines + 1).min(line_count);
+    let mut line_rows = Vec::new();
+    let mut row = 0;
+    for line in state.scroll_top..=last_drawn_line {
+        line_rows.push(row);
+        row += get_row_count(line);
+    }
+    let cursor_row_count = get_row_count(cursor_line);
+
+    // without wrapping, the view follows the cursor sideways and can be panned until the end of
+    //     the longest visible line is in view
+    let text_width = pane_width - line_number_offset;
+    state.scroll_column = match wrap_width {
+        Some(_) => 0,
+        None => calculate_scroll_start(
+            state.scroll_column,
+            cursor_column,
+            (text_width / char_width.max(1.0)) as usize,
+            state.side_scroll_off,
+        ),
+    };
+    let cursor_offset_x = -(state.scroll_column as f32 * char_width);
+    let last_visible_line = (state.scroll_top + state.visible_lines).min(buffer.get_last_line());
+    let longest_line = (state.scroll_top..=last_visible_line)
+        .map(|line| buffer.get_line_length(line))
+        .max()
+        .unwrap_or(0);
+    let max_scroll_x = match wrap_width {
+        Some(_) => 0.0,
+        None => longest_line as f32 * char_width - text_width + cursor_offset_x,
+    };
+    state.scroll_x = state.scroll_x.min(max_scroll_x.max(0.0));
+
+    // positions on screen are offset by the position of the pane
+    let camera_offset = (
+        pane_x + cursor_offset_x - state.scroll_x,
+        pane_y - state.scroll_top as f32 * state.line_height,
+    );
+    state.text_origin = (line_number_offset + camera_offset.0, camera_offset.1);
+
+    // only the lines on screen are highlighted
+    let buffer = state.editor.buffer_mut();
+    let extension = buffer.get_extension().to_string();
+    let highlighted_lines = state.highlighter.highlight(
+        &buffer.text,
+        &mut buffer.highlight_cache,
+        &extension,
+        &state.options.theme,
+        &state.theme,
+        state.scroll_top..state.scroll_top + state.visible_lines + 1,
+    );
+    let buffer = state.editor.buffer();
+
+    // lines that are not on screen keep their own row, which is off screen too
+    let scroll_top = state.scroll_top;
+    let line_height = state.line_height;
+    let get_line_row = |line: usize| match line.checked_sub(scroll_top) {
+        Some(index) if index < line_rows.len() => scroll_top + line_rows[index],
+        _ => line,
+    };
+    let get_screen_position = |cursor: Cursor| {
+        let line = buffer.text.char_to_line(cursor);
+        let (row, column) = match wrap_width {
+            Some(width) => buffer.get_wrapped_position(cursor, width, tab_stop),
+            None => (0, buffer.get_display_column(cursor, tab_stop)),
+        };
+        (
+            column as f32 * char_width + line_number_offset + camera_offset.0,
+            (get_line_row(line) + row) as f32 * line_height + camera_offset.1,
+        )
+    };
+
+    // render the cursor line behind everything else, across all of its display lines
+    if state.options.cursor_line {
+        let line_color = match theme.settings.line_highlight {
+            Some(color) => convert_color(color),
+            None => convert_color(theme.settings.foreground.unwrap()).with_alpha(0.08),
+        };
+        let y_position = get_line_row(cursor_line) as f32 * line_height + camera_offset.1;
+        draw.rect(
+            (pane_x, y_position),
+            (pane_width, cursor_row_count as f32 * line_height),
+        )
+        .color(line_color);
+    }
+
+    // render the rulers behind the text, tinting their columns
+    let ruler_color = match theme.settings.guide {
+        Some(color) => convert_color(color),
+        None => convert_color(theme.settings.foreground.unwrap()).with_alpha(0.08),
+    };
+    for column in state.color_columns.iter() {
+        let x_position = (column - 1) as f32 * char_width + line_number_offset + camera_offset.0;
+        if x_position >= pane_x + line_number_offset {
+            draw.rect((x_position, pane_y), (char_width, pane_height))
+                .color(ruler_color);
+        }
+    }
+
+    // search matches and the selection tint the background of the visible text, the selection
+    //     over the matches
+    let match_color = convert_color(
+        theme
+            .settings
+            .find_highlight
+            .or(theme.settings.selection)
+            .unwrap(),
+    );
+    let mut overlays: Vec<(Range<Cursor>, Color)> = search_matches
+        .iter()
+        .map(|(start, end)| (*start..*end, match_color))
+        .collect();
+    let selection_color = convert_color(theme.settings.selection.unwrap());
+    if let (Some(PendingInput::ConfirmSubstitution(substitution)), true) = (&state.pending, focused)
+    {
+        if let Some((range, _)) = &substitution.current {
+            overlays.push((range.clone(), selection_color));
+        }
+    }
+    if let (Mode::Visual, Some((start, end)), true) = (&state.mode, buffer.get_selection(), focused)
+    {
+        overlays.push((start..end, selection_color));
+    }
+    if let (Mode::VisualBlock, Some((lines, columns)), true) =
+        (&state.mode, buffer.get_block(tab_stop), focused)
+    {
+        for line in lines {
+            let row = buffer.get_block_row(line, columns.clone(), tab_stop);
+            overlays.push((row, selection_color));
+        }
+    }
+
+    let visible_start = buffer.text.line_to_char(state.scroll_top.min(line_count));
+    let visible_end = buffer.text.line_to_char(last_drawn_line + 1);
+    for (range, color) in merge_overlays(&overlays, visible_start..visible_end) {
+        // each display line of the range gets a single rectangle
+        let mut row_start: Option<(f32, f32)> = None;
+        let mut row_end = 0.0;
+        for cursor in range {
+            let (x_position, y_position) = get_screen_position(cursor);
+            if let Some((start_x, start_y)) = row_start.filter(|(_, y)| *y != y_position) {
+                draw.rect((start_x, start_y), (row_end - start_x, line_height))
+                    .color(color);
+                row_start = None;
+            }
+            let start = *row_start.get_or_insert((x_position, y_position));
+            let columns = match buffer.text.char(cursor) {
+                '\t' => tab_stop - buffer.get_display_column(cursor, tab_stop) % tab_stop,
+                _ => 1,
+            };
+            row_end = x_position + columns as f32 * char_width;
+            row_start = Some(start);
+        }
+        if let Some((start_x, start_y)) = row_start {
+            draw.rect((start_x, start_y), (row_end - start_x, line_height))
+                .color(color);
+        }
+    }
+
+    // draw highlighted text
+    let wrap_width = wrap_width.unwrap_or(usize::MAX);
+    for (index, line) in highlighted_lines.iter().enumerate() {
+        let mut row = get_line_row(state.scroll_top + index);
+        let mut char_index = 0usize;
+        // the display column the current display line of a wrapped line starts at
+        let mut row_start = 0usize;
+
+        for (style, fragment) in line {
+            // the parts of the fragment on different display lines are drawn separately
+            let mut parts = vec![(row, char_index - row_start, String::new())];
+            for character in fragment.chars() {
+                // tabs are drawn as the spaces up to the next tab stop
+                let width = match character {
+                    '\t' => tab_stop - char_index % tab_stop,
+                    _ => 1,
+                };
+                let wraps = char_index > row_start && char_index + width - row_start > wrap_width;
+                if wraps && character != '\n' {
+                    row += 1;
+                    row_start = char_index;
+                    parts.push((row, 0, String::new()));
+                }
+                let text = &mut parts.last_mut().unwrap().2;
+                match character {
+                    '\t' => text.push_str(&" ".repeat(width)),
+                    _ => text.push(character),
+                }
+                char_index += width;
+            }
+
+            for (row, column, text) in parts {
+                draw.text(state.font(), &text)
+                    .position(
+                        line_number_offset + camera_offset.0 + column as f32 * char_width,
+                        row as f32 * state.line_height + camera_offset.1,
+                    )
+                    .size(state.line_height)
+                    .color(convert_color(style.foreground));
+            }
+        }
+    }
+
+    // with list set, tabs and trailing spaces are drawn as faint glyphs
+    if state.options.list {
+        let whitespace_color = theme
+            .settings
+            .guide
+            .or(theme.settings.gutter_foreground)
+            .map_or(Color::GRAY, convert_color);
+        for line in state.scroll_top..=last_drawn_line.min(buffer.get_last_line()) {
+            let start = buffer.text.line_to_char(line);
+            let characters: Vec<char> = buffer
+                .text
+                .slice(start..start + buffer.get_line_length(line))
+                .chars()
+                .collect();
+            for (offset, glyph) in get_list_glyphs(&characters) {
+                let (x_position, y_position) = get_screen_position(start + offset);
+                draw.text(state.font(), glyph)
+                    .position(x_position, y_position)
+                    .size(state.line_height)
+                    .color(whitespace_color);
+            }
+        }
+    }
+
+    // render the primary cursor and the secondary ones that are on screen, in the focused pane
+    let cursor_color = convert_color(theme.settings.caret.unwrap());
+    let visible_lines = state.scroll_top..=last_drawn_line;
+    let cursors = std::iter::once(buffer.cursor)
+        .chain(buffer.cursors.iter().copied())
+        .filter(|_| focused)
+        .filter(|cursor| visible_lines.contains(&buffer.text.char_to_line(*cursor)));
+    for cursor in cursors {
+        let (x_position, y_position) = get_screen_position(cursor);
+
+        match state.mode {
+            Mode::Normal | Mode::Visual | Mode::VisualBlock | Mode::Replace => {
+                // the block covers the whole width of a tab, and one column on an empty line
+                let columns = match buffer.text.get_char(cursor) {
+                    Some('\t') => tab_stop - buffer.get_display_column(cursor, tab_stop) % tab_stop,
+                    _ => 1,
+                };
+                draw.rect(
+                    (x_position, y_position),
+                    (columns as f32 * char_width, state.line_height),
+                )
+                .color(cursor_color);
+            }
+            Mode::Insert => {
+                draw.line(
+                    (x_position, y_position),
+                    (x_position, y_position + state.line_height),
+                )
+                .color(cursor_color);
+            }
+            // the caret is drawn in the line that is being typed instead
+            Mode::Command | Mode::Search | Mode::QuickMenu => {}
+        }
+    }
+
+    // render line number background, which hides text that is scrolled to the left of the gutter
+    let number_background_color = convert_color(theme.settings.background.unwrap());
+    draw.rect((pane_x, pane_y), (line_number_offset, pane_height))
+        .color(number_background_color);
+
+    // render line numbers and the first sign of each line, next to its first display line
+    let mark_color = convert_color(
+        theme
+            .settings
+            .gutter_foreground
+            .or(theme.settings.foreground)
+            .unwrap(),
+    );
+    for index in state.scroll_top..=last_drawn_line {
+        let y_position = get_line_row(index) as f32 * state.line_height;
+
+        if let Some(sign) = buffer.signs.get(&index).and_then(|signs| signs.first()) {
+            let color = match sign {
+                Sign::Mark(_) => mark_color,
+                Sign::SearchMatch => match_color,
+            };
+            draw.text(state.font(), &sign.get_glyph().to_string())
+                .position(pane_x, y_position + camera_offset.1)
+                .size(state.line_height)
+                .color(color);
+        }
+
+        if show_line_numbers {
+            let number = state.options.get_line_number(index, cursor_line);
+
+            // pad the line number with spaces on the left
+            let line_number = format!(
+                "{:>width$}",
+                &number.to_string(),
+                width = line_number_digit_count
+            );
+
+            // draw the line number
+            draw.text(state.font(), &line_number)
+                .position(pane_x + sign_column_width, y_position + camera_offset.1)
+                .size(state.line_height)
+                .color(Color::GRAY);
+        }
+    }
+
+    search_matches
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Return the state with a buffer of the text, as it is before any config is loaded
+    fn state(text: &str) -> State {
+        let mut state = State::new(None, create_keymap());
+        state.editor.add_buffer(ropey::Rope::from_str(text), None);
+        state
+    }
+
+    fn text(state: &State) -> String {
+        state.editor.buffer().text.to_string()
+    }
+
+    #[test]
+    fn scrolls_only_to_keep_the_cursor_inside_the_margins() {
+        assert_eq!(calculate_scroll_start(0, 5, 10, 3), 0);
+        assert_eq!(calculate_scroll_start(0, 7, 10, 3), 1);
+        assert_eq!(calculate_scroll_start(5, 6, 10, 3), 3);
+        assert_eq!(calculate_scroll_start(5, 0, 10, 3), 0);
+        // the margins never take more than half of what is visible
+        assert_eq!(calculate_scroll_start(0, 3, 4, 10), 1);
+        assert_eq!(calculate_scroll_start(2, 2, 4, 10), 1);
+        assert_eq!(calculate_scroll_start(0, 5, 0, 3), 5);
+    }
+
+    #[test]
+    fn scrolling_stops_at_the_first_and_last_line() {
+        assert_eq!(calculate_scrolled_top(4, 10, 20), 14);
+        assert_eq!(calculate_scrolled_top(14, 10, 20), 20);
+        assert_eq!(calculate_scrolled_top(4, -10, 20), 0);
+        assert_eq!(calculate_scrolled_top(0, 5, 0), 0);
+    }
+
+    #[test]
+    fn dot_repeats_the_last_change_with_its_count() {
+        let mut state = state("abcdefg hi jk\n");
+        state.count = Some(2);
+        delete_characters(&mut state);
+        repeat_change(&mut state);
+        assert_eq!(text(&state), "efg hi jk\n");
+        // a new count replaces the one of the change
+        state.count = Some(1);
+        repeat_change(&mut state);
+        assert_eq!(text(&state), "fg hi jk\n");
+
+        state.action = Some(Action::Delete);
+        state.count = Some(2);
+        execute_motion(&mut state, Motion::Right);
+        repeat_change(&mut state);
+        assert_eq!(text(&state), "i jk\n");
+    }
+
+    #[test]
+    fn dot_repeats_an_insert_with_the_text_typed_in_it() {
+        let mut state = state("ab\n");
+        start_insert(&mut state, ModeChange::InsertEnd);
+        event(&mut state, Event::ReceivedCharacter('!'));
+        event(&mut state, Event::ReceivedCharacter('?'));
+        state.mode = Mode::Normal;
+        repeat_change(&mut state);
+        assert_eq!(text(&state), "ab!?!?\n");
+        assert_eq!(state.mode, Mode::Normal);
+    }
+
+    #[test]
+    fn recording_stores_the_macro_in_its_register() {
+        let mut state = state("");
+        toggle_macro_recording(&mut state, Some('a'));
+        event(&mut state, Event::ReceivedCharacter('x'));
+        // characters that are replayed are not recorded a second time
+        state.editor.macros.replay_depth = 1;
+        event(&mut state, Event::ReceivedCharacter('y'));
+        assert_eq!(state.editor.macros.characters, vec!['x']);
+
+        let frame = MacroFrame {
+            keys: vec![Shortcut::new(KeyCode::X)],
+            characters: Vec::new(),
+        };
+        if let Some((_, frames)) = &mut state.editor.macros.recording {
+            frames.push(frame);
+        }
+        toggle_macro_recording(&mut state, None);
+        assert!(state.editor.macros.recording.is_none());
+        assert_eq!(state.editor.macros.registers[&'a'].len(), 1);
+    }
+
+    #[test]
+    fn opened_lines_take_the_indentation_of_the_cursor_line() {
+        let mut state = state("a\n  b\n");
+        state.editor.buffer_mut().cursor = 4;
+        start_insert(&mut state, ModeChange::OpenBelow);
+        assert_eq!(text(&state), "a\n  b\n  \n");
+        assert_eq!(state.editor.buffer().cursor, 8);
+        assert_eq!(state.mode, Mode::Insert);
+
+        state.editor.buffer_mut().cursor = 4;
+        start_insert(&mut state, ModeChange::OpenAbove);
+        assert_eq!(text(&state), "a\n  \n  b\n  \n");
+        assert_eq!(state.editor.buffer().cursor, 4);
+    }
+
+    #[test]
+    fn selection_actions_include_both_ends_of_the_selection() {
+        let mut state = state("abc def\n");
+        let buffer = state.editor.buffer_mut();
+        buffer.selection_anchor = Some(4);
+        buffer.cursor = 1;
+        state.mode = Mode::Visual;
+        apply_selection_action(&mut state, Action::Yank);
+        assert_eq!(state.register.text, "bc d");
+        assert_eq!(state.mode, Mode::Normal);
+        assert!(state.editor.buffer().selection_anchor.is_none());
+
+        state.editor.buffer_mut().selection_anchor = Some(6);
+        apply_selection_action(&mut state, Action::Delete);
+        assert_eq!(text(&state), "a\n");
+    }
+
+    #[test]
+    fn the_mouse_wheel_scrolls_the_view_but_not_the_cursor() {
+        let mut state = state(&"line\n".repeat(10));
+        state.char_width = 10.0;
+        event(
+            &mut state,
+            Event::MouseWheel {
+                delta_x: 0.0,
+                delta_y: -1.0,
+            },
+        );
+        assert_eq!(state.scroll_top, 3);
+        assert_eq!(state.editor.buffer().cursor, 0);
+        event(
+            &mut state,
+            Event::MouseWheel {
+                delta_x: 0.0,
+                delta_y: 1.0,
+            },
+        );
+        event(
+            &mut state,
+            Event::MouseWheel {
+                delta_x: 0.0,
+                delta_y: 1.0,
+            },
+        );
+        assert_eq!(state.scroll_top, 0);
+
+        event(
+            &mut state,
+            Event::MouseWheel {
+                delta_x: -1.0,
+                delta_y: 0.0,
+            },
+        );
+        assert_eq!(state.scroll_x, 30.0);
+    }
+
+    #[test]
+    fn replacing_characters_needs_enough_of_them_on_the_line() {
+        let mut state = state("abcd\n");
+        state.count = Some(2);
+        replace_characters(&mut state, 'x');
+        assert_eq!(text(&state), "xxcd\n");
+        assert_eq!(state.editor.buffer().cursor, 1);
+
+        state.count = Some(4);
+        replace_characters(&mut state, 'y');
+        assert_eq!(text(&state), "xxcd\n");
+
+        // a line break takes the place of all the characters
+        state.count = Some(2);
+        replace_characters(&mut state, '\n');
+        assert_eq!(text(&state), "x\nd\n");
+        assert_eq!(state.editor.buffer().cursor, 2);
+    }
+
+    #[test]
+    fn tilde_toggles_case_up_to_the_end_of_the_line() {
+        let mut state = state("aBß1c\nd\n");
+        state.count = Some(3);
+        toggle_case(&mut state);
+        assert_eq!(text(&state), "Abß1c\nd\n");
+        assert_eq!(state.editor.buffer().cursor, 3);
+
+        state.count = Some(9);
+        toggle_case(&mut state);
+        assert_eq!(text(&state), "Abß1C\nd\n");
+        assert_eq!(state.editor.buffer().cursor, 4);
+    }
+
+    #[test]
+    fn escape_cancels_the_operator_waiting_for_its_motion() {
+        let mut state = state("abc def\n");
+        state.action = Some(Action::Delete);
+        return_to_normal_mode(&mut state);
+        execute_motion(&mut state, Motion::ForwardWord);
+        assert_eq!(text(&state), "abc def\n");
+        assert_eq!(state.editor.buffer().cursor, 4);
+        assert!(state.action.is_none());
+    }
+
+    #[test]
+    fn case_operators_apply_over_the_line_and_the_motion() {
+        let mut state = state("Abc Def\nGHI\n");
+        state.editor.buffer_mut().marks.insert('a', 4);
+        // `guu`
+        execute_line_action(&mut state, Action::ChangeCase(Case::Lower));
+        assert_eq!(text(&state), "abc def\nGHI\n");
+        // `gUw`
+        state.action = Some(Action::ChangeCase(Case::Upper));
+        execute_motion(&mut state, Motion::ForwardWord);
+        assert_eq!(text(&state), "ABC def\nGHI\n");
+        assert_eq!(state.editor.buffer().get_mark('a'), Some(4));
+    }
+
+    #[test]
+    fn operators_over_line_motions_take_whole_lines() {
+        let mut state = state("ab\ncd\nef\n");
+        state.editor.buffer_mut().cursor = 4;
+        state.action = Some(Action::Delete);
+        execute_motion(&mut state, Motion::Up);
+        assert_eq!(text(&state), "ef\n");
+    }
+
+    #[test]
+    fn operators_report_that_they_only_use_the_primary_cursor() {
+        let mut state = state("ab ab ab\n");
+        assert!(state.editor.buffer_mut().add_cursor_at_next_occurrence());
+        state.action = Some(Action::Delete);
+        execute_motion(&mut state, Motion::ForwardWord);
+        assert_eq!(text(&state), "ab ab\n");
+        assert_eq!(
+            state.message.as_ref().map(|message| message.text.as_str()),
+            Some("The operator only uses the primary cursor")
+        );
+    }
+
+    #[test]
+    fn a_failed_command_replaces_the_message_with_an_error() {
+        let mut state = state("abc\n");
+        run_command(&mut state, "s/b/x/", 0.0);
+        let message = state.message.as_ref().unwrap();
+        assert_eq!(message.severity, Severity::Info);
+        run_command(&mut state, "nosuchcommand", 0.0);
+        let message = state.message.as_ref().unwrap();
+        assert_eq!(message.severity, Severity::Error);
+        assert_ne!(message.text, "1 substitution");
+    }
+
+    #[test]
+    fn auto_pairs_close_brackets_and_type_over_the_closing_one() {
+        let mut state = state("it\n");
+        state.options.auto_pairs = true;
+        state.mode = Mode::Insert;
+        state.editor.buffer_mut().cursor = 2;
+        for c in "'(a".chars() {
+            event(&mut state, Event::ReceivedCharacter(c));
+        }
+        // a quote after a word stays alone, like an apostrophe
+        assert_eq!(text(&state), "it'(a)\n");
+        event(&mut state, Event::ReceivedCharacter(')'));
+        event(&mut state, Event::ReceivedCharacter('"'));
+        assert_eq!(text(&state), "it'(a)\"\"\n");
+        assert_eq!(state.editor.buffer().cursor, 7);
+    }
+
+    #[test]
+    fn the_caret_column_counts_characters_not_bytes() {
+        assert_eq!(get_caret_column("", 0), 0);
+        assert_eq!(get_caret_column(":s/é/e", 5), 4);
+        assert_eq!(get_caret_column(":s/é/e", 7), 6);
+        assert_eq!(get_caret_column("ab", 5), 2);
+    }
+
+    #[test]
+    fn typed_characters_go_in_at_the_caret() {
+        let mut state = state("");
+        state.mode = Mode::Command;
+        state.command_line = String::from(":wq");
+        state.editor.command_cursor = 2;
+        event(&mut state, Event::ReceivedCharacter('é'));
+        event(&mut state, Event::ReceivedCharacter('!'));
+        assert_eq!(state.command_line, ":wé!q");
+        assert_eq!(state.editor.command_cursor, 5);
+        assert_eq!(
+            get_caret_column(&state.command_line, state.editor.command_cursor),
+            4
+        );
+    }
+
+    #[test]
+    fn deleting_before_the_cursor_stops_at_the_start_of_the_line() {
+        let mut state = state("ab\ncd ef\n");
+        state.editor.buffer_mut().cursor = 8;
+        // Ctrl-w
+        delete_before_cursor(&mut state, Motion::BackWord);
+        assert_eq!(text(&state), "ab\ncd \n");
+        // Ctrl-u
+        delete_before_cursor(&mut state, Motion::StartOfLine);
+        assert_eq!(text(&state), "ab\n\n");
+        // at the start of the line it joins the line with the one before
+        delete_before_cursor(&mut state, Motion::BackWord);
+        assert_eq!(text(&state), "ab\n");
+        assert_eq!(state.editor.buffer().cursor, 2);
+    }
+
+    #[test]
+    fn list_shows_tabs_and_only_the_trailing_spaces() {
+        let characters: Vec<char> = "\ta b \t ".chars().collect();
+        assert_eq!(
+            get_list_glyphs(&characters),
+            vec![(0, "»"), (4, "·"), (5, "»"), (6, "·")]
+        );
+        let blank: Vec<char> = "  ".chars().collect();
+        assert_eq!(get_list_glyphs(&blank), vec![(0, "·"), (1, "·")]);
+        assert!(get_list_glyphs(&[]).is_empty());
+    }
+
+    #[test]
+    fn deleted_multibyte_word_pastes_back_verbatim() {
+        let mut state = state("żółw ąę\n");
+        state.action = Some(Action::Delete);
+        execute_motion(&mut state, Motion::ForwardWord);
+        assert_eq!(text(&state), "ąę\n");
+        assert_eq!(state.register.text, "żółw ");
+        assert!(state.editor.buffer().modified);
+        paste(&mut state, true);
+        assert_eq!(text(&state), "żółw ąę\n");
+    }
+
+    #[test]
+    fn star_searches_the_word_and_n_repeats_it() {
+        let mut state = state("foo bar\nfood foo\nfoo\n");
+        search_word_under_cursor(&mut state, true);
+        assert_eq!(state.editor.buffer().cursor, 13);
+        // `n`, the whole word only, wrapping around
+        assert!(state.search_forward);
+        search_next(&mut state, true);
+        assert_eq!(state.editor.buffer().cursor, 17);
+        search_next(&mut state, true);
+        assert_eq!(state.editor.buffer().cursor, 0);
+        // `N` goes back
+        search_next(&mut state, false);
+        assert_eq!(state.editor.buffer().cursor, 17);
+    }
+
+    #[test]
+    fn the_search_being_typed_previews_its_first_match() {
+        let mut state = state("ab ab\nab\n");
+        state.editor.buffer_mut().cursor = 1;
+        state.editor.search_start = Some(1);
+        state.mode = Mode::Search;
+        state.command_line = String::from("/ab");
+        preview_search(&mut state);
+        assert_eq!(state.editor.buffer().cursor, 3);
+        // a query without a match leaves the cursor where the search started
+        state.command_line = String::from("/x");
+        preview_search(&mut state);
+        assert_eq!(state.editor.buffer().cursor, 1);
+
+        state.command_line = String::from("/ab");
+        preview_search(&mut state);
+        return_to_normal_mode(&mut state);
+        assert_eq!(state.editor.buffer().cursor, 1);
+        assert!(state.editor.search_start.is_none());
+    }
+
+    #[test]
+    fn normal_types_the_keys_at_the_start_of_each_line_of_the_range() {
+        let mut state = state("abc\ndef\nghi\n");
+        run_command(&mut state, "2,3normal x", 0.0);
+        assert_eq!(text(&state), "abc\nef\nhi\n");
+
+        // the mode the keys leave open is escaped on each line
+        run_command(&mut state, "%normal A!", 0.0);
+        assert_eq!(text(&state), "abc!\nef!\nhi!\n");
+        assert_eq!(state.mode, Mode::Normal);
+    }
+}
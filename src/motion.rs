@@ -1,5 +1,6 @@
-use crate::buffer::{Buffer, Cursor, cursor_add};
-use ropey::iter::Chars;
+use crate::buffer::{Buffer, Cursor};
+use ropey::RopeSlice;
+use std::ops::{Range, RangeInclusive};
 
 #[derive(Debug, Clone)]
 pub enum Motion {
@@ -10,67 +11,623 @@ pub enum Motion {
     ForwardWord,
     ForwardWordEnd,
     BackWord,
+    ForwardWORD,
+    ForwardWORDEnd,
+    BackWORD,
     EndOfLine,
+    StartOfLine,
+    FirstNonBlank,
+    FindForward(char),
+    FindBackward(char),
+    TillForward(char),
+    TillBackward(char),
+    FirstLine,
+    LastLine,
+    NextParagraph,
+    PrevParagraph,
+    MatchBracket,
+    /// the position of a mark, as with `` `a ``
+    Mark(char),
+    /// the first non-blank character on the line of a mark, as with `'a`
+    MarkLine(char),
+    /// the first non-blank character on the top, middle or bottom line of the view, as with `H`,
+    ///     `M` and `L`
+    ScreenTop,
+    ScreenMiddle,
+    ScreenBottom,
 }
-fn skip_while<F>(chars: Chars, predicate: F) -> Cursor
-where
-    F: Fn(usize, char) -> bool,
-{
-    let mut index = 0;
-    for (i, character) in chars.enumerate() {
-        if !predicate(i, character) {
-            break;
+/// Kind of character that word motions move between
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CharacterClass {
+    Whitespace,
+    Word,
+    Punctuation,
+}
+
+/// Return the class of the character. with `big_word` all non-whitespace characters are part of
+///     words, as for the `W`, `B` and `E` motions
+pub fn get_character_class(character: char, big_word: bool) -> CharacterClass {
+    if character.is_whitespace() {
+        CharacterClass::Whitespace
+    } else if big_word || character.is_alphanumeric() || character == '_' {
+        CharacterClass::Word
+    } else {
+        CharacterClass::Punctuation
+    }
+}
+
+/// Whether the position is the start of an empty line, which word motions stop at
+fn is_empty_line(buffer: &Buffer, position: Cursor) -> bool {
+    buffer.text.char(position) == '\n' && (position == 0 || buffer.text.char(position - 1) == '\n')
+}
+
+/// Return the start of the next word, or the last character if there is none
+fn next_word_start(buffer: &Buffer, cursor: Cursor, big_word: bool) -> Cursor {
+    let length = buffer.text.len_chars();
+    if cursor + 1 >= length {
+        return cursor;
+    }
+    let class = |position: Cursor| get_character_class(buffer.text.char(position), big_word);
+
+    let start_class = class(cursor);
+    let mut position = cursor;
+    if start_class != CharacterClass::Whitespace {
+        while position < length && class(position) == start_class {
+            position += 1;
+        }
+    }
+    while position < length && class(position) == CharacterClass::Whitespace {
+        if position != cursor && is_empty_line(buffer, position) {
+            return position;
         }
-        index += 1;
+        position += 1;
     }
-    index
+    position.min(length - 1)
 }
 
+/// Return the end of the word after the cursor, or the last character if there is none
+fn next_word_end(buffer: &Buffer, cursor: Cursor, big_word: bool) -> Cursor {
+    let length = buffer.text.len_chars();
+    if cursor + 1 >= length {
+        return cursor;
+    }
+    let class = |position: Cursor| get_character_class(buffer.text.char(position), big_word);
+
+    let mut position = cursor + 1;
+    while position < length && class(position) == CharacterClass::Whitespace {
+        position += 1;
+    }
+    if position >= length {
+        return length - 1;
+    }
+    let run_class = class(position);
+    while position + 1 < length && class(position + 1) == run_class {
+        position += 1;
+    }
+    position
+}
+
+/// Return the start of the word before the cursor, or the first character if there is none
+fn previous_word_start(buffer: &Buffer, cursor: Cursor, big_word: bool) -> Cursor {
+    if cursor == 0 {
+        return cursor;
+    }
+    let class = |position: Cursor| get_character_class(buffer.text.char(position), big_word);
+
+    let mut position = cursor - 1;
+    while position > 0 && class(position) == CharacterClass::Whitespace {
+        if is_empty_line(buffer, position) {
+            return position;
+        }
+        position -= 1;
+    }
+    let run_class = class(position);
+    while position > 0 && class(position - 1) == run_class {
+        position -= 1;
+    }
+    position
+}
+
+/// Return the start of the next blank line after the paragraph, or the end of the text
+fn next_paragraph(buffer: &Buffer, cursor: Cursor) -> Cursor {
+    let last_line = buffer.get_last_line();
+    let line = buffer.text.char_to_line(cursor);
+    let blank_line = buffer
+        .text
+        .lines_at(line)
+        .enumerate()
+        .skip_while(|(_, content)| is_blank(content))
+        .find(|(_, content)| is_blank(content))
+        .map(|(offset, _)| line + offset)
+        .filter(|line| *line <= last_line);
+
+    match blank_line {
+        Some(line) => buffer.text.line_to_char(line),
+        None => buffer.get_cursor_at(last_line, usize::MAX),
+    }
+}
+
+/// Return the start of the previous blank line before the paragraph, or the start of the text
+fn previous_paragraph(buffer: &Buffer, cursor: Cursor) -> Cursor {
+    let line = buffer.text.char_to_line(cursor);
+    let mut lines = buffer.text.lines_at(line + 1);
+    let blank_line = std::iter::from_fn(|| lines.prev())
+        .enumerate()
+        .skip_while(|(_, content)| is_blank(content))
+        .find(|(_, content)| is_blank(content))
+        .map(|(offset, _)| line - offset);
+
+    blank_line.map_or(0, |line| buffer.text.line_to_char(line))
+}
+
+/// Whether the line only consists of whitespace, which separates paragraphs
+fn is_blank(line: &RopeSlice) -> bool {
+    line.chars().all(|character| character.is_whitespace())
+}
+
+/// Return the bracket matching the one under the cursor, or the first one after it on the line
+fn match_bracket(buffer: &Buffer, cursor: Cursor) -> Option<Cursor> {
+    let line = buffer.text.char_to_line(cursor);
+    let line_end = buffer.text.line_to_char(line) + buffer.get_line_length(line);
+    let position = (cursor..line_end).find(|position| {
+        matches!(
+            buffer.text.char(*position),
+            '(' | ')' | '[' | ']' | '{' | '}'
+        )
+    })?;
+
+    let (open, close, forward) = match buffer.text.char(position) {
+        '(' => ('(', ')', true),
+        ')' => ('(', ')', false),
+        '[' => ('[', ']', true),
+        ']' => ('[', ']', false),
+        '{' => ('{', '}', true),
+        _ => ('{', '}', false),
+    };
+
+    // count the nesting depth until the bracket that closes the one at the position
+    let mut depth = 0;
+    let mut chars = buffer
+        .text
+        .chars_at(if forward { position } else { position + 1 });
+    let mut index = position;
+    loop {
+        let character = if forward {
+            chars.next()?
+        } else {
+            chars.prev()?
+        };
+        if character == open {
+            depth += 1;
+        } else if character == close {
+            depth -= 1;
+        }
+        if depth == 0 {
+            return Some(index);
+        }
+        if forward {
+            index += 1;
+        } else {
+            index = index.checked_sub(1)?;
+        }
+    }
+}
+
+/// Find the next occurrence of the character within the cursor's line in the given direction
+fn find_in_line(buffer: &Buffer, cursor: Cursor, target: char, forward: bool) -> Option<Cursor> {
+    let line = buffer.text.char_to_line(cursor);
+    let line_start = buffer.text.line_to_char(line);
+    let position = cursor - line_start;
+
+    let chars = buffer.text.line(line).chars().enumerate();
+    if forward {
+        chars
+            .skip(position + 1)
+            .take_while(|(_, character)| *character != '\n')
+            .find(|(_, character)| *character == target)
+            .map(|(index, _)| line_start + index)
+    } else {
+        chars
+            .take(position)
+            .filter(|(_, character)| *character == target)
+            .last()
+            .map(|(index, _)| line_start + index)
+    }
+}
+
+/// Return the position of the mark, kept within the text
+fn get_mark(buffer: &Buffer, name: char) -> Option<Cursor> {
+    let mark = buffer.get_mark(name)?;
+    Some(mark.min(buffer.text.len_chars().saturating_sub(1)))
+}
 
 impl Motion {
-    /// Return the target location of this movement
-    pub fn get_target(self, buffer: &Buffer) -> Cursor {
-        match self {
-            Motion::ForwardWord => {
-                let chars = buffer.text.chars_at(buffer.cursor);
-                let is_alphanumeric_start = buffer.text.char(buffer.cursor).is_alphanumeric();
-                let offset = skip_while(chars, |_, character| {
-                    // skip to the next non-alphanumeric character
-                    is_alphanumeric_start == character.is_alphanumeric()
-                });
-                buffer.get_movement_x(buffer.cursor, offset as i32)
+    /// Whether the characters at both the cursor and the target location are included when an
+    ///     action is applied. `F` and `T` are not, so that `dFx` keeps the character under the
+    ///     cursor
+    pub fn is_inclusive(&self) -> bool {
+        matches!(
+            self,
+            Motion::EndOfLine
+                | Motion::ForwardWordEnd
+                | Motion::ForwardWORDEnd
+                | Motion::FindForward(_)
+                | Motion::TillForward(_)
+                | Motion::MatchBracket
+        )
+    }
+
+    /// Return the characters an action applies to when the motion moves the cursor to the
+    ///     target, for motions that are not linewise
+    pub fn get_action_range(&self, buffer: &Buffer, target: Cursor) -> Range<Cursor> {
+        let (start, end) = if buffer.cursor <= target {
+            (buffer.cursor, target)
+        } else {
+            (target, buffer.cursor)
+        };
+        let end = if self.is_inclusive() {
+            // an inclusive motion never takes the newline, as `d$` on an empty line
+            match buffer.text.get_char(end) {
+                Some(character) if character != '\n' => end + 1,
+                _ => end,
+            }
+        } else if end > start
+            && buffer.find_line_position(end) == 0
+            && buffer.text.char_to_line(end) > buffer.text.char_to_line(start)
+        {
+            // an exclusive motion that ends at the start of a later line stops at the end of
+            //     the line before, so that `dw` on the last word of a line keeps the newline
+            end - 1
+        } else {
+            end
+        };
+        start..end.min(buffer.text.len_chars())
+    }
+
+    /// Whether an action applies to all of the lines from the cursor to the target location, as
+    ///     `dj` deletes two lines
+    pub fn is_linewise(&self) -> bool {
+        matches!(
+            self,
+            Motion::Up
+                | Motion::Down
+                | Motion::FirstLine
+                | Motion::LastLine
+                | Motion::MarkLine(_)
+                | Motion::ScreenTop
+                | Motion::ScreenMiddle
+                | Motion::ScreenBottom
+        )
+    }
+
+    /// Whether the movement may go far, so that the position before it is kept in the jump list
+    pub fn is_jump(&self) -> bool {
+        matches!(
+            self,
+            Motion::FirstLine
+                | Motion::LastLine
+                | Motion::NextParagraph
+                | Motion::PrevParagraph
+                | Motion::MatchBracket
+                | Motion::Mark(_)
+                | Motion::MarkLine(_)
+                | Motion::ScreenTop
+                | Motion::ScreenMiddle
+                | Motion::ScreenBottom
+        )
+    }
+
+    /// Return the target of a motion that depends on the lines in view, which are not known to
+    ///     the buffer, or None for the other motions. a count is the line to go to counted from
+    ///     the top for `H` and from the bottom for `L`
+    pub fn get_screen_target(
+        &self,
+        buffer: &Buffer,
+        visible_lines: RangeInclusive<usize>,
+        count: Option<usize>,
+    ) -> Option<Cursor> {
+        let (first, last) = (*visible_lines.start(), *visible_lines.end());
+        let offset = count.unwrap_or(1).max(1) - 1;
+        let line = match self {
+            Motion::ScreenTop => (first + offset).min(last),
+            Motion::ScreenMiddle => first + (last - first) / 2,
+            Motion::ScreenBottom => last.saturating_sub(offset).max(first),
+            _ => return None,
+        };
+        Some(buffer.get_first_non_blank_cursor(line.min(buffer.get_last_line())))
+    }
+
+    /// Return the target location of this movement or None if the movement is not possible.
+    ///     the count repeats the movement, except for line jumps where it is the line number
+    pub fn get_target(self, buffer: &Buffer, count: Option<usize>) -> Option<Cursor> {
+        match (&self, count) {
+            (Motion::FirstLine, Some(line)) => {
+                let line = (line.max(1) - 1).min(buffer.get_last_line());
+                Some(buffer.get_cursor_at(line, buffer.find_line_position(buffer.cursor)))
+            }
+            (Motion::LastLine, Some(line)) => {
+                let line = (line.max(1) - 1).min(buffer.get_last_line());
+                Some(buffer.get_first_non_blank_cursor(line))
+            }
+            // repeating the jump would only go back and forth between the brackets
+            (Motion::MatchBracket, _) => match_bracket(buffer, buffer.cursor),
+            (Motion::Mark(_) | Motion::MarkLine(_), _) => self.step(buffer, buffer.cursor),
+            // stepping again from right before the character would not get past it
+            (Motion::TillForward(character), _) | (Motion::TillBackward(character), _) => {
+                let forward = matches!(self, Motion::TillForward(_));
+                let mut cursor = buffer.cursor;
+                for _ in 0..count.unwrap_or(1) {
+                    cursor = find_in_line(buffer, cursor, *character, forward)?;
+                }
+                Some(if forward { cursor - 1 } else { cursor + 1 })
             }
-            Motion::ForwardWordEnd => {
-                let chars = buffer.text.chars_at(buffer.get_movement_x(buffer.cursor, 1));
-                let is_alphanumeric_start = buffer
-                    .text
-                    .char((buffer.cursor.max(1) + 1).min(buffer.text.len_chars() - 1))
-                    .is_alphanumeric();
-
-                let offset = skip_while(chars, |_, character| {
-                    // skip to the next non-alphanumeric character
-                    is_alphanumeric_start == character.is_alphanumeric()
-                }) + 1;
-                buffer.get_movement_x(buffer.cursor, offset as i32 - 1)
+            _ => {
+                let mut cursor = buffer.cursor;
+                for _ in 0..count.unwrap_or(1) {
+                    cursor = self.step(buffer, cursor)?;
+                }
+                Some(cursor)
             }
-            Motion::BackWord => {
-                let chars = buffer.text.chars_at(buffer.cursor).reversed();
-                let is_alphanumeric_start =
-                    buffer.text.char(buffer.cursor.max(1) - 1).is_alphanumeric();
-                let offset = skip_while(chars, |_, character| {
-                    // skip to the next non-alphanumeric character
-                    is_alphanumeric_start == character.is_alphanumeric()
-                });
-                cursor_add(buffer.cursor, -(offset as i32))
+        }
+    }
+
+    /// Return the target of a single movement starting at the cursor
+    fn step(&self, buffer: &Buffer, cursor: Cursor) -> Option<Cursor> {
+        let target = match *self {
+            Motion::ForwardWord => next_word_start(buffer, cursor, false),
+            Motion::ForwardWordEnd => next_word_end(buffer, cursor, false),
+            Motion::BackWord => previous_word_start(buffer, cursor, false),
+            Motion::ForwardWORD => next_word_start(buffer, cursor, true),
+            Motion::ForwardWORDEnd => next_word_end(buffer, cursor, true),
+            Motion::BackWORD => previous_word_start(buffer, cursor, true),
+            Motion::Left => buffer.get_movement_x(cursor, -1),
+            Motion::Down => buffer.get_movement_y(cursor, 1),
+            Motion::Up => buffer.get_movement_y(cursor, -1),
+            Motion::Right => buffer.get_movement_x(cursor, 1),
+
+            Motion::EndOfLine => buffer.get_end_of_line_cursor(cursor),
+            Motion::StartOfLine => buffer.text.line_to_char(buffer.text.char_to_line(cursor)),
+            Motion::FirstNonBlank => {
+                buffer.get_first_non_blank_cursor(buffer.text.char_to_line(cursor))
+            }
+
+            Motion::FirstLine => buffer.get_cursor_at(0, buffer.find_line_position(cursor)),
+            Motion::LastLine => buffer.get_first_non_blank_cursor(buffer.get_last_line()),
+
+            Motion::NextParagraph => next_paragraph(buffer, cursor),
+            Motion::PrevParagraph => previous_paragraph(buffer, cursor),
+            Motion::MatchBracket => match_bracket(buffer, cursor)?,
+            Motion::Mark(name) => get_mark(buffer, name)?,
+            Motion::MarkLine(name) => {
+                buffer.get_first_non_blank_cursor(buffer.text.char_to_line(get_mark(buffer, name)?))
             }
-            Motion::Left => buffer.get_movement_x(buffer.cursor, -1),
-            Motion::Down => buffer.get_movement_y(buffer.cursor, 1),
-            Motion::Up => buffer.get_movement_y(buffer.cursor, -1),
-            Motion::Right => buffer.get_movement_x(buffer.cursor, 1),
 
-            Motion::EndOfLine => buffer.get_end_of_line_cursor(buffer.cursor),
+            Motion::FindForward(character) => find_in_line(buffer, cursor, character, true)?,
+            Motion::FindBackward(character) => find_in_line(buffer, cursor, character, false)?,
+            Motion::TillForward(character) => find_in_line(buffer, cursor, character, true)? - 1,
+            Motion::TillBackward(character) => find_in_line(buffer, cursor, character, false)? + 1,
 
-            _ => buffer.cursor,
+            // the lines in view are needed, see `get_screen_target`
+            Motion::ScreenTop | Motion::ScreenMiddle | Motion::ScreenBottom => return None,
+        };
+        Some(target)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    /// Return where the motion moves the cursor to, count times
+    fn target(text: &str, cursor: Cursor, motion: Motion, count: Option<usize>) -> Option<Cursor> {
+        let mut buffer = Buffer::new(Rope::from_str(text), None);
+        buffer.cursor = cursor;
+        motion.get_target(&buffer, count)
+    }
+
+    /// Return the text left after deleting from the cursor over the motion
+    fn delete(text: &str, cursor: Cursor, motion: Motion) -> String {
+        let mut buffer = Buffer::new(Rope::from_str(text), None);
+        buffer.cursor = cursor;
+        let target = motion.clone().get_target(&buffer, None).unwrap();
+        let range = motion.get_action_range(&buffer, target);
+        buffer.remove(range);
+        buffer.text.to_string()
+    }
+
+    #[test]
+    fn find_forward_includes_the_found_character() {
+        assert_eq!(delete("abxcd\n", 0, Motion::FindForward('x')), "cd\n");
+        assert_eq!(delete("abxcd\n", 0, Motion::TillForward('x')), "xcd\n");
+    }
+
+    #[test]
+    fn start_of_line_motions_stay_on_the_line() {
+        assert_eq!(target("ab\n  cd\n", 6, Motion::StartOfLine, None), Some(3));
+        assert_eq!(
+            target("ab\n  cd\n", 3, Motion::FirstNonBlank, None),
+            Some(5)
+        );
+        // the empty last line has no character for `$` to land on
+        assert_eq!(target("ab\n", 3, Motion::EndOfLine, None), Some(3));
+    }
+
+    #[test]
+    fn find_backward_keeps_the_cursor_character() {
+        assert_eq!(delete("xabcd\n", 3, Motion::FindBackward('x')), "cd\n");
+        assert_eq!(delete("xabcd\n", 3, Motion::TillBackward('x')), "xcd\n");
+        // `dTx` right after the character deletes nothing
+        assert_eq!(delete("xabcd\n", 1, Motion::TillBackward('x')), "xabcd\n");
+    }
+
+    #[test]
+    fn matching_bracket_backward_includes_both_brackets() {
+        assert_eq!(delete("a(b)c\n", 3, Motion::MatchBracket), "ac\n");
+        assert_eq!(delete("a(b)c\n", 1, Motion::MatchBracket), "ac\n");
+    }
+
+    #[test]
+    fn inclusive_and_exclusive_motions_keep_the_newline() {
+        assert_eq!(delete("ab\n\ncd\n", 3, Motion::EndOfLine), "ab\n\ncd\n");
+        assert_eq!(delete("ab cd\nef\n", 3, Motion::ForwardWord), "ab \nef\n");
+        assert_eq!(delete("ab cd\n", 0, Motion::ForwardWordEnd), " cd\n");
+    }
+
+    #[test]
+    fn counted_till_goes_past_the_adjacent_character() {
+        assert_eq!(
+            target("axbxc\n", 0, Motion::TillForward('x'), Some(2)),
+            Some(2)
+        );
+        assert_eq!(
+            target("axbxc\n", 4, Motion::TillBackward('x'), Some(2)),
+            Some(2)
+        );
+        // a single `tx` right before the character stays put
+        assert_eq!(
+            target("axbxc\n", 2, Motion::TillForward('x'), None),
+            Some(2)
+        );
+        assert_eq!(
+            target("axbxc\n", 0, Motion::TillForward('x'), Some(3)),
+            None
+        );
+    }
+
+    #[test]
+    fn characters_are_classified_like_vim() {
+        assert_eq!(get_character_class('a', false), CharacterClass::Word);
+        assert_eq!(get_character_class('_', false), CharacterClass::Word);
+        assert_eq!(get_character_class('é', false), CharacterClass::Word);
+        assert_eq!(get_character_class('.', false), CharacterClass::Punctuation);
+        assert_eq!(get_character_class('.', true), CharacterClass::Word);
+        assert_eq!(get_character_class('\t', true), CharacterClass::Whitespace);
+    }
+
+    #[test]
+    fn word_motions_stop_at_punctuation_unless_big() {
+        let text = "foo.bar baz\n";
+        assert_eq!(target(text, 0, Motion::ForwardWord, None), Some(3));
+        assert_eq!(target(text, 0, Motion::ForwardWORD, None), Some(8));
+        assert_eq!(target(text, 0, Motion::ForwardWordEnd, None), Some(2));
+        assert_eq!(target(text, 0, Motion::ForwardWORDEnd, None), Some(6));
+        assert_eq!(target(text, 10, Motion::BackWord, None), Some(8));
+        assert_eq!(target(text, 8, Motion::BackWord, None), Some(4));
+        assert_eq!(target(text, 8, Motion::BackWORD, None), Some(0));
+        assert_eq!(target(text, 0, Motion::ForwardWord, Some(3)), Some(8));
+    }
+
+    #[test]
+    fn word_motions_stop_at_empty_lines_and_the_ends_of_the_text() {
+        let text = "ab\n\ncd";
+        assert_eq!(target(text, 0, Motion::ForwardWord, None), Some(3));
+        assert_eq!(target(text, 3, Motion::ForwardWord, None), Some(4));
+        assert_eq!(target(text, 4, Motion::BackWord, None), Some(3));
+        // without a word after the cursor, the motions stop at the last character
+        assert_eq!(target(text, 4, Motion::ForwardWord, None), Some(5));
+        assert_eq!(target(text, 5, Motion::ForwardWordEnd, None), Some(5));
+        assert_eq!(target(text, 0, Motion::BackWord, None), Some(0));
+        assert_eq!(target("", 0, Motion::ForwardWord, None), Some(0));
+        assert_eq!(target("", 0, Motion::BackWORD, None), Some(0));
+    }
+
+    #[test]
+    fn paragraph_motions_go_to_the_blank_lines_between_paragraphs() {
+        let text = "a\nb\n\n  \nc\n\nd";
+        assert_eq!(target(text, 0, Motion::NextParagraph, None), Some(4));
+        assert_eq!(target(text, 4, Motion::NextParagraph, None), Some(10));
+        assert_eq!(target(text, 11, Motion::NextParagraph, None), Some(11));
+        assert_eq!(target(text, 11, Motion::PrevParagraph, None), Some(10));
+        assert_eq!(target(text, 8, Motion::PrevParagraph, None), Some(5));
+        assert_eq!(target(text, 2, Motion::PrevParagraph, None), Some(0));
+    }
+
+    #[test]
+    fn horizontal_motions_work_in_an_empty_buffer() {
+        let motions = [
+            Motion::Left,
+            Motion::Right,
+            Motion::EndOfLine,
+            Motion::FirstNonBlank,
+        ];
+        for motion in motions {
+            assert_eq!(target("", 0, motion, None), Some(0));
         }
+        assert_eq!(target("\n", 0, Motion::Right, Some(5)), Some(0));
+        // the cursor stays off the newline at the end of a line
+        assert_eq!(target("ab\ncd", 0, Motion::Right, Some(5)), Some(1));
+        assert_eq!(target("ab\ncd", 4, Motion::Left, Some(5)), Some(3));
+        assert_eq!(target("ab\ncd", 3, Motion::EndOfLine, None), Some(4));
+    }
+
+    #[test]
+    fn percent_jumps_to_the_matching_bracket() {
+        let text = "f(a[b](c)) {\n}\n";
+        assert_eq!(target(text, 1, Motion::MatchBracket, None), Some(9));
+        assert_eq!(target(text, 9, Motion::MatchBracket, None), Some(1));
+        assert_eq!(target(text, 3, Motion::MatchBracket, None), Some(5));
+        // from before a bracket, the first one on the line is used
+        assert_eq!(target(text, 0, Motion::MatchBracket, None), Some(9));
+        assert_eq!(target(text, 11, Motion::MatchBracket, None), Some(13));
+        assert_eq!(target(text, 13, Motion::MatchBracket, None), Some(11));
+        // a count does not repeat the jump
+        assert_eq!(target(text, 1, Motion::MatchBracket, Some(2)), Some(9));
+    }
+
+    #[test]
+    fn percent_fails_without_a_matching_bracket() {
+        assert_eq!(target("a(b\n", 1, Motion::MatchBracket, None), None);
+        assert_eq!(target("a)b\n", 0, Motion::MatchBracket, None), None);
+        assert_eq!(target("ab\n(", 0, Motion::MatchBracket, None), None);
+        assert_eq!(target("", 0, Motion::MatchBracket, None), None);
+    }
+
+    #[test]
+    fn jumps_to_marks_and_their_lines() {
+        let mut buffer = Buffer::new(Rope::from_str("one\n  two\n"), None);
+        buffer.marks.insert('a', 8);
+        buffer.marks.insert('b', 100);
+        assert_eq!(Motion::Mark('a').get_target(&buffer, None), Some(8));
+        assert_eq!(Motion::MarkLine('a').get_target(&buffer, None), Some(6));
+        // marks past the end of the text stay on its last character
+        assert_eq!(Motion::Mark('b').get_target(&buffer, None), Some(9));
+        assert_eq!(Motion::Mark('c').get_target(&buffer, None), None);
+        assert_eq!(target("", 0, Motion::MarkLine('a'), None), None);
+    }
+
+    #[test]
+    fn end_of_line_takes_the_rest_of_the_line_for_d_and_c() {
+        assert_eq!(delete("abc\ndef\n", 1, Motion::EndOfLine), "a\ndef\n");
+        assert_eq!(delete("abc\ndef\n", 2, Motion::EndOfLine), "ab\ndef\n");
+        assert_eq!(delete("abc\ndef", 4, Motion::EndOfLine), "abc\n");
+        assert_eq!(delete("", 0, Motion::EndOfLine), "");
+
+        // the cursor left on the newline moves back onto the line, as after `D`
+        let mut buffer = Buffer::new(Rope::from_str("abc\n"), None);
+        buffer.cursor = 1;
+        buffer.remove(1..3);
+        buffer.clamp_cursor();
+        assert_eq!(buffer.cursor, 0);
+    }
+
+    #[test]
+    fn screen_motions_go_to_the_lines_in_view() {
+        let buffer = Buffer::new(Rope::from_str(&"  x\n".repeat(10)), None);
+        let line = |motion: Motion, visible_lines: RangeInclusive<usize>, count: Option<usize>| {
+            let cursor = motion.get_screen_target(&buffer, visible_lines, count)?;
+            assert_eq!(cursor % 4, 2, "the first non-blank character");
+            Some(buffer.text.char_to_line(cursor))
+        };
+        assert_eq!(line(Motion::ScreenTop, 2..=6, None), Some(2));
+        assert_eq!(line(Motion::ScreenTop, 2..=6, Some(3)), Some(4));
+        assert_eq!(line(Motion::ScreenTop, 2..=6, Some(10)), Some(6));
+        assert_eq!(line(Motion::ScreenMiddle, 2..=6, None), Some(4));
+        assert_eq!(line(Motion::ScreenBottom, 2..=6, None), Some(6));
+        assert_eq!(line(Motion::ScreenBottom, 2..=6, Some(2)), Some(5));
+        assert_eq!(line(Motion::ScreenBottom, 2..=6, Some(10)), Some(2));
+        // the view may reach past the end of the text
+        assert_eq!(line(Motion::ScreenBottom, 5..=20, None), Some(9));
+        assert_eq!(line(Motion::Down, 2..=6, None), None);
     }
 }
@@ -1,11 +1,31 @@
 use crate::action::Action;
 use crate::buffer::{Buffer, Cursor};
+use crate::commands::{create_commands, Commands};
+use crate::fuzzy::rank_matches;
+use crate::highlight::Highlighter;
+use crate::io::{LoadedFile, Swap};
 use crate::motion::Motion;
+use crate::text_object::TextObject;
 use notan::draw::Font;
 use notan::prelude::{AppState, KeyCode};
-use std::collections::HashMap;
+use regex::{Regex, RegexBuilder};
+use ropey::Rope;
+use std::collections::{HashMap, HashSet};
+use std::sync::mpsc::Receiver;
+use std::time::Instant;
+use syntect::highlighting::Theme;
 
-#[derive(PartialEq, Eq, Hash)]
+pub const TAB_SIZE: usize = 4;
+/// number of files listed by the file finder, and how many of them are shown at most
+pub const MAX_FINDER_FILES: usize = 20_000;
+pub const MAX_FINDER_RESULTS: usize = 200;
+/// number of entries kept in the command and search history
+pub const MAX_HISTORY: usize = 100;
+pub const MAX_JUMPS: usize = 100;
+/// files of at least this many bytes are loaded in the background
+pub const LARGE_FILE_SIZE: u64 = 1 << 20;
+
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
 pub struct Shortcut {
     pub key: KeyCode,
     pub ctrl: bool,
@@ -33,10 +53,17 @@ impl Shortcut {
         self
     }
 
-    pub fn alt(&mut self) -> &mut Self {
+    pub fn alt(mut self) -> Self {
         self.alt = true;
         self
     }
+
+    /// Whether the held modifiers are exactly those of the shortcut. a modifier that is held but
+    ///     not part of the shortcut stops it from matching, so that `w` and `W` or `p` and Ctrl-p
+    ///     can be bound separately and only one of them fires
+    pub fn matches_modifiers(&self, shift: bool, ctrl: bool, alt: bool) -> bool {
+        self.shift == shift && self.ctrl == ctrl && self.alt == alt
+    }
 }
 
 #[derive(PartialEq, Eq, Hash, Clone)]
@@ -45,43 +72,1853 @@ pub enum ModeChange {
     InsertAfter,
     InsertEnd,
     InsertStart,
+    OpenBelow,
+    OpenAbove,
+    EnterVisual,
+    EnterVisualBlock,
+    EnterReplace,
     Escape,
     EnterCommand,
+    EnterSearch,
+    EnterQuickMenu,
 }
 
 #[derive(Debug, PartialEq, Clone, Hash, Eq)]
 pub enum Mode {
     Normal,
     Insert,
+    /// insert mode that types over the existing characters
+    Replace,
+    Visual,
+    /// visual mode that selects the same columns on every line of a block of lines
+    VisualBlock,
     Command,
+    Search,
+    QuickMenu,
+}
+
+impl std::fmt::Display for Mode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Mode::Normal => "NORMAL",
+            Mode::Insert => "INSERT",
+            Mode::Replace => "REPLACE",
+            Mode::Visual => "VISUAL",
+            Mode::VisualBlock => "VISUAL BLOCK",
+            Mode::Command => "COMMAND",
+            Mode::Search => "SEARCH",
+            Mode::QuickMenu => "QUICK MENU",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Text that was yanked or deleted and can be pasted again
+#[derive(Default, Clone)]
+pub struct Register {
+    pub text: String,
+    /// whether the text consists of whole lines, which are pasted as separate lines
+    pub linewise: bool,
+}
+
+/// An edit that can be repeated with `.`
+#[derive(Clone)]
+pub enum Change {
+    /// an operator applied over the range of a motion
+    Motion {
+        action: Action,
+        motion: Motion,
+        count: Option<usize>,
+    },
+    /// an operator applied to a text object, like `diw`
+    TextObject {
+        action: Action,
+        object: TextObject,
+        inner: bool,
+    },
+    /// an operator applied to whole lines, like `dd`
+    Line {
+        action: Action,
+        count: usize,
+    },
+    DeleteCharacters {
+        count: usize,
+    },
+    SubstituteCharacters {
+        count: usize,
+    },
+    ReplaceCharacters {
+        character: char,
+        count: usize,
+    },
+    ToggleCase {
+        count: usize,
+    },
+    Paste {
+        before: bool,
+        count: usize,
+    },
+    Join {
+        count: usize,
+        spaces: bool,
+    },
+    Insert(ModeChange),
+    /// text typed over the existing text in replace mode
+    Overwrite,
+}
+
+/// Settings that can be changed while the editor is running
+pub struct Options {
+    pub number: bool,
+    pub relative_number: bool,
+    /// start new lines with the indentation of the previous one
+    pub auto_indent: bool,
+    /// indent one level further after a line ending in ':', as blocks start in Python
+    pub smart_indent: bool,
+    /// number of spaces that `>` and `<` indent by
+    pub shift_width: usize,
+    /// insert spaces instead of a tab character when Tab is pressed
+    pub expand_tab: bool,
+    /// number of columns a tab character takes up
+    pub tab_stop: usize,
+    /// number of lines or columns scrolled for each step of the mouse wheel
+    pub scroll_speed: usize,
+    /// name of the syntax highlighting theme
+    pub theme: String,
+    /// keep the command history in a file, so that it is available after a restart
+    pub persist_history: bool,
+    /// create the missing directories of the path a file is written to
+    pub create_directories: bool,
+    /// insert the closing bracket or quote when an opening one is typed
+    pub auto_pairs: bool,
+    /// show lines that are wider than the window on several display lines
+    pub wrap: bool,
+    /// make tabs and trailing spaces visible
+    pub list: bool,
+    /// highlight the background of the line the cursor is on
+    pub cursor_line: bool,
+    /// match searches without regard to case
+    pub ignore_case: bool,
+    /// with `ignore_case`, match case anyway if the query has an uppercase letter
+    pub smart_case: bool,
+    /// search for regular expressions, instead of the literal text
+    pub magic: bool,
+    /// save the current buffer to its file once it has been left alone for `auto_save_delay`
+    ///     seconds after a change, and when the mouse leaves the window
+    pub auto_save: bool,
+    pub auto_save_delay: f32,
+    /// keep the unsaved changes of each buffer in a swap file next to its file, written every
+    ///     `swap_interval` seconds, to recover them if the editor stops without saving
+    pub swap_file: bool,
+    pub swap_interval: f32,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            number: true,
+            relative_number: false,
+            auto_indent: true,
+            smart_indent: false,
+            shift_width: TAB_SIZE,
+            expand_tab: true,
+            tab_stop: TAB_SIZE,
+            scroll_speed: 3,
+            theme: String::from("base16-ocean.dark"),
+            persist_history: true,
+            create_directories: true,
+            auto_pairs: false,
+            wrap: false,
+            list: false,
+            cursor_line: false,
+            ignore_case: false,
+            smart_case: false,
+            magic: true,
+            auto_save: false,
+            auto_save_delay: 2.0,
+            swap_file: true,
+            swap_interval: 4.0,
+        }
+    }
+}
+
+impl Options {
+    /// Compile the search query into the pattern it matches with the search options. `\<` and
+    ///     `\>` around the query only match it at the start and the end of a word, as in Vim.
+    ///     an invalid regular expression, as while one is being typed, is matched literally
+    pub fn compile_search(&self, query: &str) -> Option<Regex> {
+        let (query, word_start) = match query.strip_prefix("\\<") {
+            Some(query) => (query, true),
+            None => (query, false),
+        };
+        let (query, word_end) = match query.strip_suffix("\\>") {
+            Some(query) => (query, true),
+            None => (query, false),
+        };
+        if query.is_empty() {
+            return None;
+        }
+
+        let case_insensitive =
+            self.ignore_case && !(self.smart_case && query.chars().any(char::is_uppercase));
+        let compile = |pattern: &str| {
+            let start = if word_start { "\\b" } else { "" };
+            let end = if word_end { "\\b" } else { "" };
+            RegexBuilder::new(&format!("{}(?:{}){}", start, pattern, end))
+                .case_insensitive(case_insensitive)
+                .build()
+                .ok()
+        };
+        let literal = regex::escape(query);
+        if self.magic {
+            compile(query).or_else(|| compile(&literal))
+        } else {
+            compile(&literal)
+        }
+    }
+
+    /// Return the text inserted for one level of indentation
+    pub fn get_indentation_unit(&self) -> String {
+        if self.expand_tab {
+            " ".repeat(self.shift_width)
+        } else {
+            String::from("\t")
+        }
+    }
+}
+
+impl Options {
+    /// Return the number shown next to the line. relative numbers show the distance to the
+    ///     cursor's line, except on that line itself
+    pub fn get_line_number(&self, line: usize, cursor_line: usize) -> usize {
+        if !self.relative_number {
+            line + 1
+        } else if line != cursor_line {
+            line.abs_diff(cursor_line)
+        } else if self.number {
+            line + 1
+        } else {
+            0
+        }
+    }
+}
+
+/// Input that needs the next typed character to be completed
+pub enum PendingInput {
+    Motion(fn(char) -> Motion),
+    /// `i` or `a` after an operator or in visual mode, waiting for the kind of text object
+    TextObject {
+        inner: bool,
+    },
+    /// `r`, waiting for the character to replace the ones under the cursor with
+    ReplaceCharacter,
+    /// `m`, waiting for the name of the mark to set at the cursor
+    SetMark,
+    /// `q`, waiting for the register to record a macro into
+    RecordMacro,
+    /// `@`, waiting for the register of the macro to replay
+    ReplayMacro,
+    /// Ctrl-w, waiting for the direction of the pane to move the focus to
+    Window,
+    /// `:s` with the `c` flag, waiting for whether to replace the current match
+    ConfirmSubstitution(Substitution),
+    /// a file with a swap file was opened, waiting for whether to recover its text
+    RecoverSwap(Swap),
+}
+
+/// A `:s` that asks before each replacement, going through the matches one at a time
+pub struct Substitution {
+    pub regex: Regex,
+    /// the replacement in the syntax of the regex crate
+    pub replacement: String,
+    pub global: bool,
+    /// where the search for the next match starts, None once the range has been gone through
+    pub position: Option<Cursor>,
+    /// the last line of the range, which moves down as replacements add lines
+    pub last_line: usize,
+    /// the match that is asked about, with the text that would replace it
+    pub current: Option<(std::ops::Range<Cursor>, String)>,
+    /// number of matches replaced so far
+    pub count: usize,
+}
+
+impl Substitution {
+    pub fn new(
+        regex: Regex,
+        replacement: String,
+        global: bool,
+        start: Cursor,
+        last_line: usize,
+    ) -> Self {
+        Substitution {
+            regex,
+            replacement,
+            global,
+            position: Some(start),
+            last_line,
+            current: None,
+            count: 0,
+        }
+    }
+
+    /// Find the next match in the buffer and make it the current one, returning whether there
+    ///     was one
+    pub fn find_next(&mut self, buffer: &Buffer) -> bool {
+        self.current = self.position.and_then(|position| {
+            buffer.find_substitution(&self.regex, &self.replacement, position, self.last_line)
+        });
+        self.current.is_some()
+    }
+
+    /// Replace the current match
+    pub fn replace(&mut self, buffer: &mut Buffer) {
+        let Some((range, text)) = self.current.take() else {
+            return;
+        };
+        buffer.remove(range.clone());
+        buffer.insert(range.start, &text);
+        self.last_line += text.matches('\n').count();
+        self.count += 1;
+        self.advance(buffer, range.start + text.chars().count(), range.is_empty());
+    }
+
+    /// Leave the current match as it is
+    pub fn skip(&mut self, buffer: &Buffer) {
+        if let Some((range, _)) = self.current.take() {
+            self.advance(buffer, range.end, range.is_empty());
+        }
+    }
+
+    /// Continue after a match that ends at the cursor, or on the next line if only the first
+    ///     match of each line is replaced. an empty match is stepped over so that it is not
+    ///     found again
+    fn advance(&mut self, buffer: &Buffer, end: Cursor, empty: bool) {
+        self.position = if self.global {
+            Some(end + empty as usize)
+        } else {
+            let line = buffer.text.char_to_line(end);
+            (line < self.last_line).then(|| buffer.text.line_to_char(line + 1))
+        };
+    }
+}
+
+/// When held keys act again, timed separately for each key so that holding one key does not
+///     change how fast another one repeats
+pub struct KeyRepeat {
+    /// seconds a key has to be held before it starts repeating
+    pub initial_delay: f32,
+    /// seconds between the repeats of a held key
+    pub interval: f32,
+    /// time each key last acted at
+    last_times: HashMap<KeyCode, f32>,
+}
+
+impl Default for KeyRepeat {
+    fn default() -> Self {
+        KeyRepeat {
+            initial_delay: 0.005,
+            interval: 0.05,
+            last_times: HashMap::new(),
+        }
+    }
+}
+
+impl KeyRepeat {
+    /// Whether the key acts at the time `now`, given whether it was just pressed and for how
+    ///     long it has been held. it acts when pressed, and while held once the initial delay
+    ///     has passed and an interval has passed since it last acted
+    pub fn is_triggered(&mut self, key: KeyCode, pressed: bool, held_time: f32, now: f32) -> bool {
+        let repeats = held_time > self.initial_delay
+            && self
+                .last_times
+                .get(&key)
+                .map_or(true, |last_time| now - last_time > self.interval);
+        if pressed || repeats {
+            self.last_times.insert(key, now);
+        }
+        pressed || repeats
+    }
+}
+
+/// The input received during one frame, as recorded in a macro
+#[derive(Clone)]
+pub struct MacroFrame {
+    pub keys: Vec<Shortcut>,
+    pub characters: Vec<char>,
+}
+
+/// The keys of one frame, read from the keyboard or typed by a macro or `:normal`, so that the
+///     input is handled the same way wherever it comes from
+#[derive(Default)]
+pub struct KeyInput {
+    /// keys pressed in this frame, leaving out the modifiers
+    pub pressed: HashSet<KeyCode>,
+    /// seconds each key has been held down for
+    pub down: HashMap<KeyCode, f32>,
+    pub ctrl: bool,
+    pub alt: bool,
+    pub shift: bool,
+    /// time of the frame, in seconds since the start
+    pub time: f32,
+}
+
+impl KeyInput {
+    /// Return the input of a recorded frame, its keys all pressed just now at the time
+    pub fn from_frame(frame: &MacroFrame, time: f32) -> Self {
+        let mut input = KeyInput {
+            time,
+            ..KeyInput::default()
+        };
+        for shortcut in frame.keys.iter() {
+            input.pressed.insert(shortcut.key);
+            input.down.insert(shortcut.key, 0.0);
+            input.ctrl |= shortcut.ctrl;
+            input.alt |= shortcut.alt;
+            input.shift |= shortcut.shift;
+        }
+        input
+    }
+
+    pub fn was_pressed(&self, key: KeyCode) -> bool {
+        self.pressed.contains(&key)
+    }
+
+    /// Return how long the key has been held for, 0 if it is not down
+    pub fn down_delta(&self, key: KeyCode) -> f32 {
+        self.down.get(&key).copied().unwrap_or(0.0)
+    }
+
+    /// Whether the key of the shortcut was pressed with exactly its modifiers held
+    pub fn matches(&self, shortcut: &Shortcut) -> bool {
+        self.was_pressed(shortcut.key)
+            && shortcut.matches_modifiers(self.shift, self.ctrl, self.alt)
+    }
+
+    /// Return the keys pressed in this frame with the modifiers that were held
+    pub fn get_shortcuts(&self) -> Vec<Shortcut> {
+        self.pressed
+            .iter()
+            .map(|key| Shortcut {
+                key: *key,
+                ctrl: self.ctrl,
+                alt: self.alt,
+                shift: self.shift,
+            })
+            .collect()
+    }
+}
+
+/// Recorded macros and the state of recording and replaying them
+#[derive(Default)]
+pub struct Macros {
+    pub registers: HashMap<char, Vec<MacroFrame>>,
+    /// register being recorded into, along with the frames recorded so far
+    pub recording: Option<(char, Vec<MacroFrame>)>,
+    /// characters received during the current frame
+    pub characters: Vec<char>,
+    pub last_register: Option<char>,
+    /// how deeply macros are being replayed inside each other
+    pub replay_depth: usize,
+    /// frames replayed since the outermost macro started, to stop macros that call themselves
+    pub replayed_frames: usize,
 }
 
 pub type KeyBindings<T> = HashMap<Shortcut, T>;
 pub type ActionBindings = KeyBindings<Action>;
 pub type MotionBindings = KeyBindings<Motion>;
+pub type CharacterMotionBindings = KeyBindings<fn(char) -> Motion>;
 pub type ModeChangeBindings = KeyBindings<ModeChange>;
 
 pub struct Keymap {
     pub action_bindings: ActionBindings,
     pub motion_bindings: MotionBindings,
+    pub character_motion_bindings: CharacterMotionBindings,
     pub mode_change_bindings: HashMap<Mode, ModeChangeBindings>,
+    pub sequence_bindings: SequenceBindings,
+}
+
+/// A key in a sequence binding, where the leader stands for whichever key is set as the leader
+#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+pub enum SequenceKey {
+    Leader,
+    Key(Shortcut),
+}
+
+/// What a sequence binding does, either one of the built-in `g` commands or a command line
+#[derive(Clone, PartialEq)]
+pub enum SequenceCommand {
+    /// `gg`
+    FirstLine,
+    /// `gJ`, joining without spaces
+    JoinLines,
+    /// `gj` and `gk`, by the number of display lines
+    DisplayLines(i32),
+    /// `gt`
+    NextTab,
+    /// `gT`
+    PreviousTab,
+    /// `<C-n>`, a cursor at the next occurrence of the word under the cursor
+    AddCursor,
+    /// `gu`, `gU`, `g~` and `gc`, operators applied to a motion or the selection
+    Action(Action),
+    /// a command line bound in the keymap file
+    Command(String),
+}
+
+/// Commands bound to sequences of keys, like `gg` or `<leader>w`, kept as a trie with one node
+///     for every prefix of a bound sequence
+#[derive(Default)]
+pub struct SequenceBindings {
+    command: Option<SequenceCommand>,
+    children: HashMap<SequenceKey, SequenceBindings>,
+}
+
+/// How the keys typed so far relate to the bound sequences
+pub enum SequenceMatch {
+    /// no sequence starts with the keys
+    None,
+    /// the keys start a longer sequence
+    Prefix,
+    /// the keys are a whole sequence, and no longer one starts with them
+    Complete(SequenceCommand),
+    /// the keys are a whole sequence, but also start a longer one
+    Ambiguous(SequenceCommand),
+}
+
+impl SequenceBindings {
+    /// Bind the command to the sequence, replacing the command it was bound to before
+    pub fn insert(&mut self, keys: &[SequenceKey], command: SequenceCommand) {
+        let node = keys
+            .iter()
+            .fold(self, |node, key| node.children.entry(*key).or_default());
+        node.command = Some(command);
+    }
+
+    /// Look up the typed keys, where the leader key may be part of a sequence either as the
+    ///     leader or as itself. a sequence with the leader is preferred when both are bound.
+    ///     with built_in_only, sequences bound to command lines are left out
+    pub fn find(&self, keys: &[Shortcut], leader: Shortcut, built_in_only: bool) -> SequenceMatch {
+        if keys.is_empty() {
+            return SequenceMatch::None;
+        }
+        let mut nodes = vec![self];
+        for key in keys {
+            nodes = nodes
+                .iter()
+                .flat_map(|node| {
+                    let as_leader = (*key == leader)
+                        .then(|| node.children.get(&SequenceKey::Leader))
+                        .flatten();
+                    as_leader
+                        .into_iter()
+                        .chain(node.children.get(&SequenceKey::Key(*key)))
+                })
+                .collect();
+        }
+
+        let command = nodes
+            .iter()
+            .find_map(|node| node.get_command(built_in_only).cloned());
+        let continues = nodes.iter().any(|node| {
+            node.children
+                .values()
+                .any(|child| child.has_command(built_in_only))
+        });
+        match (command, continues) {
+            (Some(command), true) => SequenceMatch::Ambiguous(command),
+            (Some(command), false) => SequenceMatch::Complete(command),
+            (None, true) => SequenceMatch::Prefix,
+            (None, false) => SequenceMatch::None,
+        }
+    }
+
+    fn get_command(&self, built_in_only: bool) -> Option<&SequenceCommand> {
+        self.command
+            .as_ref()
+            .filter(|command| !built_in_only || !matches!(command, SequenceCommand::Command(_)))
+    }
+
+    /// Whether a sequence ends at this node or after it
+    fn has_command(&self, built_in_only: bool) -> bool {
+        self.get_command(built_in_only).is_some()
+            || self
+                .children
+                .values()
+                .any(|child| child.has_command(built_in_only))
+    }
+}
+
+/// Changes to the current buffer that are waiting to be saved automatically
+#[derive(Default)]
+pub struct AutoSave {
+    /// the buffer and its version as the changes were last seen
+    pub buffer_index: usize,
+    pub version: usize,
+    /// time the changes were last seen at
+    pub time: f32,
+    /// whether the changes still have to be saved
+    pub waiting: bool,
+    /// whether to save the changes without waiting for the delay
+    pub flush: bool,
+}
+
+impl AutoSave {
+    /// Note the changes to the buffer, and return whether they are due to be saved at the time,
+    ///     after the delay or right away when flushed
+    pub fn is_due(&mut self, buffer_index: usize, buffer: &Buffer, now: f32, delay: f32) -> bool {
+        let flush = std::mem::take(&mut self.flush);
+        if (self.buffer_index, self.version) != (buffer_index, buffer.version) {
+            self.buffer_index = buffer_index;
+            self.version = buffer.version;
+            self.time = now;
+            self.waiting = buffer.modified && buffer.filepath.is_some() && !buffer.read_only;
+        }
+        if !self.waiting || !flush && now - self.time < delay {
+            return false;
+        }
+
+        // a save that fails is reported and not tried again until the next change
+        self.waiting = false;
+        true
+    }
+}
+
+/// Keys given to `:normal`, waiting to be typed after the command, since commands can not drive
+///     the input themselves
+pub struct NormalKeys {
+    pub frames: Vec<MacroFrame>,
+    /// the lines to type the keys at the start of, or None to type them once at the cursor
+    pub lines: Option<std::ops::RangeInclusive<usize>>,
+}
+
+/// Keys typed in normal or visual mode that may become a sequence binding, waiting for the
+///     next key
+pub struct SequenceInput {
+    pub leader: Shortcut,
+    /// seconds to wait for the next key of a sequence, after which an ambiguous sequence runs
+    ///     its command and other keys are handled as if there were no sequence
+    pub timeout: f32,
+    /// the input of the keys typed so far, handled as usual if they do not become a sequence
+    pub frames: Vec<MacroFrame>,
+    /// time the last of the keys was typed at
+    pub time: f32,
+    /// whether the frames are being handled as usual, so that they are not collected again
+    pub replaying: bool,
+}
+
+impl Default for SequenceInput {
+    fn default() -> Self {
+        SequenceInput {
+            leader: Shortcut::new(KeyCode::Space),
+            timeout: 1.0,
+            frames: Vec::new(),
+            time: 0.0,
+            replaying: false,
+        }
+    }
+}
+
+impl SequenceInput {
+    /// Return the keys typed so far, one for each frame
+    pub fn get_keys(&self) -> Vec<Shortcut> {
+        self.frames
+            .iter()
+            .filter_map(|frame| frame.keys.first().copied())
+            .collect()
+    }
+}
+
+/// Positions that the cursor jumped away from, as the index of the buffer and the cursor in it.
+///     Ctrl-o goes back through them and Ctrl-i forward again
+#[derive(Default)]
+pub struct JumpList {
+    entries: Vec<(usize, Cursor)>,
+    /// the entry the cursor is at while going through the list, the number of entries otherwise
+    index: usize,
+}
+
+impl JumpList {
+    /// Add the position of a jump, dropping the entries that were gone back from
+    pub fn push(&mut self, position: (usize, Cursor)) {
+        self.entries.truncate(self.index);
+        if self.entries.last() != Some(&position) {
+            self.entries.push(position);
+        }
+        if self.entries.len() > MAX_JUMPS {
+            self.entries.remove(0);
+        }
+        self.index = self.entries.len();
+    }
+
+    /// Return the position before the current one, if there is any
+    pub fn back(&mut self, current: (usize, Cursor)) -> Option<(usize, Cursor)> {
+        if self.index == self.entries.len() {
+            // the current position is added so that Ctrl-i can return to it
+            if self.entries.last() != Some(&current) {
+                self.entries.push(current);
+            }
+            self.index = self.entries.len() - 1;
+        }
+        if self.index == 0 {
+            return None;
+        }
+        self.index -= 1;
+        Some(self.entries[self.index])
+    }
+
+    /// Return the position after the current one, after going back
+    pub fn forward(&mut self) -> Option<(usize, Cursor)> {
+        if self.index + 1 >= self.entries.len() {
+            return None;
+        }
+        self.index += 1;
+        Some(self.entries[self.index])
+    }
+
+    /// Forget the positions in a buffer that was closed
+    pub fn remove_buffer(&mut self, buffer_index: usize) {
+        self.entries.retain(|(index, _)| *index != buffer_index);
+        for (index, _) in self.entries.iter_mut() {
+            if *index > buffer_index {
+                *index -= 1;
+            }
+        }
+        self.index = self.entries.len();
+    }
+}
+
+/// Lines entered before in the command line, which can be recalled with the arrow keys
+#[derive(Default)]
+pub struct History {
+    pub entries: Vec<String>,
+    /// entry being shown, or None while a new line is edited
+    index: Option<usize>,
+    /// the new line, kept while older entries are shown
+    draft: String,
+}
+
+impl History {
+    /// Add the line as the newest entry, unless it repeats the newest one
+    pub fn add(&mut self, line: &str) {
+        self.reset();
+        if line.is_empty() || self.entries.last().map(String::as_str) == Some(line) {
+            return;
+        }
+        self.entries.push(line.to_string());
+        if self.entries.len() > MAX_HISTORY {
+            self.entries.remove(0);
+        }
+    }
+
+    /// Go back to editing a new line
+    pub fn reset(&mut self) {
+        self.index = None;
+        self.draft.clear();
+    }
+
+    /// Return the entry older than the one shown. the line being edited is kept when the
+    ///     newest entry is recalled, so that it can be returned to
+    pub fn previous(&mut self, line: &str) -> Option<String> {
+        let index = match self.index {
+            None if self.entries.is_empty() => return None,
+            None => {
+                self.draft = line.to_string();
+                self.entries.len() - 1
+            }
+            Some(0) => return None,
+            Some(index) => index - 1,
+        };
+        self.index = Some(index);
+        Some(self.entries[index].clone())
+    }
+
+    /// Return the entry newer than the one shown, or the line that was being edited
+    pub fn next(&mut self) -> Option<String> {
+        let index = self.index? + 1;
+        if index < self.entries.len() {
+            self.index = Some(index);
+            Some(self.entries[index].clone())
+        } else {
+            self.index = None;
+            Some(std::mem::take(&mut self.draft))
+        }
+    }
+}
+
+/// Candidates for completing the word being typed in the command line, cycled through with Tab
+pub struct Completion {
+    pub candidates: Vec<String>,
+    /// candidate that was put into the command line, None until Tab is pressed again
+    pub index: Option<usize>,
+    /// byte offset in the command line where the completed word starts
+    pub start: usize,
+}
+
+/// What the entries of the quick menu are, which decides what choosing one does
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuickMenuKind {
+    /// the open buffers, as listed by `:ls`
+    Buffers,
+    /// the files under the working directory that match the typed line
+    Files,
+    /// the syntax highlighting themes, as listed by `:colorscheme`
+    Themes,
+}
+
+/// A list of entries shown at the top of the screen, one of which can be chosen
+#[derive(Default)]
+pub struct QuickMenu {
+    pub kind: Option<QuickMenuKind>,
+    /// text typed into the quick menu
+    pub line: String,
+    /// everything that can be listed, of which the entries are shown
+    pub candidates: Vec<String>,
+    pub entries: Vec<String>,
+    pub selected: usize,
+}
+
+impl QuickMenu {
+    pub fn new(kind: QuickMenuKind, candidates: Vec<String>, selected: usize) -> Self {
+        let mut quick_menu = QuickMenu {
+            kind: Some(kind),
+            line: String::new(),
+            entries: candidates.clone(),
+            candidates,
+            selected,
+        };
+        quick_menu.update_entries();
+        quick_menu
+    }
+
+    /// Update the entries after the typed line changed. files and themes are ranked by how well
+    ///     they match it, while a typed number selects that buffer
+    pub fn update_entries(&mut self) {
+        match self.kind {
+            // every theme is listed until something is typed, with the current one selected
+            Some(QuickMenuKind::Themes) if self.line.is_empty() => {
+                self.entries = self.candidates.clone();
+            }
+            Some(QuickMenuKind::Files | QuickMenuKind::Themes) => {
+                let limit = MAX_FINDER_RESULTS;
+                self.entries = rank_matches(&self.line, &self.candidates, limit);
+                self.selected = 0;
+            }
+            Some(QuickMenuKind::Buffers) => self.select_typed_number(),
+            None => {}
+        }
+    }
+
+    /// Move the selection by the number of entries, wrapping around at either end
+    pub fn move_selection(&mut self, offset: i64) {
+        if self.entries.is_empty() {
+            return;
+        }
+        let length = self.entries.len() as i64;
+        self.selected = (self.selected as i64 + offset).rem_euclid(length) as usize;
+    }
+
+    /// Select the entry whose number was typed, counting from 1
+    fn select_typed_number(&mut self) {
+        if let Ok(number) = self.line.trim().parse::<usize>() {
+            if (1..=self.entries.len()).contains(&number) {
+                self.selected = number - 1;
+            }
+        }
+    }
+}
+
+/// How the window is divided between two panes
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SplitDirection {
+    /// the panes are above each other, as after `:split`
+    Horizontal,
+    /// the panes are next to each other, as after `:vsplit`
+    Vertical,
+}
+
+/// What a pane that is not focused shows: its buffer, its cursor and where it is scrolled to.
+///     the focused pane keeps these on the `State` and the buffer instead
+#[derive(Clone)]
+pub struct Pane {
+    pub buffer_index: usize,
+    pub cursor: Cursor,
+    pub scroll_top: usize,
+    pub scroll_column: usize,
+    pub scroll_x: f32,
+    pub last_cursor: Cursor,
+    pub visible_lines: usize,
+    pub text_origin: (f32, f32),
+    pub wrap_width: Option<usize>,
+    pub gutter_width: f32,
+    pub area: (f32, f32, f32, f32),
+}
+
+impl Pane {
+    /// Keep showing the same buffer after the one at the index was closed, or the buffer that
+    ///     took its place if it was the closed one
+    fn remove_buffer(&mut self, buffer_index: usize) {
+        if self.buffer_index > buffer_index {
+            self.buffer_index -= 1;
+        }
+    }
+}
+
+/// The window divided into two panes of equal size, one of which has the focus
+pub struct Split {
+    pub direction: SplitDirection,
+    /// the pane that does not have the focus
+    pub other: Pane,
+    /// whether the focused pane is the top or left one
+    pub focus_first: bool,
+}
+
+/// A tab page that is not shown, with the panes it had when it was left
+pub struct TabPage {
+    /// the view of the focused pane
+    pub pane: Pane,
+    pub split: Option<Split>,
+}
+
+/// The open buffers and which of them is being edited
+pub struct Editor {
+    pub buffers: Vec<Buffer>,
+    pub current_buffer_index: usize,
+    pub quick_menu: QuickMenu,
+    /// byte index of the caret in the line that is being typed, the command line, the search or
+    ///     the quick menu line
+    pub command_cursor: usize,
+    pub command_history: History,
+    pub completion: Option<Completion>,
+    pub search_history: History,
+    /// cursor from before the search that is being typed, which the cursor is moved away from to
+    ///     preview the first match and goes back to if the search is cancelled
+    pub search_start: Option<Cursor>,
+    pub last_change: Option<Change>,
+    /// text typed since the last change, which `.` inserts again if that change entered insert mode
+    pub inserted_text: String,
+    /// characters that typing in replace mode wrote over, None where it made the line longer
+    ///     instead, so that backspace can put them back
+    pub overwritten: Vec<Option<char>>,
+    pub macros: Macros,
+    /// files that are being loaded in the background
+    pub loading_files: Vec<LoadingFile>,
+    pub jumps: JumpList,
+    pub split: Option<Split>,
+    /// the tab pages that are not shown, in order, the shown one keeping its panes on the
+    ///     `State` and the editor instead
+    pub tabs: Vec<TabPage>,
+    /// position of the shown tab page among the others
+    pub current_tab: usize,
+}
+
+/// A file that is read on another thread and opened in a buffer once it is loaded
+pub struct LoadingFile {
+    pub filepath: String,
+    pub receiver: Receiver<std::io::Result<LoadedFile>>,
+}
+
+impl Editor {
+    pub fn new() -> Self {
+        Editor {
+            buffers: Vec::new(),
+            current_buffer_index: 0,
+            quick_menu: QuickMenu::default(),
+            command_history: History::default(),
+            completion: None,
+            command_cursor: 0,
+            search_history: History::default(),
+            search_start: None,
+            last_change: None,
+            inserted_text: String::new(),
+            overwritten: Vec::new(),
+            macros: Macros::default(),
+            loading_files: Vec::new(),
+            jumps: JumpList::default(),
+            split: None,
+            tabs: Vec::new(),
+            current_tab: 0,
+        }
+    }
+
+    pub fn buffer(&self) -> &Buffer {
+        &self.buffers[self.current_buffer_index]
+    }
+
+    pub fn buffer_mut(&mut self) -> &mut Buffer {
+        &mut self.buffers[self.current_buffer_index]
+    }
+
+    /// Open a new buffer with the text and make it the current one
+    pub fn add_buffer(&mut self, text: Rope, filepath: Option<String>) {
+        self.buffers.push(Buffer::new(text, filepath));
+        self.current_buffer_index = self.buffers.len() - 1;
+    }
+
+    /// Close the current buffer and switch to the one that took its place, or to the one before
+    ///     it if it was the last. closing the only buffer leaves an empty `[No Name]` buffer
+    pub fn remove_buffer(&mut self) {
+        if self.current_buffer_index < self.buffers.len() {
+            self.buffers.remove(self.current_buffer_index);
+            self.jumps.remove_buffer(self.current_buffer_index);
+            let index = self.current_buffer_index;
+            let splits = self.tabs.iter_mut().filter_map(|tab| tab.split.as_mut());
+            for split in splits.chain(self.split.as_mut()) {
+                split.other.remove_buffer(index);
+            }
+            for tab in self.tabs.iter_mut() {
+                tab.pane.remove_buffer(index);
+            }
+        }
+        if self.buffers.is_empty() {
+            self.buffers.push(Buffer::new(Rope::new(), None));
+        }
+        self.switch_buffer(self.current_buffer_index);
+    }
+
+    /// Remember a position in the current buffer that the cursor jumps away from
+    pub fn push_jump(&mut self, cursor: Cursor) {
+        self.jumps.push((self.current_buffer_index, cursor));
+    }
+
+    /// Make the buffer at the index the current one, with its cursor inside the text in case the
+    ///     text changed while it was in the background
+    pub fn switch_buffer(&mut self, index: usize) {
+        if self.buffers.is_empty() {
+            return;
+        }
+        self.current_buffer_index = index.min(self.buffers.len() - 1);
+        self.buffer_mut().clamp_cursor();
+    }
+
+    pub fn next_buffer(&mut self) {
+        if self.buffers.is_empty() {
+            return;
+        }
+        self.switch_buffer((self.current_buffer_index + 1) % self.buffers.len());
+    }
+
+    pub fn previous_buffer(&mut self) {
+        if self.buffers.is_empty() {
+            return;
+        }
+        let count = self.buffers.len();
+        self.switch_buffer((self.current_buffer_index + count - 1) % count);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Severity {
+    /// feedback from the last command, which stays until the next one
+    Info,
+    /// why the last command failed, which goes away when it times out or another key is pressed
+    Error,
+}
+
+/// The line shown where the command line is while it is closed
+pub struct Message {
+    pub text: String,
+    pub severity: Severity,
+    /// when the message was shown, which errors time out from
+    pub time: Instant,
 }
 
 #[derive(AppState)]
 pub struct State {
-    pub font: Font,
+    /// None where nothing is drawn, as in tests
+    pub font: Option<Font>,
     pub line_height: f32,
+    /// space around the text of the command line and the quick menu
+    pub command_box_padding: f32,
+    pub highlighter: Highlighter,
+    /// the theme named by `options.theme`, kept so that it is not looked up every frame
+    pub theme: Theme,
 
-    pub buffer: Buffer,
+    pub editor: Editor,
+    pub register: Register,
     pub command_line: String,
+    pub search_query: String,
+    /// whether `n` goes on in the direction of the text, as after `/` and `*`, or against it, as
+    ///     after `#`
+    pub search_forward: bool,
+    pub message: Option<Message>,
 
     pub mode: Mode,
 
     pub action: Option<Action>,
+    pub pending: Option<PendingInput>,
+    pub count: Option<usize>,
+    /// whether insert mode was entered on every row of a block, to drop the extra cursors again
+    ///     when it is left
+    pub block_insert: bool,
+    pub received_character: Option<char>,
 
     pub keymap: Keymap,
+    pub commands: Commands,
+    pub options: Options,
+
+    /// first line shown at the top of the screen
+    pub scroll_top: usize,
+    /// number of lines kept visible above and below the cursor when scrolling
+    pub scroll_off: usize,
+    /// number of lines that fit on the screen, as of the last frame
+    pub visible_lines: usize,
+    /// first display column on screen when lines do not wrap
+    pub scroll_column: usize,
+    /// number of columns kept visible left and right of the cursor when scrolling sideways
+    pub side_scroll_off: usize,
+    /// columns marked with a ruler, counted from 1 as in `:set colorcolumn=80,100`
+    pub color_columns: Vec<usize>,
+    /// distance the view is panned to the right of where the cursor would put it
+    pub scroll_x: f32,
+    /// cursor as of the last frame. the view only follows the cursor when it moves, so that
+    ///     scrolling with the mouse wheel can leave it off screen
+    pub last_cursor: Cursor,
+    /// width of a character and the screen position of the first character, as of the last frame
+    pub char_width: f32,
+    pub text_origin: (f32, f32),
+    /// width of the sign column and the line numbers left of the text, as of the last frame
+    pub gutter_width: f32,
+    /// position and size of the text area of the focused pane, as of the last frame
+    pub pane_area: (f32, f32, f32, f32),
+    /// number of columns long lines wrap at as of the last frame, None if they do not wrap
+    pub wrap_width: Option<usize>,
+
+    /// whether the left mouse button was pressed on the text and is still held
+    pub dragging: bool,
+    pub last_click_time: f32,
+    /// number of clicks in quick succession, 2 selects a word and 3 a line
+    pub click_count: usize,
+
+    pub key_repeat: KeyRepeat,
+    pub sequence: SequenceInput,
+    pub normal_keys: Option<NormalKeys>,
+    pub auto_save: AutoSave,
+    /// time the swap files were last written at
+    pub swap_time: f32,
+}
+
+impl State {
+    /// Create the state with the keymap and an editor without any buffers
+    pub fn new(font: Option<Font>, keymap: Keymap) -> Self {
+        let options = Options::default();
+        let highlighter = Highlighter::default();
+        let theme = highlighter
+            .get_theme(&options.theme)
+            .cloned()
+            .unwrap_or_default();
+
+        State {
+            font,
+            line_height: 16.0,
+            command_box_padding: 8.0,
+            highlighter,
+            theme,
+
+            editor: Editor::new(),
+            register: Register::default(),
+            command_line: String::new(),
+            search_query: String::new(),
+            search_forward: true,
+            message: None,
+
+            mode: Mode::Normal,
+
+            action: None,
+            pending: None,
+            count: None,
+            block_insert: false,
+            received_character: None,
+            keymap,
+            commands: create_commands(),
+
+            options,
+
+            scroll_top: 0,
+            scroll_off: 4,
+            visible_lines: 0,
+            scroll_column: 0,
+            color_columns: Vec::new(),
+            side_scroll_off: 8,
+            scroll_x: 0.0,
+            last_cursor: 0,
+            char_width: 0.0,
+            text_origin: (0.0, 0.0),
+            pane_area: (0.0, 0.0, 0.0, 0.0),
+            gutter_width: 0.0,
+            wrap_width: None,
+
+            dragging: false,
+            last_click_time: 0.0,
+            click_count: 0,
+
+            key_repeat: KeyRepeat::default(),
+            sequence: SequenceInput::default(),
+            normal_keys: None,
+            auto_save: AutoSave::default(),
+            swap_time: 0.0,
+        }
+    }
+
+    /// Show feedback from the last command in place of the previous message
+    pub fn set_message(&mut self, text: String) {
+        self.show(text, Severity::Info);
+    }
+
+    /// Show why the last command failed in place of the previous message
+    pub fn set_error(&mut self, text: String) {
+        self.show(text, Severity::Error);
+    }
+
+    fn show(&mut self, text: String, severity: Severity) {
+        let time = Instant::now();
+        self.message = Some(Message {
+            text,
+            severity,
+            time,
+        });
+    }
+
+    /// Return the font the text is drawn with, which is only left out where nothing is drawn
+    pub fn font(&self) -> &Font {
+        self.font.as_ref().unwrap()
+    }
+
+    /// Return the view of the focused pane, to keep it while another one is shown
+    pub fn get_pane(&self) -> Pane {
+        Pane {
+            buffer_index: self.editor.current_buffer_index,
+            cursor: self.editor.buffer().cursor,
+            scroll_top: self.scroll_top,
+            scroll_column: self.scroll_column,
+            scroll_x: self.scroll_x,
+            last_cursor: self.last_cursor,
+            visible_lines: self.visible_lines,
+            text_origin: self.text_origin,
+            wrap_width: self.wrap_width,
+            gutter_width: self.gutter_width,
+            area: self.pane_area,
+        }
+    }
+
+    /// Show the view in the focused pane. the cursor is only kept inside the text, so that
+    ///     showing a view for drawing it and then the previous one again does not move the
+    ///     cursor
+    pub fn set_pane(&mut self, pane: Pane) {
+        self.editor.current_buffer_index = pane.buffer_index.min(self.editor.buffers.len() - 1);
+        let buffer = self.editor.buffer_mut();
+        buffer.cursor = pane.cursor.min(buffer.text.len_chars());
+        self.scroll_top = pane.scroll_top;
+        self.scroll_column = pane.scroll_column;
+        self.scroll_x = pane.scroll_x;
+        self.last_cursor = pane.last_cursor;
+        self.visible_lines = pane.visible_lines;
+        self.text_origin = pane.text_origin;
+        self.wrap_width = pane.wrap_width;
+        self.gutter_width = pane.gutter_width;
+        self.pane_area = pane.area;
+    }
+
+    /// Show the view of the other pane in place of the focused one, which is kept in the split
+    ///     instead
+    pub fn swap_panes(&mut self) {
+        let pane = self.get_pane();
+        let Some(split) = self.editor.split.as_mut() else {
+            return;
+        };
+        split.focus_first = !split.focus_first;
+        let other = std::mem::replace(&mut split.other, pane);
+        self.set_pane(other);
+    }
+
+    /// Divide the window into two panes that show the current buffer, the top or left one of
+    ///     which gets the focus
+    pub fn split(&mut self, direction: SplitDirection) -> Result<(), String> {
+        if self.editor.split.is_some() {
+            return Err(String::from("Only one split is supported"));
+        }
+        self.editor.split = Some(Split {
+            direction,
+            other: self.get_pane(),
+            focus_first: true,
+        });
+        Ok(())
+    }
+
+    /// Open a tab page after the current one, which shows the current buffer until another one
+    ///     is opened in it
+    pub fn new_tab(&mut self) {
+        let tab = TabPage {
+            pane: self.get_pane(),
+            split: self.editor.split.take(),
+        };
+        self.editor.tabs.insert(self.editor.current_tab, tab);
+        self.editor.current_tab += 1;
+        self.scroll_top = 0;
+        self.scroll_column = 0;
+        self.scroll_x = 0.0;
+    }
+
+    /// Show the tab page at the index, counting the shown one
+    pub fn switch_tab(&mut self, index: usize) {
+        if index == self.editor.current_tab || index > self.editor.tabs.len() {
+            return;
+        }
+        let tab = TabPage {
+            pane: self.get_pane(),
+            split: self.editor.split.take(),
+        };
+        self.editor.tabs.insert(self.editor.current_tab, tab);
+        let tab = self.editor.tabs.remove(index);
+        self.editor.current_tab = index;
+        self.show_tab(tab);
+    }
+
+    /// Ask whether to replace the current match of the substitution, moving the cursor to it,
+    ///     or finish the substitution if there is none
+    pub fn confirm_substitution(&mut self, substitution: Substitution) {
+        let Some((range, text)) = &substitution.current else {
+            self.finish_substitution(substitution);
+            return;
+        };
+        let buffer = self.editor.buffer_mut();
+        buffer.cursor = range.start;
+        buffer.desired_column = None;
+        self.set_message(format!(
+            "replace with {} (y/n/a/q)?",
+            text.replace('\n', "\\n")
+        ));
+        self.pending = Some(PendingInput::ConfirmSubstitution(substitution));
+    }
+
+    /// Stop asking about the matches of the substitution and report how many were replaced
+    pub fn finish_substitution(&mut self, substitution: Substitution) {
+        self.editor.buffer_mut().clamp_cursor();
+        self.set_message(match substitution.count {
+            1 => String::from("1 substitution"),
+            count => format!("{} substitutions", count),
+        });
+    }
+
+    pub fn next_tab(&mut self) {
+        let count = self.editor.tabs.len() + 1;
+        self.switch_tab((self.editor.current_tab + 1) % count);
+    }
+
+    pub fn previous_tab(&mut self) {
+        let count = self.editor.tabs.len() + 1;
+        self.switch_tab((self.editor.current_tab + count - 1) % count);
+    }
+
+    /// Close the shown tab page and show the one after it, or the one before it if it was the
+    ///     last
+    pub fn close_tab(&mut self) {
+        if self.editor.tabs.is_empty() {
+            return;
+        }
+        let index = self.editor.current_tab.min(self.editor.tabs.len() - 1);
+        let tab = self.editor.tabs.remove(index);
+        self.editor.current_tab = index;
+        self.show_tab(tab);
+    }
+
+    fn show_tab(&mut self, tab: TabPage) {
+        self.set_pane(tab.pane);
+        self.editor.split = tab.split;
+        self.editor.buffer_mut().clamp_cursor();
+    }
+
+    /// Close the focused pane, giving the whole window to the other one
+    pub fn close_pane(&mut self) {
+        if self.editor.split.is_some() {
+            self.swap_panes();
+            self.editor.split = None;
+            self.editor.buffer_mut().clamp_cursor();
+        }
+    }
+
+    /// Highlight with the theme of the name, keeping the current theme if there is no such one
+    pub fn set_theme(&mut self, name: &str) -> Result<(), String> {
+        let theme = self
+            .highlighter
+            .get_theme(name)
+            .ok_or(format!("Unknown theme: {}", name))?;
+        self.theme = theme.clone();
+        self.options.theme = name.to_string();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_show_their_names_in_capitals() {
+        assert_eq!(Mode::Normal.to_string(), "NORMAL");
+        assert_eq!(Mode::Insert.to_string(), "INSERT");
+        assert_eq!(Mode::Search.to_string(), "SEARCH");
+    }
 
-    pub last_time: f32,
-    pub initial_movement_delay: f32,
-    pub inter_movement_delay: f32,
+    #[test]
+    fn relative_numbers_count_from_the_cursor_line() {
+        let mut options = Options::default();
+        assert_eq!(options.get_line_number(4, 2), 5);
+        options.relative_number = true;
+        assert_eq!(options.get_line_number(4, 2), 2);
+        assert_eq!(options.get_line_number(0, 2), 2);
+        // the cursor's line shows its own number, or zero without `number`
+        assert_eq!(options.get_line_number(2, 2), 3);
+        options.number = false;
+        assert_eq!(options.get_line_number(2, 2), 0);
+    }
+
+    #[test]
+    fn history_skips_empty_and_repeated_lines() {
+        let mut history = History::default();
+        history.add("w");
+        history.add("");
+        history.add("w");
+        history.add("q");
+        assert_eq!(history.entries, vec!["w", "q"]);
+    }
+
+    #[test]
+    fn history_returns_to_the_draft() {
+        let mut history = History::default();
+        assert_eq!(history.previous("draft"), None);
+        assert_eq!(history.next(), None);
+
+        history.add("w");
+        history.add("q");
+        assert_eq!(history.previous("dra").as_deref(), Some("q"));
+        assert_eq!(history.previous("q").as_deref(), Some("w"));
+        assert_eq!(history.previous("w"), None);
+        assert_eq!(history.next().as_deref(), Some("q"));
+        assert_eq!(history.next().as_deref(), Some("dra"));
+        assert_eq!(history.next(), None);
+    }
+
+    #[test]
+    fn history_keeps_the_newest_entries() {
+        let mut history = History::default();
+        for index in 0..MAX_HISTORY + 2 {
+            history.add(&index.to_string());
+        }
+        assert_eq!(history.entries.len(), MAX_HISTORY);
+        assert_eq!(history.entries[0], "2");
+    }
+
+    #[test]
+    fn jumps_go_back_and_forward_through_the_list() {
+        let mut jumps = JumpList::default();
+        jumps.push((0, 1));
+        jumps.push((0, 1));
+        jumps.push((1, 5));
+        // the current position is kept, so that going forward returns to it
+        assert_eq!(jumps.back((1, 9)), Some((1, 5)));
+        assert_eq!(jumps.back((1, 5)), Some((0, 1)));
+        assert_eq!(jumps.back((0, 1)), None);
+        assert_eq!(jumps.forward(), Some((1, 5)));
+        assert_eq!(jumps.forward(), Some((1, 9)));
+        assert_eq!(jumps.forward(), None);
+
+        // a new jump drops the entries that were gone back from
+        jumps.back((1, 9));
+        jumps.push((0, 7));
+        assert_eq!(jumps.back((0, 8)), Some((0, 7)));
+        assert_eq!(jumps.back((0, 7)), Some((0, 1)));
+    }
+
+    #[test]
+    fn closing_a_buffer_forgets_its_jumps() {
+        let mut jumps = JumpList::default();
+        for position in [(0, 1), (1, 2), (2, 3)] {
+            jumps.push(position);
+        }
+        jumps.remove_buffer(1);
+        assert_eq!(jumps.entries, vec![(0, 1), (1, 3)]);
+    }
+
+    #[test]
+    fn new_editor_has_no_buffers_until_one_is_added() {
+        let mut editor = Editor::new();
+        assert!(editor.buffers.is_empty());
+        assert!(editor.quick_menu.line.is_empty());
+        editor.add_buffer(Rope::from_str("a\n"), Some(String::from("notes.txt")));
+        assert_eq!(editor.buffer().filepath.as_deref(), Some("notes.txt"));
+        assert_eq!(editor.current_buffer_index, 0);
+    }
+
+    #[test]
+    fn closing_the_only_buffer_leaves_an_empty_one() {
+        let mut editor = Editor::new();
+        editor.add_buffer(Rope::from_str("a"), None);
+        editor.add_buffer(Rope::from_str("b"), None);
+        editor.remove_buffer();
+        assert_eq!(editor.buffers.len(), 1);
+        assert_eq!(editor.buffer().text.to_string(), "a");
+
+        editor.remove_buffer();
+        assert_eq!(editor.buffers.len(), 1);
+        assert_eq!(editor.buffer().text.len_chars(), 0);
+    }
+
+    #[test]
+    fn quick_menu_selection_wraps_around() {
+        let candidates = vec![String::from("a"), String::from("b"), String::from("c")];
+        let mut quick_menu = QuickMenu::new(QuickMenuKind::Buffers, candidates, 0);
+        quick_menu.move_selection(-1);
+        assert_eq!(quick_menu.selected, 2);
+        quick_menu.move_selection(2);
+        assert_eq!(quick_menu.selected, 1);
+
+        // a typed number selects that buffer, unless there is none with that number
+        quick_menu.line = String::from("3");
+        quick_menu.update_entries();
+        assert_eq!(quick_menu.selected, 2);
+        quick_menu.line = String::from("4");
+        quick_menu.update_entries();
+        assert_eq!(quick_menu.selected, 2);
+
+        let mut empty = QuickMenu::new(QuickMenuKind::Files, Vec::new(), 0);
+        empty.move_selection(1);
+        assert_eq!(empty.selected, 0);
+    }
+
+    #[test]
+    fn buffers_are_cycled_through_in_order() {
+        let mut editor = Editor::new();
+        editor.next_buffer();
+        editor.previous_buffer();
+        for name in ["a.txt", "b.txt", "c.txt"] {
+            editor.add_buffer(Rope::new(), Some(format!("dir/{}", name)));
+        }
+        assert_eq!(editor.current_buffer_index, 2);
+        editor.next_buffer();
+        assert_eq!(editor.buffer().get_name(), "a.txt");
+        editor.previous_buffer();
+        assert_eq!(editor.buffer().get_name(), "c.txt");
+        editor.previous_buffer();
+        assert_eq!(editor.buffer().get_name(), "b.txt");
+
+        editor.add_buffer(Rope::new(), None);
+        assert_eq!(editor.buffer().get_name(), "[No Name]");
+    }
+
+    #[test]
+    fn indentation_unit_follows_expand_tab() {
+        let mut options = Options {
+            shift_width: 2,
+            ..Options::default()
+        };
+        assert_eq!(options.get_indentation_unit(), "  ");
+        options.expand_tab = false;
+        assert_eq!(options.get_indentation_unit(), "\t");
+    }
+
+    #[test]
+    fn switching_buffers_keeps_the_cursor_in_the_text() {
+        let mut editor = Editor::new();
+        editor.add_buffer(Rope::from_str("abc\ndef\n"), None);
+        editor.buffer_mut().cursor = 7;
+        editor.buffer_mut().cursors = vec![3, 5];
+        editor.add_buffer(Rope::new(), None);
+
+        // the text of the first buffer got shorter while it was in the background
+        editor.buffers[0].text = Rope::from_str("ab\n");
+        editor.switch_buffer(0);
+        assert_eq!(editor.buffer().cursor, 0);
+        // the cursors that end up on the same character are merged
+        assert!(editor.buffer().cursors.is_empty());
+
+        editor.switch_buffer(5);
+        assert_eq!(editor.current_buffer_index, 1);
+        assert_eq!(editor.buffer().cursor, 0);
+    }
+
+    #[test]
+    fn panes_keep_their_own_buffer_and_cursor() {
+        let mut state = State::new(None, crate::keymap::create_keymap());
+        state.editor.add_buffer(Rope::from_str("abc\n"), None);
+        state.editor.buffer_mut().cursor = 2;
+        state.split(SplitDirection::Vertical).unwrap();
+        assert!(state.split(SplitDirection::Horizontal).is_err());
+
+        state.editor.add_buffer(Rope::from_str("de\n"), None);
+        state.editor.buffer_mut().cursor = 1;
+        state.swap_panes();
+        assert_eq!(state.editor.current_buffer_index, 0);
+        assert_eq!(state.editor.buffer().cursor, 2);
+        assert!(!state.editor.split.as_ref().unwrap().focus_first);
+
+        // closing the focused pane leaves the other one
+        state.close_pane();
+        assert!(state.editor.split.is_none());
+        assert_eq!(state.editor.current_buffer_index, 1);
+        assert_eq!(state.editor.buffer().cursor, 1);
+    }
+
+    #[test]
+    fn tab_pages_keep_their_panes_in_order() {
+        let mut state = State::new(None, crate::keymap::create_keymap());
+        state.editor.add_buffer(Rope::from_str("abc\n"), None);
+        state.split(SplitDirection::Horizontal).unwrap();
+        state.new_tab();
+        assert!(state.editor.split.is_none());
+        state.editor.add_buffer(Rope::from_str("de\n"), None);
+        state.new_tab();
+        assert_eq!(state.editor.current_tab, 2);
+
+        state.next_tab();
+        assert_eq!(state.editor.current_tab, 0);
+        assert_eq!(state.editor.current_buffer_index, 0);
+        assert!(state.editor.split.is_some());
+        state.previous_tab();
+        assert_eq!(state.editor.current_tab, 2);
+
+        // closing the last tab page shows the one before it
+        state.close_tab();
+        assert_eq!(state.editor.current_tab, 1);
+        assert_eq!(state.editor.tabs.len(), 1);
+        assert_eq!(state.editor.current_buffer_index, 1);
+    }
+
+    #[test]
+    fn held_keys_repeat_on_their_own_timers() {
+        let mut repeat = KeyRepeat::default();
+        assert!(repeat.is_triggered(KeyCode::J, true, 0.0, 1.0));
+        // not held long enough yet, then held but within the interval
+        assert!(!repeat.is_triggered(KeyCode::J, false, 0.003, 1.01));
+        assert!(!repeat.is_triggered(KeyCode::J, false, 0.02, 1.03));
+        assert!(repeat.is_triggered(KeyCode::J, false, 0.1, 1.06));
+
+        // another key held at the same time is not slowed down by the first
+        assert!(repeat.is_triggered(KeyCode::K, false, 0.1, 1.07));
+        assert!(!repeat.is_triggered(KeyCode::J, false, 0.1, 1.08));
+        assert!(!repeat.is_triggered(KeyCode::L, false, 0.0, 1.08));
+    }
+
+    #[test]
+    fn shortcuts_match_exactly_their_modifiers() {
+        let shortcut = Shortcut::new(KeyCode::P).ctrl().shift();
+        assert!(shortcut.ctrl && shortcut.shift && !shortcut.alt);
+        assert!(shortcut == Shortcut::new(KeyCode::P).shift().ctrl());
+
+        assert!(shortcut.matches_modifiers(true, true, false));
+        // a modifier that is held but not part of the shortcut stops it from matching
+        assert!(!shortcut.matches_modifiers(true, true, true));
+        assert!(!shortcut.matches_modifiers(false, true, false));
+        assert!(Shortcut::new(KeyCode::P).matches_modifiers(false, false, false));
+        assert!(!Shortcut::new(KeyCode::P).matches_modifiers(false, true, false));
+    }
+
+    fn is_match(options: &Options, query: &str, text: &str) -> bool {
+        options
+            .compile_search(query)
+            .is_some_and(|pattern| pattern.is_match(text))
+    }
+
+    #[test]
+    fn search_options_change_what_matches() {
+        let mut options = Options::default();
+        assert!(options.compile_search("").is_none());
+        assert!(options.compile_search("\\<\\>").is_none());
+        assert!(!is_match(&options, "foo", "FOO"));
+
+        options.ignore_case = true;
+        assert!(is_match(&options, "foo", "FOO"));
+        options.smart_case = true;
+        assert!(is_match(&options, "foo", "FOO"));
+        assert!(!is_match(&options, "Foo", "FOO"));
+    }
+
+    #[test]
+    fn search_queries_are_regular_expressions_unless_invalid() {
+        let mut options = Options::default();
+        assert!(is_match(&options, "a.c", "abc"));
+        // an unfinished group, as while typing, is matched literally
+        assert!(is_match(&options, "f(o", "f(o"));
+
+        options.magic = false;
+        assert!(!is_match(&options, "a.c", "abc"));
+        assert!(is_match(&options, "a.c", "a.c"));
+    }
+
+    #[test]
+    fn search_word_boundaries() {
+        let options = Options::default();
+        assert!(is_match(&options, "\\<foo\\>", "a foo b"));
+        assert!(!is_match(&options, "\\<foo\\>", "foobar"));
+        assert!(is_match(&options, "\\<foo", "foobar"));
+        assert!(!is_match(&options, "foo\\>", "foobar"));
+    }
+
+    fn key(key: KeyCode) -> Shortcut {
+        Shortcut::new(key)
+    }
+
+    fn command(line: &str) -> SequenceCommand {
+        SequenceCommand::Command(line.to_string())
+    }
+
+    fn sequence_bindings() -> SequenceBindings {
+        let g = SequenceKey::Key(key(KeyCode::G));
+        let d = SequenceKey::Key(key(KeyCode::D));
+        let w = SequenceKey::Key(key(KeyCode::W));
+        let mut bindings = SequenceBindings::default();
+        bindings.insert(&[g, g], SequenceCommand::FirstLine);
+        bindings.insert(&[SequenceKey::Leader, w], command("w"));
+        bindings.insert(&[SequenceKey::Leader], command("ls"));
+        bindings.insert(&[g, d], command("definition"));
+        bindings
+    }
+
+    #[test]
+    fn sequences_match_prefixes_and_whole_sequences() {
+        let bindings = sequence_bindings();
+        let leader = key(KeyCode::Space);
+        let find = |keys: &[Shortcut]| bindings.find(keys, leader, false);
+
+        assert!(matches!(find(&[]), SequenceMatch::None));
+        assert!(matches!(find(&[key(KeyCode::X)]), SequenceMatch::None));
+        assert!(matches!(find(&[key(KeyCode::G)]), SequenceMatch::Prefix));
+        assert!(matches!(
+            find(&[key(KeyCode::G), key(KeyCode::G)]),
+            SequenceMatch::Complete(SequenceCommand::FirstLine)
+        ));
+        assert!(matches!(
+            find(&[key(KeyCode::G), key(KeyCode::X)]),
+            SequenceMatch::None
+        ));
+        // the shifted key is a different one
+        assert!(matches!(
+            find(&[key(KeyCode::G), key(KeyCode::G).shift()]),
+            SequenceMatch::None
+        ));
+    }
+
+    #[test]
+    fn ambiguous_sequences_wait_for_the_next_key() {
+        let bindings = sequence_bindings();
+        let leader = key(KeyCode::Space);
+
+        // on a timeout the command of the shorter sequence runs
+        match bindings.find(&[leader], leader, false) {
+            SequenceMatch::Ambiguous(found) => assert!(found == command("ls")),
+            _ => panic!("expected an ambiguous match"),
+        }
+        match bindings.find(&[leader, key(KeyCode::W)], leader, false) {
+            SequenceMatch::Complete(found) => assert!(found == command("w")),
+            _ => panic!("expected a complete match"),
+        }
+    }
+
+    #[test]
+    fn sequences_follow_the_leader() {
+        let bindings = sequence_bindings();
+        let leader = key(KeyCode::Comma);
+        assert!(matches!(
+            bindings.find(&[key(KeyCode::Space)], leader, false),
+            SequenceMatch::None
+        ));
+        assert!(matches!(
+            bindings.find(&[leader, key(KeyCode::W)], leader, false),
+            SequenceMatch::Complete(_)
+        ));
+    }
+
+    #[test]
+    fn built_in_only_leaves_out_command_lines() {
+        let bindings = sequence_bindings();
+        let leader = key(KeyCode::Space);
+        let find = |keys: &[Shortcut]| bindings.find(keys, leader, true);
+
+        assert!(matches!(find(&[leader]), SequenceMatch::None));
+        assert!(matches!(
+            find(&[key(KeyCode::G), key(KeyCode::D)]),
+            SequenceMatch::None
+        ));
+        assert!(matches!(find(&[key(KeyCode::G)]), SequenceMatch::Prefix));
+        assert!(matches!(
+            find(&[key(KeyCode::G), key(KeyCode::G)]),
+            SequenceMatch::Complete(SequenceCommand::FirstLine)
+        ));
+    }
+
+    #[test]
+    fn inserting_a_sequence_replaces_its_command() {
+        let mut bindings = sequence_bindings();
+        let g = SequenceKey::Key(key(KeyCode::G));
+        bindings.insert(&[g, g], command("1"));
+
+        let keys = [key(KeyCode::G), key(KeyCode::G)];
+        match bindings.find(&keys, key(KeyCode::Space), false) {
+            SequenceMatch::Complete(found) => assert!(found == command("1")),
+            _ => panic!("expected a complete match"),
+        }
+        assert!(matches!(
+            bindings.find(&keys, key(KeyCode::Space), true),
+            SequenceMatch::None
+        ));
+    }
+
+    #[test]
+    fn substitutions_go_through_the_matches_one_at_a_time() {
+        let mut buffer = Buffer::new(Rope::from_str("aa a\nx a\n"), None);
+        let regex = Regex::new("a").unwrap();
+        let mut substitution = Substitution::new(regex.clone(), String::from("b"), true, 0, 0);
+        assert!(substitution.find_next(&buffer));
+        substitution.replace(&mut buffer);
+        assert!(substitution.find_next(&buffer));
+        substitution.skip(&buffer);
+        assert!(substitution.find_next(&buffer));
+        substitution.replace(&mut buffer);
+        // the matches after the range are left alone
+        assert!(!substitution.find_next(&buffer));
+        assert_eq!(buffer.text.to_string(), "ba b\nx a\n");
+        assert_eq!(substitution.count, 2);
+    }
+
+    #[test]
+    fn substitutions_without_g_go_on_after_the_lines_they_add() {
+        let mut buffer = Buffer::new(Rope::from_str("a a\na\n"), None);
+        let regex = Regex::new("a").unwrap();
+        let mut substitution = Substitution::new(regex, String::from("c\n"), false, 0, 1);
+        assert!(substitution.find_next(&buffer));
+        substitution.replace(&mut buffer);
+        assert_eq!(substitution.last_line, 2);
+        // the rest of the line is skipped, which now starts after the added line
+        assert!(substitution.find_next(&buffer));
+        let current = substitution.current.as_ref();
+        assert_eq!(current.map(|(range, _)| range.start), Some(5));
+        substitution.skip(&buffer);
+        assert!(!substitution.find_next(&buffer));
+        assert_eq!(buffer.text.to_string(), "c\n a\na\n");
+        assert_eq!(substitution.count, 1);
+    }
+
+    #[test]
+    fn changes_are_due_to_be_saved_after_the_delay_or_when_flushed() {
+        let mut auto_save = AutoSave::default();
+        let mut buffer = Buffer::new(Rope::from_str("a\n"), Some(String::from("a.txt")));
+        assert!(!auto_save.is_due(0, &buffer, 0.0, 2.0));
+
+        buffer.insert(0, "b");
+        assert!(!auto_save.is_due(0, &buffer, 1.0, 2.0));
+        assert!(!auto_save.is_due(0, &buffer, 2.5, 2.0));
+        assert!(auto_save.is_due(0, &buffer, 3.0, 2.0));
+        // saved once until the next change
+        assert!(!auto_save.is_due(0, &buffer, 9.0, 2.0));
+
+        buffer.insert(0, "c");
+        assert!(!auto_save.is_due(0, &buffer, 10.0, 2.0));
+        auto_save.flush = true;
+        assert!(auto_save.is_due(0, &buffer, 10.5, 2.0));
+
+        // a buffer without a file is left alone
+        buffer.filepath = None;
+        buffer.insert(0, "d");
+        auto_save.flush = true;
+        assert!(!auto_save.is_due(0, &buffer, 11.0, 2.0));
+    }
 }
@@ -0,0 +1,107 @@
+use crate::buffer::{Buffer, Cursor};
+
+/// A span of text around the cursor that an operator can be applied to, like the word in `diw`
+#[derive(Debug, Clone, PartialEq)]
+pub enum TextObject {
+    Word,
+    BigWord,
+    /// text between a pair of brackets, which may be nested
+    Brackets(char, char),
+    /// text between a pair of quotes on the same line
+    Quotes(char),
+}
+
+impl TextObject {
+    /// Return the text object typed after `i` or `a`, if the character names one
+    pub fn from_character(character: char) -> Option<Self> {
+        let object = match character {
+            'w' => TextObject::Word,
+            'W' => TextObject::BigWord,
+            '(' | ')' | 'b' => TextObject::Brackets('(', ')'),
+            '[' | ']' => TextObject::Brackets('[', ']'),
+            '{' | '}' | 'B' => TextObject::Brackets('{', '}'),
+            '<' | '>' => TextObject::Brackets('<', '>'),
+            '"' | '\'' | '`' => TextObject::Quotes(character),
+            _ => return None,
+        };
+        Some(object)
+    }
+
+    /// Return the range of the object at the cursor, excluding the surrounding whitespace or
+    ///     delimiters if `inner` is set, or None if the cursor is not in such an object
+    pub fn get_range(&self, buffer: &Buffer, inner: bool) -> Option<(Cursor, Cursor)> {
+        let cursor = buffer.cursor;
+        match *self {
+            TextObject::Word => Some(buffer.get_word_object_range(cursor, false, !inner)),
+            TextObject::BigWord => Some(buffer.get_word_object_range(cursor, true, !inner)),
+            TextObject::Brackets(open, close) => {
+                buffer.get_bracket_range(cursor, open, close, inner)
+            }
+            TextObject::Quotes(quote) => buffer.get_quote_range(cursor, quote, inner),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ropey::Rope;
+
+    /// Return the text of the object typed as `character` at the cursor
+    fn object(text: &str, cursor: Cursor, character: char, inner: bool) -> Option<String> {
+        let mut buffer = Buffer::new(Rope::from_str(text), None);
+        buffer.cursor = cursor;
+        let (start, end) = TextObject::from_character(character)?.get_range(&buffer, inner)?;
+        Some(buffer.text.slice(start..end).to_string())
+    }
+
+    #[test]
+    fn word_objects_take_the_whitespace_after_or_before() {
+        let text = "foo bar.baz\n";
+        assert_eq!(object(text, 1, 'w', true).as_deref(), Some("foo"));
+        assert_eq!(object(text, 1, 'w', false).as_deref(), Some("foo "));
+        // without whitespace after the word, the whitespace before it is taken
+        assert_eq!(object(text, 5, 'w', false).as_deref(), Some(" bar"));
+        assert_eq!(object(text, 5, 'W', false).as_deref(), Some(" bar.baz"));
+        // on whitespace, `aw` takes the word after it
+        assert_eq!(object(text, 3, 'w', false).as_deref(), Some(" bar"));
+        assert_eq!(object("", 0, 'w', true).as_deref(), Some(""));
+    }
+
+    #[test]
+    fn bracket_objects_skip_nested_pairs() {
+        let text = "f(a, (b), c)\n";
+        assert_eq!(object(text, 3, '(', true).as_deref(), Some("a, (b), c"));
+        assert_eq!(object(text, 3, 'b', false).as_deref(), Some("(a, (b), c)"));
+        assert_eq!(object(text, 6, ')', true).as_deref(), Some("b"));
+        // on a closing bracket, its own pair is used
+        assert_eq!(object(text, 7, ')', false).as_deref(), Some("(b)"));
+        assert_eq!(object(text, 1, '(', true).as_deref(), Some("a, (b), c"));
+        assert_eq!(object(text, 0, '(', true), None);
+        assert_eq!(
+            object("{\n  a\n}\n", 4, 'B', true).as_deref(),
+            Some("\n  a\n")
+        );
+        assert_eq!(object("", 0, '[', true), None);
+    }
+
+    #[test]
+    fn quote_objects_pair_quotes_from_the_start_of_the_line() {
+        let text = "a \"b \\\"c\" d 'e'\n";
+        assert_eq!(object(text, 3, '"', true).as_deref(), Some("b \\\"c"));
+        assert_eq!(object(text, 3, '"', false).as_deref(), Some("\"b \\\"c\""));
+        // before the quotes, the next quoted text is used
+        assert_eq!(object(text, 0, '\'', true).as_deref(), Some("e"));
+        assert_eq!(object(text, 15, '"', true), None);
+        assert_eq!(object("", 0, '"', true), None);
+    }
+
+    #[test]
+    fn other_characters_are_not_objects() {
+        assert_eq!(TextObject::from_character('x'), None);
+        assert_eq!(
+            TextObject::from_character(']'),
+            Some(TextObject::Brackets('[', ']'))
+        );
+    }
+}